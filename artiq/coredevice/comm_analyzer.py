@@ -48,18 +48,52 @@ class WaveformType(Enum):
     LOG = 3
 
 
-def get_analyzer_dump(host, port=1382):
+def _recv_exactly(sock, length):
+    r = bytes()
+    while len(r) < length:
+        buf = sock.recv(min(8192, length - len(r)))
+        if not buf:
+            raise ConnectionError("analyzer connection closed prematurely")
+        r += buf
+    return r
+
+
+def _read_header(sock, endian):
+    version = _recv_exactly(sock, 1)[0]
+    if version == 1:
+        fields = struct.unpack(endian + "IQbbbI", _recv_exactly(sock, 19))
+        # extension fields of newer firmware
+        extension_length = struct.unpack(endian + "H", _recv_exactly(sock, 2))[0]
+        _recv_exactly(sock, extension_length)
+        return fields
+    else:
+        raise ValueError("unsupported analyzer header version {}".format(version))
+
+
+def get_analyzer_dump(host, port=1382, max_bytes=0):
+    """Retrieves the analyzer capture and returns it as a single dump.
+    With a non-zero ``max_bytes``, it is pulled in installments of at most
+    that many bytes; otherwise the core device sends it all at once."""
     sock = socket.create_connection((host, port))
     try:
-        r = bytes()
+        if max_bytes:
+            sock.sendall(b"i")
+        endian_byte = _recv_exactly(sock, 1)
+        endian = "<" if endian_byte == b"e" else ">"
+        data = bytes()
         while True:
-            buf = sock.recv(8192)
-            if not buf:
+            if max_bytes:
+                sock.sendall(struct.pack(endian + "I", max_bytes))
+            (sent_bytes, total_byte_count, error_occurred, log_channel,
+             dds_onehot_sel, remaining_bytes) = _read_header(sock, endian)
+            data += _recv_exactly(sock, sent_bytes)
+            if not remaining_bytes:
                 break
-            r += buf
     finally:
         sock.close()
-    return r
+    header = struct.pack(endian + "IQbbb", len(data), total_byte_count,
+                         error_occurred, log_channel, dds_onehot_sel)
+    return endian_byte + header + data
 
 
 OutputMessage = namedtuple(
@@ -191,6 +191,7 @@ class CommKernel:
         self.port = port
         self.read_buffer = bytearray()
         self.write_buffer = bytearray()
+        self.kernel_metadata = b""
 
 
     def open(self):
@@ -384,6 +385,7 @@ class CommKernel:
             raise LoadError(self._read_string())
         else:
             self._read_expect(Reply.LoadCompleted)
+            self.kernel_metadata = self._read_bytes()
 
     def upload_subkernel(self, kernel_library, id, destination):
         self._write_header(Request.SubkernelUpload)
@@ -397,6 +399,7 @@ class CommKernel:
             raise LoadError(self._read_string())
         else:
             self._read_expect(Reply.LoadCompleted)
+            self._read_bytes()
 
     def run(self):
         self._write_empty(Request.RunKernel)
@@ -670,6 +673,9 @@ class CommKernel:
             filename = read_exception_string()
             line = self._read_int32()
             column = self._read_int32()
+            if self._read_bool():
+                logger.warning("exception %s carries implausible source "
+                               "location %d:%d", name, line, column)
             function = read_exception_string()
             nested_exceptions.append([name, message, params,
                                       filename, line, column, function])
@@ -731,7 +737,9 @@ class CommKernel:
             elif self._read_type == Reply.KernelException:
                 self._serve_exception(embedding_map, symbolizer, demangler)
             elif self._read_type == Reply.ClockFailure:
-                raise exceptions.ClockFailure
+                lost_at_us = self._read_int64()
+                raise exceptions.ClockFailure(
+                    "RTIO PLL lost lock at {} us".format(lost_at_us))
             else:
                 self._read_expect(Reply.KernelFinished)
                 self._process_async_error()
@@ -22,6 +22,11 @@ class Request(Enum):
     Reboot = 5
 
     DebugAllocator = 8
+    CsrRead = 17
+    CsrWrite = 18
+    StabilityReport = 19
+    Diagnostics = 20
+    GetLogRecords = 21
 
 
 class Reply(Enum):
@@ -35,6 +40,11 @@ class Reply(Enum):
 
     RebootImminent = 3
 
+    CsrData = 9
+    StabilityReport = 10
+    Diagnostics = 11
+    LogRecords = 12
+
 
 class LogLevel(Enum):
     OFF = 0
@@ -87,6 +97,9 @@ class CommMgmt:
     def _write_int32(self, value):
         self._write(struct.pack(self.endian + "l", value))
 
+    def _write_uint32(self, value):
+        self._write(struct.pack(self.endian + "L", value))
+
     def _write_bytes(self, value):
         self._write_int32(len(value))
         self._write(value)
@@ -119,6 +132,10 @@ class CommMgmt:
         (value, ) = struct.unpack(self.endian + "l", self._read(4))
         return value
 
+    def _read_uint32(self):
+        (value, ) = struct.unpack(self.endian + "L", self._read(4))
+        return value
+
     def _read_bytes(self):
         return self._read(self._read_int32())
 
@@ -141,6 +158,21 @@ class CommMgmt:
         self._read_expect(Reply.LogContent)
         return self._read_string()
 
+    def get_log_records(self):
+        """Returns the (level, timestamp in microseconds, target, message)
+        of every log record written since the previous call on this
+        connection, oldest first. Unlike ``pull_log``, it leaves the log
+        buffer intact."""
+        self._write_header(Request.GetLogRecords)
+        self._read_expect(Reply.LogRecords)
+        records = []
+        for _ in range(self._read_uint32()):
+            level, timestamp = struct.unpack(self.endian + "BQ", self._read(9))
+            target = self._read_string()
+            message = self._read_string()
+            records.append((LogLevel(level), timestamp, target, message))
+        return records
+
     def set_log_level(self, level):
         if level not in LogLevel.__members__:
             raise ValueError("invalid log level {}".format(level))
@@ -194,3 +226,102 @@ class CommMgmt:
 
     def debug_allocator(self):
         self._write_header(Request.DebugAllocator)
+
+    def stability_report(self, reset=False):
+        self._write_header(Request.StabilityReport)
+        self._write_int8(reset)
+
+        self._read_expect(Reply.StabilityReport)
+        boots = self._read_uint32()
+        clean_shutdowns = self._read_uint32()
+        panics = self._read_uint32()
+        last_crash = self._read_bytes().decode("utf-8", "replace")
+        return {
+            "boots": boots,
+            "clean_shutdowns": clean_shutdowns,
+            "panics": panics,
+            "last_crash": last_crash or None
+        }
+
+    def diagnostics(self):
+        self._write_header(Request.Diagnostics)
+
+        self._read_expect(Reply.Diagnostics)
+        version = self._read(1)[0]
+        result = {"version": version}
+        for _ in range(self._read_uint32()):
+            tag = self._read(1)[0]
+            payload = self._read_bytes()
+            if tag == 1:
+                uptime_ms, unix_known, unix_us, rtio_clock_locked = \
+                    struct.unpack(self.endian + "Q?Q?", payload[:18])
+                result["clock"] = {
+                    "uptime_ms": uptime_ms,
+                    "unix_us": unix_us if unix_known else None,
+                    "rtio_clock_locked": rtio_clock_locked
+                }
+            elif tag == 2:
+                (length, ) = struct.unpack(self.endian + "L", payload[:4])
+                result["network"] = payload[4:4+length].decode("utf-8", "replace")
+            elif tag == 3:
+                busy, idle = struct.unpack(self.endian + "LL", payload[:8])
+                result["memory"] = {"busy": busy, "idle": idle}
+            elif tag == 4:
+                boots, clean_shutdowns, panics, length = \
+                    struct.unpack(self.endian + "LLLL", payload[:16])
+                last_crash = payload[16:16+length].decode("utf-8", "replace")
+                result["stability"] = {
+                    "boots": boots,
+                    "clean_shutdowns": clean_shutdowns,
+                    "panics": panics,
+                    "last_crash": last_crash or None
+                }
+            elif tag == 5:
+                tasks = []
+                (count, ) = struct.unpack(self.endian + "L", payload[:4])
+                offset = 4
+                for _ in range(count):
+                    (length, ) = struct.unpack(self.endian + "L", payload[offset:offset+4])
+                    name = payload[offset+4:offset+4+length].decode("utf-8", "replace")
+                    offset += 4 + length
+                    state, stack_high_water, stack_size, run_count, runtime_us = \
+                        struct.unpack(self.endian + "BLLLQ", payload[offset:offset+21])
+                    offset += 21
+                    tasks.append({
+                        "name": name,
+                        "state": ["running", "ready", "sleeping", "dead"][state],
+                        "stack_high_water": stack_high_water,
+                        "stack_size": stack_size,
+                        "run_count": run_count,
+                        "runtime_us": runtime_us
+                    })
+                result["tasks"] = tasks
+            else:
+                logger.debug("ignoring unknown diagnostics section %d", tag)
+        return result
+
+    def _check_csr_reply(self, ty):
+        if ty == Reply.Unavailable:
+            raise IOError("CSR access is disabled (set debug_csr_access to 1)")
+        elif ty == Reply.Error:
+            raise IOError("Invalid CSR address")
+
+    def csr_read(self, addr):
+        self._write_header(Request.CsrRead)
+        self._write_uint32(addr)
+
+        ty = self._read_header()
+        self._check_csr_reply(ty)
+        if ty != Reply.CsrData:
+            raise IOError("Incorrect reply from device: {}".format(ty))
+        return self._read_uint32()
+
+    def csr_write(self, addr, value):
+        self._write_header(Request.CsrWrite)
+        self._write_uint32(addr)
+        self._write_uint32(value)
+
+        ty = self._read_header()
+        self._check_csr_reply(ty)
+        if ty != Reply.Success:
+            raise IOError("Incorrect reply from device: {}".format(ty))
@@ -92,6 +92,12 @@ class CommMonInj:
                     payload = await self._reader.readexactly(6)
                     channel, override, value = struct.unpack("<lbb", payload)
                     self.injection_status_cb(channel, override, value)
+                elif ty == b"\x02":
+                    payload = await self._reader.readexactly(5)
+                    channel, override = struct.unpack("<lb", payload)
+                    logger.warning("cannot inject into channel 0x%06x "
+                                   "(override %d): channel busy by kernel",
+                                   channel, override)
                 else:
                     raise ValueError("Unknown packet type", ty)
         except Exception:
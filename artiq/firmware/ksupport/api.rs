@@ -140,10 +140,14 @@ static mut API: &'static [(&'static str, *const ())] = &[
     api!(cache_get = ::cache_get),
     api!(cache_put = ::cache_put),
 
+    api!(cleanup_register = ::cleanup_register),
+
     /* direct syscalls */
     api!(rtio_init = ::rtio::init),
     api!(rtio_get_destination_status = ::rtio::get_destination_status),
     api!(rtio_get_counter = ::rtio::get_counter),
+    api!(rtio_claim_channel = ::rtio::claim_channel),
+    api!(rtio_release_channel = ::rtio::release_channel),
     api!(rtio_log),
     api!(rtio_output = ::rtio::output),
     api!(rtio_output_wide = ::rtio::output_wide),
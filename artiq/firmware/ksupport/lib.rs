@@ -16,7 +16,6 @@ extern crate riscv;
 
 use core::{mem, ptr, slice, str, convert::TryFrom};
 use cslice::CSlice;
-use io::Cursor;
 use dyld::Library;
 use board_artiq::{mailbox, rpc_queue};
 use proto_artiq::{kernel_proto, rpc_proto};
@@ -25,13 +24,17 @@ use board_misoc::csr;
 use riscv::register::{mcause, mepc, mtval};
 
 fn send(request: &Message) {
+    check_abort();
     unsafe { mailbox::send(request as *const _ as usize) }
     while !mailbox::acknowledged() {}
 }
 
 fn recv<R, F: FnOnce(&Message) -> R>(f: F) -> R {
     let mut msg_ptr = 0;
-    while msg_ptr == 0 { msg_ptr = mailbox::receive(); }
+    while msg_ptr == 0 {
+        check_abort();
+        msg_ptr = mailbox::receive();
+    }
     let result = f(unsafe { &*(msg_ptr as *const Message) });
     mailbox::acknowledge();
     result
@@ -64,6 +67,7 @@ pub fn panic_fmt(info: &core::panic::PanicInfo) -> ! {
     } else {
         send(&Log(format_args!("\n")));
     }
+    run_cleanup();
     send(&RunAborted);
     loop {}
 }
@@ -122,8 +126,38 @@ pub extern fn send_to_rtio_log(text: CSlice<u8>) {
     rtio::log(text.as_ref())
 }
 
+// Only the comms CPU can report a corrupted queue to the host; panicking
+// aborts the kernel and gets the message there.
+fn rpc_queue_empty() -> bool {
+    rpc_queue::empty().unwrap_or_else(|err| panic!("{}", err))
+}
+
+// Deliberately without a timeout: the queue drains as fast as the host takes
+// asynchronous RPCs, and when the comms CPU gives up on the kernel, it resets
+// this CPU. A deadline here would only turn a slow host into a kernel panic.
+fn wait_rpc_queue_empty() {
+    while !rpc_queue_empty() {}
+}
+
+enum AsyncRpcError {
+    Io(io::Error<!>),
+    Queue(rpc_queue::Corrupted)
+}
+
+impl From<io::Error<!>> for AsyncRpcError {
+    fn from(value: io::Error<!>) -> AsyncRpcError {
+        AsyncRpcError::Io(value)
+    }
+}
+
+impl From<rpc_queue::Corrupted> for AsyncRpcError {
+    fn from(value: rpc_queue::Corrupted) -> AsyncRpcError {
+        AsyncRpcError::Queue(value)
+    }
+}
+
 extern fn rpc_send(service: u32, tag: &CSlice<u8>, data: *const *const ()) {
-    while !rpc_queue::empty() {}
+    wait_rpc_queue_empty();
     send(&RpcSend {
         async:   false,
         service: service,
@@ -133,18 +167,18 @@ extern fn rpc_send(service: u32, tag: &CSlice<u8>, data: *const *const ()) {
 }
 
 extern fn rpc_send_async(service: u32, tag: &CSlice<u8>, data: *const *const ()) {
-    while rpc_queue::full() {}
-    rpc_queue::enqueue(|mut slice| {
-        let length = {
-            let mut writer = Cursor::new(&mut slice[4..]);
-            rpc_proto::send_args(&mut writer, service, tag.as_ref(), data, true)?;
-            writer.position()
-        };
-        io::ProtoWrite::write_u32(&mut slice, length as u32)
+    while rpc_queue::full().unwrap_or_else(|err| panic!("{}", err)) {}
+    rpc_queue::enqueue(|slice| -> Result<(), AsyncRpcError> {
+        rpc_queue::write_slot(slice, |writer| {
+            Ok(rpc_proto::send_args(writer, service, tag.as_ref(), data, true)?)
+        })
     }).unwrap_or_else(|err| {
-        assert!(err == io::Error::UnexpectedEnd);
+        match err {
+            AsyncRpcError::Io(err) => assert!(err == io::Error::UnexpectedEof),
+            AsyncRpcError::Queue(err) => panic!("{}", err)
+        }
 
-        while !rpc_queue::empty() {}
+        wait_rpc_queue_empty();
         send(&RpcSend {
             async:   true,
             service: service,
@@ -190,9 +224,42 @@ extern "C-unwind" fn rpc_recv(slot: *mut ()) -> usize {
     })
 }
 
+static mut CLEANUP_HANDLER: CleanupHandler = CleanupHandler::new();
+static mut ABORTING: bool = false;
+
+/// Registers a routine that puts the hardware in a safe state, run if the
+/// kernel is aborted by a panic, an uncaught exception or the comms CPU.
+extern fn cleanup_register(handler: extern fn()) {
+    unsafe { CLEANUP_HANDLER.register(handler) }
+    send(&CleanupRegistered);
+}
+
+fn run_cleanup() {
+    if let Some(handler) = unsafe { CLEANUP_HANDLER.take() } {
+        send(&CleanupStarted);
+        handler()
+    }
+}
+
+/// Aborts the kernel if the comms CPU asked for it, e.g. because the host
+/// disconnected. This is checked whenever the kernel talks to the comms CPU;
+/// a kernel that never does is stopped once the abort times out.
+fn check_abort() {
+    unsafe {
+        if !ABORTING && mailbox::abort_requested() {
+            ABORTING = true;
+            run_cleanup();
+            send(&RunAborted);
+            loop {}
+        }
+    }
+}
+
 fn terminate(exceptions: &'static [Option<eh_artiq::Exception<'static>>],
              stack_pointers: &'static [eh_artiq::StackPointerBacktrace],
              backtrace: &mut [(usize, usize)]) -> ! {
+    run_cleanup();
+    rtio::replay::send_log();
     send(&RunException {
         exceptions,
         stack_pointers,
@@ -251,7 +318,10 @@ extern "C-unwind" fn dma_record_start(name: &CSlice<u8>) {
             raise!("DMAError", "DMA is already recording")
         }
 
-        let library = LIBRARY.as_ref().unwrap();
+        let library = match LIBRARY.as_ref() {
+            Some(library) => library,
+            None => raise!("DMAError", "DMA recording is not available in flat kernels")
+        };
         library.rebind(b"rtio_output",
                        dma_record_output as *const () as u32).unwrap();
         library.rebind(b"rtio_output_wide",
@@ -575,6 +645,44 @@ unsafe fn attribute_writeback(typeinfo: *const ()) {
 
 static mut STACK_GUARD_BASE: usize = 0x0;
 
+enum Payload {
+    Library(Library<'static>),
+    Flat(usize),
+    SelfTest
+}
+
+/// Runs a pre-linked image copied verbatim by LoadFlatRequest. There is no
+/// ELF, so there are no relocations, no BSS to clear and no unwind tables:
+/// the image must be self-contained and must not raise exceptions.
+unsafe fn flat_kernel(entry: usize) -> ! {
+    board_misoc::cache::flush_cpu_dcache();
+    board_misoc::cache::flush_cpu_icache();
+
+    (mem::transmute::<usize, fn()>(entry))();
+
+    send(&RpcFlush);
+    send(&RunFinished);
+
+    loop {}
+}
+
+/// Built-in stand-in for a user kernel, used to check the kernel CPU and
+/// the mailbox path independently of any user code.
+unsafe fn self_test_kernel() -> ! {
+    // Exercise a request/reply round-trip through the comms CPU.
+    send(&CacheGetRequest { key: "__self_test__" });
+    recv!(&CacheGetReply { value } => {
+        if value.is_null() {
+            panic!("self-test kernel: null cache reply");
+        }
+    });
+
+    send(&RpcFlush);
+    send(&RunFinished);
+
+    loop {}
+}
+
 #[no_mangle]
 pub unsafe fn main() {
     eh_artiq::reset_exception_buffer(KERNELCPU_PAYLOAD_ADDRESS);
@@ -582,18 +690,45 @@ pub unsafe fn main() {
                                           kernel_proto::KERNELCPU_LAST_ADDRESS -
                                           kernel_proto::KERNELCPU_PAYLOAD_ADDRESS);
 
-    let library = recv!(&LoadRequest(library) => {
-        match Library::load(library, image, &api::resolve) {
-            Err(error) => {
-                send(&LoadReply(Err(error)));
-                loop {}
+    let payload = recv(move |request| {
+        match request {
+            &LoadRequest(library) => match Library::load(library, image, &api::resolve) {
+                Err(error) => {
+                    send(&LoadReply(Err(error)));
+                    loop {}
+                },
+                Ok(library) => {
+                    send(&LoadReply(Ok(())));
+                    Payload::Library(library)
+                }
             },
-            Ok(library) => {
+            &LoadFlatRequest { image: flat_image, entry_offset, load_addr } => {
+                match load_flat_image(image, flat_image, entry_offset, load_addr) {
+                    Err(error) => {
+                        send(&LoadReply(Err(dyld::Error::Parsing(error))));
+                        loop {}
+                    }
+                    Ok(entry) => {
+                        send(&LoadReply(Ok(())));
+                        Payload::Flat(entry)
+                    }
+                }
+            }
+            &SelfTestRequest => {
                 send(&LoadReply(Ok(())));
-                library
+                Payload::SelfTest
+            }
+            _ => {
+                send(&Log(format_args!("unexpected reply: {:?}\n", request)));
+                loop {}
             }
         }
     });
+    let library = match payload {
+        Payload::Library(library) => library,
+        Payload::Flat(entry) => flat_kernel(entry),
+        Payload::SelfTest => self_test_kernel()
+    };
 
     let __bss_start = library.lookup(b"__bss_start").unwrap();
     let _end = library.lookup(b"_end").unwrap();
@@ -610,11 +745,29 @@ pub unsafe fn main() {
     board_misoc::cache::flush_cpu_dcache();
     board_misoc::cache::flush_cpu_icache();
 
+    send(&StartTimestampRequest);
+    recv!(&StartTimestampReply { timestamp } => {
+        if let Some(timestamp) = timestamp {
+            // a late start would silently desynchronize this kernel from its peers
+            let counter = rtio::get_counter();
+            if counter >= timestamp {
+                raise!("RTIOUnderflow",
+                    "kernel start timestamp {0} mu already passed (counter {1} mu)",
+                    timestamp, counter, 0);
+            }
+            rtio::set_now(timestamp);
+        }
+    });
+
+    send(&RtioReplayConfigRequest);
+    recv!(&RtioReplayConfigReply { enabled } => rtio::replay::enable(enabled));
+
     (mem::transmute::<u32, fn()>(__modinit__))();
 
     if let Some(typeinfo) = typeinfo {
         attribute_writeback(typeinfo as *const ());
     }
+    CLEANUP_HANDLER.finish();
 
     // Make sure all async RPCs are processed before exiting.
     // Otherwise, if the comms and kernel CPU run in the following sequence:
@@ -629,6 +782,7 @@ pub unsafe fn main() {
     // the async RPC would be missed.
     send(&RpcFlush);
 
+    rtio::replay::send_log();
     send(&RunFinished);
 
     loop {}
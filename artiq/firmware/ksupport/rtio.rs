@@ -4,6 +4,45 @@ pub struct TimestampedData {
     data: i32,
 }
 
+/// Records the outputs submitted by the kernel, in order, for offline replay.
+pub mod replay {
+    use kernel_proto::{RtioReplayBuffer, RtioReplayEvent, RtioReplayLog};
+    use ::send;
+
+    static mut ENABLED: bool = false;
+    static mut BUFFER: RtioReplayBuffer = RtioReplayBuffer::new();
+
+    pub fn enable(enabled: bool) {
+        unsafe {
+            ENABLED = enabled;
+            BUFFER.clear();
+        }
+    }
+
+    #[inline(always)]
+    pub fn enabled() -> bool {
+        unsafe { ENABLED }
+    }
+
+    pub fn record(timestamp: i64, target: i32, data: i32) {
+        unsafe {
+            BUFFER.record(RtioReplayEvent {
+                timestamp: timestamp,
+                target: target as u32,
+                data: data as u32
+            })
+        }
+    }
+
+    pub fn send_log() {
+        if enabled() {
+            unsafe {
+                send(&RtioReplayLog { events: BUFFER.events(), truncated: BUFFER.truncated() });
+            }
+        }
+    }
+}
+
 #[cfg(has_rtio)]
 mod imp {
     use core::ptr::{read_volatile, write_volatile};
@@ -14,6 +53,7 @@ mod imp {
     use ::send;
     use ::recv;
     use kernel_proto::*;
+    use super::replay;
 
     pub const RTIO_O_STATUS_WAIT:                      u8 = 1;
     pub const RTIO_O_STATUS_UNDERFLOW:                 u8 = 2;
@@ -38,6 +78,14 @@ mod imp {
         }
     }
 
+    pub extern fn claim_channel(channel: i32) {
+        send(&RtioChannelClaimRequest { channel: channel as u32, claim: true });
+    }
+
+    pub extern fn release_channel(channel: i32) {
+        send(&RtioChannelClaimRequest { channel: channel as u32, claim: false });
+    }
+
     pub extern fn get_counter() -> i64 {
         unsafe {
             csr::rtio::counter_update_write(1);
@@ -45,6 +93,13 @@ mod imp {
         }
     }
 
+    pub fn set_now(timestamp: i64) {
+        unsafe {
+            csr::rtio::now_hi_write((timestamp >> 32) as u32);
+            csr::rtio::now_lo_write(timestamp as u32);
+        }
+    }
+
     // writing the LSB of o_data (offset=0) triggers the RTIO write
     #[inline(always)]
     pub unsafe fn rtio_o_data_write(offset: usize, data: u32) {
@@ -61,7 +116,7 @@ mod imp {
 
     #[inline(never)]
     unsafe fn process_exceptional_status(channel: i32, status: u8) {
-        let timestamp = ((csr::rtio::now_hi_read() as i64) << 32) | (csr::rtio::now_lo_read() as i64);
+        let timestamp = now();
         if status & RTIO_O_STATUS_WAIT != 0 {
             while csr::rtio::o_status_read() & RTIO_O_STATUS_WAIT != 0 {}
         }
@@ -77,8 +132,16 @@ mod imp {
         }
     }
 
+    #[inline(always)]
+    unsafe fn now() -> i64 {
+        ((csr::rtio::now_hi_read() as i64) << 32) | (csr::rtio::now_lo_read() as i64)
+    }
+
     pub extern fn output(target: i32, data: i32) {
         unsafe {
+            if replay::enabled() {
+                replay::record(now(), target, data);
+            }
             csr::rtio::target_write(target as u32);
             // writing target clears o_data
             rtio_o_data_write(0, data as _);
@@ -91,6 +154,9 @@ mod imp {
 
     pub extern fn output_wide(target: i32, data: &CSlice<i32>) {
         unsafe {
+            if replay::enabled() {
+                replay::record(now(), target, if data.len() > 0 { data[0] } else { 0 });
+            }
             csr::rtio::target_write(target as u32);
             // writing target clears o_data
             for i in (0..data.len()).rev() {
@@ -227,10 +293,22 @@ mod imp {
         unimplemented!("not(has_rtio)")
     }
 
+    pub extern fn claim_channel(_channel: i32) {
+        unimplemented!("not(has_rtio)")
+    }
+
+    pub extern fn release_channel(_channel: i32) {
+        unimplemented!("not(has_rtio)")
+    }
+
     pub extern fn get_counter() -> i64 {
         unimplemented!("not(has_rtio)")
     }
 
+    pub fn set_now(_timestamp: i64) {
+        unimplemented!("not(has_rtio)")
+    }
+
     pub extern fn output(_target: i32, _data: i32) {
         unimplemented!("not(has_rtio)")
     }
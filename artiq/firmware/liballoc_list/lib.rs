@@ -34,6 +34,40 @@ impl ListAlloc {
     pub unsafe fn add_range(&mut self, begin: *mut u8, end: *mut u8) {
         self.add(begin, end as usize - begin as usize)
     }
+
+    /// Returns the number of bytes currently allocated, excluding headers.
+    pub fn busy(&self) -> usize {
+        unsafe {
+            let mut total_busy = 0;
+            let mut curr = self.root;
+            while !curr.is_null() {
+                match (*curr).magic {
+                    MAGIC_BUSY => total_busy += (*curr).size,
+                    MAGIC_FREE => (),
+                    _ => break
+                }
+                curr = (*curr).next;
+            }
+            total_busy
+        }
+    }
+
+    /// Returns the number of bytes currently free, excluding headers.
+    pub fn idle(&self) -> usize {
+        unsafe {
+            let mut total_idle = 0;
+            let mut curr = self.root;
+            while !curr.is_null() {
+                match (*curr).magic {
+                    MAGIC_FREE => total_idle += (*curr).size,
+                    MAGIC_BUSY => (),
+                    _ => break
+                }
+                curr = (*curr).next;
+            }
+            total_idle
+        }
+    }
 }
 
 unsafe impl GlobalAlloc for ListAlloc {
@@ -156,3 +190,36 @@ impl fmt::Display for ListAlloc {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busy_returns_to_baseline() {
+        let mut heap = [0usize; 1024];
+        let mut alloc = EMPTY;
+        unsafe { alloc.add(heap.as_mut_ptr() as *mut u8, mem::size_of_val(&heap)) }
+
+        // allocations that outlive the kernel, such as the network buffers
+        let resident = unsafe { alloc.alloc(Layout::from_size_align(64, 8).unwrap()) };
+        assert!(!resident.is_null());
+        let baseline = alloc.busy();
+
+        let layouts = [Layout::from_size_align(24, 4).unwrap(),
+                       Layout::from_size_align(100, 16).unwrap(),
+                       Layout::from_size_align(256, 64).unwrap()];
+        let mut kernel_state = [ptr::null_mut(); 3];
+        for (ptr, layout) in kernel_state.iter_mut().zip(layouts.iter()) {
+            *ptr = unsafe { alloc.alloc(layout.clone()) };
+            assert!(!ptr.is_null());
+        }
+        assert!(alloc.busy() > baseline);
+
+        // what a kernel state reset does, minus the bookkeeping
+        for (&ptr, layout) in kernel_state.iter().zip(layouts.iter()) {
+            unsafe { alloc.dealloc(ptr, layout.clone()) }
+        }
+        assert_eq!(alloc.busy(), baseline);
+    }
+}
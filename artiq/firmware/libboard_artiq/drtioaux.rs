@@ -102,8 +102,8 @@ pub fn recv(linkno: u8) -> Result<Option<Packet>, Error<!>> {
 
 pub fn recv_timeout(linkno: u8, timeout_ms: Option<u64>) -> Result<Packet, Error<!>> {
     let timeout_ms = timeout_ms.unwrap_or(10);
-    let limit = clock::get_ms() + timeout_ms;
-    while clock::get_ms() < limit {
+    let timeout = clock::Timeout::after_ms(timeout_ms);
+    while !timeout.expired() {
         match recv(linkno)? {
             None => (),
             Some(packet) => return Ok(packet),
@@ -1,6 +1,10 @@
 #![feature(asm, lang_items, never_type)]
 #![no_std]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 extern crate failure;
 #[cfg(has_drtio)]
 #[macro_use]
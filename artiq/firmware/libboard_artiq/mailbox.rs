@@ -1,15 +1,29 @@
 use core::ptr::{read_volatile, write_volatile};
-use board_misoc::{mem, cache};
+use board_misoc::{mem, cache, clock, csr::CONFIG_DATA_WIDTH_BYTES};
 
 const MAILBOX: *mut usize = mem::MAILBOX_BASE as *mut usize;
+// Set by the comms CPU to ask the running kernel to abort; the two words
+// in between belong to rpc_queue.
+const ABORT: *mut usize = (mem::MAILBOX_BASE + (CONFIG_DATA_WIDTH_BYTES * 3) as usize) as *mut usize;
 static mut LAST: usize = 0;
 
+// The mailbox holds the address of a message, which both CPUs access
+// through their data caches:
+// - the sender completes every write to the message before the address
+//   is published (`send`);
+// - the receiver sees the address before it reads the message, and drops
+//   its cached copy first, which may be stale (`receive`);
+// - the receiver completes every read of the message before it hands the
+//   message back by clearing the mailbox (`acknowledge`).
+// The fences order the accesses of each CPU, and as asm! blocks that may
+// touch memory they also keep the compiler from moving accesses across.
+
 pub unsafe fn send(data: usize) {
     LAST = data;
-    // after Rust toolchain update to LLVM12, this empty asm! block is required
+    // after Rust toolchain update to LLVM12, a barrier here is required
     // to ensure that the compiler doesn't take any shortcuts
     // otherwise, the comm CPU will read garbage data and crash
-    asm!("", options(preserves_flags, readonly, nostack));
+    asm!("fence rw, w", options(preserves_flags, nostack));
     write_volatile(MAILBOX, data);
 }
 
@@ -27,6 +41,7 @@ pub fn receive() -> usize {
             0
         } else {
             if data != 0 {
+                asm!("fence r, r", options(preserves_flags, nostack));
                 cache::flush_cpu_dcache()
             }
             data
@@ -34,6 +49,64 @@ pub fn receive() -> usize {
     }
 }
 
+/// Polls `receive` until there is a message or `timeout_ms` has passed,
+/// e.g. because the other CPU crashed in the middle of an exchange.
+pub fn receive_timeout(timeout_ms: u64) -> Option<usize> {
+    receive_until(&clock::Timeout::after_ms(timeout_ms), receive)
+}
+
+fn receive_until<F: FnMut() -> usize>(timeout: &clock::Timeout, mut receive: F) -> Option<usize> {
+    let mut data = 0;
+    timeout.poll_while(|| {
+        data = receive();
+        data == 0
+    });
+    if data == 0 { None } else { Some(data) }
+}
+
 pub fn acknowledge() {
-    unsafe { write_volatile(MAILBOX, 0) }
+    unsafe {
+        asm!("fence rw, w", options(preserves_flags, nostack));
+        write_volatile(MAILBOX, 0)
+    }
+}
+
+/// Asks the kernel CPU to abort the running kernel, which it does the next
+/// time it talks to the comms CPU.
+pub fn request_abort() {
+    unsafe { write_volatile(ABORT, 1) }
+}
+
+/// Withdraws an abort request, before the kernel CPU is started again.
+pub fn clear_abort() {
+    unsafe { write_volatile(ABORT, 0) }
+}
+
+pub fn abort_requested() -> bool {
+    unsafe { read_volatile(ABORT) != 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receive_gives_up_after_deadline() {
+        let timeout = clock::Timeout::after_ms(2);
+        let mut polls = 0;
+        // the other CPU never answers
+        assert_eq!(receive_until(&timeout, || { polls += 1; 0 }), None);
+        assert!(timeout.expired());
+        assert!(polls > 0);
+    }
+
+    #[test]
+    fn receive_returns_message() {
+        let timeout = clock::Timeout::after_ms(1000);
+        let mut polls = 0;
+        assert_eq!(receive_until(&timeout, || { polls += 1; if polls < 3 { 0 } else { 0x4000_0100 } }),
+                   Some(0x4000_0100));
+        assert_eq!(polls, 3);
+        assert!(!timeout.expired());
+    }
 }
@@ -1,5 +1,7 @@
 use core::ptr::{read_volatile, write_volatile};
-use core::slice;
+use core::{fmt, slice};
+use byteorder::{ByteOrder, NativeEndian};
+use io::Cursor;
 use board_misoc::{mem, cache, csr::CONFIG_DATA_WIDTH_BYTES};
 
 const SEND_MAILBOX: *mut usize = (mem::MAILBOX_BASE + CONFIG_DATA_WIDTH_BYTES as usize) as *mut usize;
@@ -8,12 +10,56 @@ const RECV_MAILBOX: *mut usize = (mem::MAILBOX_BASE + (CONFIG_DATA_WIDTH_BYTES *
 const QUEUE_BEGIN: usize = 0x44000000;
 const QUEUE_END:   usize = 0x44ffff80;
 const QUEUE_CHUNK: usize = 0x1000;
+// every chunk-aligned address below QUEUE_END starts a slot
+const QUEUE_SLOTS: usize = (QUEUE_END - QUEUE_BEGIN + QUEUE_CHUNK - 1) / QUEUE_CHUNK;
 
+/// Largest serialized async RPC that fits a slot, after its length prefix.
+pub const SLOT_PAYLOAD_BYTES: usize = QUEUE_CHUNK - 4;
+/// RPCs are not split across slots, so every one has to fit a single slot.
+pub const MAX_FRAGMENTS: usize = 1;
+
+/// Resets both pointers. The kernel CPU owns the send pointer and the comms
+/// CPU the receive pointer, so this is only done while the kernel CPU is
+/// held in reset.
 pub unsafe fn init() {
     write_volatile(SEND_MAILBOX, QUEUE_BEGIN);
     write_volatile(RECV_MAILBOX, QUEUE_BEGIN);
 }
 
+fn valid(addr: usize) -> bool {
+    addr % QUEUE_CHUNK == 0 && addr >= QUEUE_BEGIN && addr < QUEUE_END
+}
+
+/// A queue pointer outside of the queue. Neither side may repair the pointer
+/// of the other, so the queue stays unusable until the kernel CPU is stopped
+/// and `init` is called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corrupted {
+    pub send: usize,
+    pub recv: usize
+}
+
+impl fmt::Display for Corrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rpc queue corrupted (send {:#010x}, receive {:#010x})", self.send, self.recv)
+    }
+}
+
+// Returns the (send, receive) pointers, unless either of them was corrupted,
+// so that no garbage address ever gets dereferenced.
+fn pointers() -> Result<(usize, usize), Corrupted> {
+    let (send, recv) = unsafe { (read_volatile(SEND_MAILBOX), read_volatile(RECV_MAILBOX)) };
+    checked(send, recv)
+}
+
+fn checked(send: usize, recv: usize) -> Result<(usize, usize), Corrupted> {
+    if valid(send) && valid(recv) {
+        Ok((send, recv))
+    } else {
+        Err(Corrupted { send: send, recv: recv })
+    }
+}
+
 fn next(mut addr: usize) -> usize {
     debug_assert!(addr % QUEUE_CHUNK == 0);
     debug_assert!(addr >= QUEUE_BEGIN && addr < QUEUE_END);
@@ -23,37 +69,115 @@ fn next(mut addr: usize) -> usize {
     addr
 }
 
-pub fn empty() -> bool {
-    unsafe { read_volatile(SEND_MAILBOX) == read_volatile(RECV_MAILBOX) }
+pub fn empty() -> Result<bool, Corrupted> {
+    let (send, recv) = pointers()?;
+    Ok(send == recv)
+}
+
+pub fn full() -> Result<bool, Corrupted> {
+    let (send, recv) = pointers()?;
+    Ok(next(send) == recv)
 }
 
-pub fn full() -> bool {
-    unsafe { next(read_volatile(SEND_MAILBOX)) == read_volatile(RECV_MAILBOX) }
+/// Number of slots enqueued but not yet dequeued. One slot always stays
+/// free, so this is below `QUEUE_SLOTS` even when the queue is `full`.
+pub fn len() -> Result<usize, Corrupted> {
+    let (send, recv) = pointers()?;
+    Ok(slots_between(send, recv))
+}
+
+fn slots_between(send: usize, recv: usize) -> usize {
+    if send >= recv {
+        (send - recv) / QUEUE_CHUNK
+    } else {
+        QUEUE_SLOTS - (recv - send) / QUEUE_CHUNK
+    }
 }
 
 pub fn enqueue<T, E, F>(f: F) -> Result<T, E>
-        where F: FnOnce(&mut [u8]) -> Result<T, E> {
-    debug_assert!(!full());
+        where F: FnOnce(&mut [u8]) -> Result<T, E>, E: From<Corrupted> {
+    debug_assert!(full() == Ok(false));
 
+    let (send, _) = pointers()?;
     unsafe {
-        let slice = slice::from_raw_parts_mut(read_volatile(SEND_MAILBOX) as *mut u8, QUEUE_CHUNK);
+        let slice = slice::from_raw_parts_mut(send as *mut u8, QUEUE_CHUNK);
         f(slice).and_then(|x| {
-            write_volatile(SEND_MAILBOX, next(read_volatile(SEND_MAILBOX)));
+            write_volatile(SEND_MAILBOX, next(send));
             Ok(x)
         })
     }
 }
 
+/// Serializes an RPC into a slot handed out by `enqueue` with `f`, behind
+/// the length prefix, so that at most SLOT_PAYLOAD_BYTES fit.
+pub fn write_slot<E, F>(slot: &mut [u8], f: F) -> Result<(), E>
+        where F: FnOnce(&mut Cursor<&mut [u8]>) -> Result<(), E> {
+    let length = {
+        let mut writer = Cursor::new(&mut slot[4..]);
+        f(&mut writer)?;
+        writer.position()
+    };
+    NativeEndian::write_u32(&mut slot[..4], length as u32);
+    Ok(())
+}
+
 pub fn dequeue<T, E, F>(f: F) -> Result<T, E>
-        where F: FnOnce(&mut [u8]) -> Result<T, E> {
-    debug_assert!(!empty());
+        where F: FnOnce(&mut [u8]) -> Result<T, E>, E: From<Corrupted> {
+    debug_assert!(empty() == Ok(false));
 
+    let (_, recv) = pointers()?;
     unsafe {
         cache::flush_cpu_dcache();
-        let slice = slice::from_raw_parts_mut(read_volatile(RECV_MAILBOX) as *mut u8, QUEUE_CHUNK);
+        let slice = slice::from_raw_parts_mut(recv as *mut u8, QUEUE_CHUNK);
         f(slice).and_then(|x| {
-            write_volatile(RECV_MAILBOX, next(read_volatile(RECV_MAILBOX)));
+            write_volatile(RECV_MAILBOX, next(recv));
             Ok(x)
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn len_wraps_around() {
+        // start a few slots before the end, so that the send pointer wraps
+        let recv = QUEUE_BEGIN + (QUEUE_SLOTS - 3) * QUEUE_CHUNK;
+        let mut send = recv;
+        for count in 0..QUEUE_SLOTS - 1 {
+            assert_eq!(slots_between(send, recv), count);
+            send = next(send);
+        }
+        // one slot always stays free, so a full queue is never seen as empty
+        assert_eq!(next(send), recv);
+        assert_eq!(slots_between(send, recv), QUEUE_SLOTS - 1);
+    }
+
+    #[test]
+    fn corrupted_pointers() {
+        let last = QUEUE_BEGIN + (QUEUE_SLOTS - 1) * QUEUE_CHUNK;
+        assert_eq!(checked(QUEUE_BEGIN, last), Ok((QUEUE_BEGIN, last)));
+
+        for &bad in &[QUEUE_BEGIN + 4, last + 1, QUEUE_BEGIN - QUEUE_CHUNK,
+                      last + QUEUE_CHUNK, 0, usize::max_value()] {
+            assert_eq!(checked(bad, QUEUE_BEGIN), Err(Corrupted { send: bad, recv: QUEUE_BEGIN }));
+            assert_eq!(checked(QUEUE_BEGIN, bad), Err(Corrupted { send: QUEUE_BEGIN, recv: bad }));
+        }
+    }
+    #[test]
+    fn slot_payload_fits() {
+        use io::{Write, Error};
+
+        let mut slot = vec![0; QUEUE_CHUNK];
+        let payload = vec![0x5a; SLOT_PAYLOAD_BYTES];
+        assert_eq!(write_slot(&mut slot, |writer| writer.write_all(&payload)), Ok(()));
+        assert_eq!(NativeEndian::read_u32(&slot[..4]) as usize, SLOT_PAYLOAD_BYTES);
+        assert_eq!(&slot[4..], &payload[..]);
+
+        // a single byte more does not fit, and RPCs are never split
+        let payload = vec![0x5a; SLOT_PAYLOAD_BYTES + 1];
+        assert_eq!(write_slot(&mut slot, |writer| writer.write_all(&payload)), Err(Error::UnexpectedEof));
+        assert_eq!(MAX_FRAGMENTS, 1);
+    }
+}
@@ -1,9 +1,14 @@
 use core::i64;
+#[cfg(not(test))]
 use csr;
+#[cfg(test)]
+use self::tests::csr;
 
 const INIT: u64 = i64::MAX as u64;
 const FREQ: u64 = csr::CONFIG_CLOCK_FREQUENCY as u64;
 
+static mut UNIX_OFFSET_US: Option<u64> = None;
+
 pub fn init() {
     unsafe {
         csr::timer0::en_write(0);
@@ -13,20 +18,112 @@ pub fn init() {
     }
 }
 
-pub fn get_us() -> u64 {
+/// Timer ticks since `init`, at the full resolution of the system clock.
+/// The count is latched before it is read, so it is never torn.
+pub fn ticks() -> u64 {
     unsafe {
         csr::timer0::update_value_write(1);
-        (INIT - csr::timer0::value_read()) / (FREQ / 1_000_000)
+        INIT - csr::timer0::value_read()
     }
 }
 
+pub fn ticks_per_us() -> u64 {
+    FREQ / 1_000_000
+}
+
+pub fn get_us() -> u64 {
+    ticks() / ticks_per_us()
+}
+
+/// Milliseconds since boot. The 64-bit count never wraps in practice, but
+/// it does wrap once truncated, e.g. to u32 after ~49.7 days; deadlines
+/// should be compared with `deadline_passed` rather than `>`.
 pub fn get_ms() -> u64 {
-    unsafe {
-        csr::timer0::update_value_write(1);
-        (INIT - csr::timer0::value_read()) / (FREQ / 1_000)
+    ticks() / (FREQ / 1_000)
+}
+
+/// Wrap-aware check of whether `now` has reached `deadline`, valid as long
+/// as the two are less than half the counter range apart.
+pub fn deadline_passed(now: u64, deadline: u64) -> bool {
+    (now.wrapping_sub(deadline) as i64) >= 0
+}
+
+/// A deadline in `get_ms` time, checked with `deadline_passed`.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    deadline: u64
+}
+
+impl Timeout {
+    pub fn after_ms(duration_ms: u64) -> Timeout {
+        Timeout::starting_at(get_ms(), duration_ms)
+    }
+
+    fn starting_at(now: u64, duration_ms: u64) -> Timeout {
+        Timeout { deadline: now.wrapping_add(duration_ms) }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.expired_at(get_ms())
+    }
+
+    fn expired_at(&self, now: u64) -> bool {
+        deadline_passed(now, self.deadline)
+    }
+
+    pub fn remaining_ms(&self) -> u64 {
+        self.remaining_ms_at(get_ms())
+    }
+
+    /// Calls `pending` until it returns false, or until the timeout expires,
+    /// and tells whether it returned false in time.
+    pub fn poll_while<F: FnMut() -> bool>(&self, mut pending: F) -> bool {
+        while pending() {
+            if self.expired() {
+                return false
+            }
+        }
+        true
+    }
+
+    fn remaining_ms_at(&self, now: u64) -> u64 {
+        if self.expired_at(now) {
+            0
+        } else {
+            self.deadline.wrapping_sub(now)
+        }
+    }
+}
+
+/// Like `deadline_passed`, for counters truncated to 32 bits.
+pub fn deadline_passed_u32(now: u32, deadline: u32) -> bool {
+    (now.wrapping_sub(deadline) as i32) >= 0
+}
+
+/// Records the host wall-clock time, so that later uptime readings can be
+/// converted to approximate UNIX time with `get_unix_us`.
+///
+/// There is no RTC on the board; the converted time drifts along with the
+/// system clock oscillator (tens of ppm, i.e. up to ~0.1s per hour), and
+/// does not account for network latency of the synchronizing request.
+/// Hosts that need better accuracy should resynchronize periodically.
+///
+/// Returns false, leaving the previous offset in place, if the time does not
+/// fit in 64 bits of microseconds.
+pub fn set_unix_ms(host_unix_ms: u64) -> bool {
+    match host_unix_ms.checked_mul(1_000) {
+        Some(host_unix_us) => {
+            unsafe { UNIX_OFFSET_US = Some(host_unix_us.wrapping_sub(get_us())) }
+            true
+        }
+        None => false
     }
 }
 
+pub fn get_unix_us() -> Option<u64> {
+    unsafe { UNIX_OFFSET_US.map(|offset| offset.wrapping_add(get_us())) }
+}
+
 pub fn spin_us(interval: u64) {
     unsafe {
         csr::timer0::update_value_write(1);
@@ -36,3 +133,141 @@ pub fn spin_us(interval: u64) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    // Models the timer: it counts down from `INIT` as ticks elapse, and
+    // `value` only follows the count when `update_value` latches it.
+    pub mod csr {
+        pub const CONFIG_CLOCK_FREQUENCY: u32 = 125_000_000;
+
+        pub mod timer0 {
+            use core::sync::atomic::{AtomicU64, Ordering};
+
+            pub static ELAPSED: AtomicU64 = AtomicU64::new(0);
+            static VALUE: AtomicU64 = AtomicU64::new(0);
+
+            pub unsafe fn en_write(_: u8) {}
+            pub unsafe fn load_write(_: u64) {}
+            pub unsafe fn reload_write(_: u64) {}
+
+            pub unsafe fn update_value_write(_: u8) {
+                VALUE.store(super::super::INIT - ELAPSED.load(Ordering::SeqCst), Ordering::SeqCst)
+            }
+
+            pub unsafe fn value_read() -> u64 {
+                VALUE.load(Ordering::SeqCst)
+            }
+        }
+    }
+
+    // Tests that move the mock timer take turns.
+    static TIMER: AtomicBool = AtomicBool::new(false);
+
+    struct TimerGuard;
+
+    impl Drop for TimerGuard {
+        fn drop(&mut self) {
+            TIMER.store(false, Ordering::Release)
+        }
+    }
+
+    fn lock_timer() -> TimerGuard {
+        while TIMER.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {}
+        TimerGuard
+    }
+
+    fn set_elapsed_us(elapsed_us: u64) {
+        csr::timer0::ELAPSED.store(elapsed_us * ticks_per_us(), Ordering::SeqCst)
+    }
+
+    #[test]
+    fn ticks_latched() {
+        let _guard = lock_timer();
+        init();
+        assert_eq!(ticks_per_us(), 125);
+        let mut last_us = 0;
+        for &elapsed in &[0, 124, 125, 124_999, 125_000, 1_000_000_007, 1 << 40] {
+            csr::timer0::ELAPSED.store(elapsed, Ordering::SeqCst);
+            // each read latches the current count rather than a stale one
+            assert_eq!(ticks(), elapsed);
+            let us = get_us();
+            assert_eq!(us, elapsed / 125);
+            assert!(us >= last_us);
+            assert_eq!(get_ms(), us / 1_000);
+            last_us = us;
+        }
+    }
+
+    #[test]
+    fn timeout_wraps_around() {
+        let timeout = Timeout::starting_at(u64::MAX - 5, 10);
+        assert!(!timeout.expired_at(u64::MAX - 5));
+        assert_eq!(timeout.remaining_ms_at(u64::MAX - 5), 10);
+        assert_eq!(timeout.remaining_ms_at(2), 2);
+        assert!(!timeout.expired_at(3));
+        assert_eq!(timeout.remaining_ms_at(3), 1);
+        // expired exactly when nothing remains
+        assert!(timeout.expired_at(4));
+        assert_eq!(timeout.remaining_ms_at(4), 0);
+        assert!(timeout.expired_at(5));
+        assert_eq!(timeout.remaining_ms_at(5), 0);
+    }
+
+    #[test]
+    fn poll_while_times_out() {
+        let _guard = lock_timer();
+        set_elapsed_us(1_000_000);
+        // answered after 5 polls, 10 ms apart
+        let mut polls = 0;
+        let timeout = Timeout::after_ms(200);
+        assert!(timeout.poll_while(|| {
+            polls += 1;
+            set_elapsed_us(1_000_000 + polls * 10_000);
+            polls < 5
+        }));
+        assert_eq!(polls, 5);
+
+        // never answered: gives up once 200 ms have passed
+        set_elapsed_us(2_000_000);
+        let mut polls = 0;
+        let timeout = Timeout::after_ms(200);
+        assert!(!timeout.poll_while(|| {
+            polls += 1;
+            set_elapsed_us(2_000_000 + polls * 10_000);
+            true
+        }));
+        assert_eq!(polls, 20);
+    }
+
+    #[test]
+    fn deadline_passed_truncated() {
+        assert!(!deadline_passed_u32(u32::MAX, 1));
+        assert!(deadline_passed_u32(1, u32::MAX));
+        assert!(deadline_passed_u32(7, 7));
+        assert!(!deadline_passed_u32(6, 7));
+        assert!(deadline_passed(7, 7));
+        assert!(!deadline_passed(6, 7));
+    }
+
+    #[test]
+    fn unix_time_offset() {
+        let _guard = lock_timer();
+        set_elapsed_us(5_000_000);
+        unsafe { UNIX_OFFSET_US = None }
+        assert_eq!(get_unix_us(), None);
+
+        let host_unix_ms = 1_700_000_000_000;
+        assert!(set_unix_ms(host_unix_ms));
+        assert_eq!(get_unix_us(), Some(host_unix_ms * 1_000));
+        set_elapsed_us(5_250_042);
+        assert_eq!(get_unix_us(), Some(host_unix_ms * 1_000 + 250_042));
+
+        // an overflowing time leaves the offset alone
+        assert!(!set_unix_ms(u64::MAX / 1_000 + 1));
+        assert_eq!(get_unix_us(), Some(host_unix_ms * 1_000 + 250_042));
+    }
+}
@@ -35,12 +35,16 @@ impl fmt::Display for Error {
     }
 }
 
-#[cfg(has_spiflash)]
+#[cfg(any(has_spiflash, test))]
 mod imp {
     use core::str;
     use byteorder::{ByteOrder, BigEndian};
+    #[cfg(not(test))]
     use cache;
+    #[cfg(not(test))]
     use spiflash;
+    #[cfg(test)]
+    use self::tests::{cache, spiflash};
     use super::Error;
     use core::fmt;
     use core::fmt::Write;
@@ -75,9 +79,16 @@ mod imp {
     }
 
     // One flash sector immediately before the firmware.
+    #[cfg(not(test))]
     const ADDR: usize = ::mem::FLASH_BOOT_ADDRESS - spiflash::SECTOR_SIZE;
+    #[cfg(test)]
+    const ADDR: usize = 0;
     const SIZE: usize = spiflash::SECTOR_SIZE;
 
+    // Number of times the sector has been erased. It is only rewritten
+    // right after an erase, so tracking it costs no additional wear.
+    const ERASE_COUNT_KEY: &'static str = "__erase_count";
+
     mod lock {
         use core::slice;
         use core::sync::atomic::{AtomicUsize, Ordering};
@@ -96,9 +107,15 @@ mod imp {
                 }
             }
 
+            #[cfg(not(test))]
             pub fn data(&self) -> &'static [u8] {
                 unsafe { slice::from_raw_parts(super::ADDR as *const u8, super::SIZE) }
             }
+
+            #[cfg(test)]
+            pub fn data(&self) -> &'static [u8] {
+                unsafe { slice::from_raw_parts(super::spiflash::FLASH.as_ptr(), super::SIZE) }
+            }
         }
 
         impl Drop for Lock {
@@ -133,10 +150,12 @@ mod imp {
                 return Some(Err(Error::Truncated { offset: self.offset }))
             }
 
-            let record_size = BigEndian::read_u32(data) as usize;
+            let record_size = BigEndian::read_u32(data);
             if record_size == !0 /* all ones; erased flash */ {
                 return None
-            } else if record_size < 4 || record_size > data.len() {
+            }
+            let record_size = record_size as usize;
+            if record_size < 4 || record_size > data.len() {
                 return Some(Err(Error::InvalidSize { offset: self.offset, size: record_size }))
             }
 
@@ -204,6 +223,26 @@ mod imp {
         Ok(offset)
     }
 
+    fn read_erase_count(data: &[u8]) -> Result<u32, Error> {
+        let mut count = 0;
+        let mut iter = Iter::new(data);
+        while let Some(result) = iter.next() {
+            let (key, value) = result?;
+            if key == ERASE_COUNT_KEY.as_bytes() && value.len() == 4 {
+                count = BigEndian::read_u32(value)
+            }
+        }
+        Ok(count)
+    }
+
+    unsafe fn erase_at(data: &[u8], erase_count: u32) -> Result<usize, Error> {
+        spiflash::erase_sector(data.as_ptr() as usize);
+
+        let mut count_bytes = [0u8; 4];
+        BigEndian::write_u32(&mut count_bytes[..], erase_count.saturating_add(1));
+        append_at(data, 0, ERASE_COUNT_KEY.as_bytes(), &count_bytes[..])
+    }
+
     fn compact() -> Result<(), Error> {
         let lock = Lock::take()?;
         let data = lock.data();
@@ -214,11 +253,11 @@ mod imp {
             &OLD_DATA[..]
         };
 
-        unsafe { spiflash::erase_sector(data.as_ptr() as usize) };
+        let erase_count = read_erase_count(old_data)?;
+        let mut offset = unsafe { erase_at(data, erase_count)? };
 
         // This is worst-case quadratic, but we're limited by a small SPI flash sector size,
         // so it does not really matter.
-        let mut offset = 0;
         let mut iter = Iter::new(old_data);
         'iter: while let Some(result) = iter.next() {
             let (key, value) = result?;
@@ -226,6 +265,10 @@ mod imp {
                 // This is a removed entry, ignore it.
                 continue
             }
+            if key == ERASE_COUNT_KEY.as_bytes() {
+                // Already carried over by erase_at.
+                continue
+            }
 
             let mut next_iter = iter.clone();
             while let Some(next_result) = next_iter.next() {
@@ -283,14 +326,97 @@ mod imp {
         let lock = Lock::take()?;
         let data = lock.data();
 
-        unsafe { spiflash::erase_sector(data.as_ptr() as usize) };
-        cache::flush_l2_cache();
+        // A corrupted sector should still be erasable; the count restarts then.
+        let erase_count = read_erase_count(data).unwrap_or(0);
+        unsafe { erase_at(data, erase_count)? };
 
         Ok(())
     }
+
+    /// Returns the address of the configuration sector and an estimate
+    /// of how many times it has been erased.
+    pub fn flash_wear() -> Result<(usize, u32), Error> {
+        let lock = Lock::take()?;
+        Ok((ADDR, read_erase_count(lock.data())?))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        pub mod cache {
+            pub fn flush_l2_cache() {}
+        }
+
+        // NOR flash: erasing sets every bit, writing can only clear them.
+        pub mod spiflash {
+            use core::sync::atomic::{AtomicUsize, Ordering};
+
+            pub const SECTOR_SIZE: usize = 4096;
+
+            pub static mut FLASH: [u8; SECTOR_SIZE] = [0xff; SECTOR_SIZE];
+            pub static ERASES: AtomicUsize = AtomicUsize::new(0);
+
+            pub unsafe fn erase_sector(addr: usize) {
+                assert_eq!(addr, FLASH.as_ptr() as usize);
+                for byte in FLASH.iter_mut() {
+                    *byte = 0xff
+                }
+                ERASES.fetch_add(1, Ordering::SeqCst);
+            }
+
+            pub unsafe fn write(addr: usize, data: &[u8]) {
+                let offset = addr - FLASH.as_ptr() as usize;
+                for (byte, &value) in FLASH[offset..offset + data.len()].iter_mut().zip(data) {
+                    *byte &= value
+                }
+            }
+        }
+
+        fn erases() -> u32 {
+            spiflash::ERASES.load(core::sync::atomic::Ordering::SeqCst) as u32
+        }
+
+        // One test, as the flash and its lock are shared.
+        #[test]
+        fn erase_count_tracked() {
+            // blank flash has never been erased by us
+            assert_eq!(flash_wear(), Ok((ADDR, 0)));
+
+            erase().unwrap();
+            assert_eq!(flash_wear(), Ok((ADDR, 1)));
+            write("key", b"value").unwrap();
+            erase().unwrap();
+            assert_eq!(flash_wear(), Ok((ADDR, 2)));
+            assert_eq!(read("key", |result| result.map(|_| ())), Err(Error::KeyNotFound));
+
+            // filling the sector compacts it, which erases it again
+            for i in 0..100u8 {
+                write("counter", &[i; 100]).unwrap();
+            }
+            assert!(erases() > 2);
+            assert_eq!(flash_wear(), Ok((ADDR, erases())));
+            read("counter", |result| assert_eq!(result, Ok(&[99u8; 100][..])));
+
+            // only one count record is kept across compactions
+            let lock = Lock::take().unwrap();
+            let records = Iter::new(lock.data())
+                .filter(|record| record.unwrap().0 == ERASE_COUNT_KEY.as_bytes())
+                .count();
+            assert_eq!(records, 1);
+            drop(lock);
+
+            // the count is only kept in flash, so it survives a reboot, and
+            // even a corrupted sector can still be erased
+            unsafe { spiflash::write(spiflash::FLASH.as_ptr() as usize, &[0; 4]) }
+            assert_eq!(flash_wear(), Err(Error::InvalidSize { offset: 0, size: 0 }));
+            erase().unwrap();
+            assert_eq!(flash_wear(), Ok((ADDR, 1)));
+        }
+    }
 }
 
-#[cfg(not(has_spiflash))]
+#[cfg(not(any(has_spiflash, test)))]
 mod imp {
     use super::Error;
 
@@ -313,6 +439,10 @@ mod imp {
     pub fn erase() -> Result<(), Error> {
         Err(Error::NoFlash)
     }
+
+    pub fn flash_wear() -> Result<(usize, u32), Error> {
+        Err(Error::NoFlash)
+    }
 }
 
 pub use self::imp::*;
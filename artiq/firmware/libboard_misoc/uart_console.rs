@@ -2,6 +2,75 @@ use core::fmt;
 
 pub struct Console;
 
+#[cfg(has_uart)]
+const UART_EV_RX: u8 = 1 << 1;
+
+impl Console {
+    /// Returns the next received byte, if there is one.
+    #[cfg(has_uart)]
+    pub fn try_read_char(&mut self) -> Option<u8> {
+        use csr;
+
+        unsafe {
+            if csr::uart::rxempty_read() != 0 {
+                return None
+            }
+            let c = csr::uart::rxtx_read();
+            csr::uart::ev_pending_write(UART_EV_RX);
+            Some(c)
+        }
+    }
+
+    #[cfg(not(has_uart))]
+    pub fn try_read_char(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// Waits for the next received byte. Never returns without a UART.
+    pub fn read_char(&mut self) -> u8 {
+        loop {
+            if let Some(c) = self.try_read_char() {
+                return c
+            }
+        }
+    }
+
+    /// Reads a line into `buf` with echo and backspace, up to CR or LF,
+    /// and returns its length. Input beyond the size of `buf` is dropped.
+    pub fn read_line(&mut self, buf: &mut [u8]) -> usize {
+        self.read_line_from(buf, |console| console.read_char())
+    }
+
+    /// Like `read_line`, but takes the input from `read_char`, e.g. to poll
+    /// other work in between with `try_read_char`.
+    pub fn read_line_from<F>(&mut self, buf: &mut [u8], mut read_char: F) -> usize
+        where F: FnMut(&mut Console) -> u8
+    {
+        use core::fmt::Write;
+
+        let mut len = 0;
+        loop {
+            match read_char(self) {
+                b'\r' | b'\n' => {
+                    let _ = self.write_str("\r\n");
+                    return len
+                }
+                // backspace, or delete as sent by most terminals
+                0x08 | 0x7f => if len > 0 {
+                    len -= 1;
+                    let _ = self.write_str("\x08 \x08");
+                },
+                c @ 0x20...0x7e => if len < buf.len() {
+                    buf[len] = c;
+                    len += 1;
+                    let _ = self.write_char(c as char);
+                },
+                _ => ()
+            }
+        }
+    }
+}
+
 impl fmt::Write for Console {
     #[cfg(has_uart)]
     fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
@@ -36,3 +105,29 @@ macro_rules! println {
     ($fmt:expr) => (print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_line(input: &[u8], buf: &mut [u8]) -> usize {
+        let mut input = input.iter();
+        Console.read_line_from(buf, |_| *input.next().unwrap())
+    }
+
+    #[test]
+    fn read_line_edits() {
+        let mut buf = [0; 16];
+        let len = read_line(b"\x08ab\x7fc\x1b\td\r", &mut buf);
+        assert_eq!(&buf[..len], b"acd");
+        let len = read_line(b"xy\x08\x08\x08z\n", &mut buf);
+        assert_eq!(&buf[..len], b"z");
+    }
+
+    #[test]
+    fn read_line_overflow() {
+        let mut buf = [0; 2];
+        let len = read_line(b"abcd\x7fe\r", &mut buf);
+        assert_eq!(&buf[..len], b"ae");
+    }
+}
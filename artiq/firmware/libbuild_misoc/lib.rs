@@ -1,6 +1,6 @@
 use std::env;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
 
 pub fn cfg() {
@@ -13,3 +13,91 @@ pub fn cfg() {
         println!("cargo:rustc-cfg={}", line.unwrap());
     }
 }
+
+/// Writes `csr_ranges.rs` into `OUT_DIR`, defining `CSR_RANGES` as the
+/// address and length in bytes of every CSR in the generated `csr.rs`,
+/// with adjacent CSRs merged.
+pub fn csr_ranges() {
+    let out_dir = env::var("BUILDINC_DIRECTORY").unwrap();
+    let csr_path = Path::new(&out_dir).join("generated").join("csr.rs");
+    println!("cargo:rerun-if-changed={}", csr_path.to_str().unwrap());
+
+    let f = BufReader::new(File::open(&csr_path).unwrap());
+    let ranges = parse_csr_ranges(f.lines().map(|line| line.unwrap()));
+
+    let dest_path = Path::new(&env::var("OUT_DIR").unwrap()).join("csr_ranges.rs");
+    let mut f = File::create(&dest_path).unwrap();
+    writeln!(f, "pub const CSR_RANGES: &'static [(u32, u32)] = &[").unwrap();
+    for (address, length) in ranges {
+        writeln!(f, "    ({:#010x}, {}),", address, length).unwrap();
+    }
+    writeln!(f, "];").unwrap();
+}
+
+// Every CSR appears in csr.rs as a pair of constants:
+//   pub const LOAD_ADDR: *mut u32 = 0xe0001000 as *mut u32;
+//   pub const LOAD_SIZE: usize = 4;
+// the size being in 32-bit words.
+fn parse_csr_ranges<I: Iterator<Item=String>>(lines: I) -> Vec<(u32, u32)> {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    let mut address = None;
+    for line in lines {
+        let line = line.trim();
+        if !line.starts_with("pub const ") {
+            continue
+        }
+        let mut parts = line["pub const ".len()..].splitn(2, '=');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim().trim_end_matches(';')),
+            _ => continue
+        };
+        if name.ends_with("_ADDR: *mut u32") && value.starts_with("0x") {
+            let value = value.trim_end_matches(" as *mut u32");
+            address = u32::from_str_radix(&value[2..], 16).ok();
+        } else if name.ends_with("_SIZE: usize") {
+            if let (Some(start), Ok(words)) = (address.take(), value.parse::<u32>()) {
+                ranges.push((start, words * 4));
+            }
+        } else {
+            address = None;
+        }
+    }
+
+    ranges.sort();
+    let mut merged: Vec<(u32, u32)> = Vec::new();
+    for (start, length) in ranges {
+        match merged.last_mut() {
+            Some(&mut (last_start, ref mut last_length)) if last_start + *last_length == start =>
+                *last_length += length,
+            _ => merged.push((start, length))
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csr_ranges_from_csr_rs() {
+        let csr_rs = "
+            #[allow(dead_code)]
+            pub mod csr {
+              pub const TIMER0_BASE: *mut u32 = 0xe0002800 as *mut u32;
+              pub mod timer0 {
+                pub const LOAD_ADDR: *mut u32 = 0xe0002800 as *mut u32;
+                pub const LOAD_SIZE: usize = 2;
+                pub const EN_ADDR: *mut u32 = 0xe0002808 as *mut u32;
+                pub const EN_SIZE: usize = 1;
+              }
+              pub mod uart {
+                pub const RXTX_ADDR: *mut u32 = 0xe0001000 as *mut u32;
+                pub const RXTX_SIZE: usize = 1;
+              }
+              pub const CONFIG_CLOCK_FREQUENCY: u32 = 125000000;
+            }";
+        let lines = csr_rs.lines().map(String::from);
+        assert_eq!(parse_csr_ranges(lines), vec![(0xe0001000, 4), (0xe0002800, 12)]);
+    }
+}
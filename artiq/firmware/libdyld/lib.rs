@@ -1,5 +1,9 @@
 #![no_std]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 use core::{mem, ptr, fmt, slice, str, convert};
 use elf::*;
 
@@ -75,6 +79,43 @@ impl<'a> fmt::Display for Error<'a> {
     }
 }
 
+/// Returns the contents of the section called `name`, or `None` if the
+/// object has no such section.
+pub fn find_section<'a>(data: &'a [u8], name: &[u8]) -> Result<Option<&'a [u8]>, Error<'a>> {
+    let ehdr = read_unaligned::<Elf32_Ehdr>(data, 0)
+                              .map_err(|()| "cannot read ELF header")?;
+    if ehdr.e_shoff == 0 {
+        return Ok(None)
+    }
+
+    let read_shdr = |i: usize| {
+        let shdr_off = ehdr.e_shoff as usize + mem::size_of::<Elf32_Shdr>() * i;
+        read_unaligned::<Elf32_Shdr>(data, shdr_off)
+                       .map_err(|()| "cannot read section header")
+    };
+    let read_contents = |shdr: &Elf32_Shdr| {
+        if shdr.sh_type == SHT_NOBITS as Elf32_Word {
+            return Ok(&[][..])
+        }
+        data.get(shdr.sh_offset as usize..)
+            .and_then(|contents| contents.get(..shdr.sh_size as usize))
+            .ok_or("section contents out of bounds")
+    };
+
+    let shstrtab = read_contents(&read_shdr(ehdr.e_shstrndx as usize)?)?;
+    for i in 0..ehdr.e_shnum as usize {
+        let shdr = read_shdr(i)?;
+        let sh_name = shstrtab.get(shdr.sh_name as usize..)
+                              .ok_or("section name out of bounds")?;
+        let sh_name = &sh_name[..sh_name.iter().position(|&c| c == 0).unwrap_or(sh_name.len())];
+        if sh_name == name {
+            return Ok(Some(read_contents(&shdr)?))
+        }
+    }
+
+    Ok(None)
+}
+
 pub fn is_elf_for_current_arch(ehdr: &Elf32_Ehdr, e_type: u16) -> bool {
     const IDENT: [u8; EI_NIDENT] = [
         ELFMAG0,    ELFMAG1,     ELFMAG2,    ELFMAG3,
@@ -419,3 +460,83 @@ impl<'a> Library<'a> {
         Ok(library)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn push<T: Copy>(object: &mut Vec<u8>, value: &T) {
+        let bytes = unsafe {
+            slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+        };
+        object.extend_from_slice(bytes)
+    }
+
+    // An object with only a section header string table and the given
+    // sections, which the headers follow.
+    fn object(sections: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut names = vec![0u8];
+        let mut headers = Vec::new();
+        let mut contents = Vec::new();
+        let contents_offset = mem::size_of::<Elf32_Ehdr>();
+        for &(name, data) in [(".shstrtab", &[][..])].iter().chain(sections) {
+            let mut shdr: Elf32_Shdr = unsafe { mem::zeroed() };
+            shdr.sh_name = names.len() as Elf32_Word;
+            shdr.sh_type = SHT_PROGBITS as Elf32_Word;
+            shdr.sh_offset = (contents_offset + contents.len()) as Elf32_Off;
+            shdr.sh_size = data.len() as Elf32_Word;
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+            contents.extend_from_slice(data);
+            headers.push(shdr);
+        }
+        // the string table goes last, once all the names are known
+        headers[0].sh_type = SHT_STRTAB as Elf32_Word;
+        headers[0].sh_offset = (contents_offset + contents.len()) as Elf32_Off;
+        headers[0].sh_size = names.len() as Elf32_Word;
+        contents.extend_from_slice(&names);
+
+        let mut ehdr: Elf32_Ehdr = unsafe { mem::zeroed() };
+        ehdr.e_shoff = (contents_offset + contents.len()) as Elf32_Off;
+        ehdr.e_shnum = headers.len() as Elf32_Half;
+        ehdr.e_shstrndx = 0;
+
+        let mut object = Vec::new();
+        push(&mut object, &ehdr);
+        object.extend_from_slice(&contents);
+        for shdr in headers.iter() {
+            push(&mut object, shdr);
+        }
+        object
+    }
+
+    #[test]
+    fn section_found() {
+        let object = object(&[(".text", b"code"), (".artiq_metadata", b"{\"name\": \"scan\"}")]);
+        assert_eq!(find_section(&object, b".artiq_metadata").unwrap(),
+                   Some(&b"{\"name\": \"scan\"}"[..]));
+        assert_eq!(find_section(&object, b".text").unwrap(), Some(&b"code"[..]));
+        // names are matched whole, not by prefix
+        assert_eq!(find_section(&object, b".artiq").unwrap(), None);
+    }
+
+    #[test]
+    fn section_absent() {
+        let object = object(&[(".text", b"code")]);
+        assert_eq!(find_section(&object, b".artiq_metadata").unwrap(), None);
+
+        let mut ehdr: Elf32_Ehdr = unsafe { mem::zeroed() };
+        let mut headerless = Vec::new();
+        push(&mut headerless, &ehdr);
+        assert_eq!(find_section(&headerless, b".artiq_metadata").unwrap(), None);
+
+        // section headers beyond the end of the object
+        ehdr.e_shoff = 1000;
+        ehdr.e_shnum = 1;
+        let mut truncated = Vec::new();
+        push(&mut truncated, &ehdr);
+        assert!(find_section(&truncated, b".artiq_metadata").is_err());
+        assert!(find_section(b"ELF", b".artiq_metadata").is_err());
+    }
+}
@@ -15,26 +15,48 @@ pub struct Exception<'a> {
     pub param:    [i64; 3]
 }
 
-fn str_err(_: core::str::Utf8Error) -> core::fmt::Error {
-    core::fmt::Error
-}
+// Exceptions are usually printed because something already went wrong,
+// so formatting them must not fail on a corrupted string.
+struct ExceptionStr<'a>(&'a CSlice<'a, u8>);
 
-fn exception_str<'a>(s: &'a CSlice<'a, u8>) -> Result<&'a str, core::str::Utf8Error> {
-    if s.len() == usize::MAX {
-        Ok("<host string>")
-    } else {
-        core::str::from_utf8(s.as_ref())
+impl<'a> core::fmt::Display for ExceptionStr<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.0.len() == usize::MAX {
+            // only the host can resolve these
+            write!(f, "<host string {}>", self.0.as_ptr() as usize)
+        } else {
+            match core::str::from_utf8(self.0.as_ref()) {
+                Ok(s) => f.write_str(s),
+                Err(_) => f.write_str("<invalid UTF-8>")
+            }
+        }
     }
 }
 
+fn exception_str<'a>(s: &'a CSlice<'a, u8>) -> ExceptionStr<'a> {
+    ExceptionStr(s)
+}
+
 impl<'a> core::fmt::Debug for Exception<'a> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "Exception {} from {} in {}:{}:{}, message: {}",
+        write!(f, "Exception {} from {} in {}:{}:{}, message: {}, param: {:?}",
             self.id,
-            exception_str(&self.function).map_err(str_err)?,
-            exception_str(&self.file).map_err(str_err)?,
+            exception_str(&self.function),
+            exception_str(&self.file),
+            self.line, self.column,
+            exception_str(&self.message),
+            self.param)
+    }
+}
+
+impl<'a> core::fmt::Display for Exception<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}:{}: in {}: exception {}: {}",
+            exception_str(&self.file),
             self.line, self.column,
-            exception_str(&self.message).map_err(str_err)?)
+            exception_str(&self.function),
+            self.id,
+            exception_str(&self.message))
     }
 }
 
@@ -45,3 +67,46 @@ pub struct StackPointerBacktrace {
     pub current_backtrace_size: usize,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cslice::AsCSlice;
+
+    fn exception<'a>(message: CSlice<'a, u8>, file: &'a [u8]) -> Exception<'a> {
+        Exception {
+            id: 3,
+            file: file.as_c_slice(),
+            line: 12,
+            column: 4,
+            function: "run".as_c_slice(),
+            message: message,
+            param: [1, 0, 0]
+        }
+    }
+
+    #[test]
+    fn local_message() {
+        let exception = exception("index out of range".as_c_slice(), b"kernel.py");
+        assert_eq!(format!("{}", exception),
+                   "kernel.py:12:4: in run: exception 3: index out of range");
+        assert_eq!(format!("{:?}", exception),
+                   "Exception 3 from run in kernel.py:12:4, message: index out of range, \
+                    param: [1, 0, 0]");
+    }
+
+    #[test]
+    fn host_message() {
+        let message = unsafe { CSlice::new(12 as *const u8, usize::MAX) };
+        let exception = exception(message, b"kernel.py");
+        assert_eq!(format!("{}", exception),
+                   "kernel.py:12:4: in run: exception 3: <host string 12>");
+        assert!(format!("{:?}", exception).contains("message: <host string 12>,"));
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let exception = exception("ok".as_c_slice(), b"kernel\xff.py");
+        assert_eq!(format!("{}", exception), "<invalid UTF-8>:12:4: in run: exception 3: ok");
+        assert!(format!("{:?}", exception).contains(" in <invalid UTF-8>:12:4,"));
+    }
+}
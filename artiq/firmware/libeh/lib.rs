@@ -1,6 +1,9 @@
 #![feature(lang_items, panic_unwind, libc)]
 #![no_std]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
 extern crate cslice;
 extern crate unwind;
 extern crate libc;
@@ -0,0 +1,199 @@
+use core::mem;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, NativeEndian};
+
+use ::{Read, Error as IoError};
+
+/// Reads frames made of a u32 length followed by that many bytes, the
+/// format of `ProtoWrite::write_bytes`, refusing frames above a size limit.
+///
+/// When the transport fails with an error that can be retried, such as
+/// `Timeout` or `WouldBlock`, the bytes read so far are kept and the next
+/// call to `read_frame` continues the same frame.
+pub struct FrameReader {
+    max_size: usize,
+    header: [u8; 4],
+    header_len: usize,
+    length: usize,
+    data: Vec<u8>
+}
+
+// The buffer grows with the bytes actually received, so that a length prefix
+// alone cannot make the reader allocate anywhere near `max_size`.
+const GROWTH_STEP: usize = 64 << 10;
+
+impl FrameReader {
+    pub fn new(max_size: usize) -> FrameReader {
+        FrameReader {
+            max_size: max_size,
+            header: [0; 4],
+            header_len: 0,
+            length: 0,
+            data: Vec::new()
+        }
+    }
+
+    /// Discards a partially read frame.
+    pub fn reset(&mut self) {
+        self.header_len = 0;
+        self.length = 0;
+        self.data = Vec::new();
+    }
+
+    pub fn read_frame<R>(&mut self, reader: &mut R) -> Result<Vec<u8>, IoError<R::ReadError>>
+        where R: Read + ?Sized
+    {
+        while self.header_len < self.header.len() {
+            let read_bytes = match reader.read(&mut self.header[self.header_len..]) {
+                Ok(read_bytes) => read_bytes,
+                Err(err) => return Err(reader.classify_read_error(err))
+            };
+            if read_bytes == 0 {
+                self.reset();
+                return Err(IoError::UnexpectedEof)
+            }
+            self.header_len += read_bytes;
+            if self.header_len == self.header.len() {
+                let length = NativeEndian::read_u32(&self.header) as usize;
+                if length > self.max_size {
+                    self.reset();
+                    return Err(IoError::TooLarge { length: length, max: self.max_size })
+                }
+                self.length = length;
+                self.data = Vec::with_capacity(length.min(GROWTH_STEP));
+            }
+        }
+
+        while self.data.len() < self.length {
+            let filled = self.data.len();
+            self.data.resize(self.length.min(filled + GROWTH_STEP), 0);
+            match reader.read(&mut self.data[filled..]) {
+                Ok(0) => {
+                    self.reset();
+                    return Err(IoError::UnexpectedEof)
+                }
+                Ok(read_bytes) => self.data.truncate(filled + read_bytes),
+                Err(err) => {
+                    self.data.truncate(filled);
+                    return Err(reader.classify_read_error(err))
+                }
+            }
+        }
+
+        self.header_len = 0;
+        self.length = 0;
+        Ok(mem::replace(&mut self.data, Vec::new()))
+    }
+}
+
+/// Reads a single frame that must not exceed `max_size` bytes.
+pub fn read_frame<R>(reader: &mut R, max_size: usize) -> Result<Vec<u8>, IoError<R::ReadError>>
+    where R: Read + ?Sized
+{
+    FrameReader::new(max_size).read_frame(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Cursor, ProtoWrite};
+
+    // Hands out the steps in turn, a `None` step failing like a timed out
+    // transport and an empty one ending the stream.
+    struct Steps<'a> {
+        steps: &'a [Option<&'a [u8]>],
+        index: usize,
+        offset: usize
+    }
+
+    impl<'a> Steps<'a> {
+        fn new(steps: &'a [Option<&'a [u8]>]) -> Steps<'a> {
+            Steps { steps: steps, index: 0, offset: 0 }
+        }
+    }
+
+    impl<'a> Read for Steps<'a> {
+        type ReadError = ();
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+            let step = self.steps.get(self.index).cloned().unwrap_or(Some(&[]));
+            let bytes = match step {
+                Some(bytes) => &bytes[self.offset..],
+                None => {
+                    self.index += 1;
+                    return Err(())
+                }
+            };
+            let len = buf.len().min(bytes.len());
+            buf[..len].copy_from_slice(&bytes[..len]);
+            if len == bytes.len() {
+                self.index += 1;
+                self.offset = 0;
+            } else {
+                self.offset += len;
+            }
+            Ok(len)
+        }
+    }
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.write_bytes(data).unwrap();
+        frame
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let frame = frame(b"hello");
+        match read_frame(&mut Cursor::new(&frame[..]), 4) {
+            Err(IoError::TooLarge { length: 5, max: 4 }) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        assert_eq!(read_frame(&mut Cursor::new(&frame[..]), 5).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn frame_resumes_after_error() {
+        let frame = frame(b"hello");
+        let steps = [Some(&frame[..2]), None, Some(&frame[2..5]), None, Some(&frame[5..])];
+        let mut reader = Steps::new(&steps);
+        let mut frame_reader = FrameReader::new(16);
+        assert_eq!(frame_reader.read_frame(&mut reader), Err(IoError::Transport(())));
+        assert_eq!(frame_reader.read_frame(&mut reader), Err(IoError::Transport(())));
+        assert_eq!(frame_reader.read_frame(&mut reader).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn frame_partial_at_end() {
+        let first = frame(b"hello");
+        let second = frame(b"hi");
+        let steps = [Some(&first[..6]), Some(&[][..]), Some(&second[..])];
+        let mut reader = Steps::new(&steps);
+        let mut frame_reader = FrameReader::new(16);
+        assert_eq!(frame_reader.read_frame(&mut reader), Err(IoError::UnexpectedEof));
+        // the partial frame is dropped rather than prepended to the next one
+        assert_eq!(frame_reader.read_frame(&mut reader).unwrap(), b"hi");
+    }
+
+
+    #[test]
+    fn frame_grows_with_data() {
+        // a huge length prefix followed by a few bytes only allocates for those
+        let mut frame = frame(&[7; 3]);
+        NativeEndian::write_u32(&mut frame[..4], 1 << 30);
+        let steps = [Some(&frame[..]), None];
+        let mut reader = Steps::new(&steps);
+        let mut frame_reader = FrameReader::new(usize::max_value());
+        assert_eq!(frame_reader.read_frame(&mut reader), Err(IoError::Transport(())));
+        assert_eq!(frame_reader.data, [7; 3]);
+        // the vector doubles when growing past the first step
+        assert!(frame_reader.data.capacity() <= 2 * GROWTH_STEP);
+    }
+
+    #[test]
+    fn frame_across_growth_steps() {
+        let data: Vec<u8> = (0..GROWTH_STEP * 2 + 5).map(|i| i as u8).collect();
+        let frame = frame(&data);
+        assert_eq!(read_frame(&mut Cursor::new(&frame[..]), data.len()).unwrap(), data);
+    }
+}
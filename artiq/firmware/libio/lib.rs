@@ -1,6 +1,8 @@
 #![no_std]
 #![feature(never_type)]
 
+#[cfg(test)]
+extern crate std;
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
@@ -13,24 +15,34 @@ extern crate byteorder;
 mod cursor;
 #[cfg(feature = "byteorder")]
 mod proto;
+#[cfg(all(feature = "byteorder", feature = "alloc"))]
+mod frame;
 
 pub use cursor::Cursor;
 #[cfg(feature = "byteorder")]
 pub use proto::{ProtoRead, ProtoWrite};
 #[cfg(all(feature = "byteorder", feature = "alloc"))]
 pub use proto::ReadStringError;
+#[cfg(all(feature = "byteorder", feature = "alloc"))]
+pub use frame::{FrameReader, read_frame};
 
 #[derive(Fail, Debug, Clone, PartialEq)]
 pub enum Error<T> {
     #[fail(display = "unexpected end of stream")]
-    UnexpectedEnd,
+    UnexpectedEof,
+    #[fail(display = "timed out")]
+    Timeout,
+    #[fail(display = "operation would block")]
+    WouldBlock,
+    #[fail(display = "frame of {} bytes exceeds the limit of {} bytes", length, max)]
+    TooLarge { length: usize, max: usize },
     #[fail(display = "{}", _0)]
-    Other(#[cause] T)
+    Transport(#[cause] T)
 }
 
 impl<T> From<T> for Error<T> {
     fn from(value: T) -> Error<T> {
-        Error::Other(value)
+        Error::Transport(value)
     }
 }
 
@@ -41,12 +53,21 @@ pub trait Read {
     /// how many bytes were read.
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::ReadError>;
 
+    /// Tells errors that are worth retrying, reported as `Timeout` or
+    /// `WouldBlock`, apart from the others. By default none are.
+    fn classify_read_error(&self, error: Self::ReadError) -> Error<Self::ReadError> {
+        Error::Transport(error)
+    }
+
     /// Read the exact number of bytes required to fill `buf`.
     fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), Error<Self::ReadError>> {
         while !buf.is_empty() {
-            let read_bytes = self.read(buf)?;
+            let read_bytes = match self.read(buf) {
+                Ok(read_bytes) => read_bytes,
+                Err(error) => return Err(self.classify_read_error(error))
+            };
             if read_bytes == 0 {
-                return Err(Error::UnexpectedEnd)
+                return Err(Error::UnexpectedEof)
             }
 
             buf = &mut { buf }[read_bytes..];
@@ -62,6 +83,10 @@ impl<'a, T: Read> Read for &'a mut T {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::ReadError> {
         T::read(self, buf)
     }
+
+    fn classify_read_error(&self, error: Self::ReadError) -> Error<Self::ReadError> {
+        T::classify_read_error(self, error)
+    }
 }
 
 pub trait Write {
@@ -75,12 +100,21 @@ pub trait Write {
     /// reach their destination.
     fn flush(&mut self) -> Result<(), Self::FlushError>;
 
+    /// Tells errors that are worth retrying, reported as `Timeout` or
+    /// `WouldBlock`, apart from the others. By default none are.
+    fn classify_write_error(&self, error: Self::WriteError) -> Error<Self::WriteError> {
+        Error::Transport(error)
+    }
+
     /// Attempts to write an entire buffer into `self`.
     fn write_all(&mut self, mut buf: &[u8]) -> Result<(), Error<Self::WriteError>> {
         while buf.len() > 0 {
-            let written_bytes = self.write(buf)?;
+            let written_bytes = match self.write(buf) {
+                Ok(written_bytes) => written_bytes,
+                Err(error) => return Err(self.classify_write_error(error))
+            };
             if written_bytes == 0 {
-                return Err(Error::UnexpectedEnd)
+                return Err(Error::UnexpectedEof)
             }
 
             buf = &buf[written_bytes..];
@@ -108,6 +142,10 @@ impl<'a, T: Write> Write for &'a mut T {
         T::flush(self)
     }
 
+    fn classify_write_error(&self, error: Self::WriteError) -> Error<Self::WriteError> {
+        T::classify_write_error(self, error)
+    }
+
     fn size_hint(&mut self, min: usize, max: Option<usize>) {
         T::size_hint(self, min, max)
     }
@@ -144,3 +182,117 @@ impl<'a> Write for alloc::vec::Vec<u8> {
         Ok(())
     }
 }
+
+/// Writes all of `data` like `write_all`, but when the writer fails with
+/// `WouldBlock` or `Timeout`, calls `backoff` and tries again, up to
+/// `retries` times in a row.
+pub fn retry_write<W, B>(writer: &mut W, mut data: &[u8], retries: u32, mut backoff: B)
+                        -> Result<(), Error<W::WriteError>>
+    where W: Write + ?Sized, B: FnMut(&Error<W::WriteError>) -> Result<(), Error<W::WriteError>>
+{
+    let mut failures = 0;
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(Error::UnexpectedEof),
+            Ok(written) => {
+                data = &data[written..];
+                failures = 0;
+            }
+            Err(error) => match writer.classify_write_error(error) {
+                error @ Error::WouldBlock | error @ Error::Timeout if failures < retries => {
+                    failures += 1;
+                    backoff(&error)?
+                }
+                error => return Err(error)
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Fault { Busy, Reset }
+
+    // Accepts at most three bytes at a time, after failing with `fault`
+    // `failures` times before each write.
+    struct Flaky {
+        written: [u8; 16],
+        len: usize,
+        fault: Fault,
+        failures: u32,
+        pending: u32
+    }
+
+    impl Flaky {
+        fn new(fault: Fault, failures: u32) -> Flaky {
+            Flaky { written: [0; 16], len: 0, fault: fault, failures: failures, pending: failures }
+        }
+    }
+
+    impl Write for Flaky {
+        type WriteError = Fault;
+        type FlushError = !;
+
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Fault> {
+            if self.pending > 0 {
+                self.pending -= 1;
+                return Err(self.fault)
+            }
+            self.pending = self.failures;
+            let len = buf.len().min(3);
+            self.written[self.len..self.len + len].copy_from_slice(&buf[..len]);
+            self.len += len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> Result<(), !> {
+            Ok(())
+        }
+
+        fn classify_write_error(&self, error: Fault) -> Error<Fault> {
+            match error {
+                Fault::Busy => Error::WouldBlock,
+                error => Error::Transport(error)
+            }
+        }
+    }
+
+    #[test]
+    fn retry_write_transient() {
+        let data = b"0123456789";
+        for failures in 0..4 {
+            let mut writer = Flaky::new(Fault::Busy, failures);
+            let mut backoffs = 0;
+            retry_write(&mut writer, data, 3, |error| {
+                assert_eq!(*error, Error::WouldBlock);
+                backoffs += 1;
+                Ok(())
+            }).unwrap();
+            assert_eq!(&writer.written[..writer.len], data);
+            // four writes of up to three bytes, each after `failures` retries
+            assert_eq!(backoffs, 4 * failures);
+        }
+    }
+
+    #[test]
+    fn retry_write_gives_up() {
+        let mut writer = Flaky::new(Fault::Busy, 4);
+        let mut backoffs = 0;
+        assert_eq!(retry_write(&mut writer, b"0123", 3, |_| { backoffs += 1; Ok(()) }),
+                   Err(Error::WouldBlock));
+        assert_eq!(backoffs, 3);
+
+        let mut writer = Flaky::new(Fault::Reset, 1);
+        assert_eq!(retry_write(&mut writer, b"0123", 3, |_| panic!("retried a reset")),
+                   Err(Error::Transport(Fault::Reset)));
+
+        // an error while backing off ends the write
+        let mut writer = Flaky::new(Fault::Busy, 1);
+        assert_eq!(retry_write(&mut writer, b"0123", 3, |_| Err(Error::Timeout)),
+                   Err(Error::Timeout));
+    }
+}
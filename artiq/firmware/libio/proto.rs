@@ -51,6 +51,21 @@ pub trait ProtoRead {
         Ok(self.read_u8()? != 0)
     }
 
+    /// Reads an unsigned LEB128 value written by `write_varint`. Bits beyond
+    /// the fifth byte, which a well-formed encoding never has, are ignored.
+    #[inline]
+    fn read_varint(&mut self) -> Result<u32, Self::ReadError> {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let byte = self.read_u8()?;
+            if shift < 32 {
+                value |= ((byte & 0x7f) as u32) << shift;
+            }
+            if byte & 0x80 == 0 { break }
+        }
+        Ok(value)
+    }
+
     #[cfg(feature = "alloc")]
     #[inline]
     fn read_bytes(&mut self) -> Result<::alloc::vec::Vec<u8>, Self::ReadError> {
@@ -66,13 +81,27 @@ pub trait ProtoRead {
         let bytes = self.read_bytes().map_err(ReadStringError::Other)?;
         String::from_utf8(bytes).map_err(|err| ReadStringError::Utf8(err.utf8_error()))
     }
+
+    /// Like `read_string`, but replaces invalid UTF-8 sequences with U+FFFD
+    /// instead of failing. For human-facing fields from untrusted sources.
+    #[cfg(feature = "alloc")]
+    #[inline]
+    fn read_lossy_string(&mut self) -> Result<::alloc::string::String, Self::ReadError> {
+        let bytes = self.read_bytes()?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
 }
 
 pub trait ProtoWrite {
     type WriteError;
+    type FlushError;
 
     fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::WriteError>;
 
+    /// Pushes out anything buffered by the transport, so that a complete
+    /// message reaches the peer promptly. A no-op for unbuffered writers.
+    fn flush(&mut self) -> Result<(), Self::FlushError>;
+
     #[inline]
     fn write_u8(&mut self, value: u8) -> Result<(), Self::WriteError> {
         let bytes = [value; 1];
@@ -132,6 +161,22 @@ pub trait ProtoWrite {
         self.write_u8(value as u8)
     }
 
+    /// Writes `value` as unsigned LEB128, taking 1 to 5 bytes. Only for new
+    /// fields; existing ones stay fixed-width for compatibility.
+    #[inline]
+    fn write_varint(&mut self, mut value: u32) -> Result<(), Self::WriteError> {
+        let mut bytes = [0; 5];
+        let mut length = 0;
+        loop {
+            bytes[length] = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 { break }
+            bytes[length] |= 0x80;
+            length += 1;
+        }
+        self.write_all(&bytes[..length + 1])
+    }
+
     #[inline]
     fn write_bytes(&mut self, value: &[u8]) -> Result<(), Self::WriteError> {
         self.write_u32(value.len() as u32)?;
@@ -142,6 +187,13 @@ pub trait ProtoWrite {
     fn write_string(&mut self, value: &str) -> Result<(), Self::WriteError> {
         self.write_bytes(value.as_bytes())
     }
+
+    /// Writes bytes that are meant to be text but may not be valid UTF-8;
+    /// the counterpart of `read_lossy_string`.
+    #[inline]
+    fn write_lossy_string(&mut self, value: &[u8]) -> Result<(), Self::WriteError> {
+        self.write_bytes(value)
+    }
 }
 
 impl<T> ProtoRead for T where T: Read + ?Sized {
@@ -154,8 +206,135 @@ impl<T> ProtoRead for T where T: Read + ?Sized {
 
 impl<T> ProtoWrite for T where T: Write + ?Sized {
     type WriteError = IoError<T::WriteError>;
+    type FlushError = IoError<T::FlushError>;
 
     fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::WriteError> {
         T::write_all(self, buf)
     }
+
+    fn flush(&mut self) -> Result<(), Self::FlushError> {
+        Write::flush(self).map_err(IoError::Transport)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Cursor;
+
+    #[test]
+    fn varint_round_trip() {
+        let cases = [(0, 1), (0x7f, 1), (0x80, 2), (0x3fff, 2), (0x4000, 3),
+                     (0x0fff_ffff, 4), (0x1000_0000, 5), (0xffff_ffff, 5)];
+        for &(value, length) in cases.iter() {
+            let mut buf = [0; 5];
+            let written = {
+                let mut writer = Cursor::new(&mut buf[..]);
+                writer.write_varint(value).unwrap();
+                writer.position()
+            };
+            assert_eq!(written, length);
+            let mut reader = Cursor::new(&buf[..]);
+            assert_eq!(reader.read_varint().unwrap(), value);
+            assert_eq!(reader.position(), length);
+        }
+    }
+
+    #[test]
+    fn varint_overlong() {
+        // bits past the 32nd are dropped, and the whole encoding consumed
+        let buf = [0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 0x2a];
+        let mut reader = Cursor::new(&buf[..]);
+        assert_eq!(reader.read_varint().unwrap(), 0xffff_ffff);
+        assert_eq!(reader.read_u8().unwrap(), 0x2a);
+    }
+
+    #[test]
+    fn varint_truncated() {
+        for length in 0..4 {
+            let buf = [0x80, 0x80, 0x80];
+            match Cursor::new(&buf[..length]).read_varint() {
+                Err(IoError::UnexpectedEof) => (),
+                other => panic!("unexpected {:?}", other)
+            }
+        }
+    }
+
+    /// Holds everything written until flushed, like a socket with Nagle.
+    struct Buffered {
+        pending: [u8; 16],
+        pending_len: usize,
+        visible: [u8; 16],
+        visible_len: usize,
+    }
+
+    impl Write for Buffered {
+        type WriteError = ();
+        type FlushError = ();
+
+        fn write(&mut self, buf: &[u8]) -> Result<usize, ()> {
+            let len = buf.len().min(self.pending.len() - self.pending_len);
+            self.pending[self.pending_len..self.pending_len + len].copy_from_slice(&buf[..len]);
+            self.pending_len += len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> Result<(), ()> {
+            let len = self.pending_len;
+            self.visible[self.visible_len..self.visible_len + len]
+                .copy_from_slice(&self.pending[..len]);
+            self.visible_len += len;
+            self.pending_len = 0;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_buffered() {
+        let mut writer = Buffered { pending: [0; 16], pending_len: 0,
+                                    visible: [0; 16], visible_len: 0 };
+        writer.write_u8(7).unwrap();
+        writer.write_u32(0x1234_5678).unwrap();
+        assert_eq!(writer.visible_len, 0);
+
+        ProtoWrite::flush(&mut writer).unwrap();
+        assert_eq!(writer.pending_len, 0);
+        let mut reader = Cursor::new(&writer.visible[..writer.visible_len]);
+        assert_eq!(reader.read_u8().unwrap(), 7);
+        assert_eq!(reader.read_u32().unwrap(), 0x1234_5678);
+        assert_eq!(reader.position(), 5);
+    }
+
+    #[test]
+    fn flush_unbuffered() {
+        let mut buf = [0; 4];
+        let mut writer = Cursor::new(&mut buf[..]);
+        writer.write_u16(0xbeef).unwrap();
+        ProtoWrite::flush(&mut writer).unwrap();
+        assert_eq!(writer.position(), 2);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn lossy_string_invalid_utf8() {
+        let mut buf = [0; 32];
+        let written = {
+            let mut writer = Cursor::new(&mut buf[..]);
+            writer.write_lossy_string(b"ttl\xff\xfe0").unwrap();
+            writer.write_lossy_string(b"caf\xc3\xa9").unwrap();
+            writer.write_lossy_string(b"cut \xe2\x82").unwrap();
+            writer.position()
+        };
+        let mut reader = Cursor::new(&buf[..written]);
+        assert_eq!(reader.read_lossy_string().unwrap(), "ttl\u{fffd}\u{fffd}0");
+        assert_eq!(reader.read_lossy_string().unwrap(), "caf\u{e9}");
+        assert_eq!(reader.read_lossy_string().unwrap(), "cut \u{fffd}");
+
+        // the strict reader still refuses them
+        let mut reader = Cursor::new(&buf[..written]);
+        match reader.read_string() {
+            Err(ReadStringError::Utf8(error)) => assert_eq!(error.valid_up_to(), 3),
+            other => panic!("unexpected {:?}", other)
+        }
+    }
 }
@@ -1,5 +1,9 @@
 #![no_std]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+extern crate alloc;
 extern crate log;
 extern crate log_buffer;
 #[macro_use]
@@ -7,20 +11,37 @@ extern crate board_misoc;
 
 use core::cell::{Cell, RefCell, RefMut};
 use core::fmt::Write;
+use alloc::{vec::Vec, string::String, format};
 use log::{Log, LevelFilter};
 use log_buffer::LogBuffer;
 use board_misoc::clock;
 
+/// The most recent complete lines of `content` that fit in a buffer of
+/// `capacity` bytes with a byte to spare, so that copying them over does not
+/// wrap around. The cut is made after a newline, which is never in the middle
+/// of a UTF-8 sequence.
+fn recent_lines(content: &str, capacity: usize) -> &str {
+    if content.len() < capacity {
+        return content
+    }
+    let start = content.len() + 1 - capacity;
+    match content.as_bytes()[start..].iter().position(|&byte| byte == b'\n') {
+        Some(newline) => &content[start + newline + 1..],
+        None => ""
+    }
+}
+
 pub struct LogBufferRef<'a> {
     buffer:        RefMut<'a, LogBuffer<&'static mut [u8]>>,
+    written:       &'a Written,
     old_log_level: LevelFilter
 }
 
 impl<'a> LogBufferRef<'a> {
-    fn new(buffer: RefMut<'a, LogBuffer<&'static mut [u8]>>) -> LogBufferRef<'a> {
+    fn new(buffer: RefMut<'a, LogBuffer<&'static mut [u8]>>, written: &'a Written) -> LogBufferRef<'a> {
         let old_log_level = log::max_level();
         log::set_max_level(LevelFilter::Off);
-        LogBufferRef { buffer, old_log_level }
+        LogBufferRef { buffer, written, old_log_level }
     }
 
     pub fn is_empty(&self) -> bool {
@@ -28,12 +49,137 @@ impl<'a> LogBufferRef<'a> {
     }
 
     pub fn clear(&mut self) {
-        self.buffer.clear()
+        self.buffer.clear();
+        self.written.bytes.set(0);
+        self.written.lines.set(0);
+        self.written.generation.set(self.written.generation.get().wrapping_add(1));
     }
 
     pub fn extract(&mut self) -> &str {
         self.buffer.extract()
     }
+
+    /// Whether older lines have been overwritten since the last `clear`.
+    pub fn overflowed(&self) -> bool {
+        self.written.bytes.get() > self.written.capacity.get()
+    }
+
+    /// How many lines have been overwritten since the last `clear`. A line
+    /// that is only partly overwritten counts as kept.
+    pub fn dropped_lines(&mut self) -> usize {
+        if !self.overflowed() {
+            return 0
+        }
+        let kept = self.buffer.extract().matches('\n').count();
+        self.written.lines.get().saturating_sub(kept)
+    }
+
+    /// Calls `f` with the level, timestamp in microseconds, target and
+    /// message of every complete record written since `cursor` was last
+    /// advanced, oldest first, and advances it past them. The buffer itself
+    /// is left alone, so `extract` and other readers still see every record.
+    pub fn read_records<F>(&mut self, cursor: &mut LogCursor, f: F)
+        where F: FnMut(log::Level, u64, &str, &str)
+    {
+        let generation = self.written.generation.get();
+        let written = self.written.bytes.get();
+        let content = self.buffer.extract();
+        let start = written.saturating_sub(content.len());
+        *cursor = read_records(content, start, generation, *cursor, f)
+    }
+
+    /// Like `extract`, but starts with a marker line if lines were dropped.
+    pub fn extract_marked(&mut self) -> String {
+        let dropped = self.dropped_lines();
+        let mut content = String::new();
+        if dropped > 0 {
+            writeln!(content, "... {} lines dropped ...", dropped).unwrap();
+        }
+        content.push_str(self.extract());
+        content
+    }
+}
+
+/// Where a reader of `LogBufferRef::read_records` left off. The default
+/// cursor starts at the oldest record in the buffer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogCursor {
+    // bumped by `clear` and `replace_buffer`, which invalidate positions
+    generation: u32,
+    // in bytes written since the buffer was last cleared
+    position:   usize
+}
+
+// `content` holds the bytes written from `start` onwards.
+fn read_records<F>(content: &str, start: usize, generation: u32, cursor: LogCursor, mut f: F)
+        -> LogCursor
+    where F: FnMut(log::Level, u64, &str, &str)
+{
+    let resume = cursor.generation == generation &&
+        cursor.position >= start && cursor.position - start <= content.len();
+    let mut rest = if resume {
+        // the cursor only ever stops right after a newline
+        &content[cursor.position - start..]
+    } else if content.starts_with('[') {
+        content
+    } else {
+        // a record cut off by the ring wrapping has lost its header
+        content.as_bytes().iter().position(|&byte| byte == b'\n')
+            .map(|newline| &content[newline + 1..]).unwrap_or("")
+    };
+    loop {
+        // messages may span lines, but only records start with a timestamp
+        let end = match rest.find("\n[") {
+            Some(newline) => newline + 1,
+            None if rest.ends_with('\n') => rest.len(),
+            None => break
+        };
+        if let Some((level, timestamp, target, message)) =
+                parse_record(rest[..end].trim_right_matches('\n')) {
+            f(level, timestamp, target, message)
+        }
+        rest = &rest[end..];
+    }
+    LogCursor {
+        generation: generation,
+        position: start + content.len() - rest.len()
+    }
+}
+
+// Undoes the formatting of `BufferLogger::emit` and `Log::log`.
+fn parse_record(record: &str) -> Option<(log::Level, u64, &str, &str)> {
+    let stamp_end = record.find("s] ")?;
+    let mut stamp = record.get(1..stamp_end)?.trim().splitn(2, '.');
+    let seconds = stamp.next()?.parse::<u64>().ok()?;
+    let micros = stamp.next()?.parse::<u64>().ok()?;
+
+    let rest = record[stamp_end + 3..].trim_left();
+    let level_end = rest.find('(')?;
+    let level = rest[..level_end].parse::<log::Level>().ok()?;
+    let rest = &rest[level_end + 1..];
+    let target_end = rest.find("): ")?;
+    Some((level, seconds * 1_000_000 + micros, &rest[..target_end], &rest[target_end + 3..]))
+}
+
+// What went into the buffer since it was last cleared, to tell whether
+// the ring has overwritten anything.
+struct Written {
+    capacity:       Cell<usize>,
+    bytes:          Cell<usize>,
+    lines:          Cell<usize>,
+    generation:     Cell<u32>
+}
+
+struct CountingWriter<'a, W: Write + 'a> {
+    inner:          &'a mut W,
+    bytes:          usize
+}
+
+impl<'a, W: Write> Write for CountingWriter<'a, W> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.bytes += s.len();
+        self.inner.write_str(s)
+    }
 }
 
 impl<'a> Drop for LogBufferRef<'a> {
@@ -43,8 +189,92 @@ impl<'a> Drop for LogBufferRef<'a> {
 }
 
 pub struct BufferLogger {
-    buffer:      RefCell<LogBuffer<&'static mut [u8]>>,
-    uart_filter: Cell<LevelFilter>
+    buffer:         RefCell<LogBuffer<&'static mut [u8]>>,
+    written:        Written,
+    uart_filter:    Cell<LevelFilter>,
+    global_filter:  Cell<LevelFilter>,
+    target_filters: RefCell<Vec<(String, LevelFilter)>>,
+    deferred:       RefCell<Option<Vec<Deferred>>>,
+    live:           RefCell<Option<LiveFeed>>,
+    rate_limit:     Cell<Option<RateLimit>>,
+    rate_sites:     RefCell<Vec<RateSite>>
+}
+
+/// At most `records` records per call site are kept in each window of
+/// `window_ms` milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub records:    u32,
+    pub window_ms:  u32
+}
+
+struct RateSite {
+    module_path:    &'static str,
+    line:           u32,
+    level:          log::Level,
+    window_start:   u64,
+    count:          u32,
+    suppressed:     u32
+}
+
+// Call sites beyond this many evict the one whose window started first.
+const MAX_RATE_SITES: usize = 32;
+
+struct Deferred {
+    level:          log::Level,
+    timestamp:      u64,
+    host_timestamp: u64,
+    message:        String
+}
+
+// Enough for a complete boot; anything beyond is dropped.
+const MAX_DEFERRED_RECORDS: usize = 256;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Deferral {
+    Hold,
+    // the boot is not going to be quiet after all
+    Flush,
+    Emit
+}
+
+fn deferral(deferred: &Option<Vec<Deferred>>, level: log::Level) -> Deferral {
+    match *deferred {
+        None => Deferral::Emit,
+        Some(_) if level > log::Level::Warn => Deferral::Hold,
+        Some(_) => Deferral::Flush
+    }
+}
+
+struct LiveFeed {
+    max_level:      LevelFilter,
+    records:        Vec<(log::Level, String)>
+}
+
+// Records pile up only while the subscriber is not reading them.
+const MAX_LIVE_RECORDS: usize = 64;
+
+fn target_matches(target: &str, filter: &str) -> bool {
+    target == filter ||
+        (target.starts_with(filter) && target[filter.len()..].starts_with("::")) ||
+        target.split("::").any(|component| component == filter)
+}
+
+/// Parses a `target=level,...` string such as `mailbox=warn,session=debug`.
+/// Returns the filters, and the malformed entries, which are skipped.
+pub fn parse_target_filters(spec: &str) -> (Vec<(String, LevelFilter)>, Vec<&str>) {
+    let mut filters = Vec::new();
+    let mut rejected = Vec::new();
+    for entry in spec.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let target = parts.next().unwrap().trim();
+        match parts.next().map(|level| level.trim().parse::<LevelFilter>()) {
+            Some(Ok(level)) if !target.is_empty() =>
+                filters.push((String::from(target), level)),
+            _ => rejected.push(entry)
+        }
+    }
+    (filters, rejected)
 }
 
 static mut LOGGER: *const BufferLogger = 0 as *const _;
@@ -52,8 +282,20 @@ static mut LOGGER: *const BufferLogger = 0 as *const _;
 impl BufferLogger {
     pub fn new(buffer: &'static mut [u8]) -> BufferLogger {
         BufferLogger {
+            written: Written {
+                capacity: Cell::new(buffer.len()),
+                bytes: Cell::new(0),
+                lines: Cell::new(0),
+                generation: Cell::new(0)
+            },
             buffer: RefCell::new(LogBuffer::new(buffer)),
             uart_filter: Cell::new(LevelFilter::Info),
+            global_filter: Cell::new(LevelFilter::Info),
+            target_filters: RefCell::new(Vec::new()),
+            deferred: RefCell::new(None),
+            live: RefCell::new(None),
+            rate_limit: Cell::new(None),
+            rate_sites: RefCell::new(Vec::new()),
         }
     }
 
@@ -71,13 +313,210 @@ impl BufferLogger {
         f(unsafe { &*LOGGER })
     }
 
+    /// Like `with`, but usable before the logger is registered, e.g. on panic.
+    pub fn try_with<R, F: FnOnce(&BufferLogger) -> R>(f: F) -> Option<R> {
+        if unsafe { LOGGER.is_null() } {
+            None
+        } else {
+            Some(Self::with(f))
+        }
+    }
+
+    /// Holds back records less severe than warnings, until either
+    /// `emit_deferred` or `discard_deferred` is called, or a warning or
+    /// error is logged, which emits them ahead of it and stops deferring.
+    pub fn defer(&self) {
+        *self.deferred.borrow_mut() = Some(Vec::new())
+    }
+
+    /// Stops deferring and emits the records held back so far.
+    pub fn emit_deferred(&self) {
+        let deferred = match self.deferred.try_borrow_mut() {
+            Ok(mut deferred) => deferred.take(),
+            Err(_) => return
+        };
+        for record in deferred.into_iter().flat_map(|records| records) {
+            self.emit(record.level, record.timestamp, record.host_timestamp, &record.message)
+        }
+    }
+
+    /// Stops deferring and drops the records held back so far.
+    pub fn discard_deferred(&self) {
+        *self.deferred.borrow_mut() = None
+    }
+
+    fn emit<M: core::fmt::Display + ?Sized>(&self, level: log::Level, timestamp: u64,
+                                            host_timestamp: u64, message: &M) {
+        self.write_buffer(host_timestamp, message);
+
+        if level <= self.uart_filter.get() {
+            println!("[{:6}.{:06}s] {}", timestamp / 1_000_000, timestamp % 1_000_000, message);
+        }
+
+        if let Ok(mut live) = self.live.try_borrow_mut() {
+            if let Some(ref mut live) = *live {
+                if level <= live.max_level && live.records.len() < MAX_LIVE_RECORDS {
+                    live.records.push((level, format!("{}", message)))
+                }
+            }
+        }
+    }
+
+    fn write_buffer<M: core::fmt::Display + ?Sized>(&self, host_timestamp: u64, message: &M) {
+        if let Ok(mut buffer) = self.buffer.try_borrow_mut() {
+            let mut writer = CountingWriter { inner: &mut *buffer, bytes: 0 };
+            // a failing Display impl must not panic, this may run in the panic handler
+            let _ = writeln!(writer, "[{:6}.{:06}s] {}",
+                             host_timestamp / 1_000_000, host_timestamp % 1_000_000, message);
+            self.written.bytes.set(self.written.bytes.get().saturating_add(writer.bytes));
+            self.written.lines.set(self.written.lines.get().saturating_add(1));
+        }
+    }
+
+    /// Writes `panic` into the buffer as an error record of target `panic`,
+    /// bypassing the filters and without allocating, so that it can be
+    /// called from the panic handler.
+    pub fn write_panic<M: core::fmt::Display + ?Sized>(&self, panic: &M) {
+        let timestamp = clock::get_us();
+        let host_timestamp = clock::get_unix_us().unwrap_or(timestamp);
+        self.write_buffer(host_timestamp, &format_args!("{:>5}(panic): {}", log::Level::Error, panic))
+    }
+
+    /// Starts collecting records up to `max_level` for `take_live_records`,
+    /// in addition to the buffer. Records must still pass the log filters.
+    pub fn subscribe(&self, max_level: LevelFilter) {
+        *self.live.borrow_mut() = Some(LiveFeed { max_level: max_level, records: Vec::new() })
+    }
+
+    /// Stops collecting records and drops those not yet taken.
+    pub fn unsubscribe(&self) {
+        *self.live.borrow_mut() = None
+    }
+
+    /// Returns the records collected since the last call, oldest first.
+    pub fn take_live_records(&self) -> Vec<(log::Level, String)> {
+        match self.live.try_borrow_mut() {
+            Ok(mut live) => live.as_mut()
+                .map(|live| core::mem::replace(&mut live.records, Vec::new()))
+                .unwrap_or_default(),
+            Err(_) => Vec::new()
+        }
+    }
+
+    /// Borrows the buffer for reading, after summarizing any records that
+    /// the rate limit has dropped so far.
     pub fn buffer<'a>(&'a self) -> Result<LogBufferRef<'a>, ()> {
+        self.emit_repeats();
         self.buffer
             .try_borrow_mut()
-            .map(LogBufferRef::new)
+            .map(|buffer| LogBufferRef::new(buffer, &self.written))
             .map_err(|_| ())
     }
 
+    /// Moves logging to `buffer`, e.g. a larger one allocated once the heap
+    /// is available. As many of the most recent complete lines as fit are
+    /// copied over. Fails if the buffer is in use, like `buffer`.
+    pub fn replace_buffer(&self, buffer: &'static mut [u8]) -> Result<(), ()> {
+        let mut current = self.buffer.try_borrow_mut().map_err(|_| ())?;
+        let capacity = buffer.len();
+        let mut old = core::mem::replace(&mut *current, LogBuffer::new(buffer));
+        let content = old.extract();
+        let tail = recent_lines(content, capacity);
+        current.write_str(tail).unwrap();
+
+        // keep counting the lines lost so far, including any left behind
+        let lines = self.written.lines.get();
+        self.written.capacity.set(capacity);
+        self.written.generation.set(self.written.generation.get().wrapping_add(1));
+        if lines > tail.matches('\n').count() {
+            self.written.bytes.set(capacity + 1)
+        } else {
+            self.written.bytes.set(tail.len())
+        }
+        Ok(())
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit.get()
+    }
+
+    /// Limits how often a single call site can log; `None`, the default,
+    /// keeps every record.
+    pub fn set_rate_limit(&self, rate_limit: Option<RateLimit>) {
+        self.rate_limit.set(rate_limit);
+        self.rate_sites.borrow_mut().clear()
+    }
+
+    // Returns whether the record should be kept, and how many records of
+    // the same call site were dropped in the window that just ended.
+    fn check_rate(&self, record: &log::Record) -> (bool, u32) {
+        let rate_limit = match self.rate_limit.get() {
+            Some(rate_limit) => rate_limit,
+            None => return (true, 0)
+        };
+        let (module_path, line) = match (record.module_path_static(), record.line()) {
+            (Some(module_path), Some(line)) => (module_path, line),
+            _ => return (true, 0)
+        };
+        let mut sites = match self.rate_sites.try_borrow_mut() {
+            Ok(sites) => sites,
+            Err(_) => return (true, 0)
+        };
+
+        let now = clock::get_ms();
+        let index = match sites.iter().position(|site|
+                site.line == line && site.module_path == module_path) {
+            Some(index) => index,
+            None => {
+                let site = RateSite { module_path, line, level: record.level(),
+                                      window_start: now, count: 0, suppressed: 0 };
+                if sites.len() < MAX_RATE_SITES {
+                    sites.push(site);
+                    sites.len() - 1
+                } else {
+                    let oldest = (0..sites.len()).min_by_key(|&index| sites[index].window_start).unwrap();
+                    sites[oldest] = site;
+                    oldest
+                }
+            }
+        };
+
+        let site = &mut sites[index];
+        site.level = record.level();
+        let mut summary = 0;
+        if clock::deadline_passed(now, site.window_start + rate_limit.window_ms as u64) {
+            summary = site.suppressed;
+            site.window_start = now;
+            site.count = 0;
+            site.suppressed = 0;
+        }
+        site.count += 1;
+        if site.count > rate_limit.records {
+            site.suppressed += 1;
+            (false, summary)
+        } else {
+            (true, summary)
+        }
+    }
+
+    // Summarizes the records dropped by the rate limit that have not been
+    // summarized yet, so that readers learn about them without waiting for
+    // their call site to log again after its window.
+    fn emit_repeats(&self) {
+        let mut sites = match self.rate_sites.try_borrow_mut() {
+            Ok(sites) => sites,
+            Err(_) => return
+        };
+        let timestamp = clock::get_us();
+        let host_timestamp = clock::get_unix_us().unwrap_or(timestamp);
+        for site in sites.iter_mut().filter(|site| site.suppressed > 0) {
+            self.emit(site.level, timestamp, host_timestamp,
+                      &format_args!("{:>5}({}): (repeated {}x)",
+                                    site.level, site.module_path, site.suppressed));
+            site.suppressed = 0;
+        }
+    }
+
     pub fn uart_log_level(&self) -> LevelFilter {
         self.uart_filter.get()
     }
@@ -85,34 +524,443 @@ impl BufferLogger {
     pub fn set_uart_log_level(&self, max_level: LevelFilter) {
         self.uart_filter.set(max_level)
     }
+
+    pub fn global_log_level(&self) -> LevelFilter {
+        self.global_filter.get()
+    }
+
+    /// Sets the level for targets without an override of their own.
+    pub fn set_global_log_level(&self, max_level: LevelFilter) {
+        self.global_filter.set(max_level);
+        self.update_max_level()
+    }
+
+    /// Replaces the per-target overrides, which take precedence over the
+    /// global level; the most specific matching target wins.
+    pub fn set_target_filters(&self, filters: Vec<(String, LevelFilter)>) {
+        *self.target_filters.borrow_mut() = filters;
+        self.update_max_level()
+    }
+
+    fn target_level(&self, target: &str) -> LevelFilter {
+        self.target_filters.borrow().iter()
+            .filter(|&&(ref filter, _)| target_matches(target, filter))
+            .max_by_key(|&&(ref filter, _)| filter.len())
+            .map(|&(_, level)| level)
+            .unwrap_or(self.global_filter.get())
+    }
+
+    // The log macros compare against the static maximum before calling
+    // into the logger, so it has to admit the most verbose override.
+    fn update_max_level(&self) {
+        let max_level = self.target_filters.borrow().iter()
+            .map(|&(_, level)| level)
+            .fold(self.global_filter.get(), |a, b| a.max(b));
+        log::set_max_level(max_level)
+    }
 }
 
 // required for impl Log
 unsafe impl Sync for BufferLogger {}
 
 impl Log for BufferLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.target_level(metadata.target())
     }
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
             let timestamp = clock::get_us();
-            let seconds   = timestamp / 1_000_000;
-            let micros    = timestamp % 1_000_000;
+            // The buffer is read by the host, so prefer its notion of time if known.
+            let host_timestamp = clock::get_unix_us().unwrap_or(timestamp);
 
-            if let Ok(mut buffer) = self.buffer.try_borrow_mut() {
-                writeln!(buffer, "[{:6}.{:06}s] {:>5}({}): {}", seconds, micros,
-                         record.level(), record.target(), record.args()).unwrap();
+            // Repeats are summarized once the call site logs again after
+            // its window, or when the buffer is read.
+            let (keep, suppressed) = self.check_rate(record);
+            if suppressed > 0 {
+                self.emit(record.level(), timestamp, host_timestamp,
+                          &format_args!("{:>5}({}): (repeated {}x)",
+                                        record.level(), record.target(), suppressed))
+            }
+            if !keep {
+                return
             }
 
-            if record.level() <= self.uart_filter.get() {
-                println!("[{:6}.{:06}s] {:>5}({}): {}", seconds, micros,
-                         record.level(), record.target(), record.args());
+            if let Ok(mut deferred) = self.deferred.try_borrow_mut() {
+                match deferral(&deferred, record.level()) {
+                    Deferral::Hold => {
+                        let deferred = deferred.as_mut().unwrap();
+                        if deferred.len() < MAX_DEFERRED_RECORDS {
+                            deferred.push(Deferred {
+                                level: record.level(),
+                                timestamp: timestamp,
+                                host_timestamp: host_timestamp,
+                                message: format!("{:>5}({}): {}",
+                                                 record.level(), record.target(), record.args())
+                            });
+                        }
+                        return
+                    }
+                    Deferral::Flush => {
+                        let records = deferred.take().unwrap();
+                        drop(deferred);
+                        for held in records {
+                            self.emit(held.level, held.timestamp, held.host_timestamp, &held.message)
+                        }
+                    }
+                    Deferral::Emit => ()
+                }
             }
+            self.emit(record.level(), timestamp, host_timestamp,
+                      &format_args!("{:>5}({}): {}", record.level(), record.target(), record.args()))
         }
     }
 
     fn flush(&self) {
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    // `log::max_level` is global, and `buffer` overrides it while borrowed,
+    // so tests that look at it take turns.
+    static MAX_LEVEL: AtomicBool = AtomicBool::new(false);
+
+    struct MaxLevelGuard;
+
+    impl Drop for MaxLevelGuard {
+        fn drop(&mut self) {
+            MAX_LEVEL.store(false, Ordering::Release)
+        }
+    }
+
+    fn lock_max_level() -> MaxLevelGuard {
+        while MAX_LEVEL.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            std::thread::yield_now()
+        }
+        MaxLevelGuard
+    }
+
+    // Counts the allocations of each thread, so that a test can check that
+    // it made none.
+    struct CountingAlloc;
+
+    thread_local!(static ALLOCATIONS: core::cell::Cell<usize> = core::cell::Cell::new(0));
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|count| count.get())
+    }
+
+    fn leak(size: usize) -> &'static mut [u8] {
+        Box::leak(vec![0u8; size].into_boxed_slice())
+    }
+
+    fn logger(size: usize) -> BufferLogger {
+        BufferLogger::new(leak(size))
+    }
+
+    #[test]
+    fn recent_lines_fit() {
+        assert_eq!(recent_lines("one\ntwo\n", 16), "one\ntwo\n");
+        assert_eq!(recent_lines("one\ntwo\nthree\n", 12), "two\nthree\n");
+        assert_eq!(recent_lines("a long line\n", 4), "");
+    }
+
+    #[test]
+    fn recent_lines_multibyte() {
+        // some cut points fall inside the three-byte arrows
+        let content = "\u{2192}\u{2192}\u{2192}\nok\n";
+        for capacity in 1..content.len() + 2 {
+            let tail = recent_lines(content, capacity);
+            assert!(content.ends_with(tail));
+            assert!(tail.len() < capacity);
+        }
+        assert_eq!(recent_lines(content, 8), "ok\n");
+    }
+
+    fn records(content: &str, start: usize, cursor: LogCursor) -> (Vec<(log::Level, u64, String)>, LogCursor) {
+        let mut seen = Vec::new();
+        let cursor = read_records(content, start, 0, cursor, |level, timestamp, target, message|
+            seen.push((level, timestamp, format!("{}: {}", target, message))));
+        (seen, cursor)
+    }
+
+    #[test]
+    fn records_parsed() {
+        let content = "[     1.000002s]  INFO(runtime): first\n\
+                       [     3.500000s]  WARN(runtime::mgmt): two\nlines\n";
+        let (seen, _) = records(content, 0, LogCursor::default());
+        assert_eq!(seen, vec![
+            (log::Level::Info, 1_000_002, String::from("runtime: first")),
+            (log::Level::Warn, 3_500_000, String::from("runtime::mgmt: two\nlines"))
+        ]);
+    }
+
+    #[test]
+    fn records_partial_at_edges() {
+        // the ring cut the first record; the last one is still being written
+        let content = "s]  INFO(runtime): cut\n[     2.000000s] ERROR(runtime): whole\n[     3.0";
+        let (seen, cursor) = records(content, 100, LogCursor::default());
+        assert_eq!(seen, vec![(log::Level::Error, 2_000_000, String::from("runtime: whole"))]);
+        assert_eq!(cursor.position, 100 + content.len() - "[     3.0".len());
+    }
+
+    #[test]
+    fn records_cursor_advances() {
+        let first = "[     1.000000s]  INFO(runtime): one\n";
+        let second = "[     2.000000s]  INFO(runtime): two\n";
+        let (seen, cursor) = records(first, 0, LogCursor::default());
+        assert_eq!(seen.len(), 1);
+        let (seen, cursor) = records(first, 0, cursor);
+        assert!(seen.is_empty());
+
+        // older bytes scrolled out of the ring, newer ones are read once
+        let (seen, cursor) = records(second, first.len(), cursor);
+        assert_eq!(seen, vec![(log::Level::Info, 2_000_000, String::from("runtime: two"))]);
+        let (seen, _) = records(second, first.len(), cursor);
+        assert!(seen.is_empty());
+
+        // after a clear, reading restarts from the oldest record
+        let mut seen = 0;
+        read_records(second, 0, 1, cursor, |_, _, _, _| seen += 1);
+        assert_eq!(seen, 1);
+    }
+
+    #[test]
+    fn deferral_flushes_on_warnings() {
+        let mut deferred = None;
+        assert_eq!(deferral(&deferred, log::Level::Info), Deferral::Emit);
+
+        deferred = Some(Vec::new());
+        assert_eq!(deferral(&deferred, log::Level::Info), Deferral::Hold);
+        assert_eq!(deferral(&deferred, log::Level::Trace), Deferral::Hold);
+        assert_eq!(deferral(&deferred, log::Level::Warn), Deferral::Flush);
+        assert_eq!(deferral(&deferred, log::Level::Error), Deferral::Flush);
+
+        // discarding, like flushing, ends the deferral
+        deferred = None;
+        assert_eq!(deferral(&deferred, log::Level::Debug), Deferral::Emit);
+    }
+
+    #[test]
+    fn swap_keeps_recent_lines() {
+        let mut old = LogBuffer::new(vec![0u8; 32]);
+        for i in 0..10 {
+            writeln!(old, "line {}", i).unwrap();
+        }
+        let mut new = LogBuffer::new(vec![0u8; 20]);
+        let tail = recent_lines(old.extract(), 20);
+        new.write_str(tail).unwrap();
+        assert_eq!(new.extract(), "line 8\nline 9\n");
+    }
+
+    #[test]
+    fn target_filters_parsed() {
+        let (filters, rejected) =
+            parse_target_filters(" mailbox=warn, session = debug,,=info,kernel,mgmt=loud ");
+        assert_eq!(filters, vec![(String::from("mailbox"), LevelFilter::Warn),
+                                 (String::from("session"), LevelFilter::Debug)]);
+        assert_eq!(rejected, vec!["=info", "kernel", "mgmt=loud"]);
+    }
+
+    #[test]
+    fn target_filters_precedence() {
+        let _guard = lock_max_level();
+        let logger = logger(64);
+        logger.set_global_log_level(LevelFilter::Info);
+        let (filters, _) = parse_target_filters("runtime=warn,runtime::session=debug");
+        logger.set_target_filters(filters);
+
+        assert_eq!(logger.target_level("runtime::session"), LevelFilter::Debug);
+        assert_eq!(logger.target_level("runtime::session::rpc"), LevelFilter::Debug);
+        assert_eq!(logger.target_level("runtime::mgmt"), LevelFilter::Warn);
+        assert_eq!(logger.target_level("satman"), LevelFilter::Info);
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+
+        // a quieter global level still lets the verbose override through
+        logger.set_global_log_level(LevelFilter::Error);
+        assert_eq!(logger.target_level("satman"), LevelFilter::Error);
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+
+        logger.set_target_filters(Vec::new());
+        assert_eq!(log::max_level(), LevelFilter::Error);
+    }
+
+    #[test]
+    fn rate_limit_summarized_on_read() {
+        let _guard = lock_max_level();
+        let logger = logger(4096);
+        logger.set_rate_limit(Some(RateLimit { records: 10, window_ms: 1000 }));
+        for _ in 0..1000 {
+            logger.log(&log::Record::builder()
+                .level(log::Level::Info)
+                .target("runtime::rtio_mgt")
+                .module_path_static(Some("runtime::rtio_mgt"))
+                .line(Some(42))
+                .args(format_args!("link down"))
+                .build());
+        }
+
+        let mut buffer = logger.buffer().unwrap();
+        let content = buffer.extract();
+        assert_eq!(content.matches("link down").count(), 10);
+        assert_eq!(content.lines().count(), 11);
+        assert!(content.lines().last().unwrap()
+            .ends_with(" INFO(runtime::rtio_mgt): (repeated 990x)"));
+        drop(buffer);
+
+        // the summary is only written once
+        let mut buffer = logger.buffer().unwrap();
+        assert_eq!(buffer.extract().lines().count(), 11);
+    }
+
+    #[test]
+    fn replace_buffer_carries_over() {
+        let _guard = lock_max_level();
+        let logger = logger(256);
+        for i in 0..5 {
+            logger.write_buffer(i * 1_000_000, &format_args!("line {}", i));
+        }
+
+        // a larger buffer takes everything
+        logger.replace_buffer(leak(1024)).unwrap();
+        {
+            let mut buffer = logger.buffer().unwrap();
+            assert_eq!(buffer.extract().lines().count(), 5);
+            assert!(!buffer.overflowed());
+            assert_eq!(buffer.dropped_lines(), 0);
+        }
+
+        // each line is 24 bytes, so a smaller one keeps the last two
+        logger.replace_buffer(leak(64)).unwrap();
+        {
+            let mut buffer = logger.buffer().unwrap();
+            assert_eq!(buffer.extract(), "[     3.000000s] line 3\n[     4.000000s] line 4\n");
+            assert!(buffer.overflowed());
+            assert_eq!(buffer.dropped_lines(), 3);
+        }
+
+        // and logging goes on in the new buffer
+        logger.write_buffer(5_000_000, &"line 5");
+        let mut buffer = logger.buffer().unwrap();
+        assert!(buffer.extract().ends_with("line 4\n[     5.000000s] line 5\n"));
+        // line 3 is only partly overwritten, so it still counts as kept
+        assert_eq!(buffer.dropped_lines(), 3);
+    }
+
+    #[test]
+    fn replace_buffer_in_use() {
+        let _guard = lock_max_level();
+        let logger = logger(64);
+        logger.write_buffer(0, &"kept");
+        let buffer = logger.buffer().unwrap();
+        assert_eq!(logger.replace_buffer(leak(128)), Err(()));
+        drop(buffer);
+
+        let mut buffer = logger.buffer().unwrap();
+        assert_eq!(buffer.extract(), "[     0.000000s] kept\n");
+    }
+
+    #[test]
+    fn overflow_marked() {
+        let _guard = lock_max_level();
+        let logger = logger(64);
+        logger.write_buffer(0, &"line 0");
+        {
+            let mut buffer = logger.buffer().unwrap();
+            assert_eq!(buffer.dropped_lines(), 0);
+            assert_eq!(buffer.extract_marked(), "[     0.000000s] line 0\n");
+        }
+
+        // 24 bytes per line: the last two fit, and the tail of the one before
+        for i in 1..10 {
+            logger.write_buffer(i * 1_000_000, &format_args!("line {}", i));
+        }
+        {
+            let mut buffer = logger.buffer().unwrap();
+            assert!(buffer.overflowed());
+            assert_eq!(buffer.dropped_lines(), 7);
+            let content = buffer.extract_marked();
+            assert!(content.starts_with("... 7 lines dropped ...\n"));
+            assert!(content.ends_with("[     8.000000s] line 8\n[     9.000000s] line 9\n"));
+            buffer.clear();
+        }
+
+        logger.write_buffer(10_000_000, &"line 10");
+        let mut buffer = logger.buffer().unwrap();
+        assert_eq!(buffer.dropped_lines(), 0);
+        assert_eq!(buffer.extract_marked(), "[    10.000000s] line 10\n");
+    }
+
+    #[test]
+    fn panic_written_without_allocating() {
+        let _guard = lock_max_level();
+        let logger = logger(256);
+        logger.set_global_log_level(LevelFilter::Off);
+        logger.set_target_filters(vec![(String::from("panic"), LevelFilter::Off)]);
+        logger.set_uart_log_level(LevelFilter::Off);
+
+        let before = allocations();
+        logger.write_panic(&format_args!("panicked at {}:{}", "main.rs", 12));
+        assert_eq!(allocations(), before);
+
+        let mut buffer = logger.buffer().unwrap();
+        assert!(buffer.extract().ends_with("s] ERROR(panic): panicked at main.rs:12\n"));
+        assert_eq!(buffer.dropped_lines(), 0);
+    }
+    #[test]
+    fn live_records_subscription() {
+        let _guard = lock_max_level();
+        let logger = logger(4096);
+        logger.set_global_log_level(LevelFilter::Debug);
+        let log = |level, message: &str| logger.log(&log::Record::builder()
+            .level(level)
+            .target("runtime::session")
+            .args(format_args!("{}", message))
+            .build());
+
+        // nothing is collected before subscribing
+        log(log::Level::Warn, "early");
+        assert!(logger.take_live_records().is_empty());
+
+        logger.subscribe(LevelFilter::Info);
+        log(log::Level::Warn, "one");
+        log(log::Level::Debug, "too verbose");
+        log(log::Level::Info, "two");
+        assert_eq!(logger.take_live_records(), vec![
+            (log::Level::Warn, String::from(" WARN(runtime::session): one")),
+            (log::Level::Info, String::from(" INFO(runtime::session): two"))
+        ]);
+        assert!(logger.take_live_records().is_empty());
+
+        // an idle subscriber only holds back a bounded number of records
+        for _ in 0..MAX_LIVE_RECORDS + 10 {
+            log(log::Level::Info, "flood");
+        }
+        assert_eq!(logger.take_live_records().len(), MAX_LIVE_RECORDS);
+
+        log(log::Level::Error, "dropped");
+        logger.unsubscribe();
+        log(log::Level::Error, "after");
+        assert!(logger.take_live_records().is_empty());
+    }
+}
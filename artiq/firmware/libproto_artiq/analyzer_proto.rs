@@ -1,4 +1,28 @@
-use io::{Write, ProtoWrite, Error as IoError};
+use core::cmp;
+use byteorder::{ByteOrder, BigEndian};
+use io::{Read, ProtoRead, Write, ProtoWrite, Error as IoError};
+
+#[derive(Fail, Debug)]
+pub enum Error<T> {
+    #[fail(display = "unknown analyzer header version {}", _0)]
+    UnknownVersion(u8),
+    #[fail(display = "{}", _0)]
+    Io(#[cause] IoError<T>)
+}
+
+impl<T> From<IoError<T>> for Error<T> {
+    fn from(value: IoError<T>) -> Error<T> {
+        Error::Io(value)
+    }
+}
+
+/// Written first, so that readers can tell the layout of the rest apart.
+/// Version 1 is the first to carry it, and ends with a length-prefixed
+/// extension area for fields added later without a version change.
+pub const HEADER_VERSION: u8 = 1;
+
+/// Size of a header as written by `write_to`.
+pub const HEADER_SIZE: usize = 22;
 
 #[derive(Debug)]
 pub struct Header {
@@ -6,18 +30,269 @@ pub struct Header {
     pub total_byte_count: u64,
     pub overflow_occurred: bool,
     pub log_channel: u8,
-    pub dds_onehot_sel: bool
+    pub dds_onehot_sel: bool,
+    pub remaining_bytes: u32
 }
 
 impl Header {
+    /// Checks that no more bytes are sent than were ever recorded, which
+    /// would indicate broken accounting rather than a real capture.
+    pub fn is_consistent(&self) -> bool {
+        self.sent_bytes as u64 <= self.total_byte_count
+    }
+
+    /// Accounts for a capture from another source, as from satellites.
+    /// Counts saturate rather than wrap, as sources cannot be trusted to
+    /// report sane ones.
+    pub fn add_capture(&mut self, total_byte_count: u64, sent_bytes: u32, overflow_occurred: bool) {
+        self.total_byte_count = self.total_byte_count.saturating_add(total_byte_count);
+        self.sent_bytes = self.sent_bytes.saturating_add(sent_bytes);
+        self.overflow_occurred |= overflow_occurred;
+    }
+
+    /// Reads a header of any known version, skipping extension fields it
+    /// does not know about.
+    pub fn read_from<R>(reader: &mut R) -> Result<Self, Error<R::ReadError>>
+        where R: Read + ?Sized
+    {
+        match reader.read_u8()? {
+            1 => {
+                let header = Header {
+                    sent_bytes: reader.read_u32()?,
+                    total_byte_count: reader.read_u64()?,
+                    overflow_occurred: reader.read_bool()?,
+                    log_channel: reader.read_u8()?,
+                    dds_onehot_sel: reader.read_bool()?,
+                    remaining_bytes: reader.read_u32()?
+                };
+                for _ in 0..reader.read_u16()? {
+                    reader.read_u8()?;
+                }
+                Ok(header)
+            }
+            version => Err(Error::UnknownVersion(version))
+        }
+    }
+
     pub fn write_to<W>(&self, writer: &mut W) -> Result<(), IoError<W::WriteError>>
         where W: Write + ?Sized
     {
+        writer.write_u8(HEADER_VERSION)?;
         writer.write_u32(self.sent_bytes)?;
         writer.write_u64(self.total_byte_count)?;
         writer.write_u8(self.overflow_occurred as u8)?;
         writer.write_u8(self.log_channel)?;
         writer.write_u8(self.dds_onehot_sel as u8)?;
+        writer.write_u32(self.remaining_bytes)?;
+        // no extension fields yet
+        writer.write_u16(0)?;
         Ok(())
     }
 }
+
+/// Sent by hosts right after connecting to receive the capture in
+/// installments, each requested with its byte budget. Hosts that send
+/// nothing get the whole capture as a single installment.
+pub const REQUEST_INSTALLMENTS: u8 = b'i';
+
+/// Length of the next installment of a capture of `total` bytes, of which
+/// `offset` have been sent, for a budget of `max_bytes` (0 for no limit).
+pub fn installment_length(total: usize, offset: usize, max_bytes: u32) -> usize {
+    let remaining = total - offset;
+    if max_bytes == 0 { remaining } else { cmp::min(remaining, max_bytes as usize) }
+}
+
+// Captures kept in flash use one flash block each, starting with a block
+// header of magic, sequence number and capture length. Anything else in
+// a block, such as erased flash, means that the block holds no capture.
+const STORED_BLOCK_MAGIC: u32 = 0x414e4131; // "ANA1"
+
+/// Size of the header preceding a capture in a flash block.
+pub const STORED_BLOCK_HEADER_SIZE: usize = 12;
+
+/// Writes the header for a block holding `length` bytes of capture.
+pub fn write_stored_block_header(buf: &mut [u8], sequence: u32, length: usize) {
+    BigEndian::write_u32(&mut buf[0..4], STORED_BLOCK_MAGIC);
+    BigEndian::write_u32(&mut buf[4..8], sequence);
+    BigEndian::write_u32(&mut buf[8..12], length as u32);
+}
+
+/// Returns the sequence number and capture held by a flash block.
+pub fn read_stored_block(block: &[u8]) -> Option<(u32, &[u8])> {
+    if block.len() < STORED_BLOCK_HEADER_SIZE ||
+            BigEndian::read_u32(&block[0..4]) != STORED_BLOCK_MAGIC {
+        return None
+    }
+    let sequence = BigEndian::read_u32(&block[4..8]);
+    let length = BigEndian::read_u32(&block[8..12]) as usize;
+    block[STORED_BLOCK_HEADER_SIZE..].get(..length).map(|capture| (sequence, capture))
+}
+
+/// Picks the block for the next capture given the sequence number held by
+/// every block, and returns it with the sequence number to store: the first
+/// unused block, or else the one with the oldest capture, which is evicted.
+pub fn next_stored_block(sequences: &[Option<u32>]) -> (usize, u32) {
+    let sequence = sequences.iter().filter_map(|&sequence| sequence).max()
+        .map(|newest| newest.wrapping_add(1)).unwrap_or(0);
+    let block = sequences.iter().position(|sequence| sequence.is_none())
+        .or_else(|| sequences.iter().enumerate()
+            .min_by_key(|&(_, &sequence)| sequence)
+            .map(|(block, _)| block))
+        .unwrap_or(0);
+    (block, sequence)
+}
+
+/// Returns the block holding a stored capture, 0 being the most recent one.
+pub fn find_stored_block(sequences: &[Option<u32>], index: u32) -> Option<usize> {
+    sequences.iter().position(|&sequence| match sequence {
+        Some(sequence) => sequences.iter()
+            .filter(|&&other| other.map(|other| other > sequence).unwrap_or(false))
+            .count() == index as usize,
+        None => false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use byteorder::NativeEndian;
+    use io::Cursor;
+
+    fn header(sent_bytes: u32) -> Header {
+        Header {
+            sent_bytes: sent_bytes,
+            total_byte_count: 0x1_0000_0000,
+            overflow_occurred: true,
+            log_channel: 7,
+            dds_onehot_sel: true,
+            remaining_bytes: 3
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut buf = Vec::new();
+        header(42).write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_SIZE);
+        assert_eq!(buf[0], HEADER_VERSION);
+
+        let read = Header::read_from(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(read.sent_bytes, 42);
+        assert_eq!(read.total_byte_count, 0x1_0000_0000);
+        assert!(read.overflow_occurred);
+        assert_eq!(read.log_channel, 7);
+        assert!(read.dds_onehot_sel);
+        assert_eq!(read.remaining_bytes, 3);
+    }
+
+    #[test]
+    fn header_skips_extensions() {
+        let mut buf = Vec::new();
+        header(1).write_to(&mut buf).unwrap();
+        buf.truncate(HEADER_SIZE - 2);
+        let mut extension_length = [0; 2];
+        NativeEndian::write_u16(&mut extension_length, 3);
+        buf.extend_from_slice(&extension_length);
+        buf.extend_from_slice(&[0xaa, 0xbb, 0xcc, 0x55]);
+
+        let mut reader = Cursor::new(&buf[..]);
+        assert_eq!(Header::read_from(&mut reader).unwrap().sent_bytes, 1);
+        assert_eq!(reader.position(), buf.len() - 1);
+    }
+
+    #[test]
+    fn header_unknown_version() {
+        let mut buf = Vec::new();
+        header(1).write_to(&mut buf).unwrap();
+        buf[0] = 2;
+        match Header::read_from(&mut Cursor::new(&buf[..])) {
+            Err(Error::UnknownVersion(2)) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    fn store(blocks: &mut [Vec<u8>], capture: &[u8]) {
+        let sequences: Vec<_> = blocks.iter().map(|block| read_stored_block(block).map(|(sequence, _)| sequence)).collect();
+        let (block, sequence) = next_stored_block(&sequences);
+        let mut data = vec![0; STORED_BLOCK_HEADER_SIZE];
+        write_stored_block_header(&mut data, sequence, capture.len());
+        data.extend_from_slice(capture);
+        blocks[block] = data;
+    }
+
+    fn retrieve(blocks: &[Vec<u8>], index: u32) -> Option<&[u8]> {
+        let sequences: Vec<_> = blocks.iter().map(|block| read_stored_block(block).map(|(sequence, _)| sequence)).collect();
+        find_stored_block(&sequences, index).map(|block| read_stored_block(&blocks[block]).unwrap().1)
+    }
+
+    #[test]
+    fn store_retrieve_evict() {
+        let mut blocks = vec![vec![0xff; 32]; 3];
+        assert_eq!(retrieve(&blocks, 0), None);
+
+        store(&mut blocks, b"first");
+        store(&mut blocks, b"second");
+        assert_eq!(retrieve(&blocks, 0), Some(&b"second"[..]));
+        assert_eq!(retrieve(&blocks, 1), Some(&b"first"[..]));
+        assert_eq!(retrieve(&blocks, 2), None);
+
+        store(&mut blocks, b"third");
+        store(&mut blocks, b"fourth");
+        assert_eq!(retrieve(&blocks, 0), Some(&b"fourth"[..]));
+        assert_eq!(retrieve(&blocks, 1), Some(&b"third"[..]));
+        assert_eq!(retrieve(&blocks, 2), Some(&b"second"[..]));
+        assert_eq!(retrieve(&blocks, 3), None);
+        // the oldest capture was evicted, its block reused
+        assert_eq!(read_stored_block(&blocks[0]).map(|(_, capture)| capture), Some(&b"fourth"[..]));
+    }
+
+    #[test]
+    fn stored_block_rejects_garbage() {
+        assert_eq!(read_stored_block(&[0xff; 32]), None);
+        assert_eq!(read_stored_block(&[0; 4]), None);
+
+        let mut block = vec![0; STORED_BLOCK_HEADER_SIZE + 4];
+        write_stored_block_header(&mut block, 5, 8);
+        assert_eq!(read_stored_block(&block), None);
+        write_stored_block_header(&mut block, 5, 4);
+        assert_eq!(read_stored_block(&block), Some((5, &[0; 4][..])));
+    }
+
+    #[test]
+    fn installments_cover_capture() {
+        let total = 10;
+        let mut offset = 0;
+        let mut lengths = Vec::new();
+        while offset < total {
+            let length = installment_length(total, offset, 4);
+            lengths.push((length, total - offset - length));
+            offset += length;
+        }
+        // the header of each installment carries its length and what remains
+        assert_eq!(lengths, vec![(4, 6), (4, 2), (2, 0)]);
+
+        assert_eq!(installment_length(total, 0, 0), total);
+        assert_eq!(installment_length(total, 0, 100), total);
+        assert_eq!(installment_length(0, 0, 4), 0);
+    }
+    #[test]
+    fn capture_accounting_saturates() {
+        let mut merged = header(1024);
+        merged.overflow_occurred = false;
+        merged.add_capture(4096, 2048, false);
+        assert_eq!((merged.total_byte_count, merged.sent_bytes), (0x1_0000_1000, 3072));
+        assert!(merged.is_consistent() && !merged.overflow_occurred);
+
+        merged.add_capture(u64::max_value() - 10, u32::max_value(), true);
+        assert_eq!((merged.total_byte_count, merged.sent_bytes), (u64::max_value(), u32::max_value()));
+        assert!(merged.overflow_occurred);
+        assert!(merged.is_consistent());
+
+        // a source claiming to send more than it recorded breaks the invariant
+        let mut broken = header(0);
+        broken.total_byte_count = 16;
+        broken.add_capture(0, 32, false);
+        assert!(!broken.is_consistent());
+    }
+}
@@ -0,0 +1,145 @@
+use alloc::vec::Vec;
+
+#[derive(Fail, Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    #[fail(display = "unknown compression algorithm {}", _0)]
+    UnknownAlgorithm(u8),
+    #[fail(display = "compressed data is truncated")]
+    Truncated,
+    #[fail(display = "compressed data refers to bytes before its start")]
+    InvalidOffset,
+    #[fail(display = "decompressed data exceeds {} bytes", _0)]
+    TooLarge(usize),
+    #[fail(display = "decompressed data is {} bytes instead of {}", actual, expected)]
+    LengthMismatch { expected: usize, actual: usize },
+}
+
+/// LZ4 block format, without the frame around it.
+pub const ALGORITHM_LZ4: u8 = 1;
+
+/// Decompresses `input`, refusing to produce more than `max_size` bytes.
+pub fn decompress(algorithm: u8, input: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+    match algorithm {
+        ALGORITHM_LZ4 => lz4_block(input, max_size),
+        _ => Err(Error::UnknownAlgorithm(algorithm))
+    }
+}
+
+/// Decompresses `input`, which must produce exactly `size` bytes.
+pub fn decompress_exact(algorithm: u8, input: &[u8], size: usize) -> Result<Vec<u8>, Error> {
+    let output = decompress(algorithm, input, size)?;
+    if output.len() != size {
+        return Err(Error::LengthMismatch { expected: size, actual: output.len() })
+    }
+    Ok(output)
+}
+
+// A nibble of 15 is continued by bytes that are added up, until one isn't 255.
+fn lz4_length(input: &[u8], pos: &mut usize, mut length: usize) -> Result<usize, Error> {
+    if length == 15 {
+        loop {
+            let byte = *input.get(*pos).ok_or(Error::Truncated)?;
+            *pos += 1;
+            length += byte as usize;
+            if byte != 255 {
+                break
+            }
+        }
+    }
+    Ok(length)
+}
+
+fn lz4_block(input: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::new();
+    let mut pos = 0;
+    loop {
+        let token = *input.get(pos).ok_or(Error::Truncated)?;
+        pos += 1;
+
+        let literals = lz4_length(input, &mut pos, (token >> 4) as usize)?;
+        if input.len() - pos < literals {
+            return Err(Error::Truncated)
+        }
+        if max_size - output.len() < literals {
+            return Err(Error::TooLarge(max_size))
+        }
+        output.extend_from_slice(&input[pos..pos + literals]);
+        pos += literals;
+        // the last sequence has no match
+        if pos == input.len() {
+            return Ok(output)
+        }
+
+        if input.len() - pos < 2 {
+            return Err(Error::Truncated)
+        }
+        let offset = input[pos] as usize | (input[pos + 1] as usize) << 8;
+        pos += 2;
+        if offset == 0 || offset > output.len() {
+            return Err(Error::InvalidOffset)
+        }
+        let length = lz4_length(input, &mut pos, (token & 0xf) as usize)? + 4;
+        if max_size - output.len() < length {
+            return Err(Error::TooLarge(max_size))
+        }
+        // the match may overlap the bytes it produces, so copy one at a time
+        let start = output.len() - offset;
+        for i in start..start + length {
+            let byte = output[i];
+            output.push(byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "abcd" followed by an overlapping match repeating it twice
+    const REPEATED: &[u8] = &[0x44, b'a', b'b', b'c', b'd', 4, 0, 0x00];
+
+    #[test]
+    fn lz4_literals_and_matches() {
+        assert_eq!(decompress(ALGORITHM_LZ4, &[0x50, b'h', b'e', b'l', b'l', b'o'], 5).unwrap(), b"hello");
+        assert_eq!(decompress(ALGORITHM_LZ4, REPEATED, 64).unwrap(), b"abcdabcdabcd");
+
+        let mut long = vec![0xf0, 5];
+        long.extend_from_slice(&[b'x'; 20]);
+        assert_eq!(decompress(ALGORITHM_LZ4, &long, 64).unwrap(), [b'x'; 20]);
+    }
+
+    #[test]
+    fn lz4_max_size() {
+        assert_eq!(decompress(ALGORITHM_LZ4, &[0x50, b'h', b'e', b'l', b'l', b'o'], 4),
+                   Err(Error::TooLarge(4)));
+        assert_eq!(decompress(ALGORITHM_LZ4, REPEATED, 11), Err(Error::TooLarge(11)));
+        assert_eq!(decompress(ALGORITHM_LZ4, REPEATED, 12).unwrap().len(), 12);
+    }
+
+    #[test]
+    fn lz4_truncated() {
+        assert_eq!(decompress(ALGORITHM_LZ4, &[], 64), Err(Error::Truncated));
+        assert_eq!(decompress(ALGORITHM_LZ4, &[0x50, b'h', b'e', b'l'], 64), Err(Error::Truncated));
+        assert_eq!(decompress(ALGORITHM_LZ4, &[0xf0], 64), Err(Error::Truncated));
+        assert_eq!(decompress(ALGORITHM_LZ4, &REPEATED[..6], 64), Err(Error::Truncated));
+        assert_eq!(decompress(ALGORITHM_LZ4, &REPEATED[..7], 64), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn lz4_bad_offset() {
+        assert_eq!(decompress(ALGORITHM_LZ4, &[0x44, b'a', b'b', b'c', b'd', 5, 0, 0x00], 64),
+                   Err(Error::InvalidOffset));
+        assert_eq!(decompress(ALGORITHM_LZ4, &[0x44, b'a', b'b', b'c', b'd', 0, 0, 0x00], 64),
+                   Err(Error::InvalidOffset));
+        assert_eq!(decompress(ALGORITHM_LZ4, &[0x04, 1, 0, 0x00], 64), Err(Error::InvalidOffset));
+    }
+
+    #[test]
+    fn exact_length() {
+        assert_eq!(decompress_exact(ALGORITHM_LZ4, REPEATED, 12).unwrap(), b"abcdabcdabcd");
+        assert_eq!(decompress_exact(ALGORITHM_LZ4, REPEATED, 13),
+                   Err(Error::LengthMismatch { expected: 13, actual: 12 }));
+        assert_eq!(decompress_exact(ALGORITHM_LZ4, REPEATED, 11), Err(Error::TooLarge(11)));
+        assert_eq!(decompress_exact(2, REPEATED, 12), Err(Error::UnknownAlgorithm(2)));
+    }
+}
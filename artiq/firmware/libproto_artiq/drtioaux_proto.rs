@@ -60,6 +60,107 @@ impl PayloadStatus {
     }
 }
 
+/// A subkernel the master has uploaded to a destination.
+#[derive(Clone, Copy, Debug)]
+pub struct ResidentSubkernel {
+    pub id: u32,
+    pub size: usize,
+    pub last_started: u64,
+    /// Running subkernels, or ones with an unretrieved result, must stay.
+    pub evictable: bool,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Eviction {
+    Fits,
+    Evict(u32),
+    NoVictim,
+}
+
+/// Decides whether a subkernel of `size` bytes fits next to `resident` within
+/// `max_count` subkernels and `max_bytes` bytes (0 meaning no limit), and if not,
+/// which subkernel to evict first: the least recently started evictable one.
+pub fn next_eviction(resident: &[ResidentSubkernel], size: usize,
+                     max_count: usize, max_bytes: usize) -> Eviction {
+    let bytes = resident.iter().fold(0, |bytes, subkernel| bytes + subkernel.size);
+    if (max_count == 0 || resident.len() < max_count) && (max_bytes == 0 || bytes + size <= max_bytes) {
+        return Eviction::Fits
+    }
+    match resident.iter().filter(|subkernel| subkernel.evictable).min_by_key(|subkernel| subkernel.last_started) {
+        Some(victim) => Eviction::Evict(victim.id),
+        None => Eviction::NoVictim
+    }
+}
+
+/// Paces the retries of a failed subkernel upload, doubling the delay after
+/// each failure until the attempts run out, or the next delay would not fit
+/// in what is left of the overall timeout.
+#[derive(Debug)]
+pub struct UploadBackoff {
+    max_attempts: u32,
+    failures: u32,
+    delay_ms: u64
+}
+
+impl UploadBackoff {
+    pub fn new(max_attempts: u32, first_delay_ms: u64) -> UploadBackoff {
+        UploadBackoff { max_attempts: max_attempts, failures: 0, delay_ms: first_delay_ms }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Records a failed attempt, and returns how long to wait before the
+    /// next one, or `None` to give up.
+    pub fn failed(&mut self, remaining_ms: u64) -> Option<u64> {
+        self.failures += 1;
+        if self.failures >= self.max_attempts || remaining_ms < self.delay_ms {
+            return None
+        }
+        let delay_ms = self.delay_ms;
+        self.delay_ms = self.delay_ms.saturating_mul(2);
+        Some(delay_ms)
+    }
+}
+
+/// Averages the round trips of PingRequest packets, timed by a wrapping
+/// microsecond counter.
+#[derive(Debug, Default)]
+pub struct RoundTrip {
+    total_us: u64,
+    samples: u64
+}
+
+impl RoundTrip {
+    pub fn new() -> RoundTrip {
+        RoundTrip::default()
+    }
+
+    /// Adds the round trip of a PingRequest sent at `sent_us` and answered
+    /// at `received_us`.
+    pub fn add(&mut self, sent_us: u64, received_us: u64) {
+        self.total_us = self.total_us.saturating_add(received_us.wrapping_sub(sent_us));
+        self.samples += 1
+    }
+
+    /// The mean of the round trips so far, if any.
+    pub fn average_us(&self) -> Option<u64> {
+        if self.samples == 0 {
+            None
+        } else {
+            Some(self.total_us / self.samples)
+        }
+    }
+}
+
+/// The occupancy and capacity, in events, of the output FIFOs of a
+/// destination, from the buffer space it reports now and the one it
+/// reported with empty FIFOs when its link came up.
+pub fn buffer_fill(capacity: u16, space: u16) -> (u16, u16) {
+    (capacity.saturating_sub(space), capacity)
+}
+
 #[derive(PartialEq, Debug)]
 pub enum Packet {
     EchoRequest,
@@ -67,6 +168,8 @@ pub enum Packet {
     ResetRequest,
     ResetAck,
     TSCAck,
+    PingRequest { destination: u8, timestamp: u64 },
+    PingReply { timestamp: u64 },
 
     DestinationStatusRequest { destination: u8 },
     DestinationDownReply,
@@ -120,7 +223,9 @@ pub enum Packet {
 
     SubkernelAddDataRequest { destination: u8, id: u32, status: PayloadStatus, length: u16, data: [u8; MASTER_PAYLOAD_MAX_SIZE] },
     SubkernelAddDataReply { succeeded: bool },
-    SubkernelLoadRunRequest { source: u8, destination: u8, id: u32, run: bool },
+    SubkernelRemoveRequest { destination: u8, id: u32 },
+    SubkernelRemoveReply { succeeded: bool },
+    SubkernelLoadRunRequest { source: u8, destination: u8, id: u32, run: bool, timestamp: u64 },
     SubkernelLoadRunReply { destination: u8, succeeded: bool },
     SubkernelFinished { destination: u8, id: u32, with_exception: bool, exception_src: u8 },
     SubkernelExceptionRequest { destination: u8 },
@@ -139,6 +244,13 @@ impl Packet {
             0x02 => Packet::ResetRequest,
             0x03 => Packet::ResetAck,
             0x04 => Packet::TSCAck,
+            0x05 => Packet::PingRequest {
+                destination: reader.read_u8()?,
+                timestamp: reader.read_u64()?
+            },
+            0x06 => Packet::PingReply {
+                timestamp: reader.read_u64()?
+            },
 
             0x20 => Packet::DestinationStatusRequest {
                 destination: reader.read_u8()?
@@ -350,11 +462,19 @@ impl Packet {
             0xc1 => Packet::SubkernelAddDataReply {
                 succeeded: reader.read_bool()?
             },
+            0xc2 => Packet::SubkernelRemoveRequest {
+                destination: reader.read_u8()?,
+                id: reader.read_u32()?
+            },
+            0xc3 => Packet::SubkernelRemoveReply {
+                succeeded: reader.read_bool()?
+            },
             0xc4 => Packet::SubkernelLoadRunRequest {
                 source: reader.read_u8()?,
                 destination: reader.read_u8()?,
                 id: reader.read_u32()?,
-                run: reader.read_bool()?
+                run: reader.read_bool()?,
+                timestamp: reader.read_u64()?
             },
             0xc5 => Packet::SubkernelLoadRunReply {
                 destination: reader.read_u8()?,
@@ -419,6 +539,15 @@ impl Packet {
                 writer.write_u8(0x03)?,
             Packet::TSCAck =>
                 writer.write_u8(0x04)?,
+            Packet::PingRequest { destination, timestamp } => {
+                writer.write_u8(0x05)?;
+                writer.write_u8(destination)?;
+                writer.write_u64(timestamp)?;
+            },
+            Packet::PingReply { timestamp } => {
+                writer.write_u8(0x06)?;
+                writer.write_u64(timestamp)?;
+            },
 
             Packet::DestinationStatusRequest { destination } => {
                 writer.write_u8(0x20)?;
@@ -644,12 +773,22 @@ impl Packet {
                 writer.write_u8(0xc1)?;
                 writer.write_bool(succeeded)?;
             },
-            Packet::SubkernelLoadRunRequest { source, destination, id, run } => {
+            Packet::SubkernelRemoveRequest { destination, id } => {
+                writer.write_u8(0xc2)?;
+                writer.write_u8(destination)?;
+                writer.write_u32(id)?;
+            },
+            Packet::SubkernelRemoveReply { succeeded } => {
+                writer.write_u8(0xc3)?;
+                writer.write_bool(succeeded)?;
+            },
+            Packet::SubkernelLoadRunRequest { source, destination, id, run, timestamp } => {
                 writer.write_u8(0xc4)?;
                 writer.write_u8(source)?;
                 writer.write_u8(destination)?;
                 writer.write_u32(id)?;
                 writer.write_bool(run)?;
+                writer.write_u64(timestamp)?;
             },
             Packet::SubkernelLoadRunReply { destination, succeeded } => {
                 writer.write_u8(0xc5)?;
@@ -722,3 +861,153 @@ impl Packet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use io::Cursor;
+
+    fn round_trip(packet: &Packet) -> Packet {
+        let mut buf = Vec::new();
+        packet.write_to(&mut buf).unwrap();
+        Packet::read_from(&mut Cursor::new(&buf[..])).unwrap()
+    }
+
+    #[test]
+    fn subkernel_remove_round_trip() {
+        let request = Packet::SubkernelRemoveRequest { destination: 3, id: 0x1234_5678 };
+        assert_eq!(round_trip(&request), request);
+        let reply = Packet::SubkernelRemoveReply { succeeded: false };
+        assert_eq!(round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn subkernel_timed_start_round_trip() {
+        for &timestamp in &[0, 1, 0x0123_4567_89ab_cdef, u64::max_value()] {
+            let request = Packet::SubkernelLoadRunRequest {
+                source: 0, destination: 2, id: 9, run: true, timestamp: timestamp
+            };
+            assert_eq!(round_trip(&request), request);
+        }
+
+        let mut buf = Vec::new();
+        Packet::SubkernelLoadRunRequest {
+            source: 1, destination: 2, id: 3, run: true, timestamp: 0x0102_0304_0506_0708
+        }.write_to(&mut buf).unwrap();
+        // the timestamp follows the other fields
+        assert_eq!(buf.len(), 16);
+        assert_eq!(buf[..3], [0xc4, 1, 2]);
+        assert_eq!(buf[8..], 0x0102_0304_0506_0708u64.to_ne_bytes());
+    }
+
+    #[test]
+    fn ping_round_trip() {
+        let request = Packet::PingRequest { destination: 4, timestamp: 0x1234_5678_9abc };
+        assert_eq!(round_trip(&request), request);
+        let reply = Packet::PingReply { timestamp: u64::max_value() };
+        assert_eq!(round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn round_trip_averaged() {
+        let mut round_trip = RoundTrip::new();
+        assert_eq!(round_trip.average_us(), None);
+        round_trip.add(100, 130);
+        assert_eq!(round_trip.average_us(), Some(30));
+        round_trip.add(200, 245);
+        round_trip.add(300, 320);
+        // the counter wrapped during this one
+        round_trip.add(u64::max_value() - 9, 25);
+        assert_eq!(round_trip.average_us(), Some((30 + 45 + 20 + 35) / 4));
+    }
+
+    #[test]
+    fn empty_upload_is_not_a_removal() {
+        let upload = Packet::SubkernelAddDataRequest {
+            destination: 3,
+            id: 7,
+            status: PayloadStatus::FirstAndLast,
+            length: 0,
+            data: [0; MASTER_PAYLOAD_MAX_SIZE]
+        };
+        assert_eq!(round_trip(&upload), upload);
+    }
+
+    fn resident(id: u32, size: usize, last_started: u64) -> ResidentSubkernel {
+        ResidentSubkernel { id: id, size: size, last_started: last_started, evictable: true }
+    }
+
+    #[test]
+    fn eviction_limits() {
+        let resident = [resident(1, 100, 5), resident(2, 100, 3)];
+        assert_eq!(next_eviction(&resident, 100, 0, 0), Eviction::Fits);
+        assert_eq!(next_eviction(&resident, 100, 3, 300), Eviction::Fits);
+        assert_eq!(next_eviction(&resident, 100, 2, 0), Eviction::Evict(2));
+        assert_eq!(next_eviction(&resident, 101, 0, 300), Eviction::Evict(2));
+    }
+
+    #[test]
+    fn eviction_keeps_busy_subkernels() {
+        let mut resident = [resident(1, 100, 5), resident(2, 100, 3)];
+        resident[1].evictable = false;
+        assert_eq!(next_eviction(&resident, 100, 2, 0), Eviction::Evict(1));
+        resident[0].evictable = false;
+        assert_eq!(next_eviction(&resident, 100, 2, 0), Eviction::NoVictim);
+    }
+
+    #[test]
+    fn evicted_subkernel_reupload_evicts_in_turn() {
+        // 1 is evicted to make room for 3; starting 1 again means uploading it
+        // again, which now pushes out 2, the least recently started left
+        let mut resident = vec![resident(1, 100, 1), resident(2, 100, 2)];
+        assert_eq!(next_eviction(&resident, 100, 2, 0), Eviction::Evict(1));
+        resident.retain(|subkernel| subkernel.id != 1);
+        resident.push(ResidentSubkernel { id: 3, size: 100, last_started: 3, evictable: true });
+        assert_eq!(next_eviction(&resident, 100, 2, 0), Eviction::Evict(2));
+        resident.retain(|subkernel| subkernel.id != 2);
+        assert_eq!(next_eviction(&resident, 100, 2, 0), Eviction::Fits);
+    }
+
+
+    // Uploads through a transport that fails `failures` times, on a clock
+    // that only advances while sleeping; returns the delays slept and the
+    // attempts made, or None if the upload was given up.
+    fn upload_with_backoff(failures: u32, max_attempts: u32, timeout_ms: u64) -> (Vec<u64>, Option<u32>) {
+        let mut backoff = UploadBackoff::new(max_attempts, 10);
+        let mut now = 0;
+        let mut delays = Vec::new();
+        loop {
+            if backoff.failures() == failures {
+                return (delays, Some(backoff.failures() + 1))
+            }
+            match backoff.failed(timeout_ms - now) {
+                Some(delay) => {
+                    delays.push(delay);
+                    now += delay;
+                }
+                None => return (delays, None)
+            }
+        }
+    }
+
+    #[test]
+    fn upload_backoff_doubles() {
+        assert_eq!(upload_with_backoff(0, 3, 10_000), (vec![], Some(1)));
+        assert_eq!(upload_with_backoff(2, 3, 10_000), (vec![10, 20], Some(3)));
+        // out of attempts
+        assert_eq!(upload_with_backoff(3, 3, 10_000), (vec![10, 20], None));
+        assert_eq!(upload_with_backoff(1, 1, 10_000), (vec![], None));
+        // out of time: 10 + 20 + 40 leaves 30 of 100 ms, short of the next 80
+        assert_eq!(upload_with_backoff(10, 100, 100), (vec![10, 20, 40], None));
+        assert_eq!(upload_with_backoff(3, 100, 100), (vec![10, 20, 40], Some(4)));
+    }
+
+    #[test]
+    fn buffer_fill_levels() {
+        assert_eq!(buffer_fill(128, 100), (28, 128));
+        assert_eq!(buffer_fill(128, 128), (0, 128));
+        // more space than at link up, as after a satellite restart
+        assert_eq!(buffer_fill(128, 130), (0, 128));
+    }
+}
@@ -10,6 +10,112 @@ pub const KERNELCPU_LAST_ADDRESS:    usize = 0x4fffffff;
 // section in ksupport.elf.
 pub const KSUPPORT_HEADER_SIZE: usize = 0x74;
 
+/// Checks that a flat kernel image fits in the kernel region and that its
+/// entry point lies within it.
+pub fn validate_flat_image(length: usize, entry_offset: usize, load_addr: usize)
+                           -> Result<(), &'static str> {
+    if load_addr < KERNELCPU_PAYLOAD_ADDRESS {
+        return Err("flat kernel load address below kernel region")
+    }
+    match load_addr.checked_add(length) {
+        Some(end) if end <= KERNELCPU_LAST_ADDRESS + 1 => (),
+        _ => return Err("flat kernel image does not fit in kernel region")
+    }
+    if entry_offset >= length {
+        return Err("flat kernel entry point outside of image")
+    }
+    Ok(())
+}
+
+/// Copies a flat kernel image into `region`, which starts at
+/// KERNELCPU_PAYLOAD_ADDRESS, and returns the address of its entry point.
+pub fn load_flat_image(region: &mut [u8], image: &[u8], entry_offset: usize, load_addr: usize)
+                       -> Result<usize, &'static str> {
+    validate_flat_image(image.len(), entry_offset, load_addr)?;
+    let offset = load_addr - KERNELCPU_PAYLOAD_ADDRESS;
+    match region.get_mut(offset..offset + image.len()) {
+        Some(target) => target.copy_from_slice(image),
+        None => return Err("flat kernel image does not fit in kernel region")
+    }
+    Ok(load_addr + entry_offset)
+}
+
+/// The cleanup handler registered by a kernel. It is handed out at most once,
+/// so that a handler that aborts itself is not re-entered, and never once the
+/// kernel has finished on its own.
+pub struct CleanupHandler(Option<extern fn()>);
+
+impl CleanupHandler {
+    pub const fn new() -> CleanupHandler {
+        CleanupHandler(None)
+    }
+
+    pub fn register(&mut self, handler: extern fn()) {
+        self.0 = Some(handler)
+    }
+
+    /// Drops the handler once the kernel has returned.
+    pub fn finish(&mut self) {
+        self.0 = None
+    }
+
+    /// Returns the handler to run when the kernel is aborted.
+    pub fn take(&mut self) -> Option<extern fn()> {
+        self.0.take()
+    }
+}
+
+/// An RTIO output as submitted by the kernel; wide outputs are recorded
+/// with their least significant word only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtioReplayEvent {
+    pub timestamp: i64,
+    pub target: u32,
+    pub data: u32
+}
+
+pub const RTIO_REPLAY_CAPACITY: usize = 512;
+
+/// The outputs submitted during one kernel run, in order. Only the first
+/// `RTIO_REPLAY_CAPACITY` are kept; any further output marks the log truncated.
+pub struct RtioReplayBuffer {
+    events: [RtioReplayEvent; RTIO_REPLAY_CAPACITY],
+    length: usize,
+    truncated: bool
+}
+
+impl RtioReplayBuffer {
+    pub const fn new() -> RtioReplayBuffer {
+        RtioReplayBuffer {
+            events: [RtioReplayEvent { timestamp: 0, target: 0, data: 0 }; RTIO_REPLAY_CAPACITY],
+            length: 0,
+            truncated: false
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.length = 0;
+        self.truncated = false;
+    }
+
+    pub fn record(&mut self, event: RtioReplayEvent) {
+        if self.length < RTIO_REPLAY_CAPACITY {
+            self.events[self.length] = event;
+            self.length += 1;
+        } else {
+            self.truncated = true;
+        }
+    }
+
+    pub fn events(&self) -> &[RtioReplayEvent] {
+        &self.events[..self.length]
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
 #[derive(Debug)]
 pub enum SubkernelStatus {
     NoError,
@@ -22,13 +128,23 @@ pub enum SubkernelStatus {
 #[derive(Debug)]
 pub enum Message<'a> {
     LoadRequest(&'a [u8]),
+    LoadFlatRequest { image: &'a [u8], entry_offset: usize, load_addr: usize },
     LoadReply(Result<(), dyld::Error<'a>>),
+    SelfTestRequest,
+    StartTimestampRequest,
+    StartTimestampReply { timestamp: Option<i64> },
 
     RtioInitRequest,
 
     RtioDestinationStatusRequest { destination: u8 },
     RtioDestinationStatusReply { up: bool },
 
+    RtioChannelClaimRequest { channel: u32, claim: bool },
+
+    RtioReplayConfigRequest,
+    RtioReplayConfigReply { enabled: bool },
+    RtioReplayLog { events: &'a [RtioReplayEvent], truncated: bool },
+
     DmaRecordStart(&'a str),
     DmaRecordAppend(&'a [u8]),
     DmaRecordStop {
@@ -71,6 +187,8 @@ pub enum Message<'a> {
         backtrace: &'a [(usize, usize)]
     },
     RunAborted,
+    CleanupRegistered,
+    CleanupStarted,
 
     RpcSend {
         async: bool,
@@ -116,3 +234,155 @@ pub enum Message<'a> {
 }
 
 pub use self::Message::*;
+
+/// What the comms CPU does with a message from the self-test kernel.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SelfTestStep {
+    Acknowledge,
+    ReplyCacheGet,
+    /// The run is over; whether the kernel passed.
+    Finished(bool)
+}
+
+/// Follows the messages of the self-test kernel started by SelfTestRequest,
+/// which loads, makes a request to the comms CPU, and finishes.
+#[derive(Debug, Default)]
+pub struct SelfTest {
+    loaded: bool
+}
+
+impl SelfTest {
+    pub fn new() -> SelfTest {
+        SelfTest::default()
+    }
+
+    pub fn step(&mut self, message: &Message) -> SelfTestStep {
+        match *message {
+            LoadReply(Ok(())) if !self.loaded => {
+                self.loaded = true;
+                SelfTestStep::Acknowledge
+            }
+            CacheGetRequest { .. } if self.loaded => SelfTestStep::ReplyCacheGet,
+            RpcFlush | Log(_) if self.loaded => SelfTestStep::Acknowledge,
+            RunFinished => SelfTestStep::Finished(self.loaded),
+            _ => SelfTestStep::Finished(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    fn abort(cleanup: &mut CleanupHandler) {
+        if let Some(handler) = cleanup.take() {
+            handler()
+        }
+    }
+
+    #[test]
+    fn cleanup_runs_once_on_abort() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        extern fn handler() { RUNS.fetch_add(1, Ordering::SeqCst); }
+
+        let mut cleanup = CleanupHandler::new();
+        cleanup.register(handler);
+        abort(&mut cleanup);
+        abort(&mut cleanup);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn cleanup_never_runs_after_finish() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        extern fn handler() { RUNS.fetch_add(1, Ordering::SeqCst); }
+
+        let mut cleanup = CleanupHandler::new();
+        cleanup.register(handler);
+        cleanup.finish();
+        abort(&mut cleanup);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn flat_image_bounds() {
+        let base = KERNELCPU_PAYLOAD_ADDRESS;
+        assert_eq!(validate_flat_image(0x100, 0, base), Ok(()));
+        assert_eq!(validate_flat_image(0x100, 0xff, KERNELCPU_LAST_ADDRESS + 1 - 0x100), Ok(()));
+        assert!(validate_flat_image(0x100, 0, base - 1).is_err());
+        assert!(validate_flat_image(0x100, 0, KERNELCPU_LAST_ADDRESS + 2 - 0x100).is_err());
+        assert!(validate_flat_image(0x100, 0, usize::max_value() - 0x10).is_err());
+        assert!(validate_flat_image(0x100, 0x100, base).is_err());
+        assert!(validate_flat_image(0, 0, base).is_err());
+    }
+
+    #[test]
+    fn flat_image_loaded() {
+        let base = KERNELCPU_PAYLOAD_ADDRESS;
+        let mut region = [0u8; 16];
+        let image = [0x13, 0x00, 0x00, 0x00, 0x67, 0x80, 0x00, 0x00];
+        assert_eq!(load_flat_image(&mut region, &image, 4, base + 8), Ok(base + 12));
+        assert_eq!(&region[..8], &[0; 8]);
+        assert_eq!(&region[8..], &image);
+
+        // rejected images leave the region untouched
+        let mut region = [0u8; 16];
+        assert!(load_flat_image(&mut region, &image, 8, base).is_err());
+        assert!(load_flat_image(&mut region, &image, 0, base + 9).is_err());
+        assert_eq!(region, [0; 16]);
+    }
+
+    #[test]
+    fn replay_keeps_order() {
+        let mut replay = RtioReplayBuffer::new();
+        replay.record(RtioReplayEvent { timestamp: 100, target: 0x200, data: 1 });
+        replay.record(RtioReplayEvent { timestamp: 90, target: 0x300, data: 0 });
+        assert_eq!(replay.events(), &[
+            RtioReplayEvent { timestamp: 100, target: 0x200, data: 1 },
+            RtioReplayEvent { timestamp: 90, target: 0x300, data: 0 },
+        ]);
+        assert!(!replay.truncated());
+    }
+
+    #[test]
+    fn replay_truncated() {
+        let mut replay = RtioReplayBuffer::new();
+        for i in 0..RTIO_REPLAY_CAPACITY {
+            replay.record(RtioReplayEvent { timestamp: i as i64, target: 0, data: 0 });
+        }
+        assert!(!replay.truncated());
+        replay.record(RtioReplayEvent { timestamp: -1, target: 0, data: 0 });
+        assert!(replay.truncated());
+        assert_eq!(replay.events().len(), RTIO_REPLAY_CAPACITY);
+        assert_eq!(replay.events().last().unwrap().timestamp, RTIO_REPLAY_CAPACITY as i64 - 1);
+
+        replay.clear();
+        assert!(replay.events().is_empty());
+        assert!(!replay.truncated());
+    }
+
+    #[test]
+    fn self_test_passes() {
+        // what the self-test kernel in ksupport sends
+        let mut self_test = SelfTest::new();
+        assert_eq!(self_test.step(&LoadReply(Ok(()))), SelfTestStep::Acknowledge);
+        assert_eq!(self_test.step(&CacheGetRequest { key: "__self_test__" }), SelfTestStep::ReplyCacheGet);
+        assert_eq!(self_test.step(&RpcFlush), SelfTestStep::Acknowledge);
+        assert_eq!(self_test.step(&RunFinished), SelfTestStep::Finished(true));
+    }
+
+    #[test]
+    fn self_test_fails() {
+        assert_eq!(SelfTest::new().step(&RunFinished), SelfTestStep::Finished(false));
+        assert_eq!(SelfTest::new().step(&RpcFlush), SelfTestStep::Finished(false));
+
+        let mut self_test = SelfTest::new();
+        self_test.step(&LoadReply(Ok(())));
+        assert_eq!(self_test.step(&LoadReply(Ok(()))), SelfTestStep::Finished(false));
+
+        let mut self_test = SelfTest::new();
+        self_test.step(&LoadReply(Ok(())));
+        assert_eq!(self_test.step(&RunAborted), SelfTestStep::Finished(false));
+    }
+}
@@ -1,5 +1,9 @@
 #![no_std]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
@@ -11,6 +15,7 @@ extern crate cslice;
 extern crate log;
 
 extern crate byteorder;
+extern crate crc;
 extern crate io;
 extern crate dyld;
 extern crate eh;
@@ -28,4 +33,6 @@ pub mod analyzer_proto;
 pub mod moninj_proto;
 #[cfg(feature = "alloc")]
 pub mod session_proto;
+#[cfg(feature = "alloc")]
+pub mod decompress;
 pub mod rpc_proto;
@@ -1,9 +1,9 @@
-use core::str::Utf8Error;
+use core::{fmt, str::Utf8Error};
 use alloc::{vec::Vec, string::String};
 #[cfg(feature = "log")]
 use log;
 
-use io::{Read, ProtoRead, Write, ProtoWrite, Error as IoError, ReadStringError};
+use io::{Read, ProtoRead, Write, ProtoWrite, Error as IoError, ReadStringError, read_frame};
 
 #[derive(Fail, Debug)]
 pub enum Error<T> {
@@ -34,6 +34,9 @@ impl<T> From<ReadStringError<IoError<T>>> for Error<T> {
     }
 }
 
+// Far more than the configuration sector can hold.
+const MAX_CONFIG_VALUE_SIZE: usize = 1 << 20;
+
 pub fn read_magic<R>(reader: &mut R) -> Result<(), Error<R::ReadError>>
     where R: Read + ?Sized
 {
@@ -62,12 +65,92 @@ pub enum Request {
     ConfigWrite  { key: String, value: Vec<u8> },
     ConfigRemove { key: String },
     ConfigErase,
+    FlashWear,
 
     Reboot,
 
     DebugAllocator,
+    CsrRead  { addr: u32 },
+    CsrWrite { addr: u32, value: u32 },
+    StabilityReport { reset: bool },
+    Diagnostics,
+    GetLogRecords,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CsrAccess {
+    Allowed,
+    /// Raw CSR access bypasses every driver, so it has to be enabled
+    /// explicitly with the `debug_csr_access` config entry.
+    Disabled,
+    /// Not the aligned address of a CSR of the gateware; other addresses may
+    /// fault the bus or reach memory.
+    InvalidAddress
+}
+
+/// Decides whether CsrRead and CsrWrite may access `addr`, given the
+/// `debug_csr_access` setting and the CSRs of the gateware as address and
+/// length in bytes.
+pub fn csr_access(enabled: bool, csrs: &[(u32, u32)], addr: u32) -> CsrAccess {
+    if !enabled {
+        CsrAccess::Disabled
+    } else if addr % 4 != 0 ||
+            !csrs.iter().any(|&(start, length)| addr >= start && addr - start < length) {
+        CsrAccess::InvalidAddress
+    } else {
+        CsrAccess::Allowed
+    }
 }
 
+/// Where the stability counters are kept.
+pub trait StabilityCounters {
+    /// The counts of boots, clean shutdowns and panics, in this order.
+    fn counts(&mut self) -> [u32; 3];
+    /// Copies as much of the last crash record as fits into `buffer`, and
+    /// returns its length.
+    fn last_crash(&mut self, buffer: &mut [u8]) -> usize;
+    /// Zeroes the counts and forgets the last crash.
+    fn reset(&mut self);
+}
+
+/// Passes a StabilityReport to `f`. With `reset`, the counters are zeroed
+/// right after being read, with no chance for the threads, which are
+/// cooperative, to record an event in between and have it lost.
+pub fn stability_report<C, F, R>(counters: &mut C, reset: bool, f: F) -> R
+    where C: StabilityCounters, F: FnOnce(Reply) -> R
+{
+    let counts = counters.counts();
+    let mut last_crash = [0; 128];
+    let last_crash_len = counters.last_crash(&mut last_crash);
+    if reset {
+        counters.reset()
+    }
+    f(Reply::StabilityReport {
+        boots: counts[0],
+        clean_shutdowns: counts[1],
+        panics: counts[2],
+        last_crash: &last_crash[..last_crash_len]
+    })
+}
+
+/// Serializes one section of a Diagnostics reply.
+pub fn diagnostics_section<F, E>(tag: u8, f: F) -> (u8, Vec<u8>)
+    where F: FnOnce(&mut Vec<u8>) -> Result<(), E>, E: fmt::Debug
+{
+    let mut payload = Vec::new();
+    f(&mut payload).expect("serializing into memory cannot fail");
+    (tag, payload)
+}
+
+/// Diagnostics replies are a list of (tag, length, payload) sections;
+/// hosts skip sections with tags they do not know.
+pub const DIAGNOSTICS_VERSION: u8 = 1;
+pub const DIAGNOSTICS_CLOCK: u8 = 1;
+pub const DIAGNOSTICS_NETWORK: u8 = 2;
+pub const DIAGNOSTICS_MEMORY: u8 = 3;
+pub const DIAGNOSTICS_STABILITY: u8 = 4;
+pub const DIAGNOSTICS_TASKS: u8 = 5;
+
 pub enum Reply<'a> {
     Success,
     Error,
@@ -76,8 +159,22 @@ pub enum Reply<'a> {
     LogContent(&'a str),
 
     ConfigData(&'a [u8]),
+    FlashWear(&'a [(u32, u32)]),
 
     RebootImminent,
+
+    CsrData(u32),
+
+    StabilityReport {
+        boots: u32,
+        clean_shutdowns: u32,
+        panics: u32,
+        last_crash: &'a [u8]
+    },
+
+    Diagnostics(&'a [(u8, Vec<u8>)]),
+    // level (1 error to 5 trace), timestamp in microseconds, target, message
+    LogRecords(&'a [(u8, u64, String, String)]),
 }
 
 impl Request {
@@ -112,16 +209,29 @@ impl Request {
             },
             13 => Request::ConfigWrite {
                 key:   reader.read_string()?,
-                value: reader.read_bytes()?
+                value: read_frame(reader, MAX_CONFIG_VALUE_SIZE)?
             },
             14 => Request::ConfigRemove {
                 key: reader.read_string()?
             },
             15 => Request::ConfigErase,
+            16 => Request::FlashWear,
 
             5 => Request::Reboot,
 
             8 => Request::DebugAllocator,
+            17 => Request::CsrRead {
+                addr: reader.read_u32()?
+            },
+            18 => Request::CsrWrite {
+                addr:  reader.read_u32()?,
+                value: reader.read_u32()?
+            },
+            19 => Request::StabilityReport {
+                reset: reader.read_bool()?
+            },
+            20 => Request::Diagnostics,
+            21 => Request::GetLogRecords,
 
             ty => return Err(Error::UnknownPacket(ty))
         })
@@ -153,11 +263,180 @@ impl<'a> Reply<'a> {
                 writer.write_u8(7)?;
                 writer.write_bytes(bytes)?;
             },
+            Reply::FlashWear(blocks) => {
+                writer.write_u8(8)?;
+                writer.write_u32(blocks.len() as u32)?;
+                for &(block, erases) in blocks {
+                    writer.write_u32(block)?;
+                    writer.write_u32(erases)?;
+                }
+            },
 
             Reply::RebootImminent => {
                 writer.write_u8(3)?;
             }
+
+            Reply::CsrData(value) => {
+                writer.write_u8(9)?;
+                writer.write_u32(value)?;
+            }
+
+            Reply::StabilityReport { boots, clean_shutdowns, panics, last_crash } => {
+                writer.write_u8(10)?;
+                writer.write_u32(boots)?;
+                writer.write_u32(clean_shutdowns)?;
+                writer.write_u32(panics)?;
+                writer.write_bytes(last_crash)?;
+            }
+
+            Reply::Diagnostics(sections) => {
+                writer.write_u8(11)?;
+                writer.write_u8(DIAGNOSTICS_VERSION)?;
+                writer.write_u32(sections.len() as u32)?;
+                for &(tag, ref payload) in sections {
+                    writer.write_u8(tag)?;
+                    writer.write_bytes(payload)?;
+                }
+            }
+
+            Reply::LogRecords(records) => {
+                writer.write_u8(12)?;
+                writer.write_u32(records.len() as u32)?;
+                for &(level, timestamp, ref target, ref message) in records {
+                    writer.write_u8(level)?;
+                    writer.write_u64(timestamp)?;
+                    writer.write_string(target)?;
+                    writer.write_string(message)?;
+                }
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSRS: &[(u32, u32)] = &[(0xe000_1000, 4), (0xe000_1004, 8), (0xe000_2800, 4)];
+
+    #[test]
+    fn csr_access_checks() {
+        assert_eq!(csr_access(true, CSRS, 0xe000_1000), CsrAccess::Allowed);
+        assert_eq!(csr_access(true, CSRS, 0xe000_1008), CsrAccess::Allowed);
+        assert_eq!(csr_access(true, CSRS, 0xe000_2800), CsrAccess::Allowed);
+        // right past a CSR, between two banks, and unaligned
+        assert_eq!(csr_access(true, CSRS, 0xe000_100c), CsrAccess::InvalidAddress);
+        assert_eq!(csr_access(true, CSRS, 0xe000_2000), CsrAccess::InvalidAddress);
+        assert_eq!(csr_access(true, CSRS, 0xe000_1002), CsrAccess::InvalidAddress);
+        assert_eq!(csr_access(true, CSRS, 0), CsrAccess::InvalidAddress);
+        assert_eq!(csr_access(true, CSRS, 0xffff_fffc), CsrAccess::InvalidAddress);
+        assert_eq!(csr_access(true, &[], 0xe000_1000), CsrAccess::InvalidAddress);
+        // disabled access is reported as such, whatever the address
+        assert_eq!(csr_access(false, CSRS, 0xe000_1000), CsrAccess::Disabled);
+        assert_eq!(csr_access(false, CSRS, 3), CsrAccess::Disabled);
+    }
+    struct MockCounters {
+        counts: [u32; 3],
+        last_crash: &'static [u8]
+    }
+
+    impl StabilityCounters for MockCounters {
+        fn counts(&mut self) -> [u32; 3] {
+            self.counts
+        }
+
+        fn last_crash(&mut self, buffer: &mut [u8]) -> usize {
+            let length = self.last_crash.len().min(buffer.len());
+            buffer[..length].copy_from_slice(&self.last_crash[..length]);
+            length
+        }
+
+        fn reset(&mut self) {
+            self.counts = [0; 3];
+            self.last_crash = b"";
+        }
+    }
+
+    fn report(counters: &mut MockCounters, reset: bool) -> Vec<u8> {
+        stability_report(counters, reset, |report| {
+            let mut packet = Vec::new();
+            report.write_to(&mut packet).unwrap();
+            packet
+        })
+    }
+
+    #[test]
+    fn stability_report_serialized() {
+        use byteorder::{ByteOrder, NativeEndian};
+        let mut counters = MockCounters { counts: [12, 9, 2], last_crash: b"panic at a.rs:1:2" };
+        let packet = report(&mut counters, false);
+        assert_eq!(packet[0], 10);
+        let counts = [NativeEndian::read_u32(&packet[1..]), NativeEndian::read_u32(&packet[5..]),
+                      NativeEndian::read_u32(&packet[9..])];
+        assert_eq!(counts, [12, 9, 2]);
+        assert_eq!(NativeEndian::read_u32(&packet[13..]), 17);
+        assert_eq!(&packet[17..], b"panic at a.rs:1:2");
+        // reading alone changes nothing
+        assert_eq!(report(&mut counters, false), packet);
+    }
+
+    #[test]
+    fn stability_report_read_and_reset() {
+        let mut counters = MockCounters { counts: [12, 9, 2], last_crash: b"panic" };
+        let before = report(&mut counters, false);
+        // the report carries the counts from before the reset
+        assert_eq!(report(&mut counters, true), before);
+        assert_eq!(counters.counts, [0; 3]);
+        let mut empty = MockCounters { counts: [0; 3], last_crash: b"" };
+        assert_eq!(report(&mut counters, false), report(&mut empty, false));
+    }
+    // reads a Diagnostics reply the way the host does, keeping known sections only
+    fn read_diagnostics(packet: &[u8]) -> (u8, Vec<(u8, Vec<u8>)>) {
+        let mut reader = ::io::Cursor::new(packet);
+        assert_eq!(reader.read_u8().unwrap(), 11);
+        let version = reader.read_u8().unwrap();
+        let mut sections = Vec::new();
+        for _ in 0..reader.read_u32().unwrap() {
+            let tag = reader.read_u8().unwrap();
+            let payload = reader.read_bytes().unwrap();
+            if tag >= DIAGNOSTICS_CLOCK && tag <= DIAGNOSTICS_TASKS {
+                sections.push((tag, payload))
+            }
+        }
+        assert_eq!(reader.position(), packet.len());
+        (version, sections)
+    }
+
+    #[test]
+    fn diagnostics_sections() {
+        let mut sections = vec![
+            diagnostics_section(DIAGNOSTICS_CLOCK, |payload| {
+                payload.write_u64(1500)?;
+                payload.write_bool(false)?;
+                payload.write_u64(0)?;
+                payload.write_bool(true)
+            }),
+            diagnostics_section(DIAGNOSTICS_NETWORK, |payload|
+                payload.write_string("IPv4 address: 192.168.1.75")),
+            diagnostics_section(DIAGNOSTICS_MEMORY, |payload| {
+                payload.write_u32(0x1000)?;
+                payload.write_u32(0x7000)
+            }),
+            diagnostics_section(DIAGNOSTICS_STABILITY, |payload| {
+                let mut counters = MockCounters { counts: [3, 2, 1], last_crash: b"panic" };
+                stability_report(&mut counters, false, |report| report.write_to(payload))
+            }),
+            diagnostics_section(DIAGNOSTICS_TASKS, |payload| payload.write_u32(0)),
+        ];
+        let known = sections.clone();
+        // a section added by a later firmware
+        sections.push(diagnostics_section(0xff, |payload| payload.write_u32(0xdeadbeef)));
+
+        let mut packet = Vec::new();
+        Reply::Diagnostics(&sections).write_to(&mut packet).unwrap();
+        let (version, parsed) = read_diagnostics(&packet);
+        assert_eq!(version, DIAGNOSTICS_VERSION);
+        assert_eq!(parsed, known);
+    }
+}
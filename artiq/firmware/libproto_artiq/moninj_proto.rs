@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use io::{Read, ProtoRead, Write, ProtoWrite, Error as IoError};
 
 #[derive(Fail, Debug)]
@@ -30,6 +31,42 @@ pub fn read_magic<R>(reader: &mut R) -> Result<(), Error<R::ReadError>>
     }
 }
 
+/// One entry of the `safe_state` config key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SafeStateEntry {
+    pub channel: u32,
+    pub level: u8
+}
+
+fn parse_channel(channel: &str) -> Option<u32> {
+    if channel.starts_with("0x") {
+        u32::from_str_radix(&channel[2..], 16).ok()
+    } else {
+        channel.parse().ok()
+    }
+}
+
+/// Parses the `safe_state` config value, a comma-separated list of
+/// `channel=level` entries (e.g. `0x000005=0,6=1`). Only TTL output
+/// channels may be listed, so the level must be 0 or 1; an entry that
+/// does not parse is returned as `Err` with its text.
+pub fn parse_safe_state<'a>(safe_state: &'a str)
+        -> impl Iterator<Item=Result<SafeStateEntry, &'a str>> + 'a {
+    safe_state.split(',')
+        .map(|entry| entry.trim())
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            let channel = parts.next().and_then(|channel| parse_channel(channel.trim()));
+            let level = parts.next().and_then(|level| level.trim().parse::<u8>().ok());
+            match (channel, level) {
+                (Some(channel), Some(level)) if level <= 1 =>
+                    Ok(SafeStateEntry { channel: channel, level: level }),
+                _ => Err(entry)
+            }
+        })
+}
+
 #[derive(Debug)]
 pub enum HostMessage {
     MonitorProbe { enable: bool, channel: u32, probe: u8 },
@@ -41,7 +78,8 @@ pub enum HostMessage {
 #[derive(Debug)]
 pub enum DeviceMessage {
     MonitorStatus { channel: u32, probe: u8, value: u64 },
-    InjectionStatus { channel: u32, overrd: u8, value: u8 }
+    InjectionStatus { channel: u32, overrd: u8, value: u8 },
+    InjectionBusy { channel: u32, overrd: u8 }
 }
 
 impl HostMessage {
@@ -89,8 +127,79 @@ impl DeviceMessage {
                 writer.write_u32(channel)?;
                 writer.write_u8(overrd)?;
                 writer.write_u8(value)?;
+            },
+            DeviceMessage::InjectionBusy { channel, overrd } => {
+                writer.write_u8(2)?;
+                writer.write_u32(channel)?;
+                writer.write_u8(overrd)?;
             }
         }
         Ok(())
     }
 }
+
+/// RTIO channels claimed by the running kernel, by full channel number.
+/// Moninj may still monitor a claimed channel, but refuses to inject into it.
+#[derive(Debug, Default)]
+pub struct ChannelClaims(Vec<u32>);
+
+impl ChannelClaims {
+    pub const fn new() -> ChannelClaims {
+        ChannelClaims(Vec::new())
+    }
+
+    pub fn claim(&mut self, channel: u32) {
+        if !self.0.contains(&channel) {
+            self.0.push(channel)
+        }
+    }
+
+    pub fn release(&mut self, channel: u32) {
+        self.0.retain(|&claimed| claimed != channel)
+    }
+
+    pub fn release_all(&mut self) {
+        self.0.clear()
+    }
+
+    pub fn is_claimed(&self, channel: u32) -> bool {
+        self.0.contains(&channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn safe_state_entries() {
+        let entries: Vec<_> = parse_safe_state(" 0x000005=0, 6 = 1 ,,").collect();
+        assert_eq!(entries, vec![Ok(SafeStateEntry { channel: 5, level: 0 }),
+                                 Ok(SafeStateEntry { channel: 6, level: 1 })]);
+    }
+
+    #[test]
+    fn safe_state_rejects_non_ttl_levels() {
+        let entries: Vec<_> = parse_safe_state("5=2,6,x=1,0x10005=1").collect();
+        assert_eq!(entries, vec![Err("5=2"), Err("6"), Err("x=1"),
+                                 Ok(SafeStateEntry { channel: 0x10005, level: 1 })]);
+    }
+
+    #[test]
+    fn claim_refuses_injection() {
+        let mut claims = ChannelClaims::new();
+        claims.claim(0x10005);
+        claims.claim(0x10005);
+        claims.claim(7);
+        assert!(claims.is_claimed(0x10005));
+        // the same local channel at another destination is not claimed
+        assert!(!claims.is_claimed(0x20005));
+
+        claims.release(0x10005);
+        assert!(!claims.is_claimed(0x10005));
+        assert!(claims.is_claimed(7));
+        claims.release_all();
+        assert!(!claims.is_claimed(7));
+    }
+}
@@ -1,4 +1,4 @@
-use core::str;
+use core::{fmt, str};
 use core::slice;
 use cslice::{CSlice, CMutSlice};
 use byteorder::{NativeEndian, ByteOrder};
@@ -368,6 +368,62 @@ pub fn send_args<W>(writer: &mut W, service: u32, tag_bytes: &[u8], data: *const
     Ok(())
 }
 
+const TRACE_MAX_BYTES: usize = 32;
+
+/// Shows at most TRACE_MAX_BYTES of an RPC tag or payload.
+pub struct TraceBytes<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Display for TraceBytes<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.iter().take(TRACE_MAX_BYTES) {
+            write!(f, "{:02x}", byte)?;
+        }
+        if self.0.len() > TRACE_MAX_BYTES {
+            write!(f, "... ({} bytes)", self.0.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// A step of the conversation between kernel and host, as traced.
+#[derive(Debug)]
+pub enum TraceRecord<'a> {
+    Call { service: u32, tag: &'a [u8], async: bool },
+    Queued(&'a [u8]),
+    Return { tag: &'a [u8] },
+    Exception { id: u32 }
+}
+
+impl<'a> fmt::Display for TraceRecord<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TraceRecord::Call { service, tag, async } =>
+                write!(f, "kernel->host service {} tag {}{}",
+                       service, TraceBytes(tag), if async { " (async)" } else { "" }),
+            TraceRecord::Queued(data) =>
+                write!(f, "kernel->host queued {}", TraceBytes(data)),
+            TraceRecord::Return { tag } =>
+                write!(f, "host->kernel return tag {}", TraceBytes(tag)),
+            TraceRecord::Exception { id } =>
+                write!(f, "host->kernel exception id {}", id)
+        }
+    }
+}
+
+/// Whether the RPCs of a session are traced, as set by the `rpc_trace`
+/// config entry.
+#[derive(Debug, Clone, Copy)]
+pub struct Trace(pub bool);
+
+impl Trace {
+    /// Hands `record` to `log` if tracing is enabled.
+    pub fn record<F: FnOnce(&TraceRecord)>(&self, record: TraceRecord, log: F) {
+        if self.0 {
+            log(&record)
+        }
+    }
+}
+
 mod tag {
     use core::fmt;
     use super::round_up;
@@ -609,3 +665,38 @@ mod tag {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    // The RPCs of a kernel calling service 3 and then 4, whose reply is an
+    // exception, with a queued async RPC in between.
+    fn conversation(trace: Trace) -> Vec<String> {
+        let mut records = Vec::new();
+        let payload = [0xa5; 40];
+        for record in [TraceRecord::Call { service: 3, tag: b"i:i", async: false },
+                       TraceRecord::Return { tag: b"i" },
+                       TraceRecord::Queued(&payload),
+                       TraceRecord::Call { service: 4, tag: b":n", async: false },
+                       TraceRecord::Exception { id: 7 }] {
+            trace.record(record, |record| records.push(record.to_string()))
+        }
+        records
+    }
+
+    #[test]
+    fn trace_record_per_rpc() {
+        let records = conversation(Trace(true));
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0], "kernel->host service 3 tag 693a69");
+        assert_eq!(records[1], "host->kernel return tag 69");
+        // payloads are cut short
+        assert_eq!(records[2], ["kernel->host queued ", &"a5".repeat(32), "... (40 bytes)"].concat());
+        assert_eq!(records[4], "host->kernel exception id 7");
+
+        assert!(conversation(Trace(false)).is_empty());
+    }
+}
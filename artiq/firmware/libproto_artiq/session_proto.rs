@@ -1,13 +1,75 @@
-use core::{str, str::Utf8Error, slice};
+use core::{fmt, str, str::Utf8Error, slice, cell::RefCell};
 use alloc::{vec::Vec, format, collections::BTreeMap, string::String};
 use eh::eh_artiq::{Exception, StackPointerBacktrace};
-use cslice::CSlice;
+use kernel_proto::{RtioReplayEvent, KERNELCPU_PAYLOAD_ADDRESS, KERNELCPU_LAST_ADDRESS};
+use cslice::{CSlice, AsCSlice};
+use byteorder::{ByteOrder, NativeEndian};
 
-use io::{Read, ProtoRead, Write, ProtoWrite, Error as IoError, ReadStringError};
+use io::{Read, ProtoRead, Write, ProtoWrite, Error as IoError, ReadStringError, read_frame};
 
+/// Keyed by the full RTIO channel number, which carries the DRTIO destination
+/// in its upper 16 bits, so one map covers every destination.
 pub type DeviceMap = BTreeMap<u32, String>;
 
-static mut RTIO_DEVICE_MAP: Option<DeviceMap> = None;
+// No sane source file has this many lines or columns; anything above
+// is assumed to come from a corrupted exception object.
+const MAX_LINE: u32 = 1 << 20;
+const MAX_COLUMN: u32 = 1 << 16;
+
+/// Versions 0 and 1 are the original protocol, 0 being what hosts that
+/// never negotiate are treated as; version 2 adds kernel metadata to
+/// LoadCompleted, the location flag in KernelException and every request
+/// with an id of 10 or above; version 3 adds the time of lock loss to
+/// ClockFailure; version 4 adds LoadKernelCompressed; version 5 adds
+/// sequence numbers to the header of every request and reply; version 6 adds
+/// source snippets to KernelException; version 7 adds the feature bitmask to
+/// VersionNegotiated; version 8 adds the uncompressed length to
+/// LoadKernelCompressed; version 9 adds a CRC32 to UploadSubkernel; version 10
+/// splits long backtraces in KernelException into chunks; version 11 adds the
+/// outcome of the previous run to SystemInfo, as the `LastRunOutcome` code
+/// in a byte after `finished_cleanly` (3, `WatchdogReset`, is reserved);
+/// version 12 adds the subkernels evicted to make room to LoadCompleted.
+pub const PROTOCOL_VERSION: u32 = 12;
+
+/// Hosts that never negotiate keep the original wire format.
+pub const DEFAULT_VERSION: u32 = 0;
+
+/// Optional capabilities of the firmware build, reported in VersionNegotiated
+/// so that the host can check for them before relying on them.
+pub const FEATURE_SUBKERNELS: u32 = 1 << 0;
+pub const FEATURE_COMPRESSED_KERNELS: u32 = 1 << 1;
+pub const FEATURE_STORED_ANALYZER: u32 = 1 << 2;
+
+/// Backtraces with more frames than this are sent in chunks of this many
+/// frames, each followed by a flag telling whether another chunk follows.
+pub const BACKTRACE_CHUNK_FRAMES: usize = 256;
+
+/// The largest kernel that fits the memory of the kernel CPU. Kernels, and
+/// chunks of them, above this are refused from their length prefix.
+pub const MAX_KERNEL_SIZE: usize = KERNELCPU_LAST_ADDRESS + 1 - KERNELCPU_PAYLOAD_ADDRESS;
+
+/// The largest serialized exception kept for GetLastException.
+pub const LAST_EXCEPTION_MAX_SIZE: usize = 16384;
+
+// An RPC reply tag describes the type of a single return value.
+const MAX_RPC_TAG_SIZE: usize = 1024;
+
+// Destinations are sent as one byte each.
+const MAX_DESTINATIONS: usize = 256;
+
+// Only ever touched by the comms CPU, whose threads are cooperative, so a
+// RefCell suffices; it still catches a lookup nested inside an update.
+struct DeviceMapCell(RefCell<Option<DeviceMaps>>);
+
+struct DeviceMaps {
+    by_channel: DeviceMap,
+    // built once per map, as lookups by name would otherwise scan it
+    by_name: BTreeMap<String, u32>
+}
+
+unsafe impl Sync for DeviceMapCell {}
+
+static RTIO_DEVICE_MAP: DeviceMapCell = DeviceMapCell(RefCell::new(None));
 
 #[derive(Fail, Debug)]
 pub enum Error<T> {
@@ -17,13 +79,22 @@ pub enum Error<T> {
     UnknownPacket(u8),
     #[fail(display = "invalid UTF-8: {}", _0)]
     Utf8(Utf8Error),
+    #[fail(display = "no sync marker within {} bytes", MAX_SYNC_SCAN)]
+    SyncNotFound,
+    #[fail(display = "payload of {} bytes exceeds the limit of {} bytes", requested, max)]
+    PayloadTooLarge { requested: usize, max: usize },
     #[fail(display = "{}", _0)]
     Io(#[cause] IoError<T>)
 }
 
 impl<T> From<IoError<T>> for Error<T> {
     fn from(value: IoError<T>) -> Error<T> {
-        Error::Io(value)
+        match value {
+            // rejected from the length prefix alone, before allocating anything
+            IoError::TooLarge { length, max } =>
+                Error::PayloadTooLarge { requested: length, max: max },
+            value => Error::Io(value)
+        }
     }
 }
 
@@ -36,6 +107,49 @@ impl<T> From<ReadStringError<IoError<T>>> for Error<T> {
     }
 }
 
+/// Per-connection protocol state: the negotiated version, which gates every
+/// versioned field, and the sequence numbers added in version 5.
+#[derive(Debug, Clone)]
+pub struct Context {
+    version: u32,
+    reply_seq: u32,
+    request_seq: Option<u32>
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context {
+            version: DEFAULT_VERSION,
+            reply_seq: 0,
+            request_seq: None
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Settles on the highest version both sides support and uses it for
+    /// all subsequent serialization.
+    /// Reply sequence numbers restart from 1 afterwards.
+    pub fn negotiate(&mut self, host_version: u32) -> u32 {
+        self.version = host_version.max(1).min(PROTOCOL_VERSION);
+        self.reply_seq = 0;
+        self.version
+    }
+
+    /// Marks the request read last as handled. Replies written until the next
+    /// request is read, e.g. RPCs or KernelFinished, are sent with an
+    /// `in_reply_to` of 0, which hosts must never use as a sequence number.
+    pub fn end_request(&mut self) {
+        self.request_seq = None
+    }
+
+    fn sequenced(&self) -> bool {
+        self.version >= 5
+    }
+}
+
 pub fn read_magic<R>(reader: &mut R) -> Result<(), Error<R::ReadError>>
     where R: Read + ?Sized
 {
@@ -50,15 +164,19 @@ pub fn read_magic<R>(reader: &mut R) -> Result<(), Error<R::ReadError>>
     }
 }
 
-fn read_sync<R>(reader: &mut R) -> Result<(), IoError<R::ReadError>>
+// Garbage in front of a packet is skipped, but only this much of it; a host
+// sending more is not speaking the protocol.
+const MAX_SYNC_SCAN: usize = 4096;
+
+fn read_sync<R>(reader: &mut R) -> Result<(), Error<R::ReadError>>
     where R: Read + ?Sized
 {
     let mut sync = [0; 4];
-    for i in 0.. {
+    for i in 0..MAX_SYNC_SCAN {
         sync[i % 4] = reader.read_u8()?;
-        if sync == [0x5a; 4] { break }
+        if sync == [0x5a; 4] { return Ok(()) }
     }
-    Ok(())
+    Err(Error::SyncNotFound)
 }
 
 fn write_sync<W>(writer: &mut W) -> Result<(), IoError<W::WriteError>>
@@ -67,12 +185,68 @@ fn write_sync<W>(writer: &mut W) -> Result<(), IoError<W::WriteError>>
     writer.write_all(&[0x5a; 4])
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum TaskState {
+    Running,
+    Ready,
+    Sleeping,
+    Dead
+}
+
+/// Why the previous run ended, to tell the user more than `finished_cleanly`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LastRunOutcome {
+    Clean = 0,
+    Panicked = 1,
+    ClockFailure = 2,
+    /// Reserved for a watchdog reboot; this firmware has no watchdog and
+    /// never reports it.
+    WatchdogReset = 3,
+    /// The board reset without leaving any record, e.g. because it lost power.
+    PowerLoss = 4
+}
+
+impl LastRunOutcome {
+    /// How the previous run ended, from the markers it left for the next
+    /// boot: whether the reset was requested, and the outcome it recorded
+    /// as `LastRunOutcome as u32`, 0 if none. Requested resets mark
+    /// themselves last, so they take precedence.
+    pub fn at_boot(clean: bool, outcome: u32) -> LastRunOutcome {
+        match (clean, outcome) {
+            (true, _) => LastRunOutcome::Clean,
+            (false, 1) => LastRunOutcome::Panicked,
+            (false, 2) => LastRunOutcome::ClockFailure,
+            (false, _) => LastRunOutcome::PowerLoss
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TaskInfo<'a> {
+    pub name: &'a str,
+    pub state: TaskState,
+    pub stack_high_water: u32,
+    pub stack_size: u32,
+    pub run_count: u32,
+    pub runtime_us: u64
+}
+
 #[derive(Debug)]
 pub enum Request {
     SystemInfo,
+    NegotiateVersion { host_version: u32 },
+    SyncTime { host_unix_ms: u64 },
+    TaskDump,
 
     LoadKernel(Vec<u8>),
+    LoadKernelChunk { total: u32, data: Vec<u8> },
+    LoadFlatKernel { image: Vec<u8>, entry_offset: u32, load_addr: u32 },
+    AbortLoad,
+    CacheKernel(Vec<u8>),
+    RunCachedKernel,
+    RunSelfKernel,
     RunKernel,
+    RunKernelRepeated { count: u32, stop_on_error: bool },
 
     RpcReply { tag: Vec<u8> },
     RpcException {
@@ -85,18 +259,38 @@ pub enum Request {
         function: u32,
     },
 
-    UploadSubkernel { id: u32, destination: u8, kernel: Vec<u8> },
+    UploadSubkernel { id: u32, destination: u8, kernel: Vec<u8>, crc32: Option<u32> },
+    StartSubkernelSynced { id: u32, destinations: Vec<u8>, at_timestamp: u64 },
+    DrtioPing { destination: u8 },
+    RtioErrorDetail,
+    GetLastException,
+    PullStoredAnalyzer { index: u32 },
+    SessionState,
+    LoadKernelCompressed { algorithm: u8, uncompressed_len: Option<u32>, data: Vec<u8> },
+    DrtioBufferStatus { destination: u8 },
+    ResetKernelState,
+    RpcBufferInfo,
+    PullRtioReplay,
+    Ping,
+    /// Levels are numbered as in `log::Level`, from 1 for errors to 5 for
+    /// trace; 0 unsubscribes.
+    SubscribeLog { min_level: u8 },
 }
 
 #[derive(Debug)]
 pub enum Reply<'a> {
     SystemInfo {
         ident: &'a str,
-        finished_cleanly: bool
+        finished_cleanly: bool,
+        last_run_outcome: LastRunOutcome
     },
+    VersionNegotiated { version: u32, features: u32 },
 
-    LoadCompleted,
+    /// `evicted` lists the subkernels evicted from the destination of an
+    /// uploaded subkernel to make room for it.
+    LoadCompleted { metadata: &'a [u8], evicted: &'a [u32] },
     LoadFailed(&'a str),
+    LoadProgress { received: u32, total: u32 },
 
     KernelFinished {
         async_errors: u8
@@ -104,6 +298,8 @@ pub enum Reply<'a> {
     KernelStartupFailed,
     KernelException {
         exceptions: &'a [Option<Exception<'a>>],
+        // source line of the exception with the same index, if known
+        source_snippets: &'a [Option<&'a str>],
         stack_pointers: &'a [StackPointerBacktrace],
         backtrace: &'a [(usize, usize)],
         async_errors: u8
@@ -111,22 +307,57 @@ pub enum Reply<'a> {
 
     RpcRequest { async: bool },
 
-    ClockFailure,
+    ClockFailure { lost_at_us: u64 },
+
+    AllRunsComplete { succeeded: u32, failed: u32 },
+    TaskDump(&'a [TaskInfo<'a>]),
+
+    SubkernelsStarted { failed: &'a [u8] },
+    // round_trip_us: CPU timer microseconds, None for local or unreachable destinations
+    DrtioRoundTrip { round_trip_us: Option<u64> },
+
+    SelfKernelResult { passed: bool },
+    RtioErrorDetail(&'a [(u8, u32)]),
+    // a complete, previously serialized KernelException reply
+    LastException(Option<&'a [u8]>),
+    // full: the most recent capture could not be stored
+    StoredAnalyzer { full: bool, capture: Option<&'a [u8]> },
+    // kernel_state: 0 absent, 1 loaded, 2 running, 3 waiting for an RPC reply
+    SessionState { kernel_state: u8, loading: bool },
+    // (queued, capacity) in events, None if the destination could not be queried
+    DrtioBufferStatus(Option<(u16, u16)>),
+    // bytes of heap released, None if refused because a kernel is running
+    KernelStateReset(Option<u32>),
+    RpcBufferInfo { slot_bytes: u32, max_fragments: u32 },
+    // outputs of the last kernel run with rtio_replay set, None if there is none
+    RtioReplay(Option<(&'a [RtioReplayEvent], bool)>),
+    // uptime truncated to 32 bits, i.e. wrapping every ~49.7 days
+    Pong { uptime_ms: u32 },
+    UploadFailed { id: u32, reason: &'a str },
+    Log { level: u8, message: &'a str },
 }
 
 impl Request {
-    pub fn read_from<R>(reader: &mut R) -> Result<Self, Error<R::ReadError>>
+    pub fn read_from<R>(reader: &mut R, context: &mut Context) -> Result<Self, Error<R::ReadError>>
         where R: Read + ?Sized
     {
         read_sync(reader)?;
-        Ok(match reader.read_u8()? {
+        if context.sequenced() {
+            context.request_seq = Some(reader.read_u32()?);
+        }
+        let version = context.version;
+        let ty = reader.read_u8()?;
+        if request_version(ty) > version {
+            return Err(Error::UnknownPacket(ty))
+        }
+        Ok(match ty {
             3  => Request::SystemInfo,
 
-            5  => Request::LoadKernel(reader.read_bytes()?),
+            5  => Request::LoadKernel(read_frame(reader, MAX_KERNEL_SIZE)?),
             6  => Request::RunKernel,
 
             7  => Request::RpcReply {
-                tag: reader.read_bytes()?
+                tag: read_frame(reader, MAX_RPC_TAG_SIZE)?
             },
             8  => Request::RpcException {
                 id:       reader.read_u32()?,
@@ -142,7 +373,62 @@ impl Request {
             9 => Request::UploadSubkernel {
                 id: reader.read_u32()?,
                 destination: reader.read_u8()?,
-                kernel: reader.read_bytes()?
+                kernel: read_frame(reader, MAX_KERNEL_SIZE)?,
+                crc32: if version >= 9 { Some(reader.read_u32()?) } else { None }
+            },
+            10 => Request::RunKernelRepeated {
+                count: reader.read_u32()?,
+                stop_on_error: reader.read_bool()?
+            },
+            11 => Request::SyncTime {
+                host_unix_ms: reader.read_u64()?
+            },
+            12 => Request::TaskDump,
+            13 => Request::StartSubkernelSynced {
+                id: reader.read_u32()?,
+                destinations: read_frame(reader, MAX_DESTINATIONS)?,
+                at_timestamp: reader.read_u64()?
+            },
+            14 => Request::DrtioPing {
+                destination: reader.read_u8()?
+            },
+            15 => Request::CacheKernel(read_frame(reader, MAX_KERNEL_SIZE)?),
+            16 => Request::RunCachedKernel,
+            17 => Request::RunSelfKernel,
+            18 => Request::LoadKernelChunk {
+                total: reader.read_u32()?,
+                data: read_frame(reader, MAX_KERNEL_SIZE)?
+            },
+            19 => Request::AbortLoad,
+            20 => Request::NegotiateVersion {
+                host_version: reader.read_u32()?
+            },
+            21 => Request::RtioErrorDetail,
+            22 => Request::LoadFlatKernel {
+                image: read_frame(reader, MAX_KERNEL_SIZE)?,
+                entry_offset: reader.read_u32()?,
+                load_addr: reader.read_u32()?
+            },
+            23 => Request::GetLastException,
+            24 => Request::PullStoredAnalyzer {
+                index: reader.read_u32()?
+            },
+            25 => Request::SessionState,
+            26 => Request::LoadKernelCompressed {
+                algorithm: reader.read_u8()?,
+                uncompressed_len:
+                    if version >= 8 { Some(reader.read_u32()?) } else { None },
+                data: read_frame(reader, MAX_KERNEL_SIZE)?
+            },
+            27 => Request::DrtioBufferStatus {
+                destination: reader.read_u8()?
+            },
+            28 => Request::ResetKernelState,
+            29 => Request::RpcBufferInfo,
+            30 => Request::PullRtioReplay,
+            31 => Request::Ping,
+            32 => Request::SubscribeLog {
+                min_level: reader.read_u8()?
             },
 
             ty  => return Err(Error::UnknownPacket(ty))
@@ -157,26 +443,100 @@ fn write_exception_string<'a, W>(writer: &mut W, s: &CSlice<'a, u8>) -> Result<(
         writer.write_u32(u32::MAX)?;
         writer.write_u32(s.as_ptr() as u32)?;
     } else {
-        writer.write_string(core::str::from_utf8(s.as_ref()).unwrap())?;
+        writer.write_string(exception_str(s.as_ref()))?;
     }
     Ok(())
 }
 
+// Failing here would turn a kernel exception into a runtime panic.
+fn exception_str(s: &[u8]) -> &str {
+    match str::from_utf8(s) {
+        Ok(s) => s,
+        Err(_err) => {
+            #[cfg(feature = "log")]
+            warn!("exception string is not valid UTF-8: {}", _err);
+            "<invalid UTF-8>"
+        }
+    }
+}
+
+fn sanitize_location(line: u32, column: u32) -> (u32, u32, bool) {
+    let suspect = (line == 0 && column == 0) || line > MAX_LINE || column > MAX_COLUMN;
+    if suspect {
+        (line.min(MAX_LINE), column.min(MAX_COLUMN), true)
+    } else {
+        (line, column, false)
+    }
+}
+
 impl<'a> Reply<'a> {
-    pub fn write_to<W>(&self, writer: &mut W) -> Result<(), IoError<W::WriteError>>
+    pub fn write_to<W>(&self, writer: &mut W, context: &mut Context) -> Result<(), IoError<W::WriteError>>
         where W: Write + ?Sized
     {
         write_sync(writer)?;
+        if context.sequenced() {
+            context.reply_seq = context.reply_seq.wrapping_add(1);
+            writer.write_u32(context.reply_seq)?;
+            writer.write_u32(context.request_seq.unwrap_or(0))?;
+        }
+        self.write_body(writer, context.version)
+    }
+
+    /// Like `write_to`, but never includes sequence numbers, for replies
+    /// that are stored rather than sent right away.
+    pub fn write_unsequenced_to<W>(&self, writer: &mut W, context: &Context) -> Result<(), IoError<W::WriteError>>
+        where W: Write + ?Sized
+    {
+        write_sync(writer)?;
+        self.write_body(writer, context.version)
+    }
+
+    /// Serializes a KernelException reply to be kept for GetLastException,
+    /// unless it is larger than LAST_EXCEPTION_MAX_SIZE.
+    pub fn to_last_exception(&self, context: &Context) -> Option<Vec<u8>> {
+        let mut serialized = Vec::new();
+        match self.write_unsequenced_to(&mut serialized, context) {
+            Ok(()) if serialized.len() <= LAST_EXCEPTION_MAX_SIZE => Some(serialized),
+            _ => {
+                warn!("kernel exception too large to be kept for GetLastException");
+                None
+            }
+        }
+    }
+
+    fn write_body<W>(&self, writer: &mut W, version: u32) -> Result<(), IoError<W::WriteError>>
+        where W: Write + ?Sized
+    {
         match *self {
-            Reply::SystemInfo { ident, finished_cleanly } => {
+            Reply::SystemInfo { ident, finished_cleanly, last_run_outcome } => {
                 writer.write_u8(2)?;
                 writer.write(b"AROR")?;
                 writer.write_string(ident)?;
                 writer.write_u8(finished_cleanly as u8)?;
+                if version >= 11 {
+                    writer.write_u8(last_run_outcome as u8)?;
+                }
             },
 
-            Reply::LoadCompleted => {
+            Reply::VersionNegotiated { version: negotiated, features } => {
+                writer.write_u8(22)?;
+                writer.write_u32(negotiated)?;
+                if version >= 7 {
+                    writer.write_u32(features)?;
+                }
+            },
+
+            Reply::LoadCompleted { metadata, evicted } => {
                 writer.write_u8(5)?;
+                if version >= 2 {
+                    writer.write_bytes(metadata)?;
+                }
+                if version >= 12 {
+                    writer.write_u32(evicted.len() as u32)?;
+                    for &id in evicted {
+                        writer.write_u32(id)?;
+                    }
+                }
             },
             Reply::LoadFailed(reason) => {
                 writer.write_u8(6)?;
@@ -192,20 +552,21 @@ impl<'a> Reply<'a> {
             },
             Reply::KernelException {
                 exceptions,
+                source_snippets,
                 stack_pointers,
                 backtrace,
                 async_errors
             } => {
                 writer.write_u8(9)?;
                 writer.write_u32(exceptions.len() as u32)?;
-                for exception in exceptions.iter() {
+                for (index, exception) in exceptions.iter().enumerate() {
                     let exception = exception.as_ref().unwrap();
                     writer.write_u32(exception.id as u32)?;
                     if exception.message.len() == usize::MAX {
                         // exception with host string
                         write_exception_string(writer, &exception.message)?;
                     } else {
-                        let msg = str::from_utf8(unsafe { slice::from_raw_parts(exception.message.as_ptr(), exception.message.len()) }).unwrap()
+                        let msg = exception_str(unsafe { slice::from_raw_parts(exception.message.as_ptr(), exception.message.len()) })
                           .replace("{rtio_channel_info:0}", &format!("0x{:04x}:{}", exception.param[0], resolve_channel_name(exception.param[0] as u32)));
                         write_exception_string(writer, unsafe { &CSlice::new(msg.as_ptr(), msg.len()) })?;
                     }
@@ -213,9 +574,18 @@ impl<'a> Reply<'a> {
                     writer.write_u64(exception.param[1] as u64)?;
                     writer.write_u64(exception.param[2] as u64)?;
                     write_exception_string(writer, &exception.file)?;
-                    writer.write_u32(exception.line)?;
-                    writer.write_u32(exception.column)?;
+                    let (line, column, suspect) = sanitize_location(exception.line, exception.column);
+                    writer.write_u32(line)?;
+                    writer.write_u32(column)?;
+                    if version >= 2 {
+                        writer.write_bool(suspect)?;
+                    }
                     write_exception_string(writer, &exception.function)?;
+                    if version >= 6 {
+                        let snippet = source_snippets.get(index).and_then(|snippet| *snippet);
+                        writer.write_bool(snippet.is_some())?;
+                        writer.write_string(snippet.unwrap_or(""))?;
+                    }
                 }
 
                 for sp in stack_pointers.iter() {
@@ -225,9 +595,14 @@ impl<'a> Reply<'a> {
                 }
 
                 writer.write_u32(backtrace.len() as u32)?;
-                for &(addr, sp) in backtrace {
-                    writer.write_u32(addr as u32)?;
-                    writer.write_u32(sp as u32)?;
+                if version >= 10 && backtrace.len() > BACKTRACE_CHUNK_FRAMES {
+                    let mut chunks = backtrace.chunks(BACKTRACE_CHUNK_FRAMES).peekable();
+                    while let Some(chunk) = chunks.next() {
+                        write_backtrace(writer, chunk)?;
+                        writer.write_bool(chunks.peek().is_some())?;
+                    }
+                } else {
+                    write_backtrace(writer, backtrace)?;
                 }
                 writer.write_u8(async_errors)?;
             },
@@ -237,27 +612,1421 @@ impl<'a> Reply<'a> {
                 writer.write_u8(async as u8)?;
             },
 
-            Reply::ClockFailure => {
+            Reply::ClockFailure { lost_at_us } => {
                 writer.write_u8(15)?;
+                if version >= 3 {
+                    writer.write_u64(lost_at_us)?;
+                }
+            },
+
+            Reply::AllRunsComplete { succeeded, failed } => {
+                writer.write_u8(16)?;
+                writer.write_u32(succeeded)?;
+                writer.write_u32(failed)?;
+            },
+            Reply::TaskDump(tasks) => {
+                writer.write_u8(17)?;
+                writer.write_u32(tasks.len() as u32)?;
+                for task in tasks.iter() {
+                    writer.write_string(task.name)?;
+                    writer.write_u8(task.state as u8)?;
+                    writer.write_u32(task.stack_high_water)?;
+                    writer.write_u32(task.stack_size)?;
+                    writer.write_u32(task.run_count)?;
+                    writer.write_u64(task.runtime_us)?;
+                }
+            },
+
+            Reply::SubkernelsStarted { failed } => {
+                writer.write_u8(18)?;
+                writer.write_bytes(failed)?;
+            },
+            Reply::DrtioRoundTrip { round_trip_us } => {
+                writer.write_u8(19)?;
+                writer.write_bool(round_trip_us.is_some())?;
+                writer.write_u64(round_trip_us.unwrap_or(0))?;
+            },
+
+            Reply::SelfKernelResult { passed } => {
+                writer.write_u8(20)?;
+                writer.write_bool(passed)?;
+            },
+
+            Reply::RtioErrorDetail(errors) => {
+                writer.write_u8(23)?;
+                writer.write_u32(errors.len() as u32)?;
+                for &(error, channel) in errors {
+                    writer.write_u8(error)?;
+                    writer.write_u32(channel)?;
+                    with_channel_name(channel, |name| writer.write_string(name))?;
+                }
+            },
+
+            Reply::LastException(exception) => {
+                writer.write_u8(24)?;
+                writer.write_bool(exception.is_some())?;
+                writer.write_bytes(exception.unwrap_or(&[]))?;
+            },
+
+            Reply::DrtioBufferStatus(status) => {
+                writer.write_u8(27)?;
+                writer.write_bool(status.is_some())?;
+                let (queued, capacity) = status.unwrap_or((0, 0));
+                writer.write_u16(queued)?;
+                writer.write_u16(capacity)?;
+            },
+
+            Reply::KernelStateReset(freed) => {
+                writer.write_u8(28)?;
+                writer.write_bool(freed.is_some())?;
+                writer.write_u32(freed.unwrap_or(0))?;
+            },
+
+            Reply::RpcBufferInfo { slot_bytes, max_fragments } => {
+                writer.write_u8(29)?;
+                writer.write_u32(slot_bytes)?;
+                writer.write_u32(max_fragments)?;
+            },
+
+            Reply::RtioReplay(replay) => {
+                writer.write_u8(30)?;
+                writer.write_bool(replay.is_some())?;
+                let (events, truncated) = replay.unwrap_or((&[], false));
+                writer.write_bool(truncated)?;
+                writer.write_u32(events.len() as u32)?;
+                for event in events {
+                    writer.write_u64(event.timestamp as u64)?;
+                    writer.write_u32(event.target)?;
+                    writer.write_u32(event.data)?;
+                }
+            },
+
+            Reply::Pong { uptime_ms } => {
+                writer.write_u8(31)?;
+                writer.write_u32(uptime_ms)?;
+            },
+            Reply::UploadFailed { id, reason } => {
+                writer.write_u8(32)?;
+                writer.write_u32(id)?;
+                writer.write_string(reason)?;
+            },
+            Reply::Log { level, message } => {
+                writer.write_u8(33)?;
+                writer.write_u8(level)?;
+                writer.write_string(message)?;
+            },
+
+            Reply::SessionState { kernel_state, loading } => {
+                writer.write_u8(26)?;
+                writer.write_u8(kernel_state)?;
+                writer.write_bool(loading)?;
+            },
+
+            Reply::StoredAnalyzer { full, capture } => {
+                writer.write_u8(25)?;
+                writer.write_bool(full)?;
+                writer.write_bool(capture.is_some())?;
+                writer.write_bytes(capture.unwrap_or(&[]))?;
+            },
+
+            Reply::LoadProgress { received, total } => {
+                writer.write_u8(21)?;
+                writer.write_u32(received)?;
+                writer.write_u32(total)?;
             },
         }
         Ok(())
     }
 }
 
+fn write_backtrace<W>(writer: &mut W, backtrace: &[(usize, usize)])
+    -> Result<(), IoError<W::WriteError>>
+    where W: Write + ?Sized
+{
+    for &(addr, sp) in backtrace {
+        writer.write_u32(addr as u32)?;
+        writer.write_u32(sp as u32)?;
+    }
+    Ok(())
+}
+
+// The first version accepting a request.
+fn request_version(ty: u8) -> u32 {
+    match ty {
+        3...9 | 20 => 0,
+        26 => 4,
+        _ => 2
+    }
+}
+
+/// Reassembles a kernel sent with LoadKernelChunk. The size declared by the
+/// first chunk is authoritative, and no more than that is ever buffered.
+#[derive(Debug)]
+pub struct ChunkedLoad {
+    total: usize,
+    data: Vec<u8>,
+    progress_interval: usize,
+    next_progress: usize
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChunkStatus {
+    Pending,
+    // LoadProgress is due, with the number of bytes received so far
+    Progress(usize),
+    Complete
+}
+
+impl ChunkedLoad {
+    pub fn new(total: usize, max_size: usize, progress_interval: usize)
+            -> Result<ChunkedLoad, &'static str> {
+        if total > max_size {
+            return Err("kernel larger than kernel CPU memory")
+        }
+        Ok(ChunkedLoad {
+            total: total,
+            data: Vec::new(),
+            progress_interval: progress_interval,
+            next_progress: progress_interval
+        })
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Appends a chunk, unless it would take the kernel past its declared
+    /// size.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<ChunkStatus, &'static str> {
+        if chunk.len() > self.total - self.data.len() {
+            return Err("kernel larger than declared size")
+        }
+        self.data.extend_from_slice(chunk);
+        let received = self.data.len();
+        if received == self.total {
+            // acknowledged by LoadCompleted/LoadFailed instead of LoadProgress
+            Ok(ChunkStatus::Complete)
+        } else if self.progress_interval != 0 && received >= self.next_progress {
+            self.next_progress = (received / self.progress_interval + 1) * self.progress_interval;
+            Ok(ChunkStatus::Progress(received))
+        } else {
+            Ok(ChunkStatus::Pending)
+        }
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Progress of a RunKernelRepeated request, which streams the outcome of
+/// every run and ends with AllRunsComplete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepeatedRun {
+    remaining: u32,
+    stop_on_error: bool,
+    succeeded: u32,
+    failed: u32
+}
+
+impl RepeatedRun {
+    pub fn new(count: u32, stop_on_error: bool) -> RepeatedRun {
+        RepeatedRun { remaining: count, stop_on_error, succeeded: 0, failed: 0 }
+    }
+
+    /// Runs still to be started.
+    pub fn remaining(&self) -> u32 {
+        self.remaining
+    }
+
+    /// Counts a finished run; with `stop_on_error`, a failure abandons the
+    /// runs that remain.
+    pub fn record(&mut self, succeeded: bool) {
+        if self.remaining == 0 {
+            return
+        }
+        if succeeded {
+            self.succeeded += 1
+        } else {
+            self.failed += 1;
+            if self.stop_on_error {
+                self.remaining = 0;
+                return
+            }
+        }
+        self.remaining -= 1
+    }
+
+    pub fn summary(&self) -> Reply<'static> {
+        Reply::AllRunsComplete { succeeded: self.succeeded, failed: self.failed }
+    }
+}
+
+/// The checksum of an UploadSubkernel kernel did not match the one sent by
+/// the host.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub actual: u32,
+    pub expected: u32
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "checksum mismatch: {:08x} instead of {:08x}", self.actual, self.expected)
+    }
+}
+
+/// Checks a subkernel against the CRC32 of UploadSubkernel; hosts older
+/// than version 9 send none, and their subkernels are accepted as they are.
+pub fn check_subkernel_crc32(kernel: &[u8], crc32: Option<u32>) -> Result<(), ChecksumMismatch> {
+    match crc32 {
+        Some(expected) => {
+            let actual = crc::crc32::checksum_ieee(kernel);
+            if actual == expected {
+                Ok(())
+            } else {
+                Err(ChecksumMismatch { actual, expected })
+            }
+        }
+        None => Ok(())
+    }
+}
+
+// FNV-1a, cheap enough to be computed while the image is being copied.
+fn kernel_checksum(kernel: &[u8]) -> u32 {
+    kernel.iter().fold(0x811c9dc5, |checksum, &byte| (checksum ^ byte as u32).wrapping_mul(0x01000193))
+}
+
+/// Lays out the kernel stored in flash by CacheKernel: a checksum, followed
+/// by the kernel itself.
+pub fn cached_kernel_record(kernel: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(4 + kernel.len());
+    record.extend_from_slice(&[0; 4]);
+    NativeEndian::write_u32(&mut record[..4], kernel_checksum(kernel));
+    record.extend_from_slice(kernel);
+    record
+}
+
+/// Extracts the kernel from a record made by `cached_kernel_record`, refusing
+/// it if the flash contents got corrupted.
+pub fn cached_kernel(record: &[u8]) -> Result<&[u8], &'static str> {
+    if record.len() < 4 {
+        return Err("no cached kernel")
+    }
+    let (checksum, kernel) = record.split_at(4);
+    if kernel_checksum(kernel) != NativeEndian::read_u32(checksum) {
+        return Err("kernel checksum mismatch")
+    }
+    Ok(kernel)
+}
+
 pub fn set_device_map(device_map: DeviceMap) {
-    unsafe { RTIO_DEVICE_MAP = Some(device_map); }
+    let mut by_name = BTreeMap::new();
+    // channels are visited in ascending order, so the lowest one is kept
+    for (&channel, name) in device_map.iter() {
+        if let Some(&first) = by_name.get(name) {
+            #[cfg(feature = "log")]
+            warn!("device {} is mapped to channels {} and {}, using {}", name, first, channel, first);
+            #[cfg(not(feature = "log"))]
+            let _ = first;
+        } else {
+            by_name.insert(name.clone(), channel);
+        }
+    }
+    *RTIO_DEVICE_MAP.0.borrow_mut() = Some(DeviceMaps {
+        by_channel: device_map,
+        by_name: by_name
+    });
+}
+
+fn with_device_map<R, F: FnOnce(Option<&DeviceMaps>) -> R>(f: F) -> R {
+    match RTIO_DEVICE_MAP.0.try_borrow() {
+        Ok(device_maps) => f(device_maps.as_ref()),
+        Err(_) => f(None)
+    }
+}
+
+pub fn resolve_channel_number(name: &str) -> Option<u32> {
+    with_device_map(|device_maps| {
+        device_maps.and_then(|device_maps| device_maps.by_name.get(name)).cloned()
+    })
+}
+
+/// Async error types, as flagged by the RTIO core and reported by
+/// RtioErrorDetail.
+pub const ASYNC_ERROR_COLLISION: u8 = 1 << 0;
+pub const ASYNC_ERROR_BUSY: u8 = 1 << 1;
+pub const ASYNC_ERROR_SEQUENCE_ERROR: u8 = 1 << 2;
+
+/// The registers of the RTIO core that report async errors.
+pub trait AsyncErrorRegisters {
+    fn async_error(&mut self) -> u8;
+    /// Clears the flags in `errors`; flags not in it stay set.
+    fn clear_async_error(&mut self, errors: u8);
+    /// The channel involved in the latest error of the given type.
+    fn error_channel(&mut self, error: u8) -> u32;
+}
+
+/// Reads and clears the flagged async errors, calling `f` with the type and
+/// channel of each, and returns the flags. Only the flags that were read
+/// are cleared, so an error flagged in the meantime is kept for next time.
+pub fn take_async_errors<R, F>(registers: &mut R, mut f: F) -> u8
+    where R: AsyncErrorRegisters, F: FnMut(u8, u32)
+{
+    let errors = registers.async_error();
+    for &error in [ASYNC_ERROR_COLLISION, ASYNC_ERROR_BUSY, ASYNC_ERROR_SEQUENCE_ERROR].iter() {
+        if errors & error != 0 {
+            f(error, registers.error_channel(error))
+        }
+    }
+    registers.clear_async_error(errors);
+    errors
+}
+
+const SOURCE_SNIPPET_MAX_LENGTH: usize = 256;
+
+/// Looks up the text of `line` of `file` in the source section of a kernel,
+/// made of entries of a u32 line followed by the NUL-terminated file and
+/// text. The text is cut to SOURCE_SNIPPET_MAX_LENGTH bytes.
+pub fn find_source_snippet<'a>(mut entries: &'a [u8], file: &[u8], line: u32) -> Option<&'a str> {
+    while entries.len() >= 4 {
+        let entry_line = NativeEndian::read_u32(entries);
+        let mut fields = entries[4..].splitn(3, |&byte| byte == 0);
+        let (entry_file, text) = match (fields.next(), fields.next()) {
+            (Some(entry_file), Some(text)) => (entry_file, text),
+            _ => break
+        };
+        entries = fields.next().unwrap_or(&[]);
+        if entry_line == line && entry_file == file {
+            let text = &text[..text.len().min(SOURCE_SNIPPET_MAX_LENGTH)];
+            // truncation may have split a character
+            return match str::from_utf8(text) {
+                Ok(text) => Some(text),
+                Err(error) => str::from_utf8(&text[..error.valid_up_to()]).ok()
+            }
+        }
+    }
+    None
+}
+
+// Host string ids index the embedding map of the host, which never gets
+// anywhere near this large; u32::MAX is the wire marker for such ids.
+const MAX_HOST_STRING_ID: u32 = 0x0100_0000;
+
+/// The most verbose level forwarded for `SubscribeLog { min_level }`, or
+/// None if the host unsubscribes. Levels past trace mean trace.
+#[cfg(feature = "log")]
+pub fn log_subscription(min_level: u8) -> Option<log::LevelFilter> {
+    use log::LevelFilter;
+    match min_level {
+        0 => None,
+        1 => Some(LevelFilter::Error),
+        2 => Some(LevelFilter::Warn),
+        3 => Some(LevelFilter::Info),
+        4 => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace)
+    }
+}
+
+/// The string of an RpcException, referenced by its id in the embedding map
+/// of the host, or "<unknown>" for an id that cannot be one.
+pub fn host_string(id: u32) -> CSlice<'static, u8> {
+    if id < MAX_HOST_STRING_ID {
+        unsafe { CSlice::new(id as *const u8, usize::MAX) }
+    } else {
+        warn!("RPC exception refers to invalid host string {:#x}", id);
+        "<unknown>".as_bytes().as_c_slice()
+    }
+}
+
+/// The state of the kernel of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelState {
+    Absent,
+    Loaded,
+    Running,
+    RpcWait
+}
+
+impl KernelState {
+    /// As reported by SessionState.
+    pub fn code(self) -> u8 {
+        match self {
+            KernelState::Absent  => 0,
+            KernelState::Loaded  => 1,
+            KernelState::Running => 2,
+            KernelState::RpcWait => 3
+        }
+    }
+
+    pub fn running(self) -> bool {
+        match self {
+            KernelState::Absent  | KernelState::Loaded  => false,
+            KernelState::Running | KernelState::RpcWait => true
+        }
+    }
+
+    /// Moves to `state`, returning the transition to log unless the state
+    /// is unchanged.
+    pub fn transition(&mut self, state: KernelState) -> Option<(KernelState, KernelState)> {
+        if *self == state {
+            None
+        } else {
+            let from = *self;
+            *self = state;
+            Some((from, state))
+        }
+    }
+}
+
+/// The RTIO clock as watched while a kernel runs.
+pub trait ClockLock {
+    fn locked(&mut self) -> bool;
+    /// The time to report a loss of lock at, in microseconds.
+    fn now_us(&mut self) -> u64;
+}
+
+/// Checks the RTIO PLL once per scheduler pass. On loss of lock, returns
+/// the time to report in ClockFailure; the kernel must then be aborted.
+pub fn check_clock_lock<C: ClockLock>(clock: &mut C) -> Result<(), u64> {
+    if clock.locked() {
+        Ok(())
+    } else {
+        Err(clock.now_us())
+    }
+}
+
+/// Combines a destination with a channel number local to it, as reported by
+/// satellites, into the channel number used by kernels and the device map.
+pub fn rtio_channel(destination: u8, local_channel: u16) -> u32 {
+    (destination as u32) << 16 | local_channel as u32
 }
 
-fn _resolve_channel_name(channel: u32, device_map: &Option<DeviceMap>) -> String {
-    if let Some(dev_map) = device_map {
-        match dev_map.get(&channel) {
-            Some(val) => val.clone(),
-            None => String::from("unknown")
+/// Calls `f` with the name of `channel`, or "unknown", borrowed from the
+/// device map instead of copied out of it.
+pub fn with_channel_name<R, F: FnOnce(&str) -> R>(channel: u32, f: F) -> R {
+    with_device_map(|device_maps| {
+        match device_maps.and_then(|device_maps| device_maps.by_channel.get(&channel)) {
+            Some(name) => f(name),
+            None => f("unknown")
         }
-    } else { String::from("unknown") }
+    })
 }
 
 pub fn resolve_channel_name(channel: u32) -> String {
-    _resolve_channel_name(channel, unsafe{&RTIO_DEVICE_MAP})
+    with_channel_name(channel, |name| String::from(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use byteorder::{ByteOrder, NativeEndian};
+    use io::Cursor;
+
+    fn u16(value: u16) -> Vec<u8> {
+        let mut bytes = vec![0; 2];
+        NativeEndian::write_u16(&mut bytes, value);
+        bytes
+    }
+
+    fn u32(value: u32) -> Vec<u8> {
+        let mut bytes = vec![0; 4];
+        NativeEndian::write_u32(&mut bytes, value);
+        bytes
+    }
+
+    fn u64(value: u64) -> Vec<u8> {
+        let mut bytes = vec![0; 8];
+        NativeEndian::write_u64(&mut bytes, value);
+        bytes
+    }
+
+    fn request(parts: &[&[u8]], context: &mut Context)
+            -> Result<Request, Error<<Cursor<Vec<u8>> as Read>::ReadError>> {
+        let mut packet = vec![0x5a; 4];
+        packet.extend(parts.concat());
+        Request::read_from(&mut Cursor::new(packet), context)
+    }
+
+    fn reply(reply: Reply, context: &mut Context) -> Vec<u8> {
+        let mut packet = Vec::new();
+        reply.write_to(&mut packet, context).unwrap();
+        assert_eq!(&packet[..4], &[0x5a; 4]);
+        packet.split_off(4)
+    }
+
+    #[test]
+    fn old_style_first_packet() {
+        let mut context = Context::new();
+        match request(&[&[3]], &mut context) {
+            Ok(Request::SystemInfo) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        let info = reply(Reply::SystemInfo {
+            ident: "id",
+            finished_cleanly: true,
+            last_run_outcome: LastRunOutcome::Panicked
+        }, &mut context);
+        assert_eq!(info, [&[2][..], b"AROR", &u32(2), b"id", &[1]].concat());
+    }
+
+    #[test]
+    fn newer_requests_need_negotiation() {
+        let mut context = Context::new();
+        match request(&[&[31]], &mut context) {
+            Err(Error::UnknownPacket(31)) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        match request(&[&[20], &u32(PROTOCOL_VERSION)], &mut context) {
+            Ok(Request::NegotiateVersion { host_version: PROTOCOL_VERSION }) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        assert_eq!(context.negotiate(PROTOCOL_VERSION), PROTOCOL_VERSION);
+        match request(&[&u32(1), &[31]], &mut context) {
+            Ok(Request::Ping) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn lower_version_omits_fields() {
+        let mut context = Context::new();
+        context.negotiate(1);
+        assert_eq!(reply(Reply::ClockFailure { lost_at_us: 5 }, &mut context), [15]);
+        assert_eq!(reply(Reply::LoadCompleted { metadata: b"m", evicted: &[3] }, &mut context), [5]);
+        assert_eq!(reply(Reply::VersionNegotiated { version: 2, features: 1 }, &mut context),
+                   [&[22][..], &u32(2)].concat());
+
+        context.negotiate(2);
+        assert_eq!(reply(Reply::ClockFailure { lost_at_us: 5 }, &mut context), [15]);
+        assert_eq!(reply(Reply::LoadCompleted { metadata: b"m", evicted: &[3] }, &mut context),
+                   [&[5][..], &u32(1), b"m"].concat());
+    }
+
+    #[test]
+    fn current_version_includes_fields() {
+        let mut context = Context::new();
+        context.negotiate(PROTOCOL_VERSION);
+        // reply and request sequence numbers come first
+        assert_eq!(reply(Reply::ClockFailure { lost_at_us: 5 }, &mut context),
+                   [&u32(1)[..], &u32(0), &[15], &u64(5)].concat());
+        assert_eq!(reply(Reply::VersionNegotiated { version: PROTOCOL_VERSION, features: 1 }, &mut context),
+                   [&u32(2)[..], &u32(0), &[22], &u32(PROTOCOL_VERSION), &u32(1)].concat());
+        assert_eq!(reply(Reply::LoadCompleted { metadata: b"m", evicted: &[3, 4] }, &mut context),
+                   [&u32(3)[..], &u32(0), &[5], &u32(1), b"m", &u32(2), &u32(3), &u32(4)].concat());
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        let mut context = Context::new();
+        context.negotiate(5);
+        request(&[&u32(7), &[3]], &mut context).unwrap();
+        assert_eq!(reply(Reply::KernelStartupFailed, &mut context), [&u32(1)[..], &u32(7), &[8]].concat());
+        context.end_request();
+        assert_eq!(reply(Reply::KernelStartupFailed, &mut context), [&u32(2)[..], &u32(0), &[8]].concat());
+
+        // every connection has its own version and numbering
+        let mut other = Context::new();
+        assert_eq!(other.version(), DEFAULT_VERSION);
+        assert_eq!(reply(Reply::KernelStartupFailed, &mut other), [8]);
+        context.negotiate(5);
+        assert_eq!(&reply(Reply::KernelStartupFailed, &mut context)[..4], &u32(1)[..]);
+    }
+
+    #[test]
+    fn chunked_load_limits() {
+        assert!(ChunkedLoad::new(17, 16, 0).is_err());
+        let mut load = ChunkedLoad::new(8, 16, 0).unwrap();
+        assert_eq!(load.push(&[1; 6]), Ok(ChunkStatus::Pending));
+        // an oversized chunk is refused before it is buffered
+        assert!(load.push(&[2; 3]).is_err());
+        assert_eq!(load.push(&[3; 2]), Ok(ChunkStatus::Complete));
+        assert_eq!(load.into_data(), [&[1; 6][..], &[3; 2][..]].concat());
+    }
+
+    #[test]
+    fn chunked_load_progress() {
+        let mut load = ChunkedLoad::new(10, 16, 4).unwrap();
+        assert_eq!(load.push(&[0; 3]), Ok(ChunkStatus::Pending));
+        assert_eq!(load.push(&[0; 6]), Ok(ChunkStatus::Progress(9)));
+        assert_eq!(load.push(&[0; 1]), Ok(ChunkStatus::Complete));
+    }
+
+    #[test]
+    fn chunked_load_abort_then_reload() {
+        let mut aborted = ChunkedLoad::new(8, 16, 0).unwrap();
+        assert_eq!(aborted.push(&[1; 4]), Ok(ChunkStatus::Pending));
+        // AbortLoad drops the partial image, and the next first chunk
+        // declares a new size
+        drop(aborted);
+        let mut load = ChunkedLoad::new(4, 16, 0).unwrap();
+        assert_eq!(load.push(&[2; 4]), Ok(ChunkStatus::Complete));
+        assert_eq!(load.into_data(), vec![2; 4]);
+    }
+
+    #[test]
+    fn cached_kernel_bit_flips() {
+        let kernel: Vec<u8> = (0..64).collect();
+        let record = cached_kernel_record(&kernel);
+        assert_eq!(cached_kernel(&record), Ok(&kernel[..]));
+        for bit in 0..record.len() * 8 {
+            let mut corrupted = record.clone();
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(cached_kernel(&corrupted), Err("kernel checksum mismatch"));
+        }
+        assert_eq!(cached_kernel(&record[..3]), Err("no cached kernel"));
+        assert_eq!(cached_kernel(&cached_kernel_record(&[])), Ok(&[][..]));
+    }
+
+    // The device map is global, so tests that set one take turns.
+    static DEVICE_MAP_BUSY: AtomicBool = AtomicBool::new(false);
+
+    struct DeviceMapGuard;
+
+    impl Drop for DeviceMapGuard {
+        fn drop(&mut self) {
+            DEVICE_MAP_BUSY.store(false, Ordering::Release)
+        }
+    }
+
+    fn device_map(entries: &[(u32, &str)]) -> DeviceMapGuard {
+        while DEVICE_MAP_BUSY.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            std::thread::yield_now()
+        }
+        set_device_map(entries.iter().map(|&(channel, name)| (channel, String::from(name))).collect());
+        DeviceMapGuard
+    }
+
+    #[test]
+    fn channel_name_borrowed() {
+        let _map = device_map(&[(5, "ttl0")]);
+        // the stored name itself is lent out, not a copy of it
+        let stored = with_channel_name(5, |name| name.as_ptr());
+        assert_eq!(with_channel_name(5, |name| name.as_ptr()), stored);
+        assert_eq!(with_channel_name(5, |name| name == "ttl0"), true);
+        assert_eq!(with_channel_name(6, |name| name == "unknown"), true);
+        assert_eq!(resolve_channel_name(5), "ttl0");
+        // a lookup during an update sees no map, rather than panicking
+        let _update = RTIO_DEVICE_MAP.0.borrow_mut();
+        assert_eq!(resolve_channel_name(5), "unknown");
+    }
+
+    #[test]
+    fn device_map_replaced() {
+        let _map = device_map(&[(5, "ttl0"), (6, "ttl1")]);
+        assert_eq!(resolve_channel_name(5), "ttl0");
+        assert_eq!(resolve_channel_name(7), "unknown");
+        set_device_map(DeviceMap::new());
+        assert_eq!(resolve_channel_name(5), "unknown");
+        // duplicate names resolve to the lowest channel
+        set_device_map([(9, "led"), (3, "led")].iter().map(|&(channel, name)| (channel, String::from(name))).collect());
+        assert_eq!(resolve_channel_name(9), "led");
+        assert_eq!(resolve_channel_number("led"), Some(3));
+    }
+
+    #[test]
+    fn channel_number_by_name() {
+        let _map = device_map(&[(1, "ttl0"), (4, "dds"), (2, "dds")]);
+        assert_eq!(resolve_channel_number("ttl0"), Some(1));
+        assert_eq!(resolve_channel_number("dds"), Some(2));
+        assert_eq!(resolve_channel_number("missing"), None);
+    }
+
+    #[test]
+    fn channel_names_per_destination() {
+        assert_eq!(rtio_channel(0, 5), 5);
+        assert_eq!(rtio_channel(2, 5), 0x2_0005);
+        assert_eq!(rtio_channel(255, 0xffff), 0xff_ffff);
+
+        let _map = device_map(&[(rtio_channel(0, 5), "ttl0"), (rtio_channel(2, 5), "sat_ttl0")]);
+        assert_eq!(resolve_channel_name(rtio_channel(0, 5)), "ttl0");
+        assert_eq!(resolve_channel_name(rtio_channel(2, 5)), "sat_ttl0");
+        assert_eq!(resolve_channel_name(rtio_channel(1, 5)), "unknown");
+        assert_eq!(resolve_channel_number("sat_ttl0"), Some(0x2_0005));
+    }
+
+    fn exception<'a>(file: &'a [u8], line: u32, column: u32) -> Exception<'a> {
+        unsafe {
+            Exception {
+                id: 1,
+                file: CSlice::new(file.as_ptr(), file.len()),
+                line: line,
+                column: column,
+                function: CSlice::new(b"f".as_ptr(), 1),
+                message: CSlice::new(b"m".as_ptr(), 1),
+                param: [0; 3]
+            }
+        }
+    }
+
+    fn kernel_exception(exception: Exception, backtrace: &[(usize, usize)], version: u32) -> Vec<u8> {
+        let mut context = Context::new();
+        context.negotiate(version);
+        reply(Reply::KernelException {
+            exceptions: &[Some(exception)],
+            source_snippets: &[],
+            stack_pointers: &[],
+            backtrace: backtrace,
+            async_errors: 0
+        }, &mut context)
+    }
+
+    #[derive(Debug)]
+    struct DecodedException {
+        file: String,
+        line: u32,
+        column: u32,
+        suspect: Option<bool>,
+        snippet: Option<String>,
+        backtrace: Vec<(u32, u32)>
+    }
+
+    fn decode_exception(packet: &[u8], version: u32) -> DecodedException {
+        let mut reader = Cursor::new(packet);
+        if version >= 5 {
+            reader.read_u32().unwrap();
+            reader.read_u32().unwrap();
+        }
+        assert_eq!(reader.read_u8().unwrap(), 9);
+        assert_eq!(reader.read_u32().unwrap(), 1);
+        assert_eq!(reader.read_u32().unwrap(), 1);
+        assert_eq!(reader.read_string().unwrap(), "m");
+        for _ in 0..3 {
+            reader.read_u64().unwrap();
+        }
+        let file = reader.read_string().unwrap();
+        let line = reader.read_u32().unwrap();
+        let column = reader.read_u32().unwrap();
+        let suspect = if version >= 2 { Some(reader.read_bool().unwrap()) } else { None };
+        assert_eq!(reader.read_string().unwrap(), "f");
+        let snippet = if version >= 6 && reader.read_bool().unwrap() {
+            Some(reader.read_string().unwrap())
+        } else {
+            if version >= 6 {
+                assert_eq!(reader.read_string().unwrap(), "");
+            }
+            None
+        };
+        let frames = reader.read_u32().unwrap() as usize;
+        let chunked = version >= 10 && frames > BACKTRACE_CHUNK_FRAMES;
+        let mut backtrace = Vec::new();
+        while backtrace.len() < frames {
+            let chunk = if chunked { BACKTRACE_CHUNK_FRAMES.min(frames - backtrace.len()) } else { frames };
+            for _ in 0..chunk {
+                backtrace.push((reader.read_u32().unwrap(), reader.read_u32().unwrap()));
+            }
+            if chunked {
+                assert_eq!(reader.read_bool().unwrap(), backtrace.len() < frames);
+            }
+        }
+        assert_eq!(reader.read_u8().unwrap(), 0);
+        assert_eq!(reader.position(), packet.len());
+        DecodedException { file: file, line: line, column: column, suspect: suspect, snippet: snippet,
+                           backtrace: backtrace }
+    }
+
+    #[test]
+    fn exception_location_clamped() {
+        assert_eq!(sanitize_location(12, 4), (12, 4, false));
+        assert_eq!(sanitize_location(0, 0), (0, 0, true));
+        assert_eq!(sanitize_location(0, 7), (0, 7, false));
+        assert_eq!(sanitize_location(u32::MAX, 3), (MAX_LINE, 3, true));
+        assert_eq!(sanitize_location(3, u32::MAX), (3, MAX_COLUMN, true));
+
+        let decoded = decode_exception(&kernel_exception(exception(b"k.py", u32::MAX, u32::MAX), &[], 2), 2);
+        assert_eq!((decoded.line, decoded.column, decoded.suspect), (MAX_LINE, MAX_COLUMN, Some(true)));
+        let decoded = decode_exception(&kernel_exception(exception(b"k.py", 10, 2), &[], 2), 2);
+        assert_eq!((decoded.line, decoded.column, decoded.suspect), (10, 2, Some(false)));
+        assert_eq!(decoded.file, "k.py");
+        assert!(decoded.backtrace.is_empty());
+        // older hosts get the clamped values, without the flag
+        let decoded = decode_exception(&kernel_exception(exception(b"k.py", u32::MAX, 2), &[], 1), 1);
+        assert_eq!((decoded.line, decoded.column, decoded.suspect), (MAX_LINE, 2, None));
+    }
+
+    #[test]
+    fn exception_invalid_utf8() {
+        assert_eq!(exception_str(b"ok"), "ok");
+        assert_eq!(exception_str(&[b'a', 0xff, 0xfe]), "<invalid UTF-8>");
+        let decoded = decode_exception(&kernel_exception(exception(&[0xc3, 0x28], 1, 1), &[], 2), 2);
+        assert_eq!(decoded.file, "<invalid UTF-8>");
+    }
+
+    #[test]
+    fn deep_backtrace_chunked() {
+        let backtrace: Vec<(usize, usize)> = (0..5000).map(|frame| (0x4000_0000 + frame * 4, 0x4fff_0000 - frame * 16)).collect();
+        let expected: Vec<(u32, u32)> = backtrace.iter().map(|&(addr, sp)| (addr as u32, sp as u32)).collect();
+        for &version in [9, 10, PROTOCOL_VERSION].iter() {
+            let packet = kernel_exception(exception(b"k.py", 1, 1), &backtrace, version);
+            assert_eq!(decode_exception(&packet, version).backtrace, expected);
+        }
+        // one flag per chunk, all but the last one set
+        let single = kernel_exception(exception(b"k.py", 1, 1), &backtrace, 9);
+        let chunked = kernel_exception(exception(b"k.py", 1, 1), &backtrace, 10);
+        let chunks = (5000 + BACKTRACE_CHUNK_FRAMES - 1) / BACKTRACE_CHUNK_FRAMES;
+        assert_eq!(chunked.len(), single.len() + chunks);
+
+        // short backtraces keep the single-shot encoding
+        let short = &backtrace[..BACKTRACE_CHUNK_FRAMES];
+        assert_eq!(decode_exception(&kernel_exception(exception(b"k.py", 1, 1), short, 10), 10).backtrace,
+                   &expected[..BACKTRACE_CHUNK_FRAMES]);
+    }
+
+    #[test]
+    fn ping_pong() {
+        let mut context = Context::new();
+        context.negotiate(4);
+        match request(&[&[31]], &mut context) {
+            Ok(Request::Ping) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        assert_eq!(reply(Reply::Pong { uptime_ms: 0x1234_5678 }, &mut context),
+                   [&[31][..], &u32(0x1234_5678)].concat());
+    }
+
+    #[test]
+    fn log_subscription_state() {
+        use log::{Level, LevelFilter};
+        let mut context = Context::new();
+        context.negotiate(4);
+        let min_level = match request(&[&[32, 3]], &mut context) {
+            Ok(Request::SubscribeLog { min_level }) => min_level,
+            other => panic!("unexpected {:?}", other)
+        };
+        assert_eq!(log_subscription(min_level), Some(LevelFilter::Info));
+        assert_eq!(log_subscription(Level::Error as u8), Some(LevelFilter::Error));
+        assert_eq!(log_subscription(Level::Trace as u8), Some(LevelFilter::Trace));
+        assert_eq!(log_subscription(200), Some(LevelFilter::Trace));
+        assert_eq!(log_subscription(0), None);
+
+        assert_eq!(reply(Reply::Log { level: Level::Warn as u8, message: "link down" }, &mut context),
+                   [&[33, 2][..], &u32(9), b"link down"].concat());
+    }
+
+    fn raw_request(packet: Vec<u8>) -> Result<Request, Error<<Cursor<Vec<u8>> as Read>::ReadError>> {
+        Request::read_from(&mut Cursor::new(packet), &mut Context::new())
+    }
+
+    #[test]
+    fn sync_not_found() {
+        match raw_request(vec![0; 8192]) {
+            Err(Error::SyncNotFound) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        // a partial marker restarts the scan rather than counting towards it
+        let mut packet = vec![0x5a, 0x5a, 0x5a, 0];
+        packet.extend_from_slice(&[0x17; 1000]);
+        packet.extend_from_slice(&[0x5a, 0x5a, 0x5a, 0x5a, 3]);
+        match raw_request(packet) {
+            Ok(Request::SystemInfo) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        let mut packet = vec![0; MAX_SYNC_SCAN - 4];
+        packet.extend_from_slice(&[0x5a, 0x5a, 0x5a, 0x5a, 3]);
+        match raw_request(packet) {
+            Ok(Request::SystemInfo) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        let mut packet = vec![0; MAX_SYNC_SCAN - 3];
+        packet.extend_from_slice(&[0x5a, 0x5a, 0x5a, 0x5a, 3]);
+        match raw_request(packet) {
+            Err(Error::SyncNotFound) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+
+    #[test]
+    fn system_info_outcome() {
+        let outcomes = [
+            LastRunOutcome::Clean, LastRunOutcome::Panicked, LastRunOutcome::ClockFailure,
+            LastRunOutcome::WatchdogReset, LastRunOutcome::PowerLoss
+        ];
+        for (code, &outcome) in outcomes.iter().enumerate() {
+            for &version in &[10, 11] {
+                let mut context = Context::new();
+                context.negotiate(version);
+                let info = reply(Reply::SystemInfo {
+                    ident: "id",
+                    finished_cleanly: outcome == LastRunOutcome::Clean,
+                    last_run_outcome: outcome
+                }, &mut context);
+                let mut expected = [&[2][..], b"AROR", &u32(2), b"id",
+                                    &[(outcome == LastRunOutcome::Clean) as u8]].concat();
+                if version >= 11 {
+                    expected.push(code as u8);
+                }
+                // skip the reply and request sequence numbers
+                assert_eq!(info[8..], expected[..]);
+            }
+        }
+    }
+
+
+    #[test]
+    fn subkernel_crc32_bit_flips() {
+        let mut kernel: Vec<u8> = (0..64).collect();
+        let crc32 = crc::crc32::checksum_ieee(&kernel);
+        assert_eq!(check_subkernel_crc32(&kernel, Some(crc32)), Ok(()));
+        assert_eq!(check_subkernel_crc32(&kernel, None), Ok(()));
+        for bit in 0..kernel.len() * 8 {
+            kernel[bit / 8] ^= 1 << (bit % 8);
+            match check_subkernel_crc32(&kernel, Some(crc32)) {
+                Err(ChecksumMismatch { expected, actual }) => {
+                    assert_eq!(expected, crc32);
+                    assert_ne!(actual, crc32);
+                }
+                other => panic!("bit {} undetected: {:?}", bit, other)
+            }
+            kernel[bit / 8] ^= 1 << (bit % 8);
+        }
+        assert_eq!(format!("{}", ChecksumMismatch { actual: 0x1234, expected: 0xabcdef01 }),
+                   "checksum mismatch: 00001234 instead of abcdef01");
+    }
+
+
+    #[test]
+    fn payload_too_large() {
+        let mut context = Context::new();
+        context.negotiate(4);
+        let oversized: &[(&[u8], usize)] = &[
+            (&[5], MAX_KERNEL_SIZE),
+            (&[7], MAX_RPC_TAG_SIZE),
+            (&[13, 0, 0, 0, 0], MAX_DESTINATIONS)
+        ];
+        for &(head, max) in oversized {
+            match request(&[head, &u32(max as u32 + 1)], &mut context) {
+                Err(Error::PayloadTooLarge { requested, max: limit }) => {
+                    assert_eq!(requested, max + 1);
+                    assert_eq!(limit, max);
+                }
+                other => panic!("unexpected {:?}", other)
+            }
+        }
+        match request(&[&[7], &u32(3), b"n:i"], &mut context) {
+            Ok(Request::RpcReply { tag }) => assert_eq!(tag, b"n:i"),
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Fault { TimedOut, Exhausted, Reset }
+
+    // Hands out `data`, then fails with `fault`, classifying it like the
+    // TCP streams of the runtime do.
+    struct FaultyReader {
+        data: Cursor<Vec<u8>>,
+        fault: Fault
+    }
+
+    impl Read for FaultyReader {
+        type ReadError = Fault;
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Fault> {
+            match self.data.read(buf) {
+                Ok(0) => Err(self.fault),
+                result => Ok(result.unwrap())
+            }
+        }
+
+        fn classify_read_error(&self, error: Fault) -> IoError<Fault> {
+            match error {
+                Fault::TimedOut => IoError::Timeout,
+                Fault::Exhausted => IoError::WouldBlock,
+                error => IoError::Transport(error)
+            }
+        }
+    }
+
+    #[test]
+    fn transport_errors_classified() {
+        // failing in the header, in the sync scan after a whole request, and
+        // in the middle of a frame
+        let load_kernel = [&[0x5a; 4][..], &[5], &u32(16), &[0; 8]].concat();
+        let prefixes: &[(&[u8], usize)] = &[
+            (&[0x5a; 4], 0),
+            (&[0x5a, 0x5a, 0x5a, 0x5a, 3, 0x5a], 1),
+            (&load_kernel, 0)
+        ];
+        for &(prefix, complete) in prefixes {
+            for &fault in &[Fault::TimedOut, Fault::Exhausted, Fault::Reset] {
+                let mut reader = FaultyReader { data: Cursor::new(prefix.to_vec()), fault: fault };
+                let mut context = Context::new();
+                for _ in 0..complete {
+                    Request::read_from(&mut reader, &mut context).unwrap();
+                }
+                match (fault, Request::read_from(&mut reader, &mut context)) {
+                    (Fault::TimedOut, Err(Error::Io(IoError::Timeout))) => (),
+                    (Fault::Exhausted, Err(Error::Io(IoError::WouldBlock))) => (),
+                    (Fault::Reset, Err(Error::Io(IoError::Transport(Fault::Reset)))) => (),
+                    (fault, other) => panic!("{:?} became {:?}", fault, other)
+                }
+            }
+        }
+        match raw_request(vec![0x5a; 4]) {
+            Err(Error::Io(IoError::UnexpectedEof)) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    // Streams the replies of a RunKernelRepeated request whose runs have the
+    // given outcomes, as the session does. KernelStartupFailed stands in for
+    // KernelException, which is more involved to build.
+    fn repeated_replies(count: u32, stop_on_error: bool, outcomes: &[bool]) -> Vec<u8> {
+        let mut context = Context::new();
+        context.negotiate(2);
+        let mut packets = Vec::new();
+        let mut run = match request(&[&[10], &u32(count), &[stop_on_error as u8]], &mut context) {
+            Ok(Request::RunKernelRepeated { count, stop_on_error }) => RepeatedRun::new(count, stop_on_error),
+            other => panic!("unexpected {:?}", other)
+        };
+        let mut outcomes = outcomes.iter();
+        while run.remaining() > 0 {
+            let &succeeded = outcomes.next().expect("more runs than outcomes");
+            if succeeded {
+                packets.extend(reply(Reply::KernelFinished { async_errors: 0 }, &mut context))
+            } else {
+                packets.extend(reply(Reply::KernelStartupFailed, &mut context))
+            }
+            run.record(succeeded);
+        }
+        packets.extend(reply(run.summary(), &mut context));
+        packets
+    }
+
+    #[test]
+    fn repeated_run_replies() {
+        assert_eq!(repeated_replies(3, false, &[true, true, true]),
+                   [&[7, 0, 7, 0, 7, 0, 16][..], &u32(3), &u32(0)].concat());
+        assert_eq!(repeated_replies(0, false, &[]),
+                   [&[16][..], &u32(0), &u32(0)].concat());
+    }
+
+    #[test]
+    fn repeated_run_stop_on_error() {
+        // without the flag, every run is attempted
+        assert_eq!(repeated_replies(4, false, &[true, false, true, false]),
+                   [&[7, 0, 8, 7, 0, 8, 16][..], &u32(2), &u32(2)].concat());
+        // with it, the first failure ends the runs
+        assert_eq!(repeated_replies(4, true, &[true, false, true, false]),
+                   [&[7, 0, 8, 16][..], &u32(1), &u32(1)].concat());
+
+        let mut run = RepeatedRun::new(1, true);
+        run.record(true);
+        run.record(false);
+        assert_eq!(run.remaining(), 0);
+        match run.summary() {
+            Reply::AllRunsComplete { succeeded: 1, failed: 0 } => (),
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn task_dump_serialized() {
+        let tasks = [
+            TaskInfo { name: "session", state: TaskState::Running, stack_high_water: 0x3000,
+                       stack_size: 0x4000, run_count: 12, runtime_us: 1 << 33 },
+            TaskInfo { name: "mgmt", state: TaskState::Ready, stack_high_water: 0x800,
+                       stack_size: 0x2000, run_count: 3, runtime_us: 70 },
+            TaskInfo { name: "moninj", state: TaskState::Sleeping, stack_high_water: 0x400,
+                       stack_size: 0x2000, run_count: 0, runtime_us: 0 },
+            TaskInfo { name: "", state: TaskState::Dead, stack_high_water: 0,
+                       stack_size: 0x1000, run_count: 1, runtime_us: 5 },
+        ];
+        let mut context = Context::new();
+        let packet = reply(Reply::TaskDump(&tasks), &mut context);
+
+        let mut reader = Cursor::new(packet);
+        assert_eq!(reader.read_u8().unwrap(), 17);
+        assert_eq!(reader.read_u32().unwrap(), 4);
+        for (task, state) in tasks.iter().zip(0..) {
+            assert_eq!(reader.read_string().unwrap(), task.name);
+            assert_eq!(reader.read_u8().unwrap(), state);
+            assert_eq!(reader.read_u32().unwrap(), task.stack_high_water);
+            assert_eq!(reader.read_u32().unwrap(), task.stack_size);
+            assert_eq!(reader.read_u32().unwrap(), task.run_count);
+            assert_eq!(reader.read_u64().unwrap(), task.runtime_us);
+        }
+        assert_eq!(reader.position(), reader.get_ref().len());
+    }
+
+    #[test]
+    fn start_subkernel_synced() {
+        let mut context = Context::new();
+        context.negotiate(2);
+        match request(&[&[13], &u32(7), &u32(3), &[1, 2, 5], &u64(0x1234_5678_9abc)], &mut context) {
+            Ok(Request::StartSubkernelSynced { id: 7, ref destinations, at_timestamp: 0x1234_5678_9abc })
+                if destinations[..] == [1, 2, 5] => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        // destinations that could not meet the timestamp are reported back
+        assert_eq!(reply(Reply::SubkernelsStarted { failed: &[5] }, &mut context),
+                   [&[18][..], &u32(1), &[5]].concat());
+        assert_eq!(reply(Reply::SubkernelsStarted { failed: &[] }, &mut context),
+                   [&[18][..], &u32(0)].concat());
+    }
+
+    #[test]
+    fn boot_outcome() {
+        // a requested reboot
+        assert_eq!(LastRunOutcome::at_boot(true, 0), LastRunOutcome::Clean);
+        assert_eq!(LastRunOutcome::at_boot(true, LastRunOutcome::Panicked as u32), LastRunOutcome::Clean);
+        // crashes that recorded why
+        assert_eq!(LastRunOutcome::at_boot(false, LastRunOutcome::Panicked as u32),
+                   LastRunOutcome::Panicked);
+        assert_eq!(LastRunOutcome::at_boot(false, LastRunOutcome::ClockFailure as u32),
+                   LastRunOutcome::ClockFailure);
+        // no record, or one that this firmware never writes
+        assert_eq!(LastRunOutcome::at_boot(false, 0), LastRunOutcome::PowerLoss);
+        assert_eq!(LastRunOutcome::at_boot(false, LastRunOutcome::WatchdogReset as u32),
+                   LastRunOutcome::PowerLoss);
+        assert_eq!(LastRunOutcome::at_boot(false, 0xdead_beef), LastRunOutcome::PowerLoss);
+    }
+
+    // Flags `arriving` right after the flags are read, like an error
+    // happening while the previous ones are handled.
+    struct MockCore {
+        async_error: u8,
+        arriving: u8,
+        // indexed by the bit of the error type
+        channels: [u32; 3]
+    }
+
+    impl AsyncErrorRegisters for MockCore {
+        fn async_error(&mut self) -> u8 {
+            let errors = self.async_error;
+            self.async_error |= self.arriving;
+            errors
+        }
+
+        fn clear_async_error(&mut self, errors: u8) {
+            self.async_error &= !errors
+        }
+
+        fn error_channel(&mut self, error: u8) -> u32 {
+            self.channels[error.trailing_zeros() as usize]
+        }
+    }
+
+    #[test]
+    fn async_errors_read_and_cleared() {
+        let mut core = MockCore {
+            async_error: ASYNC_ERROR_COLLISION | ASYNC_ERROR_SEQUENCE_ERROR,
+            arriving: ASYNC_ERROR_BUSY,
+            channels: [12, 13, 0x1_0005]
+        };
+        let mut seen = Vec::new();
+        assert_eq!(take_async_errors(&mut core, |error, channel| seen.push((error, channel))),
+                   ASYNC_ERROR_COLLISION | ASYNC_ERROR_SEQUENCE_ERROR);
+        assert_eq!(seen, [(ASYNC_ERROR_COLLISION, 12), (ASYNC_ERROR_SEQUENCE_ERROR, 0x1_0005)]);
+        // the busy error flagged during the read is not lost
+        assert_eq!(core.async_error, ASYNC_ERROR_BUSY);
+
+        core.arriving = 0;
+        seen.clear();
+        assert_eq!(take_async_errors(&mut core, |error, channel| seen.push((error, channel))),
+                   ASYNC_ERROR_BUSY);
+        assert_eq!(seen, [(ASYNC_ERROR_BUSY, 13)]);
+        assert_eq!(core.async_error, 0);
+
+        let _guard = device_map(&[(12, "ttl0")]);
+        let mut context = Context::new();
+        assert_eq!(reply(Reply::RtioErrorDetail(&[(ASYNC_ERROR_COLLISION, 12), (ASYNC_ERROR_BUSY, 13)]),
+                         &mut context),
+                   [&[23][..], &u32(2), &[1], &u32(12), &u32(4), b"ttl0",
+                    &[2], &u32(13), &u32(7), b"unknown"].concat());
+    }
+    struct MockClock {
+        now_us: u64,
+        lost_at_us: u64
+    }
+
+    impl ClockLock for MockClock {
+        fn locked(&mut self) -> bool {
+            self.now_us < self.lost_at_us
+        }
+
+        fn now_us(&mut self) -> u64 {
+            self.now_us
+        }
+    }
+
+    #[test]
+    fn clock_lost_mid_run() {
+        let mut context = Context::new();
+        context.negotiate(PROTOCOL_VERSION);
+        let mut clock = MockClock { now_us: 1_000, lost_at_us: 1_250 };
+        // the kernel worker loop, one scheduler pass every 100 us
+        let mut passes = 0;
+        let lost_at_us = loop {
+            match check_clock_lock(&mut clock) {
+                Ok(()) => passes += 1,
+                Err(lost_at_us) => break lost_at_us
+            }
+            clock.now_us += 100;
+        };
+        assert_eq!(passes, 3);
+        assert_eq!(lost_at_us, 1_300);
+        assert_eq!(reply(Reply::ClockFailure { lost_at_us: lost_at_us }, &mut context),
+                   [&u32(1)[..], &u32(0), &[15], &u64(1_300)].concat());
+    }
+    #[test]
+    fn last_exception_round_trip() {
+        let mut context = Context::new();
+        context.negotiate(PROTOCOL_VERSION);
+        let exception = exception(b"kernel.py", 12, 4);
+        let stored = Reply::KernelException {
+            exceptions: &[Some(exception)],
+            source_snippets: &[],
+            stack_pointers: &[],
+            backtrace: &[(0x4000_0100, 0)],
+            async_errors: 0
+        }.to_last_exception(&context).unwrap();
+        // kept without sequence numbers, which belong to the original connection
+        assert_eq!(&stored[..5], &[0x5a, 0x5a, 0x5a, 0x5a, 9]);
+
+        let packet = reply(Reply::LastException(Some(&stored)), &mut context);
+        let mut reader = Cursor::new(&packet[..]);
+        reader.read_u32().unwrap();
+        reader.read_u32().unwrap();
+        assert_eq!(reader.read_u8().unwrap(), 24);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_bytes().unwrap(), stored);
+
+        let packet = reply(Reply::LastException(None), &mut context);
+        assert_eq!(&packet[8..], [&[24, 0][..], &u32(0)].concat());
+    }
+
+    #[test]
+    fn last_exception_bounded() {
+        let context = Context::new();
+        let message = vec![b'x'; LAST_EXCEPTION_MAX_SIZE];
+        let mut exception = exception(b"kernel.py", 12, 4);
+        exception.message = unsafe { CSlice::new(message.as_ptr(), message.len()) };
+        assert!(Reply::KernelException {
+            exceptions: &[Some(exception)],
+            source_snippets: &[],
+            stack_pointers: &[],
+            backtrace: &[],
+            async_errors: 0
+        }.to_last_exception(&context).is_none());
+    }
+    #[test]
+    fn rpc_exception_invalid_string() {
+        let mut context = Context::new();
+        let (id, message, file) = match request(&[&[8], &u32(3), &u32(0xffff_ffff), &u64(0), &u64(0), &u64(0),
+                                                  &u32(0x0200_0000), &u32(7), &u32(1), &u32(42)], &mut context) {
+            Ok(Request::RpcException { id, message, file, .. }) => (id, message, file),
+            other => panic!("unexpected {:?}", other)
+        };
+        assert_eq!(id, 3);
+        assert_eq!(host_string(message).as_ref(), b"<unknown>");
+        assert_eq!(host_string(file).as_ref(), b"<unknown>");
+
+        // a valid id is passed through for the host to resolve
+        let function = host_string(42);
+        assert_eq!((function.as_ptr() as usize, function.len()), (42, usize::MAX));
+    }
+    #[test]
+    fn kernel_state_transitions() {
+        use self::KernelState::*;
+        let mut state = Absent;
+        // load, run with one RPC, finish and run again from the loaded kernel
+        let transitions = [Loaded, Running, RpcWait, Running, Running, Loaded, Running, Absent, Absent]
+            .iter()
+            .filter_map(|&next| state.transition(next))
+            .collect::<Vec<_>>();
+        assert_eq!(transitions, [(Absent, Loaded), (Loaded, Running), (Running, RpcWait),
+                                 (RpcWait, Running), (Running, Loaded), (Loaded, Running),
+                                 (Running, Absent)]);
+
+        let mut context = Context::new();
+        assert_eq!(reply(Reply::SessionState { kernel_state: RpcWait.code(), loading: false }, &mut context),
+                   [26, 3, 0]);
+        assert!(RpcWait.running() && !Loaded.running());
+    }
+    #[test]
+    fn drtio_buffer_status() {
+        let mut context = Context::new();
+        context.negotiate(2);
+        match request(&[&[27, 3]], &mut context) {
+            Ok(Request::DrtioBufferStatus { destination: 3 }) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        let mut context = Context::new();
+        assert_eq!(reply(Reply::DrtioBufferStatus(Some((28, 128))), &mut context),
+                   [&[27, 1][..], &u16(28), &u16(128)].concat());
+        // unreachable or timed out
+        assert_eq!(reply(Reply::DrtioBufferStatus(None), &mut context),
+                   [&[27, 0][..], &u16(0), &u16(0)].concat());
+    }
+    fn source_entry(line: u32, file: &[u8], text: &[u8]) -> Vec<u8> {
+        [&u32(line)[..], file, &[0], text, &[0]].concat()
+    }
+
+    #[test]
+    fn source_snippet_reported() {
+        // a two-byte character straddling the length limit
+        let long = [&[b'#'; SOURCE_SNIPPET_MAX_LENGTH - 1][..], "\u{e9}".as_bytes(), b"!"].concat();
+        let section = [source_entry(3, b"scan.py", b"    delay(1*us)"),
+                       source_entry(4, b"scan.py", b"    self.ttl0.on()"),
+                       source_entry(4, b"lib.py", b"    raise ValueError"),
+                       source_entry(9, b"lib.py", &long)].concat();
+        assert_eq!(find_source_snippet(&section, b"scan.py", 4), Some("    self.ttl0.on()"));
+        assert_eq!(find_source_snippet(&section, b"lib.py", 4), Some("    raise ValueError"));
+        assert_eq!(find_source_snippet(&section, b"scan.py", 5), None);
+        assert_eq!(find_source_snippet(&section, b"other.py", 3), None);
+        assert_eq!(find_source_snippet(&[], b"scan.py", 3), None);
+        // cut to length, without splitting the last character
+        let cut = find_source_snippet(&section, b"lib.py", 9).unwrap();
+        assert_eq!(cut.len(), SOURCE_SNIPPET_MAX_LENGTH - 1);
+        // a truncated section is no more than an absent entry
+        assert_eq!(find_source_snippet(&section[..30], b"scan.py", 4), None);
+
+        let mut context = Context::new();
+        context.negotiate(PROTOCOL_VERSION);
+        let snippet = find_source_snippet(&section, b"scan.py", 4);
+        let packet = reply(Reply::KernelException {
+            exceptions: &[Some(exception(b"scan.py", 4, 9))],
+            source_snippets: &[snippet],
+            stack_pointers: &[],
+            backtrace: &[],
+            async_errors: 0
+        }, &mut context);
+        let decoded = decode_exception(&packet, PROTOCOL_VERSION);
+        assert_eq!((decoded.file.as_str(), decoded.line), ("scan.py", 4));
+        assert_eq!(decoded.snippet.as_ref().map(|s| s.as_str()), Some("    self.ttl0.on()"));
+    }
+    #[test]
+    fn rpc_buffer_info() {
+        let mut context = Context::new();
+        context.negotiate(2);
+        match request(&[&[29]], &mut context) {
+            Ok(Request::RpcBufferInfo) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        assert_eq!(reply(Reply::RpcBufferInfo { slot_bytes: 0xffc, max_fragments: 1 }, &mut context),
+                   [&[29][..], &u32(0xffc), &u32(1)].concat());
+    }
+    #[test]
+    fn rtio_replay_format() {
+        let mut context = Context::new();
+        context.negotiate(2);
+        match request(&[&[30]], &mut context) {
+            Ok(Request::PullRtioReplay) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+        let events = [
+            RtioReplayEvent { timestamp: 1000, target: 0x1200, data: 1 },
+            RtioReplayEvent { timestamp: -8, target: 0x1300, data: 0xffffffff },
+        ];
+        assert_eq!(reply(Reply::RtioReplay(Some((&events, true))), &mut context),
+                   [&[30, 1, 1][..], &u32(2),
+                    &u64(1000), &u32(0x1200), &u32(1),
+                    &u64(-8i64 as u64), &u32(0x1300), &u32(0xffffffff)].concat());
+        assert_eq!(reply(Reply::RtioReplay(None), &mut context),
+                   [&[30, 0, 0][..], &u32(0)].concat());
+    }
 }
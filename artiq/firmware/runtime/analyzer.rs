@@ -1,7 +1,8 @@
-use io::{Write, Error as IoError};
-#[cfg(has_drtio)]
+use core::{cmp, slice};
+use io::{Write, ProtoRead, Error as IoError};
 use alloc::vec::Vec;
-use board_misoc::{csr, cache};
+use byteorder::{ByteOrder, BigEndian};
+use board_misoc::{csr, cache, clock, config, spiflash, mem as board_mem};
 use sched::{Io, Mutex, TcpListener, TcpStream, Error as SchedError};
 use analyzer_proto::*;
 use urc::Urc;
@@ -28,6 +29,7 @@ fn arm() {
         csr::rtio_analyzer::dma_last_address_write(last_addr as u64);
         csr::rtio_analyzer::dma_reset_write(1);
         csr::rtio_analyzer::enable_write(1);
+        STORED_BYTE_COUNT = None;
     }
 }
 
@@ -40,6 +42,163 @@ fn disarm() {
     }
 }
 
+// Continues a capture stopped by `disarm`, without starting over.
+fn resume() {
+    unsafe {
+        csr::rtio_analyzer::enable_write(1);
+    }
+}
+
+// Captures are kept in a dedicated flash region, one capture per sector,
+// described by the `analyzer_to_flash` config entry as `<address>:<sectors>`
+// (e.g. `0xf00000:16`). It is not used for anything else, so the
+// configuration sector is never written to for storing captures.
+// Each stored capture erases a sector, which only lasts some 100k erases;
+// with kernels finishing every second, a region of 16 sectors wears out
+// within a month. Runs that captured nothing new are not stored again.
+struct StoreRegion {
+    address: usize,
+    blocks: usize
+}
+
+// Size of an analyzer message, see comm_analyzer.py.
+const MESSAGE_SIZE: usize = 32;
+
+static mut STORE_FAILED: bool = false;
+// `dma_byte_count` when the last capture was stored.
+static mut STORED_BYTE_COUNT: Option<u64> = None;
+
+fn store_region() -> Option<StoreRegion> {
+    let region = config::read_str("analyzer_to_flash", |r| r.ok().and_then(|region| {
+        let mut parts = region.splitn(2, ':');
+        let address = parts.next().and_then(|address| {
+            let address = address.trim();
+            if address.starts_with("0x") {
+                usize::from_str_radix(&address[2..], 16).ok()
+            } else {
+                address.parse().ok()
+            }
+        });
+        let blocks = parts.next().and_then(|blocks| blocks.trim().parse().ok());
+        match (address, blocks) {
+            (Some(address), Some(blocks)) => Some(StoreRegion { address: address, blocks: blocks }),
+            _ => {
+                warn!("analyzer_to_flash: expected <address>:<sectors>, got {:?}", region);
+                None
+            }
+        }
+    }))?;
+
+    // The region must not overlap the firmware, which ends right after the
+    // length and CRC header at the boot address.
+    let firmware_end = unsafe {
+        board_mem::FLASH_BOOT_ADDRESS + 8 +
+            BigEndian::read_u32(slice::from_raw_parts(board_mem::FLASH_BOOT_ADDRESS as *const u8, 4)) as usize
+    };
+    if region.blocks == 0 || region.address % spiflash::SECTOR_SIZE != 0 ||
+            region.address < firmware_end {
+        warn!("analyzer_to_flash: region at 0x{:08x} must be sector-aligned and after the firmware (0x{:08x})",
+              region.address, firmware_end);
+        return None
+    }
+    Some(region)
+}
+
+fn store_block(region: &StoreRegion, block: usize) -> &'static [u8] {
+    let address = region.address + block * spiflash::SECTOR_SIZE;
+    unsafe { slice::from_raw_parts(address as *const u8, spiflash::SECTOR_SIZE) }
+}
+
+fn store_sequences(region: &StoreRegion) -> Vec<Option<u32>> {
+    (0..region.blocks)
+        .map(|block| read_stored_block(store_block(region, block)).map(|(sequence, _)| sequence))
+        .collect()
+}
+
+/// Whether the last capture could not be stored. Storing is retried for the
+/// next capture, which clears this once it succeeds.
+pub fn store_full() -> bool {
+    unsafe { STORE_FAILED }
+}
+
+/// Stores the tail of the local capture, in the format of an analyzer dump,
+/// evicting the oldest stored capture once all sectors are in use. Nothing
+/// is stored if nothing was captured since the last call.
+pub fn store_capture() {
+    let region = match store_region() {
+        Some(region) => region,
+        None => return
+    };
+
+    // stop the DMA so that the tail is not overwritten while it is copied,
+    // unless the capture is already stopped for sending it to the host
+    let armed = unsafe { csr::rtio_analyzer::enable_read() != 0 };
+    disarm();
+    let (local_data, total_byte_count, overflow_occurred) = unsafe {
+        (&BUFFER.data[..],
+         csr::rtio_analyzer::dma_byte_count_read(),
+         csr::rtio_analyzer::message_encoder_overflow_read() != 0)
+    };
+    if unsafe { STORED_BYTE_COUNT } == Some(total_byte_count) {
+        if armed {
+            resume()
+        }
+        return
+    }
+    let available = cmp::min(total_byte_count, BUFFER_SIZE as u64) as usize;
+    // keep whole messages, so that the tail starts on a message
+    let capacity = spiflash::SECTOR_SIZE - STORED_BLOCK_HEADER_SIZE - HEADER_SIZE;
+    let length = cmp::min(available, capacity - capacity % MESSAGE_SIZE);
+    let end = (total_byte_count % BUFFER_SIZE as u64) as usize;
+
+    let (block, sequence) = next_stored_block(&store_sequences(&region));
+
+    let mut data = vec![0; STORED_BLOCK_HEADER_SIZE];
+    write_stored_block_header(&mut data, sequence, HEADER_SIZE + length);
+    Header {
+        sent_bytes: length as u32,
+        total_byte_count: total_byte_count,
+        overflow_occurred: overflow_occurred,
+        log_channel: csr::CONFIG_RTIO_LOG_CHANNEL as u8,
+        dds_onehot_sel: true,
+        remaining_bytes: 0
+    }.write_to(&mut data).unwrap();
+    if length <= end {
+        data.extend_from_slice(&local_data[end - length..end]);
+    } else {
+        data.extend_from_slice(&local_data[BUFFER_SIZE - (length - end)..]);
+        data.extend_from_slice(&local_data[..end]);
+    }
+    if armed {
+        resume()
+    }
+
+    let stored = store_block(&region, block);
+    unsafe {
+        spiflash::erase_sector(stored.as_ptr() as usize);
+        spiflash::write(stored.as_ptr() as usize, &data);
+        cache::flush_l2_cache();
+    }
+    if &stored[..data.len()] == &data[..] {
+        debug!("stored analyzer capture #{} in sector {} ({} bytes)", sequence, block, data.len());
+        unsafe {
+            STORE_FAILED = false;
+            STORED_BYTE_COUNT = Some(total_byte_count);
+        }
+    } else {
+        error!("cannot store analyzer capture: sector at 0x{:08x} did not verify",
+               stored.as_ptr() as usize);
+        unsafe { STORE_FAILED = true }
+    }
+}
+
+/// Returns a stored capture, 0 being the most recent one.
+pub fn stored_capture(index: u32) -> Option<Vec<u8>> {
+    let region = store_region()?;
+    let block = find_stored_block(&store_sequences(&region), index)?;
+    read_stored_block(store_block(&region, block)).map(|(_, capture)| capture.to_vec())
+}
+
 #[cfg(has_drtio)]
 pub mod remote_analyzer {
     use super::*;
@@ -58,15 +217,16 @@ pub mod remote_analyzer {
             // gets data from satellites and returns consolidated data
             let mut remote_data: Vec<u8> = Vec::new();
             let mut remote_overflow = false;
-            let mut remote_sent_bytes = 0;
-            let mut remote_total_bytes = 0;
+            let mut remote_sent_bytes: u32 = 0;
+            let mut remote_total_bytes: u64 = 0;
 
             let data_vec = drtio::analyzer_query(
                 io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table, up_destinations
             )?;
             for data in data_vec {
-                remote_total_bytes += data.total_byte_count;
-                remote_sent_bytes += data.sent_bytes;
+                // satellites report their own counters, which cannot be trusted to be sane
+                remote_total_bytes = remote_total_bytes.saturating_add(data.total_byte_count);
+                remote_sent_bytes = remote_sent_bytes.saturating_add(data.sent_bytes);
                 remote_overflow |= data.overflow_occurred;
                 remote_data.extend(data.data);
             }
@@ -100,13 +260,18 @@ fn worker(stream: &mut TcpStream, _io: &Io, _aux_mutex: &Mutex,
         _io, _aux_mutex, _ddma_mutex, _subkernel_mutex, _routing_table, _up_destinations);
     #[cfg(has_drtio)]
     let (header, remote_data) = match remote {
-        Ok(remote) => (Header {
-            total_byte_count: local_total_byte_count + remote.total_byte_count,
-            sent_bytes: local_sent_bytes + remote.sent_bytes,
-            overflow_occurred: local_overflow_occurred | remote.overflow_occurred,
-            log_channel: csr::CONFIG_RTIO_LOG_CHANNEL as u8,
-            dds_onehot_sel: true 
-        }, remote.data),
+        Ok(remote) => {
+            let mut header = Header {
+                total_byte_count: local_total_byte_count,
+                sent_bytes: local_sent_bytes,
+                overflow_occurred: local_overflow_occurred,
+                log_channel: csr::CONFIG_RTIO_LOG_CHANNEL as u8,
+                dds_onehot_sel: true,
+                remaining_bytes: 0
+            };
+            header.add_capture(remote.total_byte_count, remote.sent_bytes, remote.overflow_occurred);
+            (header, remote.data)
+        }
         Err(e) => {
             error!("Error getting remote analyzer data: {}", e);
             (Header {
@@ -114,7 +279,8 @@ fn worker(stream: &mut TcpStream, _io: &Io, _aux_mutex: &Mutex,
                 sent_bytes: local_sent_bytes,
                 overflow_occurred: true,
                 log_channel: csr::CONFIG_RTIO_LOG_CHANNEL as u8,
-                dds_onehot_sel: true 
+                dds_onehot_sel: true,
+                remaining_bytes: 0
             },
             Vec::new())
         }
@@ -126,21 +292,82 @@ fn worker(stream: &mut TcpStream, _io: &Io, _aux_mutex: &Mutex,
         sent_bytes: local_sent_bytes,
         overflow_occurred: local_overflow_occurred,
         log_channel: csr::CONFIG_RTIO_LOG_CHANNEL as u8,
-        dds_onehot_sel: true  // kept for backward compatibility of analyzer dumps
+        dds_onehot_sel: true,  // kept for backward compatibility of analyzer dumps
+        remaining_bytes: 0
     };
     debug!("{:?}", header);
+    if !header.is_consistent() {
+        warn!("analyzer accounting inconsistent: {} bytes sent of {} recorded",
+              header.sent_bytes, header.total_byte_count);
+    }
 
+    let local_parts = if wraparound {
+        [&local_data[pointer..], &local_data[..pointer]]
+    } else {
+        [&local_data[..pointer], &[][..]]
+    };
+    #[cfg(has_drtio)]
+    let parts = [local_parts[0], local_parts[1], &remote_data[..]];
+    #[cfg(not(has_drtio))]
+    let parts = local_parts;
+
+    // The capture is sent in installments, each preceded by a header whose
+    // sent_bytes covers only that installment, while total_byte_count still
+    // describes the whole capture. Hosts that asked for installments request
+    // every one with its byte budget, 0 meaning no limit; others get it all.
+    let installments = installments_requested(_io, stream)?;
     stream.write_all("e".as_bytes())?;
-    header.write_to(stream)?;
-    if wraparound {
-        stream.write_all(&local_data[pointer..])?;
-        stream.write_all(&local_data[..pointer])?;
+    let total = parts.iter().map(|part| part.len()).sum::<usize>();
+    let mut offset = 0;
+    loop {
+        let max_bytes = if installments { stream.read_u32()? } else { 0 };
+        let remaining = total - offset;
+        let length = installment_length(total, offset, max_bytes);
+        Header {
+            sent_bytes: length as u32,
+            remaining_bytes: (remaining - length) as u32,
+            ..header
+        }.write_to(stream)?;
+        write_range(stream, &parts, offset, length)?;
+        offset += length;
+        if offset == total {
+            break
+        }
+    }
+
+    Ok(())
+}
+
+// Long enough for the request of a host that sends it right after connecting.
+const INSTALLMENTS_REQUEST_WAIT_MS: u64 = 200;
+
+fn installments_requested(io: &Io, stream: &mut TcpStream) -> Result<bool, IoError<SchedError>> {
+    let timeout = clock::Timeout::after_ms(INSTALLMENTS_REQUEST_WAIT_MS);
+    while !stream.can_recv() && stream.may_recv() && !timeout.expired() {
+        io.sleep(1).map_err(IoError::Transport)?;
+    }
+    if stream.can_recv() {
+        Ok(stream.read_u8()? == REQUEST_INSTALLMENTS)
     } else {
-        stream.write_all(&local_data[..pointer])?;
+        Ok(false)
     }
-    #[cfg(has_drtio)]
-    stream.write_all(&remote_data)?;
+}
 
+fn write_range(stream: &mut TcpStream, parts: &[&[u8]], mut offset: usize, mut length: usize)
+        -> Result<(), IoError<SchedError>> {
+    for part in parts {
+        if offset >= part.len() {
+            offset -= part.len();
+            continue
+        }
+        let chunk = &part[offset..cmp::min(part.len(), offset + length)];
+        stream.write_all(chunk)?;
+        length -= chunk.len();
+        offset = 0;
+        if length == 0 {
+            break
+        }
+    }
     Ok(())
 }
 
@@ -2,4 +2,5 @@ extern crate build_misoc;
 
 fn main() {
     build_misoc::cfg();
+    build_misoc::csr_ranges();
 }
@@ -2,6 +2,7 @@ use board_misoc::csr;
 use core::{ptr, slice};
 use mailbox;
 use rpc_queue;
+use rtio_claims;
 
 use kernel_proto::{KERNELCPU_EXEC_ADDRESS, KERNELCPU_LAST_ADDRESS, KSUPPORT_HEADER_SIZE};
 
@@ -28,6 +29,7 @@ pub unsafe fn start() {
         panic!("failed to load kernel CPU image (ksupport.elf): {}", msg);
     }
 
+    mailbox::clear_abort();
     csr::kernel_cpu::reset_write(0);
 
     rpc_queue::init();
@@ -42,6 +44,8 @@ pub unsafe fn stop() {
     #[cfg(has_kernel_cpu)]
     csr::kernel_cpu::reset_write(1);
 
+    rtio_claims::release_all();
+
     mailbox::acknowledge();
     rpc_queue::init();
 }
@@ -93,8 +97,10 @@ pub fn validate(ptr: usize) -> bool {
 pub mod subkernel {
     use alloc::{vec::Vec, collections::btree_map::BTreeMap};
     use board_artiq::drtio_routing::RoutingTable;
-    use board_misoc::clock;
-    use proto_artiq::{drtioaux_proto::{PayloadStatus, MASTER_PAYLOAD_MAX_SIZE}, rpc_proto as rpc};
+    use board_misoc::{clock, config};
+    use proto_artiq::{drtioaux_proto::{PayloadStatus, MASTER_PAYLOAD_MAX_SIZE, ResidentSubkernel, Eviction, next_eviction,
+                                     UploadBackoff},
+                      rpc_proto as rpc};
     use io::Cursor;
     use rtio_mgt::drtio;
     use sched::{Io, Mutex, Error as SchedError};
@@ -112,6 +118,7 @@ pub mod subkernel {
         Uploaded,
         Running,
         Finished { status: FinishStatus },
+        Evicted,
     }
 
     #[derive(Fail, Debug)]
@@ -128,6 +135,12 @@ pub mod subkernel {
         RpcIoError,
         #[fail(display = "subkernel finished prematurely")]
         SubkernelFinished,
+        #[fail(display = "upload to destination {} failed after {} attempt(s): {}", destination, attempts, cause)]
+        UploadFailed { destination: u8, attempts: u32, #[cause] cause: drtio::Error },
+        #[fail(display = "subkernel {} was evicted from destination {}, it must be uploaded again", id, destination)]
+        Evicted { id: u32, destination: u8 },
+        #[fail(display = "subkernel limits of destination {} exceeded, and no subkernel can be evicted", destination)]
+        NoRoom { destination: u8 },
     }
 
     impl From<drtio::Error> for Error {
@@ -160,7 +173,8 @@ pub mod subkernel {
     struct Subkernel {
         pub destination: u8,
         pub data: Vec<u8>,
-        pub state: SubkernelState
+        pub state: SubkernelState,
+        pub last_started: u64
     }
 
     impl Subkernel {
@@ -168,12 +182,15 @@ pub mod subkernel {
             Subkernel {
                 destination: destination,
                 data: data,
-                state: SubkernelState::NotLoaded
+                state: SubkernelState::NotLoaded,
+                last_started: 0
             }
         }
     }
 
     static mut SUBKERNELS: BTreeMap<u32, Subkernel> = BTreeMap::new();
+    // orders subkernel starts for the eviction policy
+    static mut START_COUNTER: u64 = 0;
 
     pub fn add_subkernel(io: &Io, subkernel_mutex: &Mutex, id: u32, destination: u8, kernel: Vec<u8>) -> Result<(), Error> {
         let _lock = subkernel_mutex.lock(io)?;
@@ -181,31 +198,181 @@ pub mod subkernel {
         Ok(())
     }
 
+    const UPLOAD_RETRY_DELAY_MS: u64 = 10;
+    // a dead destination must not stall the session forever, whatever the retry count
+    const UPLOAD_TIMEOUT_MS: u64 = 10_000;
+
+    fn upload_retries() -> u32 {
+        config::read_str("drtio_upload_retries", |r| r.ok().and_then(|s| s.parse().ok()))
+            .unwrap_or(2)
+    }
+
+    // per destination limits, 0 meaning unlimited
+    fn destination_limits() -> (usize, usize) {
+        let max_count = config::read_str("subkernel_max_count", |r| r.ok().and_then(|s| s.parse().ok()))
+            .unwrap_or(0);
+        let max_bytes = config::read_str("subkernel_max_bytes", |r| r.ok().and_then(|s| s.parse().ok()))
+            .unwrap_or(0);
+        (max_count, max_bytes)
+    }
+
+    fn resident_on(destination: u8, except: u32) -> Vec<ResidentSubkernel> {
+        unsafe { SUBKERNELS.iter() }
+            .filter(|&(&id, subkernel)| id != except && subkernel.destination == destination)
+            .filter(|&(_, subkernel)| match subkernel.state {
+                SubkernelState::NotLoaded | SubkernelState::Evicted => false,
+                _ => true
+            })
+            .map(|(&id, subkernel)| ResidentSubkernel {
+                id: id,
+                size: subkernel.data.len(),
+                last_started: subkernel.last_started,
+                evictable: subkernel.state == SubkernelState::Uploaded
+            })
+            .collect()
+    }
+
+    /// Evicts the least recently started idle subkernels of the destination
+    /// of `id` until uploading it stays within the configured limits, and
+    /// returns the evicted ones.
+    fn make_room(io: &Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &Mutex,
+             routing_table: &RoutingTable, id: u32) -> Result<Vec<u32>, Error> {
+        let mut evicted = Vec::new();
+        let (max_count, max_bytes) = destination_limits();
+        if max_count == 0 && max_bytes == 0 {
+            return Ok(evicted)
+        }
+        let (destination, size) = {
+            let subkernel = unsafe { SUBKERNELS.get(&id).unwrap() };
+            (subkernel.destination, subkernel.data.len())
+        };
+        loop {
+            let victim = match next_eviction(&resident_on(destination, id), size, max_count, max_bytes) {
+                Eviction::Fits => return Ok(evicted),
+                Eviction::Evict(victim) => victim,
+                Eviction::NoVictim => return Err(Error::NoRoom { destination: destination })
+            };
+            drtio::subkernel_remove(io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table,
+                victim, destination)?;
+            warn!("evicted subkernel {} from destination {} to make room for subkernel {}",
+                  victim, destination, id);
+            let subkernel = unsafe { SUBKERNELS.get_mut(&victim).unwrap() };
+            subkernel.state = SubkernelState::Evicted;
+            subkernel.data = Vec::new();
+            evicted.push(victim);
+        }
+    }
+
+    pub struct Uploaded {
+        pub attempts: u32,
+        /// Subkernels evicted from the destination to make room.
+        pub evicted: Vec<u32>
+    }
+
+    /// Uploads the subkernel, retrying with exponential backoff on failure.
+    /// The subkernel mutex is only held during each attempt, so that other
+    /// threads can use it meanwhile.
     pub fn upload(io: &Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &Mutex, 
-             routing_table: &RoutingTable, id: u32) -> Result<(), Error> {
-        let _lock = subkernel_mutex.lock(io)?;
-        let subkernel = unsafe { SUBKERNELS.get_mut(&id).unwrap() };
-        drtio::subkernel_upload(io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table, id, 
-            subkernel.destination, &subkernel.data)?;
-        subkernel.state = SubkernelState::Uploaded; 
-        Ok(()) 
+             routing_table: &RoutingTable, id: u32) -> Result<Uploaded, Error> {
+        let evicted = {
+            let _lock = subkernel_mutex.lock(io)?;
+            make_room(io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table, id)?
+        };
+        let mut backoff = UploadBackoff::new(upload_retries().saturating_add(1), UPLOAD_RETRY_DELAY_MS);
+        let deadline = clock::Timeout::after_ms(UPLOAD_TIMEOUT_MS);
+        let destination = loop {
+            let (destination, error) = {
+                let _lock = subkernel_mutex.lock(io)?;
+                // the session may have dropped it while the lock was released
+                let subkernel = match unsafe { SUBKERNELS.get_mut(&id) } {
+                    Some(subkernel) => subkernel,
+                    None => return Err(Error::IncorrectState)
+                };
+                match drtio::subkernel_upload(io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table, id, 
+                        subkernel.destination, &subkernel.data) {
+                    Ok(()) => {
+                        subkernel.state = SubkernelState::Uploaded;
+                        break subkernel.destination
+                    }
+                    Err(drtio::Error::SchedError(e)) => return Err(Error::SchedError(e)),
+                    Err(e) => (subkernel.destination, e)
+                }
+            };
+            match backoff.failed(deadline.remaining_ms()) {
+                Some(delay) => {
+                    warn!("subkernel {} upload to destination {} failed ({}), retrying in {} ms",
+                          id, destination, error, delay);
+                    io.sleep(delay)?;
+                }
+                None => return Err(Error::UploadFailed {
+                    destination: destination,
+                    attempts: backoff.failures(),
+                    cause: error
+                })
+            }
+        };
+        let attempts = backoff.failures() + 1;
+        if attempts > 1 {
+            info!("subkernel {} uploaded to destination {} after {} attempts",
+                  id, destination, attempts);
+        }
+        Ok(Uploaded { attempts: attempts, evicted: evicted })
     }
 
     pub fn load(io: &Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &Mutex, routing_table: &RoutingTable,
             id: u32, run: bool) -> Result<(), Error> {
         let _lock = subkernel_mutex.lock(io)?;
         let subkernel = unsafe { SUBKERNELS.get_mut(&id).unwrap() };
+        if subkernel.state == SubkernelState::Evicted {
+            return Err(Error::Evicted { id: id, destination: subkernel.destination });
+        }
         if subkernel.state != SubkernelState::Uploaded {
             error!("for id: {} expected Uploaded, got: {:?}", id, subkernel.state);
             return Err(Error::IncorrectState);
         }
-        drtio::subkernel_load(io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table, id, subkernel.destination, run)?;
+        drtio::subkernel_load(io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table, id, subkernel.destination, run, 0)?;
         if run {
             subkernel.state = SubkernelState::Running;
+            unsafe {
+                START_COUNTER += 1;
+                subkernel.last_started = START_COUNTER;
+            }
         }
         Ok(())
     }
 
+    /// Starts subkernel `id` on every destination so that they all begin at
+    /// the same RTIO timestamp, and returns the destinations that could not
+    /// be started. A destination whose timeline is already past `timestamp`
+    /// does not start late; its subkernel finishes with an exception instead.
+    pub fn start_synced(io: &Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &Mutex,
+            routing_table: &RoutingTable, id: u32, destinations: &[u8], timestamp: u64) -> Result<Vec<u8>, Error> {
+        let _lock = subkernel_mutex.lock(io)?;
+        let mut failed = Vec::new();
+        for &destination in destinations {
+            match drtio::subkernel_load(io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table,
+                    id, destination, true, timestamp) {
+                Ok(()) => {
+                    if let Some(subkernel) = unsafe { SUBKERNELS.get_mut(&id) } {
+                        if subkernel.destination == destination {
+                            subkernel.state = SubkernelState::Running;
+                            unsafe {
+                                START_COUNTER += 1;
+                                subkernel.last_started = START_COUNTER;
+                            }
+                        }
+                    }
+                }
+                Err(drtio::Error::SchedError(e)) => return Err(Error::SchedError(e)),
+                Err(e) => {
+                    error!("cannot start subkernel {} on destination {}: {}", id, destination, e);
+                    failed.push(destination);
+                }
+            }
+        }
+        Ok(failed)
+    }
+
     pub fn clear_subkernels(io: &Io, subkernel_mutex: &Mutex) -> Result<(), Error> {
         let _lock = subkernel_mutex.lock(io)?;
         unsafe {
@@ -239,7 +406,8 @@ pub mod subkernel {
         let _lock = subkernel_mutex.lock(io).unwrap();
         let subkernels_iter = unsafe { SUBKERNELS.iter_mut() };
         for (id, subkernel) in subkernels_iter {
-            if subkernel.destination == destination {
+            // evicted subkernels stay gone until the host uploads them again
+            if subkernel.destination == destination && subkernel.state != SubkernelState::Evicted {
                 if up {
                     match drtio::subkernel_upload(io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table, *id, destination, &subkernel.data)
                     {
@@ -289,9 +457,9 @@ pub mod subkernel {
                 }
             }
         }
-        let max_time = clock::get_ms() + timeout as u64;
+        let deadline = clock::Timeout::after_ms(timeout as u64);
         let _res = io.until(|| {
-            if timeout > 0 && clock::get_ms() > max_time {
+            if timeout > 0 && deadline.expired() {
                 return true;
             }
             if subkernel_mutex.test_lock() {
@@ -305,7 +473,7 @@ pub mod subkernel {
                 _ => false
             }
         })?;
-        if timeout > 0 && clock::get_ms() > max_time {
+        if timeout > 0 && deadline.expired() {
             error!("Remote subkernel finish await timed out");
             return Err(Error::Timeout);
         }
@@ -377,9 +545,9 @@ pub mod subkernel {
         }
             is_subkernel
         };
-        let max_time = clock::get_ms() + timeout as u64;
+        let deadline = clock::Timeout::after_ms(timeout as u64);
         let message = io.until_ok(|| {
-            if timeout > 0 && clock::get_ms() > max_time {
+            if timeout > 0 && deadline.expired() {
                 return Ok(None);
             }
             if subkernel_mutex.test_lock() {
@@ -404,7 +572,7 @@ pub mod subkernel {
         match message {
             Ok(Some(message)) => Ok(message),
             Ok(None) => {
-                if clock::get_ms() > max_time {
+                if deadline.expired() {
                     Err(Error::Timeout)
                 } else {
                     let _lock = subkernel_mutex.lock(io)?;
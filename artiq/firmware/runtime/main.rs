@@ -28,6 +28,7 @@ extern crate riscv;
 #[cfg(has_drtio)]
 extern crate tar_no_std;
 
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use core::cell::RefCell;
 use core::convert::TryFrom;
@@ -43,7 +44,7 @@ use board_misoc::net_settings::{Ipv4AddrConfig};
 use board_artiq::drtioaux;
 use board_artiq::drtio_routing;
 use board_artiq::{mailbox, rpc_queue};
-use proto_artiq::{mgmt_proto, moninj_proto, rpc_proto, session_proto, kernel_proto};
+use proto_artiq::{mgmt_proto, moninj_proto, rpc_proto, session_proto, kernel_proto, decompress};
 #[cfg(has_wrpll)]
 use board_artiq::si549;
 #[cfg(has_drtio_eem)]
@@ -60,8 +61,10 @@ mod rtio_mgt;
 
 mod urc;
 mod sched;
+mod sched_policy;
 mod cache;
 mod rtio_dma;
+mod rtio_claims;
 
 mod mgmt;
 mod kernel;
@@ -87,10 +90,21 @@ fn setup_log_levels() {
         Ok(Ok(log_level_filter)) => {
             info!("log level set to {} by `log_level` config key",
                   log_level_filter);
-            log::set_max_level(log_level_filter);
+            logger_artiq::BufferLogger::with(|logger|
+                logger.set_global_log_level(log_level_filter));
         }
         _ => info!("log level set to INFO by default")
     }
+    config::read_str("log_filters", |r| {
+        if let Ok(spec) = r {
+            info!("per-target log levels set to {} by `log_filters` config key", spec);
+            let (filters, rejected) = logger_artiq::parse_target_filters(spec);
+            for entry in rejected {
+                warn!("ignoring malformed log filter {:?}", entry);
+            }
+            logger_artiq::BufferLogger::with(|logger| logger.set_target_filters(filters));
+        }
+    });
     match config::read_str("uart_log_level", |r| r.map(|s| s.parse())) {
         Ok(Ok(uart_log_level_filter)) => {
             info!("UART log level set to {} by `uart_log_level` config key",
@@ -100,15 +114,60 @@ fn setup_log_levels() {
         }
         _ => info!("UART log level set to INFO by default")
     }
+    // `records/window_ms`, e.g. `10/1000`
+    config::read_str("log_rate_limit", |r| {
+        if let Ok(spec) = r {
+            let mut parts = spec.splitn(2, '/').map(|part| part.trim().parse::<u32>());
+            match (parts.next(), parts.next()) {
+                (Some(Ok(records)), Some(Ok(window_ms))) if window_ms > 0 => {
+                    info!("log rate limited to {} records per {} ms per call site by `log_rate_limit` config key",
+                          records, window_ms);
+                    logger_artiq::BufferLogger::with(|logger|
+                        logger.set_rate_limit(Some(logger_artiq::RateLimit { records, window_ms })));
+                }
+                _ => warn!("ignoring malformed `log_rate_limit` {:?}", spec)
+            }
+        }
+    });
+}
+
+// the enlarged buffer is never freed, so keep it to a small part of the heap
+const MAX_LOG_BUFFER_SIZE: usize = 1 << 20;
+
+fn setup_log_buffer() {
+    let size = config::read_str("log_buffer_size", |r| r.ok().and_then(|s| s.parse::<usize>().ok()));
+    let size = match size {
+        Some(size) if size > MAX_LOG_BUFFER_SIZE => {
+            warn!("`log_buffer_size` of {} bytes is too large, using {} bytes", size, MAX_LOG_BUFFER_SIZE);
+            Some(MAX_LOG_BUFFER_SIZE)
+        }
+        size => size
+    };
+    match size {
+        Some(size) if size > unsafe { LOG_BUFFER.len() } => {
+            let buffer: &'static mut [u8] = Box::leak(vec![0; size].into_boxed_slice());
+            match logger_artiq::BufferLogger::with(|logger| logger.replace_buffer(buffer)) {
+                Ok(()) => info!("log buffer enlarged to {} bytes by `log_buffer_size` config key", size),
+                Err(()) => warn!("cannot enlarge log buffer, it is in use")
+            }
+        }
+        Some(size) => warn!("ignoring `log_buffer_size` of {} bytes, the built-in buffer is larger", size),
+        None => ()
+    }
 }
 
 fn startup() {
     clock::init();
+    // keep a successful boot out of the log, but have everything at hand if it fails
+    if config::read_str("quiet_boot", |r| r == Ok("1")) {
+        logger_artiq::BufferLogger::with(|logger| logger.defer());
+    }
     info!("ARTIQ runtime starting...");
     info!("software ident {}", csr::CONFIG_IDENTIFIER_STR);
     info!("gateware ident {}", ident::read(&mut [0; 64]));
 
     setup_log_levels();
+    setup_log_buffer();
     #[cfg(has_i2c)]
     board_misoc::i2c::init().expect("I2C initialization failed");
     #[cfg(all(soc_platform = "kasli", hw_rev = "v2.0"))]
@@ -201,19 +260,19 @@ fn startup() {
     let io = scheduler.io();
 
     if use_dhcp {
-        io.spawn(4096, dhcp::dhcp_thread);
+        io.spawn("dhcp", 4096, dhcp::dhcp_thread);
     }
 
     rtio_mgt::startup(&io, &aux_mutex, &drtio_routing_table, &up_destinations, &ddma_mutex, &subkernel_mutex);
 
-    io.spawn(4096, mgmt::thread);
+    io.spawn("mgmt", 4096, mgmt::thread);
     {
         let aux_mutex = aux_mutex.clone();
         let drtio_routing_table = drtio_routing_table.clone();
         let up_destinations = up_destinations.clone();
         let ddma_mutex = ddma_mutex.clone();
         let subkernel_mutex = subkernel_mutex.clone();
-        io.spawn(32768, move |io| { session::thread(io, &aux_mutex, &drtio_routing_table, &up_destinations, &ddma_mutex, &subkernel_mutex) });
+        io.spawn_with_priority("session", 32768, session::PRIORITY, move |io| { session::thread(io, &aux_mutex, &drtio_routing_table, &up_destinations, &ddma_mutex, &subkernel_mutex) });
     }
     #[cfg(any(has_rtio_moninj, has_drtio))]
     {
@@ -221,7 +280,7 @@ fn startup() {
         let ddma_mutex = ddma_mutex.clone();
         let subkernel_mutex = subkernel_mutex.clone();
         let drtio_routing_table = drtio_routing_table.clone();
-        io.spawn(4096, move |io| { moninj::thread(io, &aux_mutex, &ddma_mutex, &subkernel_mutex, &drtio_routing_table) });
+        io.spawn("moninj", 4096, move |io| { moninj::thread(io, &aux_mutex, &ddma_mutex, &subkernel_mutex, &drtio_routing_table) });
     }
     #[cfg(has_rtio_analyzer)]
     {
@@ -230,16 +289,24 @@ fn startup() {
         let subkernel_mutex = subkernel_mutex.clone();
         let drtio_routing_table = drtio_routing_table.clone();
         let up_destinations = up_destinations.clone();
-        io.spawn(8192, move |io| { analyzer::thread(io, &aux_mutex, &ddma_mutex, &subkernel_mutex, &drtio_routing_table, &up_destinations) });
+        io.spawn("analyzer", 8192, move |io| { analyzer::thread(io, &aux_mutex, &ddma_mutex, &subkernel_mutex, &drtio_routing_table, &up_destinations) });
     }
 
     #[cfg(has_grabber)]
-    io.spawn(4096, grabber_thread);
+    io.spawn("grabber", 4096, grabber_thread);
+
+    let net_poll_budget = config::read_str("net_poll_budget", |r| r.ok().and_then(|s| s.parse().ok()))
+        .unwrap_or(0);
+    if net_poll_budget != 0 {
+        info!("network polled at most {} times per scheduler pass", net_poll_budget);
+    }
+
+    logger_artiq::BufferLogger::with(|logger| logger.discard_deferred());
 
     let mut net_stats = ethmac::EthernetStatistics::new();
     loop {
         scheduler.run();
-        scheduler.run_network();
+        scheduler.run_network(net_poll_budget);
 
         if let Some(_net_stats_diff) = net_stats.update() {
             debug!("ethernet mac:{}", ethmac::EthernetStatistics::new());
@@ -364,11 +431,21 @@ pub fn oom(layout: core::alloc::Layout) -> ! {
 #[no_mangle] // https://github.com/rust-lang/rust/issues/{38281,51647}
 #[panic_handler]
 pub fn panic_impl(info: &core::panic::PanicInfo) -> ! {
+    static mut PANICKING: bool = false;
+    // anything below may panic again, e.g. by reading a corrupted config
+    if unsafe { PANICKING } {
+        println!("panic while handling a panic, halting.");
+        loop {}
+    }
+    unsafe { PANICKING = true }
+
     #[cfg(has_error_led)]
     unsafe {
         csr::error_led::out_write(1);
     }
 
+    logger_artiq::BufferLogger::try_with(|logger| logger.emit_deferred());
+
     if let Some(location) = info.location() {
         print!("panic at {}:{}:{}", location.file(), location.line(), location.column());
     } else {
@@ -380,6 +457,9 @@ pub fn panic_impl(info: &core::panic::PanicInfo) -> ! {
         println!("");
     }
 
+    logger_artiq::BufferLogger::try_with(|logger| logger.write_panic(info));
+    mgmt::record_panic(info);
+
     println!("backtrace for software version {}:", csr::CONFIG_IDENTIFIER_STR);
     let _ = unwind_backtrace::backtrace(|ip| {
         // Backtrace gives us the return address, i.e. the address after jal(r) insn,
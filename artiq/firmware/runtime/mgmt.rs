@@ -1,30 +1,345 @@
+use core::{cmp, fmt};
+use alloc::{vec::Vec, string::String};
 use log::{self, LevelFilter};
 
 use io::{Write, ProtoWrite, Error as IoError};
-use board_misoc::{config, spiflash};
-use logger_artiq::BufferLogger;
+use board_misoc::{cache, clock, config, net_settings, spiflash};
+use logger_artiq::{BufferLogger, LogCursor};
 use mgmt_proto::*;
-use sched::{Io, TcpListener, TcpStream, Error as SchedError};
+use session_proto::LastRunOutcome;
+use sched::{Io, TcpListener, TcpStream, TaskState, Error as SchedError};
+use rtio_clocking;
 
 impl From<SchedError> for Error<SchedError> {
     fn from(value: SchedError) -> Error<SchedError> {
-        Error::Io(IoError::Other(value))
+        Error::Io(value.into_io())
     }
 }
 
+const BOOT_COUNT_KEY: &'static str = "__boot_count";
+const CLEAN_SHUTDOWN_COUNT_KEY: &'static str = "__clean_shutdown_count";
+const PANIC_COUNT_KEY: &'static str = "__panic_count";
+const LAST_CRASH_KEY: &'static str = "__last_crash";
+
+// Counts are folded into the config at most this often, as every write
+// erases the config sector; a loss of power forgets up to this many boots.
+const PERSIST_INTERVAL_BOOTS: u32 = 16;
+
+fn read_counter(key: &str) -> u32 {
+    config::read_str(key, |r| r.ok().and_then(|s| s.parse().ok())).unwrap_or(0)
+}
+
+fn add_to_counter(key: &str, count: u32) {
+    if count == 0 {
+        return
+    }
+    if let Err(err) = config::write_int(key, read_counter(key).wrapping_add(count)) {
+        warn!("cannot update {}: {}", key, err)
+    }
+}
+
+// What the stability report needs from one run to the next, kept where
+// neither the boot nor the panic handler has to erase the config sector for
+// it. The `.retained` section (see firmware.ld) is neither cleared at
+// startup nor touched by the bootloader, so the record survives a warm
+// reset; after power-up its contents are garbage, which the magic and
+// checksum reject.
+#[repr(C)]
+struct RetainedRecord {
+    magic: u32,
+    // set right before a requested reboot, cleared at boot
+    clean: u32,
+    outcome: u32,
+    // counted since last folded into the config
+    boots: u32,
+    clean_shutdowns: u32,
+    panics: u32,
+    // a crash not yet stored in the config
+    crash_len: u32,
+    crash: [u8; 128],
+    checksum: u32
+}
+
+const RETAINED_MAGIC: u32 = 0x52455441; // "RETA"
+
+#[link_section = ".retained"]
+static mut RETAINED: RetainedRecord = RetainedRecord {
+    magic: 0, clean: 0, outcome: 0, boots: 0, clean_shutdowns: 0, panics: 0,
+    crash_len: 0, crash: [0; 128], checksum: 0
+};
+
+impl RetainedRecord {
+    // FNV-1a over everything but the checksum itself
+    fn compute_checksum(&self) -> u32 {
+        let mut hash = 0x811c9dc5u32;
+        {
+            let mut feed = |bytes: &[u8]| for &byte in bytes {
+                hash = (hash ^ byte as u32).wrapping_mul(0x01000193)
+            };
+            for &word in [self.magic, self.clean, self.outcome, self.boots,
+                          self.clean_shutdowns, self.panics, self.crash_len].iter() {
+                feed(&word.to_le_bytes());
+            }
+            feed(&self.crash);
+        }
+        hash
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == RETAINED_MAGIC && self.checksum == self.compute_checksum() &&
+            self.crash_len as usize <= self.crash.len()
+    }
+
+    fn clear(&mut self) {
+        self.clean = 0;
+        self.outcome = 0;
+        self.boots = 0;
+        self.clean_shutdowns = 0;
+        self.panics = 0;
+        self.crash_len = 0;
+    }
+
+    fn seal(&mut self) {
+        self.magic = RETAINED_MAGIC;
+        self.checksum = self.compute_checksum();
+        // the reset that follows does not write back the data cache
+        cache::flush_cpu_dcache();
+        cache::flush_l2_cache();
+    }
+
+    fn crash(&self) -> &[u8] {
+        &self.crash[..self.crash_len as usize]
+    }
+
+    // Folds the pending counts and crash into the config.
+    fn persist(&mut self) {
+        add_to_counter(BOOT_COUNT_KEY, self.boots);
+        add_to_counter(CLEAN_SHUTDOWN_COUNT_KEY, self.clean_shutdowns);
+        add_to_counter(PANIC_COUNT_KEY, self.panics);
+        if self.crash_len > 0 {
+            if let Err(err) = config::write(LAST_CRASH_KEY, self.crash()) {
+                warn!("cannot store crash record: {}", err)
+            }
+        }
+        self.boots = 0;
+        self.clean_shutdowns = 0;
+        self.panics = 0;
+        self.crash_len = 0;
+    }
+}
+
+fn retained() -> &'static mut RetainedRecord {
+    unsafe { &mut RETAINED }
+}
+
+/// Returns how the previous run ended, and clears the markers so that a
+/// crash of this run is detected on next boot. An unclean end that left no
+/// outcome behind is taken to be a loss of power.
+/// Also counts the boot for the stability report. The config is only
+/// written after a crash, to store its record, and every
+/// `PERSIST_INTERVAL_BOOTS` boots.
+pub fn record_boot() -> LastRunOutcome {
+    let retained = retained();
+    if !retained.is_valid() {
+        retained.clear();
+    }
+    let clean = retained.clean != 0;
+    let outcome = LastRunOutcome::at_boot(clean, retained.outcome);
+    retained.clean = 0;
+    retained.outcome = 0;
+
+    retained.boots += 1;
+    if clean {
+        retained.clean_shutdowns += 1;
+    }
+    if outcome == LastRunOutcome::Panicked {
+        retained.panics += 1;
+    }
+    if retained.crash_len > 0 || retained.boots >= PERSIST_INTERVAL_BOOTS {
+        retained.persist();
+    }
+    retained.seal();
+    outcome
+}
+
+/// Marks the coming reset as requested, for the next boot to report.
+fn record_clean_shutdown() {
+    let retained = retained();
+    retained.clean = 1;
+    retained.seal();
+}
+
+/// Records why this run is about to end abnormally, for the next boot to
+/// report.
+pub fn record_outcome(outcome: LastRunOutcome) {
+    let retained = retained();
+    retained.outcome = outcome as u32;
+    retained.seal();
+}
+
+// Formats without allocating, the allocator may be what panicked.
+struct CrashBuffer<'a> {
+    data: &'a mut [u8],
+    len: usize
+}
+
+impl<'a> fmt::Write for CrashBuffer<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let length = cmp::min(s.len(), self.data.len() - self.len);
+        self.data[self.len..self.len + length].copy_from_slice(&s.as_bytes()[..length]);
+        self.len += length;
+        Ok(())
+    }
+}
+
+/// Called from the panic handler. Only fills in the retained record, whose
+/// crash `record_boot` stores on the next boot.
+pub fn record_panic(info: &core::panic::PanicInfo) {
+    let retained = retained();
+    let len = {
+        let mut crash = CrashBuffer { data: &mut retained.crash, len: 0 };
+        let _ = match info.location() {
+            Some(location) => fmt::write(&mut crash, format_args!("panic at {}:{}:{}",
+                                         location.file(), location.line(), location.column())),
+            None => fmt::write(&mut crash, format_args!("panic at unknown location"))
+        };
+        if let Some(message) = info.message() {
+            let _ = fmt::write(&mut crash, format_args!(": {}", message));
+        }
+        crash.len
+    };
+    retained.crash_len = len as u32;
+    retained.outcome = LastRunOutcome::Panicked as u32;
+    retained.seal();
+}
+
+// The counts folded into the config plus those of the retained record.
+struct StoredCounters;
+
+impl StabilityCounters for StoredCounters {
+    fn counts(&mut self) -> [u32; 3] {
+        let retained = retained();
+        [read_counter(BOOT_COUNT_KEY).wrapping_add(retained.boots),
+         read_counter(CLEAN_SHUTDOWN_COUNT_KEY).wrapping_add(retained.clean_shutdowns),
+         read_counter(PANIC_COUNT_KEY).wrapping_add(retained.panics)]
+    }
+
+    fn last_crash(&mut self, buffer: &mut [u8]) -> usize {
+        config::read(LAST_CRASH_KEY, |r| match r {
+            Ok(value) => {
+                let length = cmp::min(value.len(), buffer.len());
+                buffer[..length].copy_from_slice(&value[..length]);
+                length
+            }
+            Err(_) => 0
+        })
+    }
+
+    fn reset(&mut self) {
+        for key in [BOOT_COUNT_KEY, CLEAN_SHUTDOWN_COUNT_KEY, PANIC_COUNT_KEY, LAST_CRASH_KEY].iter() {
+            if let Err(err) = config::remove(key) {
+                warn!("cannot reset {}: {}", key, err)
+            }
+        }
+        let retained = retained();
+        retained.boots = 0;
+        retained.clean_shutdowns = 0;
+        retained.panics = 0;
+        retained.seal();
+    }
+}
+
+/// Collects the state usually asked for in bug reports. Only reads, so
+/// unlike StabilityReport it never resets anything.
+fn diagnostics(io: &Io) -> Vec<(u8, Vec<u8>)> {
+    let mut sections = Vec::new();
+
+    sections.push(diagnostics_section(DIAGNOSTICS_CLOCK, |payload| {
+        payload.write_u64(clock::get_ms())?;
+        payload.write_bool(clock::get_unix_us().is_some())?;
+        payload.write_u64(clock::get_unix_us().unwrap_or(0))?;
+        payload.write_bool(rtio_clocking::crg::check())
+    }));
+
+    sections.push(diagnostics_section(DIAGNOSTICS_NETWORK, |payload| {
+        let mut addresses = String::new();
+        fmt::write(&mut addresses, format_args!("{}", net_settings::get_adresses())).unwrap();
+        payload.write_string(&addresses)
+    }));
+
+    sections.push(diagnostics_section(DIAGNOSTICS_MEMORY, |payload| {
+        let (busy, idle) = unsafe { (::ALLOC.busy(), ::ALLOC.idle()) };
+        payload.write_u32(busy as u32)?;
+        payload.write_u32(idle as u32)
+    }));
+
+    sections.push(diagnostics_section(DIAGNOSTICS_STABILITY, |payload| {
+        stability_report(&mut StoredCounters, false, |report| match report {
+            Reply::StabilityReport { boots, clean_shutdowns, panics, last_crash } => {
+                payload.write_u32(boots)?;
+                payload.write_u32(clean_shutdowns)?;
+                payload.write_u32(panics)?;
+                payload.write_bytes(last_crash)
+            }
+            _ => unreachable!()
+        })
+    }));
+
+    sections.push(diagnostics_section(DIAGNOSTICS_TASKS, |payload| {
+        let tasks = io.tasks();
+        payload.write_u32(tasks.len() as u32)?;
+        for task in tasks.iter() {
+            payload.write_string(task.name)?;
+            payload.write_u8(match task.state {
+                TaskState::Running  => 0,
+                TaskState::Ready    => 1,
+                TaskState::Sleeping => 2,
+                TaskState::Dead     => 3
+            })?;
+            payload.write_u32(task.stack_high_water as u32)?;
+            payload.write_u32(task.stack_size as u32)?;
+            payload.write_u32(task.run_count)?;
+            payload.write_u64(task.runtime_us)?;
+        }
+        Ok(())
+    }));
+
+    sections
+}
+
+// The address and length in bytes of every CSR of the gateware, as (u32, u32).
+include!(concat!(env!("OUT_DIR"), "/csr_ranges.rs"));
+
+fn check_csr_access(addr: u32) -> CsrAccess {
+    let enabled = config::read_str("debug_csr_access", |r| r == Ok("1"));
+    csr_access(enabled, CSR_RANGES, addr)
+}
+
 fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
     read_magic(stream)?;
     Write::write_all(stream, "e".as_bytes())?;
     info!("new connection from {}", stream.remote_endpoint());
 
+    // GetLogRecords only returns what this connection has not seen yet
+    let mut log_cursor = LogCursor::default();
     loop {
         match Request::read_from(stream)? {
             Request::GetLog => {
                 BufferLogger::with(|logger| {
                     let mut buffer = io.until_ok(|| logger.buffer())?;
-                    Reply::LogContent(buffer.extract()).write_to(stream)
+                    Reply::LogContent(&buffer.extract_marked()).write_to(stream)
                 })?;
             }
+            Request::GetLogRecords => {
+                let mut records = Vec::new();
+                BufferLogger::with(|logger| -> Result<(), Error<SchedError>> {
+                    let mut buffer = io.until_ok(|| logger.buffer())?;
+                    buffer.read_records(&mut log_cursor, |level, timestamp, target, message|
+                        records.push((level as u8, timestamp, String::from(target), String::from(message))));
+                    Ok(())
+                })?;
+                Reply::LogRecords(&records).write_to(stream)?;
+            }
             Request::ClearLog => {
                 BufferLogger::with(|logger| -> Result<(), Error<SchedError>> {
                     let mut buffer = io.until_ok(|| logger.buffer())?;
@@ -43,6 +358,12 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
                         let mut buffer = io.until_ok(|| logger.buffer())?;
                         if buffer.is_empty() { continue }
 
+                        let dropped = buffer.dropped_lines();
+                        if dropped > 0 {
+                            let mut marker = String::new();
+                            fmt::write(&mut marker, format_args!("... {} lines dropped ...\n", dropped)).unwrap();
+                            stream.write_string(&marker)?;
+                        }
                         stream.write_string(buffer.extract())?;
 
                         if log_level == LevelFilter::Trace {
@@ -53,7 +374,7 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
                             //
                             // Any messages unrelated to this management socket that arrive
                             // while it is flushed are lost, but such is life.
-                            stream.flush()?;
+                            Write::flush(stream)?;
                         }
 
                         // Clear the log *after* flushing the network buffers, or we're just
@@ -64,7 +385,8 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
             }
             Request::SetLogFilter(level) => {
                 info!("changing log level to {}", level);
-                log::set_max_level(level);
+                BufferLogger::with(|logger|
+                    logger.set_global_log_level(level));
                 Reply::Success.write_to(stream)?;
             }
             Request::SetUartLogFilter(level) => {
@@ -101,18 +423,63 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
                     Err(_) => Reply::Error.write_to(stream)
                 }?;
             }
+            Request::FlashWear => {
+                match config::flash_wear() {
+                    Ok((block, erases)) => {
+                        let blocks = vec![(block as u32, erases)];
+                        Reply::FlashWear(&blocks).write_to(stream)
+                    }
+                    Err(_) => Reply::Error.write_to(stream)
+                }?;
+            }
 
             Request::Reboot => {
                 Reply::RebootImminent.write_to(stream)?;
                 stream.close()?;
-                stream.flush()?;
+                Write::flush(stream)?;
 
                 warn!("restarting");
+                // must be the very last thing done before the reset
+                record_clean_shutdown();
                 unsafe { spiflash::reload(); }
             }
 
             Request::DebugAllocator =>
                 unsafe { println!("{}", ::ALLOC) },
+
+            Request::StabilityReport { reset } =>
+                stability_report(&mut StoredCounters, reset, |report| report.write_to(stream))?,
+
+            Request::Diagnostics =>
+                Reply::Diagnostics(&diagnostics(io)).write_to(stream)?,
+
+            Request::CsrRead { addr } => {
+                match check_csr_access(addr) {
+                    CsrAccess::Disabled => Reply::Unavailable.write_to(stream)?,
+                    CsrAccess::InvalidAddress => {
+                        warn!("refusing to read invalid CSR address {:#010x}", addr);
+                        Reply::Error.write_to(stream)?;
+                    }
+                    CsrAccess::Allowed => {
+                        let value = unsafe { (addr as *const u32).read_volatile() };
+                        Reply::CsrData(value).write_to(stream)?;
+                    }
+                }
+            }
+            Request::CsrWrite { addr, value } => {
+                match check_csr_access(addr) {
+                    CsrAccess::Disabled => Reply::Unavailable.write_to(stream)?,
+                    CsrAccess::InvalidAddress => {
+                        warn!("refusing to write invalid CSR address {:#010x}", addr);
+                        Reply::Error.write_to(stream)?;
+                    }
+                    CsrAccess::Allowed => {
+                        warn!("CSR write {:#010x} <- {:#010x}", addr, value);
+                        unsafe { (addr as *mut u32).write_volatile(value) };
+                        Reply::Success.write_to(stream)?;
+                    }
+                }
+            }
         };
     }
 }
@@ -124,11 +491,11 @@ pub fn thread(io: Io) {
 
     loop {
         let stream = listener.accept().expect("mgmt: cannot accept").into_handle();
-        io.spawn(4096, move |io| {
+        io.spawn("mgmt_conn", 4096, move |io| {
             let mut stream = TcpStream::from_handle(&io, stream);
             match worker(&io, &mut stream) {
                 Ok(()) => (),
-                Err(Error::Io(IoError::UnexpectedEnd)) => (),
+                Err(Error::Io(IoError::UnexpectedEof)) => (),
                 Err(err) => error!("aborted: {}", err)
             }
             stream.close().expect("mgmt: close socket");
@@ -5,8 +5,9 @@ use io::Error as IoError;
 use moninj_proto::*;
 use sched::{Io, Mutex, TcpListener, TcpStream, Error as SchedError};
 use urc::Urc;
-use board_misoc::clock;
+use board_misoc::{clock, config};
 use board_artiq::drtio_routing;
+use rtio_claims;
 
 #[cfg(has_rtio_moninj)]
 mod local_moninj {
@@ -47,6 +48,49 @@ mod local_moninj {
     pub fn read_injection_status(_channel: u16, _overrd: u8) -> u8 { 0 }
 }
 
+const TTL_OVERRIDE_EN: u8 = 0;
+const TTL_OVERRIDE_LEVEL: u8 = 1;
+
+/// Calls `f` with every local channel listed in the `safe_state` config
+/// entry and the level it should be driven to.
+fn for_each_safe_state<F: FnMut(u16, u8)>(mut f: F) {
+    config::read_str("safe_state", |result| {
+        let safe_state = match result {
+            Ok(safe_state) => safe_state,
+            Err(_) => return
+        };
+        for entry in parse_safe_state(safe_state) {
+            match entry {
+                Ok(SafeStateEntry { channel, level }) if channel >> 16 == 0 =>
+                    f(channel as u16, level),
+                Ok(SafeStateEntry { channel, .. }) =>
+                    warn!("safe_state: remote channel 0x{:06x} not supported, skipped", channel),
+                Err(entry) =>
+                    warn!("safe_state: invalid entry {:?}, expected <TTL channel>=<0|1>", entry)
+            }
+        }
+    })
+}
+
+/// Drives the TTL output channels listed in the `safe_state` config entry
+/// (e.g. `0x000005=0,0x000006=1`) to the given level using overrides.
+/// Only local TTL channels are supported, as this has to work without the
+/// scheduler, e.g. when a session is torn down. The overrides stay in place
+/// until `release_safe_state` hands the channels back to the next kernel.
+pub fn apply_safe_state() {
+    for_each_safe_state(|channel, level| {
+        local_moninj::inject(channel, TTL_OVERRIDE_LEVEL, level);
+        local_moninj::inject(channel, TTL_OVERRIDE_EN, 1);
+    })
+}
+
+/// Clears the overrides set by `apply_safe_state`.
+pub fn release_safe_state() {
+    for_each_safe_state(|channel, _level| {
+        local_moninj::inject(channel, TTL_OVERRIDE_EN, 0);
+    })
+}
+
 #[cfg(has_drtio)]
 mod remote_moninj {
     use drtioaux;
@@ -156,8 +200,21 @@ fn connection_worker(io: &Io, _aux_mutex: &Mutex, _ddma_mutex: &Mutex, _subkerne
                         let _ = inject_watch_list.remove(&(channel, overrd));
                     }
                 },
-                HostMessage::Inject { channel, overrd, value } => dispatch!(
-                    io, _aux_mutex, _ddma_mutex, _subkernel_mutex, _routing_table, channel, inject, overrd, value),
+                HostMessage::Inject { channel, overrd, value } => {
+                    // monitoring stays available, but overriding a channel the
+                    // kernel is driving would only produce collisions
+                    if rtio_claims::is_claimed(channel) {
+                        let reply = DeviceMessage::InjectionBusy {
+                            channel: channel,
+                            overrd: overrd
+                        };
+
+                        trace!("moninj->host {:?}", reply);
+                        reply.write_to(stream)?;
+                    } else {
+                        dispatch!(io, _aux_mutex, _ddma_mutex, _subkernel_mutex, _routing_table, channel, inject, overrd, value)
+                    }
+                },
                 HostMessage::GetInjectionStatus { channel, overrd } => {
                     let value = dispatch!(io, _aux_mutex, _ddma_mutex, _subkernel_mutex, _routing_table, channel, read_injection_status, overrd);
                     let reply = DeviceMessage::InjectionStatus {
@@ -208,7 +265,7 @@ fn connection_worker(io: &Io, _aux_mutex: &Mutex, _ddma_mutex: &Mutex, _subkerne
             next_check = clock::get_ms() + 200;
         }
 
-        io.relinquish().map_err(|err| Error::Io(IoError::Other(err)))?;
+        io.relinquish().map_err(|err| Error::Io(IoError::Transport(err)))?;
     }
 }
 
@@ -222,7 +279,7 @@ pub fn thread(io: Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &M
         let subkernel_mutex = subkernel_mutex.clone();
         let routing_table = routing_table.clone();
         let stream = listener.accept().expect("moninj: cannot accept").into_handle();
-        io.spawn(16384, move |io| {
+        io.spawn("moninj_conn", 16384, move |io| {
             let routing_table = routing_table.borrow();
             let mut stream = TcpStream::from_handle(&io, stream);
             match connection_worker(&io, &aux_mutex, &ddma_mutex, &subkernel_mutex, &routing_table, &mut stream) {
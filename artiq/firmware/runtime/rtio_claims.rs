@@ -0,0 +1,24 @@
+// RTIO channels claimed by the running kernel.
+//
+// Moninj may still monitor a claimed channel, but refuses to inject into it.
+// All claims are dropped when the kernel CPU is stopped.
+
+use moninj_proto::ChannelClaims;
+
+static mut CLAIMED: ChannelClaims = ChannelClaims::new();
+
+pub fn claim(channel: u32) {
+    unsafe { CLAIMED.claim(channel) }
+}
+
+pub fn release(channel: u32) {
+    unsafe { CLAIMED.release(channel) }
+}
+
+pub fn release_all() {
+    unsafe { CLAIMED.release_all() }
+}
+
+pub fn is_claimed(channel: u32) -> bool {
+    unsafe { CLAIMED.is_claimed(channel) }
+}
@@ -81,9 +81,9 @@ pub mod remote_dma {
     }
 
     pub fn await_done(io: &Io, ddma_mutex: &Mutex, id: u32, timeout: u64) -> Result<RemoteState, Error> {
-        let max_time = clock::get_ms() + timeout as u64;
+        let timeout = clock::Timeout::after_ms(timeout);
         io.until(|| {
-            if clock::get_ms() > max_time {
+            if timeout.expired() {
                 return true;
             }
             if ddma_mutex.test_lock() {
@@ -100,7 +100,7 @@ pub mod remote_dma {
             }
             true
         })?;
-        if clock::get_ms() > max_time {
+        if timeout.expired() {
             error!("Remote DMA await done timed out");
             return Err(Error::Timeout);
         }
@@ -1,4 +1,5 @@
 use core::cell::RefCell;
+use alloc::vec::Vec;
 use urc::Urc;
 use board_misoc::{csr, config};
 #[cfg(has_drtio)]
@@ -7,22 +8,20 @@ use board_artiq::drtio_routing;
 use sched::Io;
 use sched::Mutex;
 use io::{Cursor, ProtoRead};
-use session_proto::{DeviceMap, resolve_channel_name, set_device_map};
-const ASYNC_ERROR_COLLISION: u8 = 1 << 0;
-const ASYNC_ERROR_BUSY: u8 = 1 << 1;
-const ASYNC_ERROR_SEQUENCE_ERROR: u8 = 1 << 2;
+use session_proto::{DeviceMap, with_channel_name, set_device_map, AsyncErrorRegisters, take_async_errors,
+                    ASYNC_ERROR_COLLISION, ASYNC_ERROR_BUSY, ASYNC_ERROR_SEQUENCE_ERROR};
 
 #[cfg(has_drtio)]
 pub mod drtio {
     use super::*;
-    use alloc::vec::Vec;
     use drtioaux;
-    use proto_artiq::drtioaux_proto::{MASTER_PAYLOAD_MAX_SIZE, PayloadStatus};
+    use proto_artiq::drtioaux_proto::{MASTER_PAYLOAD_MAX_SIZE, PayloadStatus, RoundTrip, buffer_fill};
     use rtio_dma::remote_dma;
     #[cfg(has_rtio_analyzer)]
     use analyzer::remote_analyzer::RemoteBuffer;
     use kernel::subkernel;
     use sched::Error as SchedError;
+    use session_proto::rtio_channel;
 
     #[derive(Fail, Debug)]
     pub enum Error {
@@ -44,8 +43,12 @@ pub mod drtio {
         DmaPlaybackFail(u8),
         #[fail(display = "error adding subkernel on satellite #{}", _0)]
         SubkernelAddFail(u8),
+        #[fail(display = "error removing subkernel on satellite #{}", _0)]
+        SubkernelRemoveFail(u8),
         #[fail(display = "error on subkernel run request on satellite #{}", _0)]
         SubkernelRunFail(u8),
+        #[fail(display = "destination {} is not reached over DRTIO", _0)]
+        NotRemote(u8),
         #[fail(display = "sched error: {}", _0)]
         SchedError(#[cause] SchedError),
     }
@@ -65,7 +68,7 @@ pub mod drtio {
         let up_destinations = up_destinations.clone();
         let ddma_mutex = ddma_mutex.clone();
         let subkernel_mutex = subkernel_mutex.clone();
-        io.spawn(16384, move |io| {
+        io.spawn("drtio_link", 16384, move |io| {
             let routing_table = routing_table.borrow();
             link_thread(io, &aux_mutex, &routing_table, &up_destinations, &ddma_mutex, &subkernel_mutex);
         });
@@ -79,12 +82,12 @@ pub mod drtio {
     }
 
     fn recv_aux_timeout(io: &Io, linkno: u8, timeout: u32) -> Result<drtioaux::Packet, Error> {
-        let max_time = clock::get_ms() + timeout as u64;
+        let timeout = clock::Timeout::after_ms(timeout as u64);
         loop {
             if !link_rx_up(linkno) {
                 return Err(Error::LinkDown);
             }
-            if clock::get_ms() > max_time {
+            if timeout.expired() {
                 return Err(Error::Timeout);
             }
             match drtioaux::recv(linkno) {
@@ -160,6 +163,37 @@ pub mod drtio {
         }
     }
 
+    const PING_SAMPLES: u32 = 4;
+
+    /// Measures the round-trip time of an aux packet to the destination, in
+    /// microseconds of the CPU timer (not RTIO machine units), averaged over
+    /// a few samples. Local and unrouted destinations have no round trip.
+    pub fn round_trip_time(io: &Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &Mutex,
+            routing_table: &drtio_routing::RoutingTable,
+            up_destinations: &Urc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
+            destination: u8) -> Result<u64, Error> {
+        let hop = routing_table.0[destination as usize][0];
+        if hop == 0 || hop == drtio_routing::INVALID_HOP {
+            return Err(Error::NotRemote(destination))
+        }
+        if !up_destinations.borrow()[destination as usize] {
+            return Err(Error::LinkDown)
+        }
+        let linkno = hop - 1;
+        let mut round_trip = RoundTrip::new();
+        for _ in 0..PING_SAMPLES {
+            let sent = clock::get_us();
+            let reply = aux_transact(io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table, linkno,
+                &drtioaux::Packet::PingRequest { destination: destination, timestamp: sent })?;
+            match reply {
+                drtioaux::Packet::PingReply { timestamp } if timestamp == sent =>
+                    round_trip.add(sent, clock::get_us()),
+                packet => return Err(Error::UnexpectedPacket(packet))
+            }
+        }
+        Ok(round_trip.average_us().unwrap())
+    }
+
     fn setup_transact(io: &Io, aux_mutex: &Mutex, linkno: u8, request: &drtioaux::Packet) -> Result<drtioaux::Packet, Error> {
         /* shorter aux_transact for setup purposes, no checking for async packets,
            as they should not be generated yet */
@@ -192,9 +226,9 @@ pub mod drtio {
             match reply {
                 Ok(drtioaux::Packet::EchoReply) => {
                     // make sure receive buffer is drained
-                    let max_time = clock::get_ms() + 200;
+                    let timeout = clock::Timeout::after_ms(200);
                     loop {
-                        if clock::get_ms() > max_time {
+                        if timeout.expired() {
                             return count;
                         }
                         let _ = drtioaux::recv(linkno);
@@ -250,19 +284,53 @@ pub mod drtio {
         Ok(())
     }
 
-    fn init_buffer_space(destination: u8, linkno: u8) {
+    const BUFFER_SPACE_TIMEOUT_MS: u64 = 200;
+
+    // Buffer space of each destination as it came up, i.e. with empty FIFOs.
+    static mut BUFFER_CAPACITY: [u16; drtio_routing::DEST_COUNT] = [0; drtio_routing::DEST_COUNT];
+
+    fn get_buffer_space(destination: u8, linkno: u8) -> Result<u16, Error> {
         let linkno = linkno as usize;
         unsafe {
             (csr::DRTIO[linkno].destination_write)(destination);
             (csr::DRTIO[linkno].force_destination_write)(1);
             (csr::DRTIO[linkno].o_get_buffer_space_write)(1);
-            while (csr::DRTIO[linkno].o_wait_read)() == 1 {}
-            info!("[DEST#{}] buffer space is {}",
-                destination, (csr::DRTIO[linkno].o_dbg_buffer_space_read)());
+            let answered = clock::Timeout::after_ms(BUFFER_SPACE_TIMEOUT_MS)
+                .poll_while(|| (csr::DRTIO[linkno].o_wait_read)() == 1);
+            let space = (csr::DRTIO[linkno].o_dbg_buffer_space_read)();
             (csr::DRTIO[linkno].force_destination_write)(0);
+            if answered { Ok(space) } else { Err(Error::Timeout) }
         }
     }
 
+    fn init_buffer_space(destination: u8, linkno: u8) {
+        match get_buffer_space(destination, linkno) {
+            Ok(space) => {
+                info!("[DEST#{}] buffer space is {}", destination, space);
+                unsafe { BUFFER_CAPACITY[destination as usize] = space }
+            }
+            Err(e) => error!("[DEST#{}] cannot get buffer space ({})", destination, e)
+        }
+    }
+
+    /// Returns how many events wait in the output FIFOs of the destination,
+    /// and how many they hold in total. The query takes over the link for
+    /// a moment, so it must not be made while a kernel is running.
+    pub fn buffer_status(routing_table: &drtio_routing::RoutingTable,
+            up_destinations: &Urc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
+            destination: u8) -> Result<(u16, u16), Error> {
+        let hop = routing_table.0[destination as usize][0];
+        if hop == 0 || hop == drtio_routing::INVALID_HOP {
+            return Err(Error::NotRemote(destination))
+        }
+        if !up_destinations.borrow()[destination as usize] {
+            return Err(Error::LinkDown)
+        }
+        let space = get_buffer_space(destination, hop - 1)?;
+        let capacity = unsafe { BUFFER_CAPACITY[destination as usize] };
+        Ok(buffer_fill(capacity, space))
+    }
+
     fn process_unsolicited_aux(io: &Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &Mutex, 
         routing_table: &drtio_routing::RoutingTable, linkno: u8) {
         let _lock = aux_mutex.lock(io).unwrap();
@@ -350,16 +418,25 @@ pub mod drtio {
                                 }
                                 drtioaux::Packet::DestinationOkReply => (),
                                 drtioaux::Packet::DestinationSequenceErrorReply { channel } => {
-                                    error!("[DEST#{}] RTIO sequence error involving channel 0x{:04x}:{}", destination, channel, resolve_channel_name(channel as u32));
+                                    let channel = rtio_channel(destination, channel);
+                                    with_channel_name(channel, |name|
+                                        error!("[DEST#{}] RTIO sequence error involving channel 0x{:04x}:{}", destination, channel, name));
                                     unsafe { SEEN_ASYNC_ERRORS |= ASYNC_ERROR_SEQUENCE_ERROR };
+                                    record_error_detail(ASYNC_ERROR_SEQUENCE_ERROR, channel);
                                 }
                                 drtioaux::Packet::DestinationCollisionReply { channel } => {
-                                    error!("[DEST#{}] RTIO collision involving channel 0x{:04x}:{}", destination, channel, resolve_channel_name(channel as u32));
+                                    let channel = rtio_channel(destination, channel);
+                                    with_channel_name(channel, |name|
+                                        error!("[DEST#{}] RTIO collision involving channel 0x{:04x}:{}", destination, channel, name));
                                     unsafe { SEEN_ASYNC_ERRORS |= ASYNC_ERROR_COLLISION };
+                                    record_error_detail(ASYNC_ERROR_COLLISION, channel);
                                 }
                                 drtioaux::Packet::DestinationBusyReply { channel } => {
-                                    error!("[DEST#{}] RTIO busy error involving channel 0x{:04x}:{}", destination, channel, resolve_channel_name(channel as u32));
+                                    let channel = rtio_channel(destination, channel);
+                                    with_channel_name(channel, |name|
+                                        error!("[DEST#{}] RTIO busy error involving channel 0x{:04x}:{}", destination, channel, name));
                                     unsafe { SEEN_ASYNC_ERRORS |= ASYNC_ERROR_BUSY };
+                                    record_error_detail(ASYNC_ERROR_BUSY, channel);
                                 }
                                 packet => error!("[DEST#{}] received unexpected aux packet: {:?}", destination, packet),
                                 
@@ -591,12 +668,27 @@ pub mod drtio {
         })
     }
 
+    /// Frees a subkernel on the destination; refused while it is running there.
+    pub fn subkernel_remove(io: &Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &Mutex,
+            routing_table: &drtio_routing::RoutingTable, id: u32, destination: u8) -> Result<(), Error> {
+        let linkno = routing_table.0[destination as usize][0] - 1;
+        let reply = aux_transact(io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table, linkno,
+            &drtioaux::Packet::SubkernelRemoveRequest { id: id, destination: destination })?;
+        match reply {
+            drtioaux::Packet::SubkernelRemoveReply { succeeded: true } => Ok(()),
+            drtioaux::Packet::SubkernelRemoveReply { succeeded: false } =>
+                Err(Error::SubkernelRemoveFail(destination)),
+            packet => Err(Error::UnexpectedPacket(packet)),
+        }
+    }
+
     pub fn subkernel_load(io: &Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &Mutex, 
-            routing_table: &drtio_routing::RoutingTable, id: u32, destination: u8, run: bool
+            routing_table: &drtio_routing::RoutingTable, id: u32, destination: u8, run: bool, timestamp: u64
         ) -> Result<(), Error> {
         let linkno = routing_table.0[destination as usize][0] - 1;
         let reply = aux_transact(io, aux_mutex, ddma_mutex, subkernel_mutex, routing_table, linkno, 
-            &drtioaux::Packet::SubkernelLoadRunRequest{ id: id, source: 0, destination: destination, run: run })?;
+            &drtioaux::Packet::SubkernelLoadRunRequest{
+                id: id, source: 0, destination: destination, run: run, timestamp: timestamp })?;
         match reply {
             drtioaux::Packet::SubkernelLoadRunReply { destination: 0, succeeded: true } => Ok(()),
             drtioaux::Packet::SubkernelLoadRunReply { destination: 0, succeeded: false } =>
@@ -655,35 +747,74 @@ pub mod drtio {
 
 static mut SEEN_ASYNC_ERRORS: u8 = 0;
 
+const MAX_ERROR_DETAILS: usize = 32;
+
+// (error type, channel) for each async error since the last take_error_details()
+static mut ERROR_DETAILS: Vec<(u8, u32)> = Vec::new();
+
 pub unsafe fn get_async_errors() -> u8 {
     let errors = SEEN_ASYNC_ERRORS;
     SEEN_ASYNC_ERRORS = 0;
     errors
 }
 
-fn async_error_thread(io: Io) {
-    loop {
+fn record_error_detail(error: u8, channel: u32) {
+    unsafe {
+        if ERROR_DETAILS.len() < MAX_ERROR_DETAILS {
+            ERROR_DETAILS.push((error, channel))
+        }
+    }
+}
+
+/// Returns and clears the recorded async errors. Nothing yields in between,
+/// so an error reported concurrently ends up in either this or the next call.
+pub fn take_error_details() -> Vec<(u8, u32)> {
+    unsafe { core::mem::replace(&mut ERROR_DETAILS, Vec::new()) }
+}
+
+struct RtioCore;
+
+impl AsyncErrorRegisters for RtioCore {
+    fn async_error(&mut self) -> u8 {
+        unsafe { csr::rtio_core::async_error_read() }
+    }
+
+    // the register is write-one-to-clear
+    fn clear_async_error(&mut self, errors: u8) {
+        unsafe { csr::rtio_core::async_error_write(errors) }
+    }
+
+    fn error_channel(&mut self, error: u8) -> u32 {
         unsafe {
-            io.until(|| csr::rtio_core::async_error_read() != 0).unwrap();
-            let errors = csr::rtio_core::async_error_read();
-            if errors & ASYNC_ERROR_COLLISION != 0 {
-                let channel = csr::rtio_core::collision_channel_read();
-                error!("RTIO collision involving channel 0x{:04x}:{}", channel, resolve_channel_name(channel as u32));
-            }
-            if errors & ASYNC_ERROR_BUSY != 0 {
-                let channel = csr::rtio_core::busy_channel_read();
-                error!("RTIO busy error involving channel 0x{:04x}:{}", channel, resolve_channel_name(channel as u32));
+            match error {
+                ASYNC_ERROR_COLLISION => csr::rtio_core::collision_channel_read() as u32,
+                ASYNC_ERROR_BUSY => csr::rtio_core::busy_channel_read() as u32,
+                _ => csr::rtio_core::sequence_error_channel_read() as u32
             }
-            if errors & ASYNC_ERROR_SEQUENCE_ERROR != 0 {
-                let channel = csr::rtio_core::sequence_error_channel_read();
-                error!("RTIO sequence error involving channel 0x{:04x}:{}", channel, resolve_channel_name(channel as u32));
-            }
-            SEEN_ASYNC_ERRORS = errors;
-            csr::rtio_core::async_error_write(errors);
         }
     }
 }
 
+fn async_error_description(error: u8) -> &'static str {
+    match error {
+        ASYNC_ERROR_COLLISION => "collision",
+        ASYNC_ERROR_BUSY => "busy error",
+        _ => "sequence error"
+    }
+}
+
+fn async_error_thread(io: Io) {
+    loop {
+        io.until(|| unsafe { csr::rtio_core::async_error_read() } != 0).unwrap();
+        let errors = take_async_errors(&mut RtioCore, |error, channel| {
+            with_channel_name(channel, |name|
+                error!("RTIO {} involving channel 0x{:04x}:{}", async_error_description(error), channel, name));
+            record_error_detail(error, channel);
+        });
+        unsafe { SEEN_ASYNC_ERRORS = errors }
+    }
+}
+
 fn read_device_map() -> DeviceMap {
     let mut device_map: DeviceMap = DeviceMap::new();
     config::read("device_map", |value: Result<&[u8], config::Error>| {
@@ -700,7 +831,7 @@ fn read_device_map() -> DeviceMap {
         let size = bytes.read_u32().unwrap();
         for _ in 0..size {
             let channel = bytes.read_u32().unwrap();
-            let device_name= bytes.read_string().unwrap();
+            let device_name = bytes.read_lossy_string().unwrap();
             if let Some(old_entry) = device_map.insert(channel, device_name.clone()) {
                 warn!("conflicting device map entries for RTIO channel {}: '{}' and '{}'",
                        channel, old_entry, device_name);
@@ -719,7 +850,7 @@ pub fn startup(io: &Io, aux_mutex: &Mutex,
     unsafe {
         csr::rtio_core::reset_phy_write(1);
     }
-    io.spawn(4096, async_error_thread);
+    io.spawn("async_error", 4096, async_error_thread);
 }
 
 pub fn reset(io: &Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &Mutex,
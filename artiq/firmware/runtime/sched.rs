@@ -1,22 +1,23 @@
 #![allow(dead_code)]
 
-use core::mem;
+use core::{mem, ptr, slice};
 use core::result;
 use core::cell::{Cell, RefCell, RefMut};
 use alloc::vec::Vec;
-use fringe::OwnedStack;
+use fringe::{OwnedStack, Stack};
 use fringe::generator::{Generator, Yielder, State as GeneratorState};
 use smoltcp::time::Duration;
 use smoltcp::Error as NetworkError;
 use smoltcp::wire::{IpEndpoint, Ipv4Address, Ipv4Cidr};
 use smoltcp::iface::{Interface, Route, SocketHandle};
 
-use io::{Read, Write};
+use io::{Read, Write, Error as IoError};
 use board_misoc::clock;
 use urc::Urc;
 use board_misoc::ethmac::EthernetDevice;
 use smoltcp::phy::Tracer;
 use ip_addr_storage::InterfaceEx;
+use sched_policy::{self, WaitResult};
 
 #[derive(Fail, Debug)]
 pub enum Error {
@@ -34,6 +35,19 @@ impl From<NetworkError> for Error {
     }
 }
 
+impl Error {
+    /// Classifies the error for the protocols: a timeout is `Timeout`, and
+    /// the network stack running out of buffers is `WouldBlock`, both of
+    /// which callers may retry.
+    pub fn into_io(self) -> IoError<Error> {
+        match self {
+            Error::TimedOut => IoError::Timeout,
+            Error::Network(NetworkError::Exhausted) => IoError::WouldBlock,
+            error => IoError::Transport(error)
+        }
+    }
+}
+
 #[derive(Debug)]
 struct WaitRequest {
     event:   Option<*mut dyn FnMut() -> bool>,
@@ -42,11 +56,62 @@ struct WaitRequest {
 
 unsafe impl Send for WaitRequest {}
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Ready,
+    Sleeping,
+    Dead
+}
+
 #[derive(Debug)]
-enum WaitResult {
-    Completed,
-    TimedOut,
-    Interrupted
+pub struct TaskInfo {
+    pub name:             &'static str,
+    pub state:            TaskState,
+    pub stack_high_water: usize,
+    pub stack_size:       usize,
+    pub run_count:        u32,
+    pub runtime_us:       u64
+}
+
+/// Priority of threads spawned with `spawn`. Among runnable threads the
+/// scheduler prefers higher priorities, and round-robins within one.
+pub const DEFAULT_PRIORITY: u8 = 0;
+
+// Fresh stacks are filled with this byte, so that their high-water mark
+// can be found later by looking for the first overwritten byte.
+const STACK_PAINT: u8 = 0xa5;
+
+// Kept outside of the RefCell wrapping the thread, so that it can be
+// inspected while the thread is running.
+#[derive(Debug)]
+struct ThreadStats {
+    name:         &'static str,
+    stack_bottom: usize,
+    stack_size:   usize,
+    run_count:    Cell<u32>,
+    runtime_us:   Cell<u64>,
+    priority:     u8,
+    // scheduler runs since this thread last ran
+    age:          Cell<u32>
+}
+
+impl ThreadStats {
+    fn record_run(&self, duration_us: u64) {
+        self.run_count.set(self.run_count.get().wrapping_add(1));
+        self.runtime_us.set(self.runtime_us.get() + duration_us);
+        self.age.set(0)
+    }
+
+    fn effective_priority(&self, max_priority: u8) -> u32 {
+        sched_policy::effective_priority(self.priority, self.age.get(), max_priority)
+    }
+
+    fn stack_high_water(&self) -> usize {
+        let stack = unsafe { slice::from_raw_parts(self.stack_bottom as *const u8, self.stack_size) };
+        let untouched = stack.iter().take_while(|&&byte| byte == STACK_PAINT).count();
+        self.stack_size - untouched
+    }
 }
 
 #[derive(Debug)]
@@ -57,18 +122,33 @@ struct Thread {
 }
 
 impl Thread {
-    unsafe fn new<F>(io: &Io, stack_size: usize, f: F) -> ThreadHandle
+    unsafe fn new<F>(io: &Io, name: &'static str, stack_size: usize, priority: u8, f: F) -> ThreadHandle
             where F: 'static + FnOnce(Io) + Send {
         let spawned = io.spawned.clone();
+        let threads = io.threads.clone();
         let network = io.network.clone();
 
         // Add a 4k stack guard to the stack of any new threads
         let stack = OwnedStack::new(stack_size + 4096);
+        let stack_bottom = stack.limit() as usize;
+        let stack_size = stack.base() as usize - stack_bottom;
+        ptr::write_bytes(stack_bottom as *mut u8, STACK_PAINT, stack_size);
+
+        let stats = ThreadStats {
+            name:         name,
+            stack_bottom: stack_bottom,
+            stack_size:   stack_size,
+            run_count:    Cell::new(0),
+            runtime_us:   Cell::new(0),
+            priority:     priority,
+            age:          Cell::new(0)
+        };
         ThreadHandle::new(Thread {
             generator: Generator::unsafe_new(stack, |yielder, _| {
                 f(Io {
                     yielder: Some(yielder),
                     spawned,
+                    threads,
                     network
                 })
             }),
@@ -77,7 +157,7 @@ impl Thread {
                 timeout: None
             },
             interrupted: false
-        })
+        }, stats)
     }
 
     pub fn terminated(&self) -> bool {
@@ -94,11 +174,29 @@ impl Thread {
 }
 
 #[derive(Clone)]
-pub struct ThreadHandle(Urc<RefCell<Thread>>);
+pub struct ThreadHandle(Urc<RefCell<Thread>>, Urc<ThreadStats>);
 
 impl ThreadHandle {
-    fn new(thread: Thread) -> ThreadHandle {
-        ThreadHandle(Urc::new(RefCell::new(thread)))
+    fn new(thread: Thread, stats: ThreadStats) -> ThreadHandle {
+        ThreadHandle(Urc::new(RefCell::new(thread)), Urc::new(stats))
+    }
+
+    pub fn info(&self) -> TaskInfo {
+        let state = match self.0.try_borrow() {
+            Err(_) => TaskState::Running,
+            Ok(ref thread) if thread.terminated() => TaskState::Dead,
+            Ok(ref thread) if thread.waiting_for.event.is_none() &&
+                              thread.waiting_for.timeout.is_none() => TaskState::Ready,
+            Ok(_) => TaskState::Sleeping
+        };
+        TaskInfo {
+            name:             self.1.name,
+            state:            state,
+            stack_high_water: self.1.stack_high_water(),
+            stack_size:       self.1.stack_size,
+            run_count:        self.1.run_count.get(),
+            runtime_us:       self.1.runtime_us.get()
+        }
     }
 
     pub fn terminated(&self) -> bool {
@@ -119,7 +217,7 @@ impl ThreadHandle {
 type Network = Interface<'static, Tracer<EthernetDevice>>;
 
 pub struct Scheduler {
-    threads: Vec<ThreadHandle>,
+    threads: Urc<RefCell<Vec<ThreadHandle>>>,
     spawned: Urc<RefCell<Vec<ThreadHandle>>>,
     network: Urc<RefCell<Network>>,
     run_idx: usize,
@@ -128,7 +226,7 @@ pub struct Scheduler {
 impl Scheduler {
     pub fn new(network: Network) -> Scheduler {
         Scheduler {
-            threads: Vec::new(),
+            threads: Urc::new(RefCell::new(Vec::new())),
             spawned: Urc::new(RefCell::new(Vec::new())),
             network: Urc::new(RefCell::new(network)),
             run_idx: 0,
@@ -139,67 +237,79 @@ impl Scheduler {
         Io {
             yielder: None,
             spawned: self.spawned.clone(),
+            threads: self.threads.clone(),
             network: self.network.clone()
         }
     }
 
     pub fn run(&mut self) {
-        self.threads.append(&mut *self.spawned.borrow_mut());
-        if self.threads.len() == 0 { return }
+        self.threads.borrow_mut().append(&mut *self.spawned.borrow_mut());
+        let thread_count = self.threads.borrow().len();
+        if thread_count == 0 { return }
 
         let now = clock::get_ms();
-        let start_idx = self.run_idx;
-        loop {
-            self.run_idx = (self.run_idx + 1) % self.threads.len();
-
-            let result = {
-                let &mut Thread { ref mut generator, ref mut interrupted, ref waiting_for } =
-                    &mut *self.threads[self.run_idx].0.borrow_mut();
-                if *interrupted {
+        let max_priority = self.threads.borrow().iter()
+            .map(|handle| handle.1.priority)
+            .max().unwrap();
+        for handle in self.threads.borrow().iter() {
+            handle.1.age.set(handle.1.age.get().saturating_add(1))
+        }
+
+        // Try the priorities from the highest down, round-robin within each;
+        // threads are only polled in this order, as their wait conditions
+        // may have side effects.
+        let threads = &self.threads;
+        let ran = sched_policy::run_next(thread_count, self.run_idx,
+            |idx| threads.borrow()[idx].1.effective_priority(max_priority),
+            |idx| {
+                let handle = threads.borrow()[idx].clone();
+                let started = clock::get_us();
+                let result = {
+                    let &mut Thread { ref mut generator, ref mut interrupted, ref waiting_for } =
+                        &mut *handle.0.borrow_mut();
+                    let event = waiting_for.event.map(|event| move || unsafe { (*event)() });
+                    let wait_result = sched_policy::resumption(*interrupted, waiting_for.timeout,
+                                                               event, now)?;
                     *interrupted = false;
-                    generator.resume(WaitResult::Interrupted)
-                } else if waiting_for.event.is_none() && waiting_for.timeout.is_none() {
-                    generator.resume(WaitResult::Completed)
-                } else if waiting_for.timeout.map(|instant| now >= instant).unwrap_or(false) {
-                    generator.resume(WaitResult::TimedOut)
-                } else if waiting_for.event.map(|event| unsafe { (*event)() }).unwrap_or(false) {
-                    generator.resume(WaitResult::Completed)
-                } else if self.run_idx == start_idx {
-                    // We've checked every thread and none of them are runnable.
-                    break
-                } else {
-                    continue
-                }
-            };
-
-            match result {
-                None => {
-                    // The thread has terminated.
-                    self.threads.remove(self.run_idx);
-                    self.run_idx = 0
-                },
-                Some(wait_request) => {
-                    // The thread has suspended itself.
-                    let mut thread = self.threads[self.run_idx].0.borrow_mut();
-                    thread.waiting_for = wait_request
-                }
+                    generator.resume(wait_result)
+                };
+                handle.1.record_run(clock::get_us() - started);
+                Some(result)
+            });
+
+        match ran {
+            // We've checked every thread and none of them are runnable.
+            None => (),
+            Some((idx, None)) => {
+                // The thread has terminated.
+                self.threads.borrow_mut().remove(idx);
+                self.run_idx = 0
+            },
+            Some((idx, Some(wait_request))) => {
+                // The thread has suspended itself.
+                let handle = self.threads.borrow()[idx].clone();
+                handle.0.borrow_mut().waiting_for = wait_request;
+                self.run_idx = idx
             }
-
-            break
         }
     }
 
-    pub fn run_network(&mut self) {
+    /// Polls the interface until it is idle, or until it has been polled
+    /// `budget` times so that a burst of packets cannot starve the threads;
+    /// a budget of 0 is unlimited.
+    pub fn run_network(&mut self, budget: u32) {
         let mut interface = self.network.borrow_mut();
-        loop {
+        sched_policy::poll_budgeted(budget, || {
             let timestamp = smoltcp::time::Instant::from_millis(clock::get_ms() as i64);
             match interface.poll(timestamp) {
-                Ok(true) => (),
-                Ok(false) => break,
-                Err(smoltcp::Error::Unrecognized) => (),
-                Err(err) => debug!("network error: {}", err)
+                Ok(processed) => processed,
+                Err(smoltcp::Error::Unrecognized) => true,
+                Err(err) => {
+                    debug!("network error: {}", err);
+                    true
+                }
             }
-        }
+        });
     }
 }
 
@@ -207,24 +317,45 @@ impl Scheduler {
 pub struct Io<'a> {
     yielder: Option<&'a Yielder<WaitResult, WaitRequest>>,
     spawned: Urc<RefCell<Vec<ThreadHandle>>>,
+    threads: Urc<RefCell<Vec<ThreadHandle>>>,
     network: Urc<RefCell<Network>>,
 }
 
 impl<'a> Io<'a> {
-    pub fn spawn<F>(&self, stack_size: usize, f: F) -> ThreadHandle
+    pub fn spawn<F>(&self, name: &'static str, stack_size: usize, f: F) -> ThreadHandle
             where F: 'static + FnOnce(Io) + Send {
-        let handle = unsafe { Thread::new(self, stack_size, f) };
+        self.spawn_with_priority(name, stack_size, DEFAULT_PRIORITY, f)
+    }
+
+    pub fn spawn_with_priority<F>(&self, name: &'static str, stack_size: usize, priority: u8,
+                                  f: F) -> ThreadHandle
+            where F: 'static + FnOnce(Io) + Send {
+        let handle = unsafe { Thread::new(self, name, stack_size, priority, f) };
         self.spawned.borrow_mut().push(handle.clone());
         handle
     }
 
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        self.threads.borrow().iter()
+            .chain(self.spawned.borrow().iter())
+            .map(|handle| handle.info())
+            .collect()
+    }
+
     fn yielder(&self) -> &'a Yielder<WaitResult, WaitRequest> {
         self.yielder.expect("cannot suspend the scheduler thread")
     }
 
+    /// Suspends the thread for `duration_ms`, letting the others run.
     pub fn sleep(&self, duration_ms: u64) -> Result<(), Error> {
+        self.yield_until(clock::get_ms() + duration_ms)
+    }
+
+    /// Suspends the thread until `clock::get_ms()` reaches `deadline_ms`;
+    /// a deadline in the past only yields once.
+    pub fn yield_until(&self, deadline_ms: u64) -> Result<(), Error> {
         let request = WaitRequest {
-            timeout: Some(clock::get_ms() + duration_ms),
+            timeout: Some(deadline_ms),
             event:   None
         };
 
@@ -540,6 +671,10 @@ impl<'a> Read for TcpStream<'a> {
             }
         }
     }
+
+    fn classify_read_error(&self, error: Error) -> IoError<Error> {
+        error.into_io()
+    }
 }
 
 impl<'a> Write for TcpStream<'a> {
@@ -576,6 +711,10 @@ impl<'a> Write for TcpStream<'a> {
             Err(Error::Network(NetworkError::Illegal))
         }
     }
+
+    fn classify_write_error(&self, error: Error) -> IoError<Error> {
+        error.into_io()
+    }
 }
 
 impl<'a> Drop for TcpStream<'a> {
@@ -0,0 +1,210 @@
+//! The decisions of the scheduler that do not depend on threads or the
+//! network, kept apart so that they can be tested on the host.
+
+/// Calls `poll` until it reports that there is nothing left to do, or until
+/// it has been called `budget` times; a budget of 0 is unlimited. Returns
+/// the number of calls.
+pub fn poll_budgeted<F: FnMut() -> bool>(budget: u32, mut poll: F) -> u32 {
+    let mut polls = 0;
+    while budget == 0 || polls < budget {
+        polls += 1;
+        if !poll() {
+            break
+        }
+    }
+    polls
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WaitResult {
+    Completed,
+    TimedOut,
+    Interrupted
+}
+
+/// How a thread waiting for `event` until `timeout` is resumed at `now`, or
+/// None if it keeps waiting. A thread that is not waiting for anything is
+/// resumed at once, and a sleeping one, waiting only for its deadline, is
+/// skipped until then.
+pub fn resumption<E>(interrupted: bool, timeout: Option<u64>, event: Option<E>,
+                     now: u64) -> Option<WaitResult>
+        where E: FnOnce() -> bool {
+    if interrupted {
+        Some(WaitResult::Interrupted)
+    } else if event.is_none() && timeout.is_none() {
+        Some(WaitResult::Completed)
+    } else if timeout.map(|instant| now >= instant).unwrap_or(false) {
+        Some(WaitResult::TimedOut)
+    } else if event.map(|event| event()).unwrap_or(false) {
+        Some(WaitResult::Completed)
+    } else {
+        None
+    }
+}
+
+// A thread that has waited this many scheduler runs is treated as one
+// priority higher, up to the highest priority of any thread, so that a
+// busy high-priority thread cannot starve the others entirely.
+const AGING_RUNS: u32 = 16;
+
+/// The priority a thread is scheduled at after waiting `age` scheduler runs.
+pub fn effective_priority(priority: u8, age: u32, max_priority: u8) -> u32 {
+    (priority as u32 + age / AGING_RUNS).min(max_priority as u32)
+}
+
+/// Offers the `count` threads to `run`, by decreasing `level`, and within
+/// one level round-robin starting after thread `last`, until `run` returns
+/// Some for one of them. Returns that thread and what `run` returned, or
+/// None if no thread could run.
+pub fn run_next<L, R, T>(count: usize, last: usize, level: L, mut run: R) -> Option<(usize, T)>
+        where L: Fn(usize) -> u32, R: FnMut(usize) -> Option<T> {
+    let mut below = None;
+    loop {
+        let current = (0..count)
+            .map(|idx| level(idx))
+            .filter(|&level| below.map(|below| level < below).unwrap_or(true))
+            .max()?;
+        for offset in 1..count + 1 {
+            let idx = (last + offset) % count;
+            if level(idx) != current {
+                continue
+            }
+            if let Some(result) = run(idx) {
+                return Some((idx, result))
+            }
+        }
+        below = Some(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use super::*;
+
+    // An interface with `pending` packets queued, handling one per poll.
+    fn burst(pending: u32, budget: u32) -> (u32, u32) {
+        let mut left = pending;
+        let polls = poll_budgeted(budget, || {
+            if left == 0 {
+                false
+            } else {
+                left -= 1;
+                true
+            }
+        });
+        (polls, left)
+    }
+
+    #[test]
+    fn unlimited_budget_drains() {
+        // the final poll finds the interface idle
+        assert_eq!(burst(100, 0), (101, 0));
+        assert_eq!(burst(0, 0), (1, 0));
+    }
+
+    #[test]
+    fn budget_bounds_burst() {
+        assert_eq!(burst(100, 8), (8, 92));
+        // the rest is left for the next scheduler pass
+        assert_eq!(burst(92, 100), (93, 0));
+        assert_eq!(burst(3, 8), (4, 0));
+    }
+
+    // Threads that are always runnable, or only while `ready` says so,
+    // scheduled the way `Scheduler::run` does; returns the order they ran in.
+    fn schedule<F>(priorities: &[u8], runs: usize, ready: F) -> Vec<usize>
+            where F: Fn(usize, usize) -> bool {
+        let max_priority = *priorities.iter().max().unwrap();
+        let mut ages = vec![0; priorities.len()];
+        let mut last = 0;
+        let mut order = Vec::new();
+        for run in 0..runs {
+            for age in ages.iter_mut() {
+                *age += 1
+            }
+            let ran = run_next(priorities.len(), last,
+                |idx| effective_priority(priorities[idx], ages[idx], max_priority),
+                |idx| if ready(run, idx) { Some(()) } else { None });
+            if let Some((idx, ())) = ran {
+                ages[idx] = 0;
+                last = idx;
+                order.push(idx)
+            }
+        }
+        order
+    }
+
+    #[test]
+    fn equal_priorities_round_robin() {
+        assert_eq!(schedule(&[0, 0, 0], 6, |_, _| true), [1, 2, 0, 1, 2, 0]);
+        // waiting threads are skipped
+        assert_eq!(schedule(&[0, 0, 0], 4, |_, idx| idx != 1), [2, 0, 2, 0]);
+    }
+
+    #[test]
+    fn higher_priority_preferred() {
+        // a runnable thread of higher priority goes first...
+        assert_eq!(schedule(&[0, 1, 0], 10, |_, _| true), [1; 10]);
+        // ...and lower ones run while it waits, starting after the last
+        // thread that ran
+        assert_eq!(schedule(&[0, 1, 0], 4, |run, idx| idx != 1 || run % 2 == 0), [1, 2, 1, 2]);
+        assert_eq!(schedule(&[0, 1, 0], 4, |run, idx| idx != 1 || run == 0), [1, 2, 0, 2]);
+    }
+
+    #[test]
+    fn busy_thread_cannot_starve() {
+        let order = schedule(&[0, 1, 0], 200, |_, _| true);
+        // the waiting threads age up to its priority and then share the CPU
+        assert!(order.iter().position(|&idx| idx != 1).unwrap() <= AGING_RUNS as usize);
+        // the low priority threads still alternate
+        let low_order: Vec<_> = order.iter().cloned().filter(|&idx| idx != 1).collect();
+        assert!(low_order.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn nothing_runnable() {
+        assert_eq!(run_next(3, 0, |_| 0, |_| None::<()>), None);
+        assert_eq!(run_next(0, 0, |_| 0, |_| Some(())), None);
+    }
+
+    #[test]
+    fn wait_conditions() {
+        let never: Option<fn() -> bool> = None;
+        assert_eq!(resumption(false, None, never, 0), Some(WaitResult::Completed));
+        assert_eq!(resumption(true, Some(100), never, 0), Some(WaitResult::Interrupted));
+        assert_eq!(resumption(false, Some(100), never, 99), None);
+        assert_eq!(resumption(false, Some(100), never, 100), Some(WaitResult::TimedOut));
+        assert_eq!(resumption(false, Some(100), Some(|| true), 50), Some(WaitResult::Completed));
+        assert_eq!(resumption(false, None, Some(|| false), 1000), None);
+        // the deadline wins over an event that would also complete
+        assert_eq!(resumption(false, Some(100), Some(|| true), 100), Some(WaitResult::TimedOut));
+    }
+
+    #[test]
+    fn sleeper_skipped_until_deadline() {
+        // thread 0 sleeps until 5 ms and then for good, while thread 1 keeps
+        // yielding; each scheduler run takes 1 ms
+        let never: Option<fn() -> bool> = None;
+        let mut deadline = 5;
+        let mut last = 0;
+        let mut progress = 0;
+        let mut woken_at = None;
+        for now in 0..10 {
+            let (idx, result) = run_next(2, last, |_| 0, |idx| {
+                let timeout = if idx == 0 { Some(deadline) } else { None };
+                resumption(false, timeout, never, now)
+            }).unwrap();
+            last = idx;
+            if idx == 0 {
+                assert_eq!(result, WaitResult::TimedOut);
+                woken_at = Some(now);
+                deadline = u64::max_value()
+            } else {
+                progress += 1
+            }
+        }
+        assert_eq!(woken_at, Some(5));
+        assert_eq!(progress, 9);
+    }
+}
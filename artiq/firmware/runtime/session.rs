@@ -1,18 +1,17 @@
 use core::{mem, str, cell::{Cell, RefCell}, fmt::Write as FmtWrite};
 use alloc::{vec::Vec, string::{String, ToString}};
 use byteorder::{ByteOrder, NativeEndian};
-use cslice::CSlice;
 #[cfg(has_drtio)]
 use tar_no_std::TarArchiveRef;
 
-use dyld::elf;
+use dyld::{elf, find_section};
 use io::{Read, Write, Error as IoError};
 #[cfg(has_drtio)]
 use io::Cursor;
-use board_misoc::{ident, cache, config};
+use board_misoc::{ident, cache, config, clock};
 use {mailbox, rpc_queue, kernel};
 use urc::Urc;
-use sched::{ThreadHandle, Io, Mutex, TcpListener, TcpStream, Error as SchedError};
+use sched::{ThreadHandle, Io, Mutex, TcpListener, TcpStream, TaskState, Error as SchedError};
 use rtio_clocking;
 use rtio_dma::Manager as DmaManager;
 #[cfg(has_drtio)]
@@ -21,13 +20,22 @@ use rtio_dma::remote_dma;
 use kernel::{subkernel, subkernel::Error as SubkernelError};
 #[cfg(has_drtio)]
 use rtio_mgt::drtio;
-use rtio_mgt::get_async_errors;
+use rtio_mgt::{get_async_errors, take_error_details};
 use cache::Cache;
 use kern_hwreq;
+use decompress;
+use logger_artiq::BufferLogger;
+use mgmt;
+#[cfg(any(has_rtio_moninj, has_drtio))]
+use moninj;
+#[cfg(has_rtio_analyzer)]
+use analyzer;
+use rtio_claims;
 use board_artiq::drtio_routing;
 
 use rpc_proto as rpc;
 use session_proto as host;
+use session_proto::KernelState;
 use kernel_proto as kern;
 
 #[derive(Fail, Debug)]
@@ -38,8 +46,8 @@ pub enum Error<T> {
     KernelNotFound,
     #[fail(display = "invalid kernel CPU pointer: {:#08x}", _0)]
     InvalidPointer(usize),
-    #[fail(display = "RTIO clock failure")]
-    ClockFailure,
+    #[fail(display = "RTIO clock lost lock at {} us", _0)]
+    ClockFailure(u64),
     #[fail(display = "protocol error: {}", _0)]
     Protocol(#[cause] host::Error<T>),
     #[fail(display = "subkernel io error")]
@@ -57,6 +65,8 @@ pub enum Error<T> {
     #[fail(display = "drtio aux error: {}", _0)]
     DrtioAux(#[cause] drtio::Error),
     #[fail(display = "{}", _0)]
+    RpcQueue(rpc_queue::Corrupted),
+    #[fail(display = "{}", _0)]
     Unexpected(String),
 }
 
@@ -78,13 +88,20 @@ impl From<drtio::Error> for Error<SchedError> {
 
 impl From<SchedError> for Error<SchedError> {
     fn from(value: SchedError) -> Error<SchedError> {
-        Error::Protocol(host::Error::Io(IoError::Other(value)))
+        Error::Protocol(host::Error::Io(value.into_io()))
     }
 }
 
 impl From<IoError<SchedError>> for Error<SchedError> {
     fn from(value: IoError<SchedError>) -> Error<SchedError> {
-        Error::Protocol(host::Error::Io(value))
+        Error::Protocol(host::Error::from(value))
+    }
+}
+
+// the session ends, and the queue is reset along with the kernel CPU
+impl<T> From<rpc_queue::Corrupted> for Error<T> {
+    fn from(value: rpc_queue::Corrupted) -> Error<T> {
+        Error::RpcQueue(value)
     }
 }
 
@@ -144,33 +161,40 @@ macro_rules! propagate_subkernel_exception {
 struct Congress {
     cache: Cache,
     dma_manager: DmaManager,
-    finished_cleanly: Cell<bool>
+    finished_cleanly: Cell<bool>,
+    last_run_outcome: Cell<host::LastRunOutcome>,
+    last_exception: Option<Vec<u8>>,
+    rtio_replay: Option<(Vec<kern::RtioReplayEvent>, bool)>
 }
 
 impl Congress {
-    fn new() -> Congress {
+    fn new(last_run_outcome: host::LastRunOutcome) -> Congress {
         Congress {
             cache: Cache::new(),
             dma_manager: DmaManager::new(),
-            finished_cleanly: Cell::new(true)
+            finished_cleanly: Cell::new(last_run_outcome == host::LastRunOutcome::Clean),
+            last_run_outcome: Cell::new(last_run_outcome),
+            last_exception: None,
+            rtio_replay: None
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum KernelState {
-    Absent,
-    Loaded,
-    Running,
-    RpcWait
-}
-
 // Per-connection state
 #[derive(Debug)]
 struct Session<'a> {
     congress: &'a mut Congress,
     kernel_state: KernelState,
-    log_buffer: String
+    log_buffer: String,
+    library: Option<Vec<u8>>,
+    pending_load: Option<host::ChunkedLoad>,
+    repeat: Option<host::RepeatedRun>,
+    proto: host::Context,
+    cleanup_deadline: Option<u64>,
+    cleanup_registered: bool,
+    rpc_trace: rpc::Trace,
+    rtio_replay: bool,
+    log_subscribed: bool
 }
 
 impl<'a> Session<'a> {
@@ -178,17 +202,29 @@ impl<'a> Session<'a> {
         Session {
             congress: congress,
             kernel_state: KernelState::Absent,
-            log_buffer: String::new()
+            log_buffer: String::new(),
+            library: None,
+            pending_load: None,
+            repeat: None,
+            proto: host::Context::new(),
+            cleanup_deadline: None,
+            cleanup_registered: false,
+            rpc_trace: rpc::Trace(config::read_str("rpc_trace", |r| r == Ok("1"))),
+            rtio_replay: config::read_str("rtio_replay", |r| r == Ok("1")),
+            log_subscribed: false
         }
     }
 
-    fn running(&self) -> bool {
-        match self.kernel_state {
-            KernelState::Absent  | KernelState::Loaded  => false,
-            KernelState::Running | KernelState::RpcWait => true
+    fn set_kernel_state(&mut self, state: KernelState) {
+        if let Some((from, to)) = self.kernel_state.transition(state) {
+            debug!("session state: {:?} -> {:?}", from, to)
         }
     }
 
+    fn running(&self) -> bool {
+        self.kernel_state.running()
+    }
+
     fn flush_log_buffer(&mut self) {
         if &self.log_buffer[self.log_buffer.len() - 1..] == "\n" {
             for line in self.log_buffer.lines() {
@@ -197,32 +233,128 @@ impl<'a> Session<'a> {
             self.log_buffer.clear()
         }
     }
+
+    fn record_run(&mut self, succeeded: bool) {
+        if let Some(ref mut run) = self.repeat {
+            run.record(succeeded)
+        }
+    }
 }
 
 impl<'a> Drop for Session<'a> {
     fn drop(&mut self) {
-        unsafe { kernel::stop() }
+        unsafe { kern_abort(self) }
+        if self.log_subscribed {
+            BufferLogger::with(|logger| logger.unsubscribe())
+        }
     }
 }
 
-fn host_read<R>(reader: &mut R) -> Result<host::Request, Error<R::ReadError>>
+/// Stops the kernel CPU and puts the configured safe state into effect.
+/// Every way a kernel can end must go through this.
+unsafe fn kern_stop() {
+    kernel::stop();
+    #[cfg(any(has_rtio_moninj, has_drtio))]
+    moninj::apply_safe_state();
+}
+
+/// Asks a running kernel that registered a cleanup handler to abort, gives
+/// the handler up to CLEANUP_TIMEOUT_MS, then stops the kernel CPU. This runs
+/// while the session is torn down, without the scheduler, so requests the
+/// handler makes to the comms CPU are acknowledged but never answered.
+unsafe fn kern_abort(session: &mut Session) {
+    if session.running() && session.cleanup_registered {
+        mailbox::request_abort();
+        let timeout = clock::Timeout::after_ms(CLEANUP_TIMEOUT_MS);
+        let mut finished = false;
+        // receive_timeout(0) still returns a message that is already there
+        while let Some(msg_ptr) = mailbox::receive_timeout(timeout.remaining_ms()) {
+            match *(msg_ptr as *const kern::Message) {
+                kern::CleanupStarted =>
+                    warn!("kernel aborted, running its cleanup handler"),
+                kern::RunAborted | kern::RunFinished | kern::RunException { .. } => {
+                    finished = true;
+                    break
+                }
+                _ => ()
+            }
+            mailbox::acknowledge()
+        }
+        if !finished {
+            warn!("kernel cleanup handler did not finish within {} ms", CLEANUP_TIMEOUT_MS)
+        }
+    }
+    kern_stop();
+}
+
+/// Releases the safe state overrides and starts the kernel CPU, so that the
+/// kernel regains control of the safe state channels.
+unsafe fn kern_start() {
+    #[cfg(any(has_rtio_moninj, has_drtio))]
+    moninj::release_safe_state();
+    kernel::start();
+}
+
+fn features() -> u32 {
+    let mut features = host::FEATURE_COMPRESSED_KERNELS;
+    if cfg!(has_drtio) {
+        features |= host::FEATURE_SUBKERNELS;
+    }
+    if cfg!(has_rtio_analyzer) {
+        features |= host::FEATURE_STORED_ANALYZER;
+    }
+    features
+}
+
+fn host_read<R>(reader: &mut R, proto: &mut host::Context) -> Result<host::Request, Error<R::ReadError>>
     where R: Read + ?Sized
 {
-    let request = host::Request::read_from(reader)?;
+    let request = host::Request::read_from(reader, proto)?;
     match &request {
         &host::Request::LoadKernel(_) => debug!("comm<-host LoadLibrary(...)"),
-        &host::Request::UploadSubkernel { id, destination, kernel: _} => debug!(
-            "comm<-host UploadSubkernel(id: {}, destination: {}, ...)", id, destination),
+        &host::Request::LoadFlatKernel { image: _, entry_offset, load_addr } => debug!(
+            "comm<-host LoadFlatKernel(entry_offset: {:#x}, load_addr: {:#08x}, ...)",
+            entry_offset, load_addr),
+        &host::Request::CacheKernel(_) => debug!("comm<-host CacheKernel(...)"),
+        &host::Request::LoadKernelCompressed { algorithm, uncompressed_len, ref data } => debug!(
+            "comm<-host LoadKernelCompressed(algorithm: {}, uncompressed_len: {:?}, [_; {:#x}])",
+            algorithm, uncompressed_len, data.len()),
+        &host::Request::LoadKernelChunk { total, ref data } => debug!(
+            "comm<-host LoadKernelChunk(total: {}, [_; {:#x}])", total, data.len()),
+        &host::Request::UploadSubkernel { id, destination, kernel: _, crc32 } => debug!(
+            "comm<-host UploadSubkernel(id: {}, destination: {}, crc32: {:x?}, ...)",
+            id, destination, crc32),
         _ => debug!("comm<-host {:?}", request)
     }
     Ok(request)
 }
 
-fn host_write<W>(writer: &mut W, reply: host::Reply) -> Result<(), IoError<W::WriteError>>
-    where W: Write + ?Sized
-{
-    debug!("comm->host {:?}", reply);
-    reply.write_to(writer)
+fn rpc_trace(session: &Session, record: rpc::TraceRecord) {
+    session.rpc_trace.record(record, |record| info!(target: "rpc_trace", "{}", record))
+}
+
+const WRITE_RETRIES: u32 = 8;
+
+/// Writes all of `data`, yielding to other threads and retrying a bounded
+/// number of times when the network stack is momentarily out of buffers.
+fn retry_write(io: &Io, stream: &mut TcpStream, data: &[u8]) -> Result<(), IoError<SchedError>> {
+    io::retry_write(stream, data, WRITE_RETRIES, |error| {
+        warn!("transient error writing to host ({}), retrying", error);
+        io.relinquish().map_err(SchedError::into_io)
+    })
+}
+
+fn host_write(io: &Io, stream: &mut TcpStream, proto: &mut host::Context,
+              reply: host::Reply) -> Result<(), IoError<SchedError>> {
+    // logging a forwarded log line would produce another one to forward
+    match reply {
+        host::Reply::Log { .. } => (),
+        _ => debug!("comm->host {:?}", reply)
+    }
+    // serialize first, so that a retry never resends part of a reply
+    let mut serialized = Vec::new();
+    reply.write_to(&mut serialized, proto).expect("serializing into memory cannot fail");
+    retry_write(io, stream, &serialized)
 }
 
 pub fn kern_send(io: &Io, request: &kern::Message) -> Result<(), Error<SchedError>> {
@@ -283,21 +415,27 @@ pub fn kern_acknowledge() -> Result<(), Error<SchedError>> {
 
 unsafe fn kern_load(io: &Io, session: &mut Session, library: &[u8])
                    -> Result<(), Error<SchedError>> {
+    kern_load_request(io, session, &kern::LoadRequest(&library))
+}
+
+unsafe fn kern_load_request(io: &Io, session: &mut Session, request: &kern::Message)
+                           -> Result<(), Error<SchedError>> {
     if session.running() {
         unexpected!("attempted to load a new kernel while a kernel was running")
     }
 
-    kernel::start();
+    kern_start();
+    session.cleanup_registered = false;
 
-    kern_send(io, &kern::LoadRequest(&library))?;
+    kern_send(io, request)?;
     kern_recv(io, |reply| {
         match reply {
             kern::LoadReply(Ok(())) => {
-                session.kernel_state = KernelState::Loaded;
+                session.set_kernel_state(KernelState::Loaded);
                 Ok(())
             }
             kern::LoadReply(Err(error)) => {
-                kernel::stop();
+                kern_stop();
                 Err(Error::Load(format!("{}", error)))
             }
             other =>
@@ -311,11 +449,214 @@ fn kern_run(session: &mut Session) -> Result<(), Error<SchedError>> {
         unexpected!("attempted to run a kernel while not in Loaded state")
     }
 
-    session.kernel_state = KernelState::Running;
+    session.set_kernel_state(KernelState::Running);
     // TODO: make this a separate request
     kern_acknowledge()
 }
 
+// Time a kernel's cleanup handler gets before the kernel CPU is forcibly stopped.
+const CLEANUP_TIMEOUT_MS: u64 = 1000;
+
+fn check_cleanup_deadline(session: &mut Session) -> Result<(), Error<SchedError>> {
+    match session.cleanup_deadline {
+        Some(deadline) if clock::deadline_passed(clock::get_ms(), deadline) => {
+            unsafe {
+                kern_stop();
+                session.congress.cache.unborrow()
+            }
+            session.set_kernel_state(KernelState::Absent);
+            session.cleanup_deadline = None;
+            unexpected!("kernel cleanup handler did not finish within {} ms", CLEANUP_TIMEOUT_MS)
+        }
+        _ => Ok(())
+    }
+}
+
+const SELF_KERNEL_TIMEOUT_MS: u64 = 1000;
+
+/// Runs the self-test kernel built into ksupport through load, run and finish,
+/// returning whether it completed cleanly.
+fn kern_self_test(io: &Io, session: &mut Session) -> Result<bool, Error<SchedError>> {
+    if session.running() {
+        unexpected!("attempted to run the self-test kernel while a kernel was running")
+    }
+
+    unsafe { kern_start() }
+    let timeout = clock::Timeout::after_ms(SELF_KERNEL_TIMEOUT_MS);
+    kern_send(io, &kern::SelfTestRequest)?;
+
+    let mut self_test = kern::SelfTest::new();
+    let passed = loop {
+        let mut msg_ptr = 0;
+        io.until(|| {
+            msg_ptr = mailbox::receive();
+            msg_ptr != 0 || timeout.expired()
+        })?;
+        if msg_ptr == 0 {
+            error!("self-test kernel timed out");
+            break false
+        }
+        if !kernel::validate(msg_ptr) {
+            error!("self-test kernel sent an invalid pointer {:#08x}", msg_ptr);
+            break false
+        }
+
+        let reply = unsafe { &*(msg_ptr as *const kern::Message) };
+        kern_recv_dotrace(reply);
+        match (self_test.step(reply), reply) {
+            (kern::SelfTestStep::ReplyCacheGet, &kern::CacheGetRequest { key }) => {
+                let value = session.congress.cache.get(key);
+                kern_send(io, &kern::CacheGetReply {
+                    value: unsafe { mem::transmute(value) }
+                })?
+            }
+            (kern::SelfTestStep::Acknowledge, &kern::Log(args)) => {
+                error!(target: "kernel", "{}", args);
+                kern_acknowledge()?
+            }
+            (kern::SelfTestStep::Acknowledge, _) => kern_acknowledge()?,
+            (kern::SelfTestStep::Finished(passed), &kern::RunFinished) => break passed,
+            (_, other) => {
+                error!("unexpected message from self-test kernel: {:?}", other);
+                break false
+            }
+        }
+    };
+
+    unsafe {
+        kern_stop();
+        session.congress.cache.unborrow()
+    }
+    session.set_kernel_state(KernelState::Absent);
+    Ok(passed)
+}
+
+const LOAD_PROGRESS_INTERVAL: usize = 65536;
+
+// Number of bytes between LoadProgress replies; 0 disables them.
+fn load_progress_interval() -> usize {
+    config::read_str("load_progress_interval", |r| r.ok().and_then(|s| s.parse().ok()))
+        .unwrap_or(LOAD_PROGRESS_INTERVAL)
+}
+
+fn host_load_kernel(io: &Io, stream: &mut TcpStream, session: &mut Session,
+                    kernel: Vec<u8>) -> Result<(), Error<SchedError>> {
+    match unsafe { kern_load(io, session, &kernel) } {
+        Ok(()) => {
+            host_write(io, stream, &mut session.proto, host::Reply::LoadCompleted {
+                metadata: kernel_metadata(&kernel),
+                evicted: &[]
+            })?;
+            session.library = Some(kernel)
+        },
+        Err(error) => {
+            let mut description = String::new();
+            write!(&mut description, "{}", error).unwrap();
+            host_write(io, stream, &mut session.proto, host::Reply::LoadFailed(&description))?;
+            kern_acknowledge()?;
+        }
+    }
+    Ok(())
+}
+
+const KERNEL_METADATA_SECTION: &'static [u8] = b".artiq_metadata";
+const KERNEL_METADATA_MAX_SIZE: usize = 4096;
+
+fn kernel_metadata(library: &[u8]) -> &[u8] {
+    match find_section(library, KERNEL_METADATA_SECTION) {
+        Ok(Some(metadata)) if metadata.len() <= KERNEL_METADATA_MAX_SIZE => metadata,
+        Ok(Some(metadata)) => {
+            warn!("kernel metadata is too large ({} bytes), ignoring", metadata.len());
+            &[]
+        }
+        Ok(None) => &[],
+        Err(error) => {
+            warn!("cannot read kernel metadata: {}", error);
+            &[]
+        }
+    }
+}
+
+// Emitted by the compiler for kernels built with source snippets; see
+// `host::find_source_snippet` for its layout.
+const KERNEL_SOURCE_SECTION: &'static [u8] = b".artiq_source";
+const KERNEL_SOURCE_MAX_SIZE: usize = 65536;
+
+fn source_snippet<'a>(library: &'a [u8], exception: &eh::eh_artiq::Exception) -> Option<&'a str> {
+    // host strings are only known to the host
+    if exception.file.len() == usize::MAX {
+        return None
+    }
+    match find_section(library, KERNEL_SOURCE_SECTION) {
+        Ok(Some(section)) if section.len() <= KERNEL_SOURCE_MAX_SIZE =>
+            host::find_source_snippet(section, exception.file.as_ref(), exception.line),
+        Ok(Some(section)) => {
+            warn!("kernel source section is too large ({} bytes), ignoring", section.len());
+            None
+        }
+        _ => None
+    }
+}
+
+const CACHED_KERNEL_KEY: &'static str = "cached_kernel";
+
+fn read_cached_kernel() -> Result<Vec<u8>, &'static str> {
+    config::read(CACHED_KERNEL_KEY, |result| {
+        match result {
+            // make a copy as kernel CPU cannot read SPI directly
+            Ok(record) => host::cached_kernel(record).map(|kernel| kernel.to_vec()),
+            Err(_) => Err("no cached kernel")
+        }
+    })
+}
+
+fn write_cached_kernel(kernel: &[u8]) -> Result<(), config::Error> {
+    config::write(CACHED_KERNEL_KEY, &host::cached_kernel_record(kernel))
+}
+
+// Returns the board to the state it is in after boot, as far as kernels are
+// concerned; the kernel stored in flash by CacheKernel is left alone.
+fn reset_kernel_state(session: &mut Session) -> usize {
+    let busy_before = unsafe { ::ALLOC.busy() };
+
+    // also drops the RTIO channel claims, reinitializes the rpc queue and
+    // applies the safe state, like the end of any other kernel
+    unsafe { kern_stop() }
+    session.set_kernel_state(KernelState::Absent);
+    session.cleanup_registered = false;
+    session.cleanup_deadline = None;
+    session.library = None;
+    session.pending_load = None;
+    session.repeat = None;
+    session.congress.cache = Cache::new();
+    session.congress.last_exception = None;
+    session.congress.rtio_replay = None;
+
+    let busy_after = unsafe { ::ALLOC.busy() };
+    let freed = busy_before.saturating_sub(busy_after);
+    info!("kernel state reset, {} bytes freed", freed);
+    freed
+}
+
+fn kern_repeat(io: &Io, stream: &mut TcpStream, session: &mut Session) -> Result<(), Error<SchedError>> {
+    let run = session.repeat.take().unwrap();
+    if run.remaining() > 0 {
+        // the kernel CPU is reset after every run, so the library has to be loaded again
+        let library = session.library.take().unwrap();
+        let result = unsafe { kern_load(io, session, &library) }.and_then(|()| kern_run(session));
+        session.library = Some(library);
+        match result {
+            Ok(()) => {
+                session.repeat = Some(run);
+                return Ok(())
+            }
+            Err(error) => error!("cannot restart kernel for repeated run: {}", error)
+        }
+    }
+    host_write(io, stream, &mut session.proto, run.summary())?;
+    Ok(())
+}
+
 
 fn process_flash_kernel(io: &Io, _aux_mutex: &Mutex, _subkernel_mutex: &Mutex, _ddma_mutex: &Mutex,
                         _routing_table: &drtio_routing::RoutingTable,
@@ -374,33 +715,218 @@ fn process_flash_kernel(io: &Io, _aux_mutex: &Mutex, _subkernel_mutex: &Mutex, _
 }
 
 fn process_host_message(io: &Io, _aux_mutex: &Mutex, _ddma_mutex: &Mutex, _subkernel_mutex: &Mutex,
-                        _routing_table: &drtio_routing::RoutingTable, stream: &mut TcpStream,
+                        _routing_table: &drtio_routing::RoutingTable,
+                        _up_destinations: &Urc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
+                        stream: &mut TcpStream,
                         session: &mut Session) -> Result<(), Error<SchedError>> {
-    match host_read(stream)? {
+    match host_read(stream, &mut session.proto)? {
         host::Request::SystemInfo => {
-            host_write(stream, host::Reply::SystemInfo {
+            host_write(io, stream, &mut session.proto, host::Reply::SystemInfo {
                 ident: ident::read(&mut [0; 64]),
-                finished_cleanly: session.congress.finished_cleanly.get()
+                finished_cleanly: session.congress.finished_cleanly.get(),
+                last_run_outcome: session.congress.last_run_outcome.get()
             })?;
-            session.congress.finished_cleanly.set(true)
+            session.congress.finished_cleanly.set(true);
+            session.congress.last_run_outcome.set(host::LastRunOutcome::Clean)
+        }
+        host::Request::NegotiateVersion { host_version } => {
+            let version = session.proto.negotiate(host_version);
+            info!("negotiated session protocol version {}", version);
+            host_write(io, stream, &mut session.proto, host::Reply::VersionNegotiated {
+                version: version,
+                features: features()
+            })?
+        }
+        host::Request::RtioErrorDetail => {
+            let errors = take_error_details();
+            host_write(io, stream, &mut session.proto, host::Reply::RtioErrorDetail(&errors))?
+        }
+        host::Request::TaskDump => {
+            let tasks: Vec<host::TaskInfo> = io.tasks().iter().map(|task| host::TaskInfo {
+                name: task.name,
+                state: match task.state {
+                    TaskState::Running  => host::TaskState::Running,
+                    TaskState::Ready    => host::TaskState::Ready,
+                    TaskState::Sleeping => host::TaskState::Sleeping,
+                    TaskState::Dead     => host::TaskState::Dead
+                },
+                stack_high_water: task.stack_high_water as u32,
+                stack_size: task.stack_size as u32,
+                run_count: task.run_count,
+                runtime_us: task.runtime_us
+            }).collect();
+            host_write(io, stream, &mut session.proto, host::Reply::TaskDump(&tasks))?
+        }
+        host::Request::SyncTime { host_unix_ms } => {
+            if clock::set_unix_ms(host_unix_ms) {
+                info!("synchronized to host time");
+            } else {
+                warn!("ignoring out of range host time {} ms", host_unix_ms);
+            }
         }
 
-        host::Request::LoadKernel(kernel) => {
-            match unsafe { kern_load(io, session, &kernel) } {
-                Ok(()) => host_write(stream, host::Reply::LoadCompleted)?,
+        host::Request::LoadKernel(kernel) =>
+            host_load_kernel(io, stream, session, kernel)?,
+        host::Request::LoadKernelCompressed { algorithm, uncompressed_len, data } => {
+            // a declared length is checked up front, before any memory is spent on it
+            let result = match uncompressed_len {
+                Some(len) if len as usize > host::MAX_KERNEL_SIZE =>
+                    Err(decompress::Error::TooLarge(host::MAX_KERNEL_SIZE)),
+                Some(len) => decompress::decompress_exact(algorithm, &data, len as usize),
+                None => decompress::decompress(algorithm, &data, host::MAX_KERNEL_SIZE)
+            };
+            match result {
+                Ok(kernel) => host_load_kernel(io, stream, session, kernel)?,
+                Err(error) => {
+                    let mut description = String::new();
+                    write!(&mut description, "cannot decompress kernel: {}", error).unwrap();
+                    host_write(io, stream, &mut session.proto, host::Reply::LoadFailed(&description))?
+                }
+            }
+        }
+        host::Request::LoadKernelChunk { total, data } => {
+            if session.pending_load.is_none() {
+                match host::ChunkedLoad::new(total as usize, host::MAX_KERNEL_SIZE, load_progress_interval()) {
+                    Ok(load) => session.pending_load = Some(load),
+                    Err(error) => {
+                        host_write(io, stream, &mut session.proto, host::Reply::LoadFailed(error))?;
+                        return Ok(())
+                    }
+                }
+            }
+            let (status, total) = {
+                let pending = session.pending_load.as_mut().unwrap();
+                (pending.push(&data), pending.total())
+            };
+            match status {
+                Ok(host::ChunkStatus::Pending) => (),
+                Ok(host::ChunkStatus::Progress(received)) =>
+                    host_write(io, stream, &mut session.proto, host::Reply::LoadProgress {
+                        received: received as u32,
+                        total: total as u32
+                    })?,
+                Ok(host::ChunkStatus::Complete) => {
+                    let kernel = session.pending_load.take().unwrap().into_data();
+                    host_load_kernel(io, stream, session, kernel)?
+                }
+                Err(error) => {
+                    session.pending_load = None;
+                    host_write(io, stream, &mut session.proto, host::Reply::LoadFailed(error))?
+                }
+            }
+        }
+        host::Request::LoadFlatKernel { image, entry_offset, load_addr } => {
+            let result = kern::validate_flat_image(
+                    image.len(), entry_offset as usize, load_addr as usize)
+                .map_err(|error| Error::Load(error.to_string()))
+                .and_then(|()| unsafe {
+                    kern_load_request(io, session, &kern::LoadFlatRequest {
+                        image: &image,
+                        entry_offset: entry_offset as usize,
+                        load_addr: load_addr as usize
+                    })
+                });
+            match result {
+                Ok(()) => {
+                    host_write(io, stream, &mut session.proto, host::Reply::LoadCompleted { metadata: &[], evicted: &[] })?;
+                    // no ELF to reload, so repeated runs are not possible
+                    session.library = None
+                }
                 Err(error) => {
                     let mut description = String::new();
                     write!(&mut description, "{}", error).unwrap();
-                    host_write(stream, host::Reply::LoadFailed(&description))?;
+                    host_write(io, stream, &mut session.proto, host::Reply::LoadFailed(&description))?;
                     kern_acknowledge()?;
                 }
             }
-        },
-        host::Request::RunKernel =>
+        }
+        host::Request::AbortLoad => {
+            // drops the partially assembled image, if any
+            session.pending_load = None;
+            host_write(io, stream, &mut session.proto, host::Reply::LoadFailed("load aborted"))?
+        }
+        host::Request::CacheKernel(kernel) => {
+            match write_cached_kernel(&kernel) {
+                Ok(()) => host_write(io, stream, &mut session.proto, host::Reply::LoadCompleted {
+                    metadata: kernel_metadata(&kernel),
+                    evicted: &[]
+                })?,
+                Err(error) => {
+                    let mut description = String::new();
+                    write!(&mut description, "cannot cache kernel: {}", error).unwrap();
+                    host_write(io, stream, &mut session.proto, host::Reply::LoadFailed(&description))?
+                }
+            }
+        }
+        host::Request::RunCachedKernel => {
+            match read_cached_kernel() {
+                Ok(library) => match unsafe { kern_load(io, session, &library) } {
+                    Ok(()) => {
+                        host_write(io, stream, &mut session.proto, host::Reply::LoadCompleted {
+                            metadata: kernel_metadata(&library),
+                            evicted: &[]
+                        })?;
+                        session.library = Some(library);
+                        match kern_run(session) {
+                            Ok(()) => (),
+                            Err(_) => host_write(io, stream, &mut session.proto, host::Reply::KernelStartupFailed)?
+                        }
+                    }
+                    Err(error) => {
+                        let mut description = String::new();
+                        write!(&mut description, "{}", error).unwrap();
+                        host_write(io, stream, &mut session.proto, host::Reply::LoadFailed(&description))?;
+                        kern_acknowledge()?;
+                    }
+                },
+                Err(reason) => host_write(io, stream, &mut session.proto, host::Reply::LoadFailed(reason))?
+            }
+        }
+        host::Request::RunSelfKernel => {
+            let passed = kern_self_test(io, session)?;
+            host_write(io, stream, &mut session.proto, host::Reply::SelfKernelResult { passed: passed })?
+        }
+        host::Request::RunKernel => {
+            session.congress.last_exception = None;
             match kern_run(session) {
                 Ok(()) => (),
-                Err(_) => host_write(stream, host::Reply::KernelStartupFailed)?
-            },
+                Err(_) => host_write(io, stream, &mut session.proto, host::Reply::KernelStartupFailed)?
+            }
+        }
+        host::Request::SessionState =>
+            host_write(io, stream, &mut session.proto, host::Reply::SessionState {
+                kernel_state: session.kernel_state.code(),
+                loading: session.pending_load.is_some()
+            })?,
+
+        host::Request::GetLastException => {
+            let last_exception = session.congress.last_exception.as_ref().map(|e| &e[..]);
+            host_write(io, stream, &mut session.proto, host::Reply::LastException(last_exception))?
+        }
+        host::Request::PullStoredAnalyzer { index: _index } => {
+            #[cfg(has_rtio_analyzer)]
+            {
+                let capture = analyzer::stored_capture(_index);
+                host_write(io, stream, &mut session.proto, host::Reply::StoredAnalyzer {
+                    full: analyzer::store_full(),
+                    capture: capture.as_ref().map(|capture| &capture[..])
+                })?
+            }
+            #[cfg(not(has_rtio_analyzer))]
+            host_write(io, stream, &mut session.proto, host::Reply::StoredAnalyzer { full: false, capture: None })?
+        }
+        host::Request::RunKernelRepeated { count, stop_on_error } => {
+            if count == 0 {
+                host_write(io, stream, &mut session.proto, host::Reply::AllRunsComplete { succeeded: 0, failed: 0 })?
+            } else if session.library.is_none() {
+                host_write(io, stream, &mut session.proto, host::Reply::KernelStartupFailed)?
+            } else {
+                match kern_run(session) {
+                    Ok(()) => session.repeat = Some(host::RepeatedRun::new(count, stop_on_error)),
+                    Err(_) => host_write(io, stream, &mut session.proto, host::Reply::KernelStartupFailed)?
+                }
+            }
+        }
 
         host::Request::RpcReply { tag } => {
             if session.kernel_state != KernelState::RpcWait {
@@ -431,7 +957,8 @@ fn process_host_message(io: &Io, _aux_mutex: &Mutex, _ddma_mutex: &Mutex, _subke
             })?;
             kern_send(io, &kern::RpcRecvReply(Ok(0)))?;
 
-            session.kernel_state = KernelState::Running
+            rpc_trace(session, rpc::TraceRecord::Return { tag: &tag });
+            session.set_kernel_state(KernelState::Running)
         }
 
         host::Request::RpcException {
@@ -449,39 +976,131 @@ fn process_host_message(io: &Io, _aux_mutex: &Mutex, _ddma_mutex: &Mutex, _subke
                 }
             })?;
 
-            unsafe {
-                let exn = eh::eh_artiq::Exception {
-                    id:       id,
-                    message:  CSlice::new(message as *const u8, usize::MAX),
-                    param:    param,
-                    file:     CSlice::new(file as *const u8, usize::MAX),
-                    line:     line,
-                    column:   column,
-                    function: CSlice::new(function as *const u8, usize::MAX),
-                };
-                kern_send(io, &kern::RpcRecvReply(Err(exn)))?;
-            }
-
-            session.kernel_state = KernelState::Running
+            let exn = eh::eh_artiq::Exception {
+                id:       id,
+                message:  host::host_string(message),
+                param:    param,
+                file:     host::host_string(file),
+                line:     line,
+                column:   column,
+                function: host::host_string(function),
+            };
+            rpc_trace(session, rpc::TraceRecord::Exception { id: id });
+            kern_send(io, &kern::RpcRecvReply(Err(exn)))?;
+
+            session.set_kernel_state(KernelState::Running)
         }
 
-        host::Request::UploadSubkernel { id: _id, destination: _dest, kernel: _kernel } => {
+        host::Request::UploadSubkernel { id: _id, destination: _dest, kernel: _kernel, crc32 } => {
+            // a corrupted kernel would otherwise only fail once it runs on the satellite
+            if let Err(mismatch) = host::check_subkernel_crc32(&_kernel, crc32) {
+                warn!("subkernel {} failed its checksum: {}", _id, mismatch);
+                let mut reason = String::new();
+                write!(&mut reason, "{}", mismatch).unwrap();
+                host_write(io, stream, &mut session.proto, host::Reply::UploadFailed { id: _id, reason: &reason })?;
+                return Ok(())
+            }
+
             #[cfg(has_drtio)]
             {
                 subkernel::add_subkernel(io, _subkernel_mutex, _id, _dest, _kernel)?;
                 match subkernel::upload(io, _aux_mutex, _ddma_mutex, _subkernel_mutex, _routing_table, _id) {
-                    Ok(_) => host_write(stream, host::Reply::LoadCompleted)?,
+                    Ok(uploaded) => host_write(io, stream, &mut session.proto, host::Reply::LoadCompleted {
+                        metadata: &[],
+                        evicted: &uploaded.evicted
+                    })?,
                     Err(error) => {
                         subkernel::clear_subkernels(io, _subkernel_mutex)?;
                         let mut description = String::new();
                         write!(&mut description, "{}", error).unwrap();
-                        host_write(stream, host::Reply::LoadFailed(&description))?
+                        host_write(io, stream, &mut session.proto, host::Reply::LoadFailed(&description))?
                     }
                 }
             }
             #[cfg(not(has_drtio))]
-            host_write(stream, host::Reply::LoadFailed("No DRTIO on this system, subkernels are not supported"))?
+            host_write(io, stream, &mut session.proto, host::Reply::LoadFailed("No DRTIO on this system, subkernels are not supported"))?
+        }
+
+        host::Request::StartSubkernelSynced { id: _id, destinations, at_timestamp: _at_timestamp } => {
+            #[cfg(has_drtio)]
+            {
+                let failed = subkernel::start_synced(io, _aux_mutex, _ddma_mutex, _subkernel_mutex, _routing_table,
+                    _id, &destinations, _at_timestamp)?;
+                host_write(io, stream, &mut session.proto, host::Reply::SubkernelsStarted { failed: &failed })?
+            }
+            #[cfg(not(has_drtio))]
+            host_write(io, stream, &mut session.proto, host::Reply::SubkernelsStarted { failed: &destinations })?
         }
+
+        host::Request::DrtioPing { destination: _destination } => {
+            #[cfg(has_drtio)]
+            let round_trip_us = match drtio::round_trip_time(io, _aux_mutex, _ddma_mutex, _subkernel_mutex,
+                    _routing_table, _up_destinations, _destination) {
+                Ok(round_trip_us) => Some(round_trip_us),
+                Err(drtio::Error::SchedError(e)) => return Err(Error::from(e)),
+                Err(e) => {
+                    warn!("cannot ping destination {}: {}", _destination, e);
+                    None
+                }
+            };
+            #[cfg(not(has_drtio))]
+            let round_trip_us = None;
+            host_write(io, stream, &mut session.proto, host::Reply::DrtioRoundTrip { round_trip_us: round_trip_us })?
+        }
+
+        host::Request::DrtioBufferStatus { destination: _destination } => {
+            #[cfg(has_drtio)]
+            let status = if session.running() {
+                warn!("cannot query DRTIO buffers while a kernel is running");
+                None
+            } else {
+                match drtio::buffer_status(_routing_table, _up_destinations, _destination) {
+                    Ok(status) => Some(status),
+                    Err(e) => {
+                        warn!("cannot query buffers of destination {}: {}", _destination, e);
+                        None
+                    }
+                }
+            };
+            #[cfg(not(has_drtio))]
+            let status = None;
+            host_write(io, stream, &mut session.proto, host::Reply::DrtioBufferStatus(status))?
+        }
+
+        host::Request::ResetKernelState => {
+            let freed = if session.running() {
+                warn!("cannot reset kernel state while a kernel is running");
+                None
+            } else {
+                Some(reset_kernel_state(session) as u32)
+            };
+            host_write(io, stream, &mut session.proto, host::Reply::KernelStateReset(freed))?
+        }
+
+        host::Request::PullRtioReplay => {
+            let replay = session.congress.rtio_replay.as_ref()
+                .map(|&(ref events, truncated)| (&events[..], truncated));
+            host_write(io, stream, &mut session.proto, host::Reply::RtioReplay(replay))?
+        }
+
+        // answered in any kernel state, so the host can tell a busy kernel from a hung board
+        host::Request::SubscribeLog { min_level } => {
+            let max_level = host::log_subscription(min_level);
+            BufferLogger::with(|logger| match max_level {
+                Some(max_level) => logger.subscribe(max_level),
+                None => logger.unsubscribe()
+            });
+            session.log_subscribed = max_level.is_some()
+        }
+
+        host::Request::Ping =>
+            host_write(io, stream, &mut session.proto, host::Reply::Pong { uptime_ms: clock::get_ms() as u32 })?,
+
+        host::Request::RpcBufferInfo =>
+            host_write(io, stream, &mut session.proto, host::Reply::RpcBufferInfo {
+                slot_bytes: rpc_queue::SLOT_PAYLOAD_BYTES as u32,
+                max_fragments: rpc_queue::MAX_FRAGMENTS as u32
+            })?,
     }
 
     Ok(())
@@ -599,10 +1218,11 @@ fn process_kern_message(io: &Io, aux_mutex: &Mutex,
                 match stream {
                     None => unexpected!("unexpected RPC in flash kernel"),
                     Some(ref mut stream) => {
-                        host_write(stream, host::Reply::RpcRequest { async: async })?;
+                        rpc_trace(session, rpc::TraceRecord::Call { service: service, tag: tag, async: async });
+                        host_write(io, stream, &mut session.proto, host::Reply::RpcRequest { async: async })?;
                         rpc::send_args(stream, service, tag, data, true)?;
                         if !async {
-                            session.kernel_state = KernelState::RpcWait
+                            session.set_kernel_state(KernelState::RpcWait)
                         }
                         kern_acknowledge()
                     }
@@ -615,6 +1235,31 @@ fn process_kern_message(io: &Io, aux_mutex: &Mutex,
                 kern_acknowledge()
             },
 
+            &kern::RtioChannelClaimRequest { channel, claim } => {
+                if claim {
+                    rtio_claims::claim(channel)
+                } else {
+                    rtio_claims::release(channel)
+                }
+                kern_acknowledge()
+            }
+
+            &kern::StartTimestampRequest => {
+                kern_send(io, &kern::StartTimestampReply { timestamp: None })
+            }
+
+            &kern::RtioReplayConfigRequest => {
+                kern_send(io, &kern::RtioReplayConfigReply { enabled: session.rtio_replay })
+            }
+
+            &kern::RtioReplayLog { events, truncated } => {
+                if truncated {
+                    warn!("RTIO replay log truncated to {} events", events.len());
+                }
+                session.congress.rtio_replay = Some((events.to_vec(), truncated));
+                kern_acknowledge()
+            }
+
             &kern::CacheGetRequest { key } => {
                 let value = session.congress.cache.get(key);
                 kern_send(io, &kern::CacheGetReply {
@@ -629,9 +1274,23 @@ fn process_kern_message(io: &Io, aux_mutex: &Mutex,
                 kern_send(io, &kern::CachePutReply { succeeded: succeeded })
             }
 
+            &kern::CleanupRegistered => {
+                session.cleanup_registered = true;
+                kern_acknowledge()
+            }
+
+            &kern::CleanupStarted => {
+                warn!("kernel aborted, running its cleanup handler");
+                session.cleanup_deadline = Some(clock::get_ms().wrapping_add(CLEANUP_TIMEOUT_MS));
+                kern_acknowledge()
+            }
+
             &kern::RunFinished => {
-                unsafe { kernel::stop() }
-                session.kernel_state = KernelState::Absent;
+                unsafe { kern_stop() }
+                session.set_kernel_state(KernelState::Absent);
+                session.record_run(true);
+                #[cfg(has_rtio_analyzer)]
+                analyzer::store_capture();
                 unsafe { session.congress.cache.unborrow() }
                 #[cfg(has_drtio)]
                 subkernel::clear_subkernels(io, subkernel_mutex)?;
@@ -639,7 +1298,7 @@ fn process_kern_message(io: &Io, aux_mutex: &Mutex,
                 match stream {
                     None => return Ok(true),
                     Some(ref mut stream) =>
-                        host_write(stream, host::Reply::KernelFinished {
+                        host_write(io, stream, &mut session.proto, host::Reply::KernelFinished {
                             async_errors: unsafe { get_async_errors() }
                         }).map_err(|e| e.into())
                 }
@@ -649,12 +1308,33 @@ fn process_kern_message(io: &Io, aux_mutex: &Mutex,
                 stack_pointers,
                 backtrace
             } => {
-                unsafe { kernel::stop() }
-                session.kernel_state = KernelState::Absent;
+                unsafe { kern_stop() }
+                session.set_kernel_state(KernelState::Absent);
+                session.cleanup_deadline = None;
+                session.record_run(false);
+                #[cfg(has_rtio_analyzer)]
+                analyzer::store_capture();
                 unsafe { session.congress.cache.unborrow() }
                 #[cfg(has_drtio)]
                 subkernel::clear_subkernels(io, subkernel_mutex)?;
 
+                let library = session.library.as_ref();
+                let source_snippets = exceptions.iter()
+                    .map(|exception| match (library, exception.as_ref()) {
+                        (Some(library), Some(exception)) => source_snippet(library, exception),
+                        _ => None
+                    })
+                    .collect::<Vec<_>>();
+                let reply = host::Reply::KernelException {
+                    exceptions: exceptions,
+                    source_snippets: &source_snippets,
+                    stack_pointers: stack_pointers,
+                    backtrace: backtrace,
+                    // leave async errors of flash kernels for the next host kernel
+                    async_errors: if stream.is_some() { unsafe { get_async_errors() } } else { 0 }
+                };
+                session.congress.last_exception = reply.to_last_exception(&session.proto);
+
                 match stream {
                     None => {
                         error!("exception in flash kernel");
@@ -663,14 +1343,8 @@ fn process_kern_message(io: &Io, aux_mutex: &Mutex,
                         }
                         return Ok(true)
                     },
-                    Some(ref mut stream) => {
-                        host_write(stream, host::Reply::KernelException {
-                            exceptions: exceptions,
-                            stack_pointers: stack_pointers,
-                            backtrace: backtrace,
-                            async_errors: unsafe { get_async_errors() }
-                        }).map_err(|e| e.into())
-                    }
+                    Some(ref mut stream) =>
+                        host_write(io, stream, &mut session.proto, reply).map_err(|e| e.into())
                 }
             }
             #[cfg(has_drtio)]
@@ -785,18 +1459,40 @@ fn process_kern_message(io: &Io, aux_mutex: &Mutex,
     })
 }
 
-fn process_kern_queued_rpc(stream: &mut TcpStream,
-                           _session: &mut Session) -> Result<(), Error<SchedError>> {
+fn process_kern_queued_rpc(io: &Io, stream: &mut TcpStream,
+                           session: &mut Session) -> Result<(), Error<SchedError>> {
     rpc_queue::dequeue(|slice| {
         debug!("comm<-kern (async RPC)");
         let length = NativeEndian::read_u32(slice) as usize;
-        host_write(stream, host::Reply::RpcRequest { async: true })?;
+        rpc_trace(session, rpc::TraceRecord::Queued(&slice[4..][..length]));
+        host_write(io, stream, &mut session.proto, host::Reply::RpcRequest { async: true })?;
         debug!("{:?}", &slice[4..][..length]);
         stream.write_all(&slice[4..][..length])?;
         Ok(())
     })
 }
 
+struct RtioClock;
+
+impl host::ClockLock for RtioClock {
+    fn locked(&mut self) -> bool {
+        rtio_clocking::crg::check()
+    }
+
+    // Lock is checked on every pass of the kernel worker loops, so the loss
+    // happened at most one scheduler iteration before this.
+    fn now_us(&mut self) -> u64 {
+        clock::get_unix_us().unwrap_or(clock::get_us())
+    }
+}
+
+fn forward_log(io: &Io, stream: &mut TcpStream, proto: &mut host::Context) -> Result<(), Error<SchedError>> {
+    for (level, message) in BufferLogger::with(|logger| logger.take_live_records()) {
+        host_write(io, stream, proto, host::Reply::Log { level: level as u8, message: &message })?
+    }
+    Ok(())
+}
+
 fn host_kernel_worker(io: &Io, aux_mutex: &Mutex,
                       routing_table: &drtio_routing::RoutingTable,
                       up_destinations: &Urc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
@@ -810,13 +1506,18 @@ fn host_kernel_worker(io: &Io, aux_mutex: &Mutex,
     loop {
         if stream.can_recv() {
             process_host_message(io, aux_mutex, ddma_mutex, subkernel_mutex,
-                routing_table, stream, &mut session)?
+                routing_table, up_destinations, stream, &mut session)?;
+            session.proto.end_request()
         } else if !stream.may_recv() {
             return Ok(())
         }
 
-        while !rpc_queue::empty() {
-            process_kern_queued_rpc(stream, &mut session)?
+        while !rpc_queue::empty()? {
+            process_kern_queued_rpc(io, stream, &mut session)?
+        }
+
+        if session.log_subscribed {
+            forward_log(io, stream, &mut session.proto)?
         }
 
         if mailbox::receive() != 0 {
@@ -825,11 +1526,16 @@ fn host_kernel_worker(io: &Io, aux_mutex: &Mutex,
                 ddma_mutex, subkernel_mutex,
                 Some(stream), &mut session)?;
         }
+        check_cleanup_deadline(&mut session)?;
+
+        if session.kernel_state == KernelState::Absent && session.repeat.is_some() {
+            kern_repeat(io, stream, &mut session)?
+        }
 
         if session.kernel_state == KernelState::Running {
-            if !rtio_clocking::crg::check() {
-                host_write(stream, host::Reply::ClockFailure)?;
-                return Err(Error::ClockFailure)
+            if let Err(lost_at_us) = host::check_clock_lock(&mut RtioClock) {
+                host_write(io, stream, &mut session.proto, host::Reply::ClockFailure { lost_at_us: lost_at_us })?;
+                return Err(Error::ClockFailure(lost_at_us))
             }
         }
 
@@ -863,7 +1569,7 @@ fn flash_kernel_worker(io: &Io, aux_mutex: &Mutex,
     kern_run(&mut session)?;
 
     loop {
-        if !rpc_queue::empty() {
+        if !rpc_queue::empty()? {
             unexpected!("unexpected background RPC in flash kernel")
         }
 
@@ -872,15 +1578,18 @@ fn flash_kernel_worker(io: &Io, aux_mutex: &Mutex,
                 return Ok(())
             }
         }
+        check_cleanup_deadline(&mut session)?;
 
-        if !rtio_clocking::crg::check() {
-            return Err(Error::ClockFailure)
-        }
+        host::check_clock_lock(&mut RtioClock).map_err(Error::ClockFailure)?;
 
         io.relinquish()?
     }
 }
 
+/// Scheduler priority of the session threads, which carry the RPCs and
+/// replies users wait on, above bulk transfers such as the analyzer's.
+pub const PRIORITY: u8 = 1;
+
 fn respawn<F>(io: &Io, handle: &mut Option<ThreadHandle>, f: F)
         where F: 'static + FnOnce(Io) + Send {
     match handle.take() {
@@ -893,7 +1602,7 @@ fn respawn<F>(io: &Io, handle: &mut Option<ThreadHandle>, f: F)
         }
     }
 
-    *handle = Some(io.spawn(32768, f))
+    *handle = Some(io.spawn_with_priority("kernel", 32768, PRIORITY, f))
 }
 
 pub fn thread(io: Io, aux_mutex: &Mutex,
@@ -904,7 +1613,12 @@ pub fn thread(io: Io, aux_mutex: &Mutex,
     listener.listen(1381).expect("session: cannot listen");
     info!("accepting network sessions");
 
-    let congress = Urc::new(RefCell::new(Congress::new()));
+    let last_run_outcome = mgmt::record_boot();
+    if last_run_outcome != host::LastRunOutcome::Clean {
+        warn!("previous run did not shut down cleanly ({:?}), check the UART log for a panic message",
+              last_run_outcome);
+    }
+    let congress = Urc::new(RefCell::new(Congress::new(last_run_outcome)));
 
     let mut kernel_thread = None;
     {
@@ -919,6 +1633,9 @@ pub fn thread(io: Io, aux_mutex: &Mutex,
                 info!("no startup kernel found"),
             Err(err) => {
                 congress.finished_cleanly.set(false);
+                if let Error::ClockFailure(_) = err {
+                    congress.last_run_outcome.set(host::LastRunOutcome::ClockFailure);
+                }
                 error!("startup kernel aborted: {}", err);
             }
         }
@@ -962,16 +1679,19 @@ pub fn thread(io: Io, aux_mutex: &Mutex,
                 match host_kernel_worker(&io, &aux_mutex, &routing_table, &up_destinations, 
                         &ddma_mutex, &subkernel_mutex, &mut stream, &mut *congress) {
                     Ok(()) => (),
-                    Err(Error::Protocol(host::Error::Io(IoError::UnexpectedEnd))) =>
+                    Err(Error::Protocol(host::Error::Io(IoError::UnexpectedEof))) =>
                         info!("connection closed"),
                     Err(Error::Protocol(host::Error::Io(
-                            IoError::Other(SchedError::Interrupted)))) => {
+                            IoError::Transport(SchedError::Interrupted)))) => {
                         info!("kernel interrupted");
                         #[cfg(has_drtio)]
                         drtio::clear_buffers(&io, &aux_mutex);
                     }
                     Err(err) => {
                         congress.finished_cleanly.set(false);
+                        if let Error::ClockFailure(_) = err {
+                            congress.last_run_outcome.set(host::LastRunOutcome::ClockFailure);
+                        }
                         error!("session aborted: {}", err);
                         #[cfg(has_drtio)]
                         drtio::clear_buffers(&io, &aux_mutex);
@@ -1004,7 +1724,7 @@ pub fn thread(io: Io, aux_mutex: &Mutex,
                     Ok(()) =>
                         info!("idle kernel finished, standing by"),
                     Err(Error::Protocol(host::Error::Io(
-                            IoError::Other(SchedError::Interrupted)))) => {
+                            IoError::Transport(SchedError::Interrupted)))) => {
                         info!("idle kernel interrupted");
                         // clear state for regular kernel
                         #[cfg(has_drtio)]
@@ -8,6 +8,7 @@ use proto_artiq::{
     drtioaux_proto::PayloadStatus,
     kernel_proto as kern, 
     session_proto::Reply::KernelException as HostKernelException, 
+    moninj_proto::ChannelClaims,
     rpc_proto as rpc};
 use eh::eh_artiq;
 use io::Cursor;
@@ -43,6 +44,7 @@ mod kernel_cpu {
                                 (KERNELCPU_EXEC_ADDRESS - KSUPPORT_HEADER_SIZE) as *mut u8,
                                 ksupport_end as usize - ksupport_start as usize);
 
+        mailbox::clear_abort();
         csr::kernel_cpu::reset_write(0);
     }
 
@@ -136,8 +138,10 @@ struct Session {
     log_buffer: String,
     last_exception: Option<Sliceable>,
     source: u8, // which destination requested running the kernel
+    start_timestamp: Option<i64>,
     messages: MessageManager,
-    subkernels_finished: Vec<u32> // ids of subkernels finished
+    subkernels_finished: Vec<u32>, // ids of subkernels finished
+    claims: ChannelClaims
 }
 
 #[derive(Debug)]
@@ -278,8 +282,10 @@ impl Session {
             log_buffer: String::new(),
             last_exception: None,
             source: 0,
+            start_timestamp: None,
             messages: MessageManager::new(),
-            subkernels_finished: Vec::new()
+            subkernels_finished: Vec::new(),
+            claims: ChannelClaims::new()
         }
     }
 
@@ -342,6 +348,20 @@ impl Manager {
         Ok(())
     }
 
+    /// Frees a subkernel the master has evicted; a running one is kept.
+    pub fn remove(&mut self, id: u32) -> Result<(), Error> {
+        if self.current_id == id && self.is_running() {
+            unexpected!("cannot remove running subkernel #{}", id)
+        }
+        if self.kernels.remove(&id).is_some() {
+            info!("removed subkernel #{}", id);
+        }
+        if self.current_id == id && self.session.kernel_state == KernelState::Loaded {
+            self.stop();
+        }
+        Ok(())
+    }
+
     pub fn is_running(&self) -> bool {
         self.session.running()
     }
@@ -353,19 +373,29 @@ impl Manager {
         }
     }
 
+    /// Whether the running subkernel has claimed the (full) RTIO channel,
+    /// so that moninj must not inject into it.
+    pub fn is_channel_claimed(&self, channel: u32) -> bool {
+        self.session.claims.is_claimed(channel)
+    }
+
     pub fn stop(&mut self) {
         unsafe { kernel_cpu::stop() }
+        self.session.claims.release_all();
         self.session.kernel_state = KernelState::Absent;
         unsafe { self.cache.unborrow() }
     }
 
-    pub fn run(&mut self, source: u8, id: u32) -> Result<(), Error> {
+    /// Starts the subkernel; a non-zero `timestamp` sets the RTIO timeline
+    /// position it starts at, instead of letting it begin immediately.
+    pub fn run(&mut self, source: u8, id: u32, timestamp: u64) -> Result<(), Error> {
         info!("starting subkernel #{}", id);
         if self.session.kernel_state != KernelState::Loaded
             || self.current_id != id {
             self.load(id)?;
         }
         self.session.source = source;
+        self.session.start_timestamp = if timestamp == 0 { None } else { Some(timestamp as i64) };
         self.session.kernel_state = KernelState::Running;
         cricon_select(RtioMaster::Kernel);
     
@@ -448,6 +478,7 @@ impl Manager {
                 column:   column!(),
                 function: format!("subkernel id {}", self.current_id).as_c_slice(),
             })],
+            source_snippets: &[],
             stack_pointers: &[StackPointerBacktrace {
                 stack_pointer: 0,
                 initial_backtrace_size: 0,
@@ -692,6 +723,14 @@ impl Manager {
                     kern_acknowledge()
                 }
 
+                &kern::StartTimestampRequest => {
+                    kern_send(&kern::StartTimestampReply { timestamp: self.session.start_timestamp })
+                }
+
+                &kern::RtioReplayConfigRequest => {
+                    kern_send(&kern::RtioReplayConfigReply { enabled: false })
+                }
+
                 &kern::CacheGetRequest { key } => {
                     let value = self.cache.get(key);
                     kern_send(&kern::CacheGetReply {
@@ -704,6 +743,22 @@ impl Manager {
                     kern_send(&kern::CachePutReply { succeeded: succeeded })
                 }
 
+                &kern::CleanupRegistered => kern_acknowledge(),
+
+                &kern::RtioChannelClaimRequest { channel, claim } => {
+                    if claim {
+                        self.session.claims.claim(channel)
+                    } else {
+                        self.session.claims.release(channel)
+                    }
+                    kern_acknowledge()
+                }
+
+                &kern::CleanupStarted => {
+                    warn!("subkernel aborted, running its cleanup handler");
+                    kern_acknowledge()
+                }
+
                 &kern::RunFinished => {
                     unsafe { kernel_cpu::stop() }
                     self.session.kernel_state = KernelState::Absent;
@@ -796,7 +851,7 @@ impl Manager {
                 &kern::SubkernelLoadRunRequest { id, destination: sk_destination, run } => {
                     self.session.kernel_state = KernelState::SubkernelAwaitLoad;
                     router.route(drtioaux::Packet::SubkernelLoadRunRequest { 
-                        source: destination, destination: sk_destination, id: id, run: run 
+                        source: destination, destination: sk_destination, id: id, run: run, timestamp: 0
                     }, routing_table, rank, destination);
                     Ok(())
                 }
@@ -834,19 +889,15 @@ fn kern_recv<R, F>(f: F) -> Result<R, Error>
 }
 
 fn kern_recv_w_timeout<R, F>(timeout: u64, f: F) -> Result<R, Error>
-        where F: FnOnce(&kern::Message) -> Result<R, Error> + Copy {
+        where F: FnOnce(&kern::Message) -> Result<R, Error> {
     // sometimes kernel may be too slow to respond immediately
     // (e.g. when receiving external messages)
     // we cannot wait indefinitely to keep the satellite responsive
     // so a timeout is used instead
-    let max_time = clock::get_ms() + timeout;
-    while clock::get_ms() < max_time {
-        match kern_recv(f) {
-            Err(Error::NoMessage) => continue,
-            anything_else => return anything_else
-        }
+    match mailbox::receive_timeout(timeout) {
+        Some(_) => kern_recv(f),
+        None => Err(Error::NoMessage)
     }
-    Err(Error::NoMessage)
 }
 
 fn kern_acknowledge() -> Result<(), Error> {
@@ -875,6 +926,7 @@ fn slice_kernel_exception(exceptions: &[Option<eh_artiq::Exception>],
     let mut writer = Cursor::new(raw_exception);
     match (HostKernelException {
         exceptions: exceptions,
+        source_snippets: &[],
         stack_pointers: stack_pointers,
         backtrace: backtrace,
         async_errors: 0
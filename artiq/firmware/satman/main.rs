@@ -250,6 +250,11 @@ fn process_aux_packet(dmamgr: &mut DmaManager, analyzer: &mut Analyzer, kernelmg
             drtioaux::send(0, &drtioaux::Packet::RoutingAck)
         }
 
+        drtioaux::Packet::PingRequest { destination: _destination, timestamp } => {
+            forward!(_routing_table, _destination, *rank, _repeaters, &packet);
+            drtioaux::send(0, &drtioaux::Packet::PingReply { timestamp: timestamp })
+        }
+
         drtioaux::Packet::MonitorRequest { destination: _destination, channel, probe } => {
             forward!(_routing_table, _destination, *rank, _repeaters, &packet);
             let value;
@@ -269,6 +274,12 @@ fn process_aux_packet(dmamgr: &mut DmaManager, analyzer: &mut Analyzer, kernelmg
         },
         drtioaux::Packet::InjectionRequest { destination: _destination, channel, overrd, value } => {
             forward!(_routing_table, _destination, *rank, _repeaters, &packet);
+            // there is no reply to refuse with; the master's moninj already
+            // refuses channels claimed by its own kernel
+            if kernelmgr.is_channel_claimed((*self_destination as u32) << 16 | channel as u32) {
+                warn!("not injecting into channel {}, claimed by the running subkernel", channel);
+                return Ok(())
+            }
             #[cfg(has_rtio_moninj)]
             unsafe {
                 csr::rtio_moninj::inj_chan_sel_write(channel as _);
@@ -423,7 +434,13 @@ fn process_aux_packet(dmamgr: &mut DmaManager, analyzer: &mut Analyzer, kernelmg
             drtioaux::send(0,
                 &drtioaux::Packet::SubkernelAddDataReply { succeeded: succeeded })
         }
-        drtioaux::Packet::SubkernelLoadRunRequest { source, destination: _destination, id, run } => {
+        drtioaux::Packet::SubkernelRemoveRequest { destination: _destination, id } => {
+            forward!(_routing_table, _destination, *rank, _repeaters, &packet);
+            let succeeded = kernelmgr.remove(id).is_ok();
+            drtioaux::send(0,
+                &drtioaux::Packet::SubkernelRemoveReply { succeeded: succeeded })
+        }
+        drtioaux::Packet::SubkernelLoadRunRequest { source, destination: _destination, id, run, timestamp } => {
             forward!(_routing_table, _destination, *rank, _repeaters, &packet);
             let mut succeeded = kernelmgr.load(id).is_ok();
             // allow preloading a kernel with delayed run
@@ -432,7 +449,7 @@ fn process_aux_packet(dmamgr: &mut DmaManager, analyzer: &mut Analyzer, kernelmg
                     // cannot run kernel while DDMA is running
                     succeeded = false;
                 } else {
-                    succeeded |= kernelmgr.run(source, id).is_ok();
+                    succeeded |= kernelmgr.run(source, id, timestamp).is_ok();
                 }
             }
             router.send(drtioaux::Packet::SubkernelLoadRunReply { 
@@ -19,7 +19,8 @@ class AMPSoC:
         self.add_cpulevel_sdram_if(self.kernel_cpu.wb_sdram)
         self.csr_devices.append("kernel_cpu")
 
-        mailbox_size = 3
+        # message, async RPC queue send and receive pointers, abort request
+        mailbox_size = 4
         self.csr_separation = self.kernel_cpu.cpu_dw//8
 
         self.submodules.mailbox = Mailbox(mailbox_size, adr_width=32-log2_int(self.csr_separation))